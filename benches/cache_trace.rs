@@ -0,0 +1,77 @@
+//! Replays a trace of page accesses against each eviction policy and reports
+//! its hit rate.
+//!
+//!     cargo bench --bench cache_trace -- [TRACE_FILE] [CACHE_PAGES]
+//!
+//! A trace file contains page IDs separated by whitespace, in the order they
+//! were accessed. Without one, a synthetic workload is used: a small set of
+//! hot pages, accessed at random, interrupted by regular scans of a larger
+//! range of pages.
+
+extern crate soupdb;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::time::Instant;
+use soupdb::io::cache::PageTable;
+use soupdb::io::eviction::EvictionPolicyType;
+use soupdb::io::page::PAGE_SIZE;
+
+fn read_trace(path: &str) -> Vec<u64> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .unwrap_or_else(|e| panic!("couldn't read trace {}: {}", path, e));
+    contents.split_whitespace()
+        .map(|s| s.parse().unwrap_or_else(|_| panic!("bad page ID in trace: {}", s)))
+        .collect()
+}
+
+fn synthetic_trace() -> Vec<u64> {
+    // a linear congruential generator, so that every run replays the same
+    // trace
+    let mut seed: u64 = 0x5eed;
+    let mut random = move || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        seed >> 33
+    };
+    let mut trace = Vec::new();
+    for _ in 0 .. 20 {
+        for _ in 0 .. 2000 {
+            trace.push(random() % 240);
+        }
+        trace.extend(1000 .. 2000);
+    }
+    trace
+}
+
+fn main() {
+    // cargo passes --bench to benchmarks
+    let args: Vec<String> = env::args().skip(1).filter(|a| !a.starts_with("--")).collect();
+    let trace = match args.first() {
+        Some(path) => read_trace(path),
+        None => synthetic_trace(),
+    };
+    let pages: u64 = match args.get(1) {
+        Some(n) => n.parse().expect("cache size should be a number of pages"),
+        None => 256,
+    };
+
+    println!("{} accesses, {} cached pages", trace.len(), pages);
+    let policies = [
+        EvictionPolicyType::Lru,
+        EvictionPolicyType::Clock,
+        EvictionPolicyType::LruK(2),
+        EvictionPolicyType::LruK(3),
+    ];
+    for policy in &policies {
+        let table = PageTable::with_policy(pages * PAGE_SIZE, *policy);
+        let start = Instant::now();
+        let result = table.replay(&trace);
+        let elapsed = start.elapsed();
+        println!("{:<10} hits {:>9}  misses {:>9}  hit rate {:>6.2}%  {:>8.1} ms",
+            format!("{:?}", policy), result.hits, result.misses, result.hit_rate() * 100.0,
+            elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6);
+    }
+}
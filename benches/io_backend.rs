@@ -0,0 +1,70 @@
+//! Compares the buffered and memory-mapped I/O backends by repeatedly
+//! scanning a table.
+//!
+//!     cargo bench --bench io_backend -- [ROWS] [SCANS]
+//!
+//! The table is created and filled once for each backend, then the database
+//! is reopened so that every scan starts with an empty page cache, which is
+//! smaller than the table.
+
+extern crate soupdb;
+extern crate tempdir;
+
+use std::env;
+use std::time::Instant;
+use soupdb::ast::Identifier;
+use soupdb::ast::command::{Command, InsertValues};
+use soupdb::ast::parse::parse_command;
+use soupdb::ast::Expr;
+use soupdb::ast::value_type::ValueType;
+use soupdb::config::Config;
+use soupdb::db::Database;
+use soupdb::io::page::PAGE_SIZE;
+use soupdb::io::pool::IoBackend;
+use tempdir::TempDir;
+
+fn open(dir: &TempDir, backend: IoBackend) -> Database {
+    let mut config = Config::with_data_dir(dir.path().to_path_buf());
+    config.working_memory = PAGE_SIZE * 64;
+    config.io_backend = backend;
+    Database::new(format!("{:?}", backend), Some(config)).unwrap()
+}
+
+fn main() {
+    // cargo passes --bench to benchmarks
+    let args: Vec<String> = env::args().skip(1).filter(|a| !a.starts_with("--")).collect();
+    let rows: u64 = match args.first() {
+        Some(n) => n.parse().expect("rows should be a number"),
+        None => 200000,
+    };
+    let scans: u64 = match args.get(1) {
+        Some(n) => n.parse().expect("scans should be a number"),
+        None => 20,
+    };
+
+    let dir = TempDir::new("soupdb-bench").unwrap();
+    println!("{} rows, {} scans", rows, scans);
+    for backend in &[IoBackend::Buffered, IoBackend::Mapped] {
+        {
+            let mut db = open(&dir, *backend);
+            db.run_command(parse_command("create table t (a int, b float);").unwrap()).unwrap();
+            db.run_command(Command::Insert {
+                model: Identifier {name: "t".to_string(), qualifier: None},
+                cols: None,
+                values: InsertValues::Rows((0 .. rows).map(|i| vec![
+                    Expr::Literal {value_type: ValueType::Int, value: format!("{}", i)},
+                    Expr::Literal {value_type: ValueType::Float, value: format!("{}.5", i)},
+                ]).collect()),
+            }).unwrap();
+        }
+
+        let mut db = open(&dir, *backend);
+        let start = Instant::now();
+        for _ in 0 .. scans {
+            db.run_command(parse_command("select a from t where a < 0;").unwrap()).unwrap();
+        }
+        let elapsed = start.elapsed();
+        let ms = elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6;
+        println!("{:<10} {:>8.1} ms  {:>8.2} ms/scan", format!("{:?}", backend), ms, ms / scans as f64);
+    }
+}
@@ -1,9 +1,15 @@
 use std::collections::HashMap;
-use soupdb::io::dbfile::DbFile;
+use std::sync::Arc;
+use soupdb::Result;
+use soupdb::config::Config;
+use soupdb::db::Database;
+use soupdb::io::pool::BufferPool;
 
 pub struct SoupDbServer {
-    pub databases: HashMap<String, DbFile>,
+    pub databases: HashMap<String, Database>,
     pub current_db: Option<String>,
+    /// working memory shared by every open database
+    pub pool: Arc<BufferPool>,
 }
 
 impl SoupDbServer {
@@ -11,9 +17,19 @@ impl SoupDbServer {
         SoupDbServer {
             databases: HashMap::new(),
             current_db: None,
+            pool: Arc::new(BufferPool::from_config(&Config::new())),
         }
     }
 
+    /// Open a database, caching its pages in the server's buffer pool.
+    pub fn open_database(&mut self, name: &str) -> Result<()> {
+        if !self.databases.contains_key(name) {
+            let db = Database::with_pool(name.to_string(), None, self.pool.clone())?;
+            self.databases.insert(name.to_string(), db);
+        }
+        Ok(())
+    }
+
     pub fn run(self) {
 
     }
@@ -1,8 +1,32 @@
 use ::ast::{Expr, Identifier};
 use ::model::ModelType;
+use ::model::index::Index;
 
-pub type OrderByClause = Option<Vec<Expr>>;
-pub type LimitClause = Option<u64>;
+pub type OrderByClause = Option<Vec<OrderBy>>;
+pub type LimitClause = Option<Limit>;
+
+/// A key to sort by. Unless NULLS FIRST or LAST is given, nulls sort as
+/// smaller than every other value: first in ascending order, and last in
+/// descending order.
+#[derive(Debug, PartialEq)]
+pub struct OrderBy {
+    pub expr: Expr,
+    pub descending: bool,
+    pub nulls_first: bool,
+}
+
+impl OrderBy {
+    pub fn new(expr: Expr, descending: bool) -> OrderBy {
+        OrderBy {expr, descending, nulls_first: !descending}
+    }
+}
+
+/// Skip the first `offset` rows, then keep at most `count` of the rest.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Limit {
+    pub count: Option<u64>,
+    pub offset: u64,
+}
 
 #[derive(Debug, PartialEq)]
 pub enum SelectColumns {
@@ -10,6 +34,47 @@ pub enum SelectColumns {
     Named(Vec<(Expr, Option<String>)>),
 }
 
+/// A source of rows in a FROM clause: a model, which can be given an alias
+/// to qualify its columns with, or a join of two other sources.
+#[derive(Debug, PartialEq)]
+pub enum FromItem {
+    Model {name: String, alias: Option<String>},
+    Join {
+        left: Box<FromItem>,
+        right: Box<FromItem>,
+        join_type: JoinType,
+        condition: JoinCondition,
+    },
+}
+
+/// Which rows without a match an outer join keeps: a LEFT join keeps the
+/// unmatched rows of its left side, padded with nulls, and so on.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JoinType {
+    Inner,
+    Left,
+    Right,
+    Full,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JoinCondition {
+    On(Expr),
+    /// Match rows on columns with the same names on both sides, which are
+    /// merged into a single column.
+    Using(Vec<String>),
+    /// Match every row with every other row, as in a CROSS JOIN or a
+    /// comma-separated FROM list.
+    Cross,
+}
+
+/// The rows an INSERT adds: literal VALUES, or the result of a SELECT.
+#[derive(Debug)]
+pub enum InsertValues {
+    Rows(Vec<Vec<Expr>>),
+    Select(Box<Command>),
+}
+
 #[derive(Debug)]
 pub enum Command {
     // database commands
@@ -22,9 +87,11 @@ pub enum Command {
     // model commands
     CreateModel {name: String, schema: Box<ModelType>},
     DropModel {name: String},
+    CreateIndex {model: String, index: Index},
+    DropIndex {name: String, model: String},
     Select {
         cols: SelectColumns,
-        from: Option<Vec<(String, Option<String>)>>,
+        from: Option<Vec<FromItem>>,
         where_expr: Option<Expr>,
         group_by: Option<Vec<Expr>>,
         having: Option<Expr>,
@@ -41,14 +108,17 @@ pub enum Command {
     Insert {
         model: Identifier,
         cols: Option<Vec<Identifier>>,
-        values: Vec<Vec<Expr>>,
+        values: InsertValues,
     },
     Delete {
         model: Identifier,
         where_expr: Option<Expr>,
         order_by: OrderByClause,
         limit: LimitClause,
-    }
+    },
+
+    // introspection commands
+    ShowCacheStats,
 }
 
 impl PartialEq for Command {
@@ -1,27 +1,32 @@
-use nom::{IResult, digit};
+use nom::{Err, ErrorKind, IResult, digit};
 use ::{Error, Result};
 use ::ast::{Expr, BinaryOperator, UnaryOperator, Identifier};
-use ::ast::command::{Command, SelectColumns, OrderByClause, LimitClause};
+use ::ast::command::{Command, FromItem, InsertValues, JoinCondition, JoinType, SelectColumns, OrderBy, OrderByClause, Limit, LimitClause};
 use ::ast::binop::{ExprToken, shunting_yard};
 use ::ast::tuple::{TupleDef, TupleEntry};
 use ::ast::value_type::ValueType;
 use ::model::document::Document;
 use ::model::geohash::GeoHash;
 use ::model::graph::Graph;
+use ::model::index::Index;
 use ::model::table::Table;
 use ::model::timeseries::TimeSeries;
 
 // TODO
 static RESERVED_WORDS: &'static [&'static str] = &[
     "and",
+    "asc",
     "bool",
     "create",
+    "cross",
     "delete",
+    "desc",
     "document",
     "drop",
     "false",
     "float",
     "from",
+    "full",
     "geohash",
     "graph",
     "having",
@@ -29,14 +34,19 @@ static RESERVED_WORDS: &'static [&'static str] = &[
     "inner",
     "insert",
     "int",
+    "into",
     "join",
     "left",
+    "limit",
     "model",
     "not",
     "null",
     "nullable",
+    "nulls",
+    "offset",
     "on",
     "or",
+    "order",
     "outer",
     "right",
     "select",
@@ -45,8 +55,11 @@ static RESERVED_WORDS: &'static [&'static str] = &[
     "table",
     "timeseries",
     "true",
+    "unique",
     "unsigned",
     "update",
+    "using",
+    "values",
     "vector",
     "where",
 ];
@@ -242,10 +255,18 @@ named!(literal_expr_parser<&str, Expr>, alt_complete!(
     string_literal_expr_parser
 ));
 
+// word operators can't be the start of a longer word, like the OR of ORDER
+named!(word_binop_parser<&str, &str>, terminated!(
+    alt_complete!(
+        tag_no_case!("and") |
+        tag_no_case!("or")
+    ),
+    not!(complete!(is_a_s!("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_0123456789")))
+));
+
 named!(binop_parser<&str, BinaryOperator>, do_parse!(
     op: alt_complete!(
-        tag_no_case!("and") |
-        tag_no_case!("or") |
+        word_binop_parser |
         tag!("*") |
         tag!("/") |
         tag!("+") |
@@ -257,7 +278,7 @@ named!(binop_parser<&str, BinaryOperator>, do_parse!(
         tag!("<") |
         tag!(">")
     ) >>
-    (op.parse::<BinaryOperator>().unwrap())
+    (op.to_lowercase().parse::<BinaryOperator>().unwrap())
 ));
 
 named!(paren_expr_parser<&str, Expr>, ws!(do_parse!(
@@ -356,7 +377,22 @@ named!(create_timeseries<&str, Command>, ws!(do_parse!(
     (Command::CreateModel {name: name, schema: Box::new(TimeSeries {schema: tuple_def})})
 )));
 
+named!(create_index<&str, Command>, ws!(do_parse!(
+    tag_no_case!("CREATE") >>
+    unique: opt!(complete!(tag_no_case!("UNIQUE"))) >>
+    tag_no_case!("INDEX") >>
+    name: identifier >>
+    tag_no_case!("ON") >>
+    model: identifier >>
+    char!('(') >>
+    columns: separated_nonempty_list!(ws!(char!(',')), ws!(identifier)) >>
+    char!(')') >>
+    char!(';') >>
+    (Command::CreateIndex {model, index: Index {name, columns, unique: unique.is_some()}})
+)));
+
 named!(create_command_parser<&str, Command>, alt_complete!(
+    create_index |
     create_table |
     create_document |
     create_geohash |
@@ -402,49 +438,306 @@ named!(select_column_parser<&str, SelectColumns>, alt_complete!(
     select_specific_columns
 ));
 
-named!(from_spec_parser<&str, Vec<(String, Option<String>)>>, ws!(do_parse!(
-    names: ws!(separated_nonempty_list!(
+named!(from_model<&str, FromItem>, do_parse!(
+    model: aliased_identifier >>
+    (FromItem::Model {name: model.0, alias: model.1})
+));
+
+named!(from_parens<&str, FromItem>, ws!(do_parse!(
+    char!('(') >>
+    item: from_item_parser >>
+    char!(')') >>
+    (item)
+)));
+
+/// Parenthesized joins are told apart from models by their first character,
+/// so that errors inside the parentheses aren't lost to backtracking.
+fn from_primary(input: &str) -> IResult<&str, FromItem> {
+    if input.trim_start().starts_with('(') {
+        from_parens(input)
+    } else {
+        from_model(input)
+    }
+}
+
+named!(outer_keyword<&str, Option<&str>>, opt!(complete!(ws!(tag_no_case!("OUTER")))));
+
+// the type of a join, and whether it's a CROSS JOIN, which takes no
+// condition
+named!(join_kind<&str, Option<(JoinType, bool)>>, opt!(complete!(ws!(alt_complete!(
+    value!((JoinType::Inner, true), tag_no_case!("CROSS")) |
+    value!((JoinType::Inner, false), tag_no_case!("INNER")) |
+    value!((JoinType::Left, false), terminated!(tag_no_case!("LEFT"), outer_keyword)) |
+    value!((JoinType::Right, false), terminated!(tag_no_case!("RIGHT"), outer_keyword)) |
+    value!((JoinType::Full, false), terminated!(tag_no_case!("FULL"), outer_keyword))
+)))));
+
+named!(join_on<&str, JoinCondition>, ws!(do_parse!(
+    tag_no_case!("ON") >>
+    expr: expr_parser >>
+    (JoinCondition::On(expr))
+)));
+
+named!(join_using<&str, JoinCondition>, ws!(do_parse!(
+    tag_no_case!("USING") >>
+    char!('(') >>
+    columns: separated_nonempty_list!(ws!(char!(',')), ws!(identifier)) >>
+    char!(')') >>
+    (JoinCondition::Using(columns))
+)));
+
+fn join_condition(input: &str) -> IResult<&str, JoinCondition> {
+    let keyword: String = input.trim_start().chars().take_while(|c| c.is_alphabetic()).collect();
+    match keyword.to_lowercase().as_str() {
+        "on" => join_on(input),
+        "using" => join_using(input),
+        _ => IResult::Error(error_position!(ErrorKind::Alt, input)),
+    }
+}
+
+/// Parse a model or parenthesized join, followed by any joins to it. Joins
+/// are left-associative, unless they're grouped with parentheses. Once a
+/// join's keywords have matched, the rest of it has to parse, so that errors
+/// point at the token that failed rather than at the JOIN.
+fn from_item_parser(input: &str) -> IResult<&str, FromItem> {
+    let (mut rest, mut item) = try_parse!(input, from_primary);
+    loop {
+        let (after, kind) = try_parse!(rest, join_kind);
+        let after = match (kind, ws!(after, tag_no_case!("JOIN"))) {
+            (_, IResult::Done(after, _)) => after,
+            (Some(_), IResult::Error(e)) => return IResult::Error(e),
+            (Some(_), IResult::Incomplete(n)) => return IResult::Incomplete(n),
+            (None, _) => return IResult::Done(rest, item),
+        };
+        let (join_type, cross) = kind.unwrap_or((JoinType::Inner, false));
+        let (after, right) = try_parse!(after, from_primary);
+        let (after, condition) = if cross {
+            (after, JoinCondition::Cross)
+        } else {
+            try_parse!(after, join_condition)
+        };
+        item = FromItem::Join {
+            left: Box::new(item),
+            right: Box::new(right),
+            join_type,
+            condition,
+        };
+        rest = after;
+    }
+}
+
+named!(from_spec_parser<&str, Vec<FromItem>>, ws!(do_parse!(
+    items: ws!(separated_nonempty_list!(
         char!(','),
-        aliased_identifier
+        from_item_parser
     )) >>
-    (names)
+    (items)
+)));
+
+named!(where_clause<&str, Option<Expr>>, ws!(do_parse!(
+    keyword: opt!(complete!(tag_no_case!("WHERE"))) >>
+    where_expr: cond_with_error!(keyword.is_some(), expr_parser) >>
+    (where_expr)
+)));
+
+named!(nulls_order<&str, bool>, alt_complete!(
+    value!(true, tag_no_case!("FIRST")) |
+    value!(false, tag_no_case!("LAST"))
+));
+
+named!(order_by_key<&str, OrderBy>, ws!(do_parse!(
+    expr: expr_parser >>
+    descending: opt!(complete!(alt_complete!(
+        value!(false, tag_no_case!("ASC")) |
+        value!(true, tag_no_case!("DESC"))
+    ))) >>
+    nulls: opt!(complete!(tag_no_case!("NULLS"))) >>
+    nulls_first: cond_with_error!(nulls.is_some(), nulls_order) >>
+    ({
+        let key = OrderBy::new(expr, descending.unwrap_or(false));
+        OrderBy {nulls_first: nulls_first.unwrap_or(key.nulls_first), ..key}
+    })
+)));
+
+named!(order_by_keys<&str, Vec<OrderBy>>, ws!(do_parse!(
+    tag_no_case!("BY") >>
+    keys: separated_nonempty_list!(ws!(char!(',')), order_by_key) >>
+    (keys)
+)));
+
+named!(order_by_clause<&str, OrderByClause>, ws!(do_parse!(
+    keyword: opt!(complete!(tag_no_case!("ORDER"))) >>
+    keys: cond_with_error!(keyword.is_some(), order_by_keys) >>
+    (keys)
+)));
+
+named!(offset_clause<&str, Option<u64>>, ws!(do_parse!(
+    keyword: opt!(complete!(tag_no_case!("OFFSET"))) >>
+    offset: cond_with_error!(keyword.is_some(), uint_literal_parser) >>
+    (offset)
+)));
+
+named!(limit_clause<&str, LimitClause>, ws!(do_parse!(
+    keyword: opt!(complete!(tag_no_case!("LIMIT"))) >>
+    count: cond_with_error!(keyword.is_some(), uint_literal_parser) >>
+    offset: offset_clause >>
+    (match (count, offset) {
+        (None, None) => None,
+        (count, offset) => Some(Limit {count, offset: offset.unwrap_or(0)}),
+    })
 )));
 
 named!(select_command_parser<&str, Command>, ws!(do_parse!(
     tag_no_case!("select") >>
     cols: select_column_parser >>
-    from: opt!(ws!(do_parse!(
-        tag_no_case!("from") >>
-        from: from_spec_parser >>
-        (from)
-    ))) >>
-    where_expr: opt!(ws!(do_parse!(
-        tag_no_case!("where") >>
-        where_expr: expr_parser >>
-        (where_expr)
-    ))) >>
+    from_keyword: opt!(complete!(tag_no_case!("from"))) >>
+    from: cond_with_error!(from_keyword.is_some(), from_spec_parser) >>
+    where_expr: where_clause >>
     group_by: opt!(ws!(do_parse!(
         tag_no_case!("group") >>
         tag_no_case!("by") >>
         exprs: ws!(separated_nonempty_list!(char!(','), expr_parser)) >>
         (exprs)
     ))) >>
+    having_keyword: opt!(complete!(tag_no_case!("HAVING"))) >>
+    having: cond_with_error!(having_keyword.is_some(), expr_parser) >>
+    order_by: order_by_clause >>
+    limit: limit_clause >>
+    char!(';') >>
+    (Command::Select {cols, from, where_expr, group_by, having, order_by, limit})
+)));
+
+named!(insert_columns<&str, Vec<Identifier>>, ws!(do_parse!(
+    char!('(') >>
+    names: separated_nonempty_list!(ws!(char!(',')), ws!(identifier)) >>
+    char!(')') >>
+    (names.into_iter().map(|name| Identifier {name, qualifier: None}).collect())
+)));
+
+named!(values_row<&str, Vec<Expr>>, ws!(do_parse!(
+    char!('(') >>
+    values: separated_nonempty_list!(ws!(char!(',')), expr_parser) >>
+    char!(')') >>
+    (values)
+)));
+
+named!(insert_rows<&str, InsertValues>, ws!(do_parse!(
+    tag_no_case!("VALUES") >>
+    rows: separated_nonempty_list!(ws!(char!(',')), values_row) >>
     char!(';') >>
-    // TODO
-    (Command::Select {cols, from, where_expr, group_by, having: None, order_by: None, limit: None})
+    (InsertValues::Rows(rows))
 )));
 
-named!(command_parser<&str, Command>, alt_complete!(
-    create_command_parser |
-    select_command_parser
+named!(insert_select<&str, InsertValues>, do_parse!(
+    select: select_command_parser >>
+    (InsertValues::Select(Box::new(select)))
 ));
 
+named!(insert_command_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("INSERT") >>
+    tag_no_case!("INTO") >>
+    model: identifier >>
+    cols: opt!(complete!(insert_columns)) >>
+    values: alt_complete!(insert_rows | insert_select) >>
+    (Command::Insert {model: Identifier {name: model, qualifier: None}, cols, values})
+)));
+
+named!(assignment<&str, (Identifier, Expr)>, ws!(do_parse!(
+    name: identifier >>
+    char!('=') >>
+    value: expr_parser >>
+    ((Identifier {name, qualifier: None}, value))
+)));
+
+named!(update_command_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("UPDATE") >>
+    model: identifier >>
+    tag_no_case!("SET") >>
+    set: separated_nonempty_list!(ws!(char!(',')), assignment) >>
+    where_expr: where_clause >>
+    order_by: order_by_clause >>
+    limit: limit_clause >>
+    char!(';') >>
+    (Command::Update {model: Identifier {name: model, qualifier: None}, where_expr, set, order_by, limit})
+)));
+
+named!(delete_command_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("DELETE") >>
+    tag_no_case!("FROM") >>
+    model: identifier >>
+    where_expr: where_clause >>
+    order_by: order_by_clause >>
+    limit: limit_clause >>
+    char!(';') >>
+    (Command::Delete {model: Identifier {name: model, qualifier: None}, where_expr, order_by, limit})
+)));
+
+named!(show_cache_stats<&str, Command>, ws!(do_parse!(
+    tag_no_case!("SHOW") >>
+    tag_no_case!("CACHE") >>
+    tag_no_case!("STATS") >>
+    char!(';') >>
+    (Command::ShowCacheStats)
+)));
+
+named!(drop_index<&str, Command>, ws!(do_parse!(
+    tag_no_case!("DROP") >>
+    tag_no_case!("INDEX") >>
+    name: identifier >>
+    tag_no_case!("ON") >>
+    model: identifier >>
+    char!(';') >>
+    (Command::DropIndex {name, model})
+)));
+
+/// Statements are parsed by the parser for their first keyword, rather than
+/// by trying each in turn, so that errors point at the token that parser
+/// failed on instead of at the start of the statement.
+fn command_parser(input: &str) -> IResult<&str, Command> {
+    let keyword: String = input.trim_start().chars().take_while(|c| c.is_alphabetic()).collect();
+    match keyword.to_lowercase().as_str() {
+        "create" => create_command_parser(input),
+        "drop" => drop_index(input),
+        "select" => select_command_parser(input),
+        "insert" => insert_command_parser(input),
+        "update" => update_command_parser(input),
+        "delete" => delete_command_parser(input),
+        "show" => show_cache_stats(input),
+        _ => IResult::Error(error_position!(ErrorKind::Alt, input)),
+    }
+}
+
+/// The furthest point in the input that any parser in an error failed at.
+fn error_position<'a>(e: &Err<&'a str>) -> Option<&'a str> {
+    let (position, next): (Option<&str>, &[Err<&str>]) = match e {
+        &Err::Code(_) => (None, &[]),
+        &Err::Node(_, ref next) => (None, next),
+        &Err::Position(_, p) => (Some(p), &[]),
+        &Err::NodePosition(_, p, ref next) => (Some(p), next),
+    };
+    position.into_iter().chain(next.iter().filter_map(error_position)).min_by_key(|p| p.len())
+}
+
+/// Describe the token that a parser failed on, given the input that remained.
+fn describe_position(input: &str, rest: &str) -> String {
+    let rest = rest.trim_start();
+    let token: String = match rest.chars().next() {
+        None => return "end of input".to_string(),
+        Some(c) if c.is_alphanumeric() || c == '_' => rest.chars().take_while(|&c| c.is_alphanumeric() || c == '_').collect(),
+        Some(c) => c.to_string(),
+    };
+    format!("{:?} at offset {}", token, input.len() - rest.len())
+}
+
 /// Provides a nom parser wrapper which returns a soupdb::error::Result.
-fn parser_wrapper<T, E: ::std::fmt::Debug>(parser: &Fn(&str) -> IResult<&str, T, E>, input: &str) -> Result<T> {
+fn parser_wrapper<T>(parser: &Fn(&str) -> IResult<&str, T>, input: &str) -> Result<T> {
     match parser(input) {
         IResult::Done("", v) => Ok(v),
         IResult::Done(s, v) => Err(Error::ParseError(format!("Parsed statement contained additional unparsed content: {:?}", s))),
-        IResult::Error(e) => Err(Error::ParseError(format!("Parse error: {:?}", e))),
+        IResult::Error(e) => Err(Error::ParseError(match error_position(&e) {
+            Some(rest) => format!("Parse error at {}", describe_position(input, rest)),
+            None => format!("Parse error: {:?}", e),
+        })),
         IResult::Incomplete(e) => Err(Error::ParseError(format!("Could not parse a complete statement: \"{:?}\"", e))),
     }
 }
@@ -484,6 +777,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_index() {
+        assert_eq!(
+            parse_command("create index by_a on t (a);"),
+            Ok(Command::CreateIndex {model: "t".to_string(), index: Index {name: "by_a".to_string(), columns: vec!["a".to_string()], unique: false}})
+        );
+        assert_eq!(
+            parse_command("CREATE UNIQUE INDEX by_a_b ON t ( a , b ) ;"),
+            Ok(Command::CreateIndex {model: "t".to_string(), index: Index {name: "by_a_b".to_string(), columns: vec!["a".to_string(), "b".to_string()], unique: true}})
+        );
+        assert!(parse_command("create index by_a on t ();").is_err());
+        assert!(parse_command("create unique by_a on t (a);").is_err());
+
+        assert_eq!(
+            parse_command("drop index by_a on t;"),
+            Ok(Command::DropIndex {name: "by_a".to_string(), model: "t".to_string()})
+        );
+        assert!(parse_command("drop index by_a;").is_err());
+    }
+
     #[test]
     fn test_parse_expr() {
         assert_eq!(
@@ -561,6 +874,16 @@ mod tests {
             Ok(Expr::Id(Identifier {name: "def".to_string(), qualifier: None}))
         );
 
+        assert_eq!(
+            parse_expr("a OR order_id"),
+            Ok(Expr::BinOp {
+                left: Box::new(Expr::Id(Identifier {name: "a".to_string(), qualifier: None})),
+                op: BinaryOperator::OpOr,
+                right: Box::new(Expr::Id(Identifier {name: "order_id".to_string(), qualifier: None})),
+            })
+        );
+        assert!(parse_expr("a orb").is_err());
+
         assert_eq!(
             parse_expr("abc.def"),
             Ok(Expr::Id(Identifier {name: "def".to_string(), qualifier: Some("abc".to_string())}))
@@ -591,7 +914,7 @@ mod tests {
             parse_command("select * from abc;"),
             Ok(Command::Select {
                 cols: SelectColumns::All,
-                from: Some(vec![("abc".to_string(), None)]),
+                from: Some(vec![FromItem::Model {name: "abc".to_string(), alias: None}]),
                 where_expr: None,
                 group_by: None,
                 having: None,
@@ -607,7 +930,7 @@ mod tests {
                     (Expr::Literal {value_type: ValueType::Int, value: "1".to_string()}, Some("col1".to_string())),
                     (Expr::Literal {value_type: ValueType::Int, value: "2".to_string()}, None),
                 ]),
-                from: Some(vec![("abc".to_string(), Some({"d".to_string()}))]),
+                from: Some(vec![FromItem::Model {name: "abc".to_string(), alias: Some("d".to_string())}]),
                 where_expr: None,
                 group_by: None,
                 having: None,
@@ -620,7 +943,7 @@ mod tests {
             parse_command("select * from abc as d where 1 = 2;"),
             Ok(Command::Select {
                 cols: SelectColumns::All,
-                from: Some(vec![("abc".to_string(), Some({"d".to_string()}))]),
+                from: Some(vec![FromItem::Model {name: "abc".to_string(), alias: Some("d".to_string())}]),
                 where_expr: Some(Expr::BinOp {
                     left: Box::new(Expr::Literal {value_type: ValueType::Int, value: "1".to_string()}),
                     op: BinaryOperator::OpEq,
@@ -633,4 +956,250 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_parse_select_clauses() {
+        let int = |v: &str| Expr::Literal {value_type: ValueType::Int, value: v.to_string()};
+        let col = |name: &str| Expr::Id(Identifier {name: name.to_string(), qualifier: None});
+        let gt = |name: &str, v: &str| Expr::BinOp {left: Box::new(col(name)), op: BinaryOperator::OpGt, right: Box::new(int(v))};
+
+        assert_eq!(
+            parse_command("select a from abc where a > 1 group by a having a > 2 order by a desc, b nulls first, c ASC NULLS LAST limit 10 offset 20;"),
+            Ok(Command::Select {
+                cols: SelectColumns::Named(vec![(col("a"), None)]),
+                from: Some(vec![FromItem::Model {name: "abc".to_string(), alias: None}]),
+                where_expr: Some(gt("a", "1")),
+                group_by: Some(vec![col("a")]),
+                having: Some(gt("a", "2")),
+                order_by: Some(vec![
+                    OrderBy {expr: col("a"), descending: true, nulls_first: false},
+                    OrderBy {expr: col("b"), descending: false, nulls_first: true},
+                    OrderBy {expr: col("c"), descending: false, nulls_first: false},
+                ]),
+                limit: Some(Limit {count: Some(10), offset: 20}),
+            })
+        );
+
+        assert_eq!(
+            parse_command("select * from abc order by a + 1 desc nulls first offset 5;"),
+            Ok(Command::Select {
+                cols: SelectColumns::All,
+                from: Some(vec![FromItem::Model {name: "abc".to_string(), alias: None}]),
+                where_expr: None,
+                group_by: None,
+                having: None,
+                order_by: Some(vec![OrderBy {
+                    expr: Expr::BinOp {left: Box::new(col("a")), op: BinaryOperator::OpAdd, right: Box::new(int("1"))},
+                    descending: true,
+                    nulls_first: true,
+                }]),
+                limit: Some(Limit {count: None, offset: 5}),
+            })
+        );
+
+        for &(statement, token) in &[
+            ("select * from abc order by a desc nulls;", "\";\" at offset 39"),
+            ("select * from abc order by a nulls middle;", "\"middle\" at offset 35"),
+            ("select * from abc limit 10 offset;", "\";\" at offset 33"),
+            ("select * from abc offset 1 limit 10;", "\"limit\" at offset 27"),
+            ("select * from abc having;", "\";\" at offset 24"),
+        ] {
+            match parse_command(statement) {
+                Err(Error::ParseError(msg)) => assert!(msg.contains(token), "{}: {}", statement, msg),
+                c => panic!("unexpected {:?}", c),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_join() {
+        let model = |name: &str, alias: Option<&str>| FromItem::Model {name: name.to_string(), alias: alias.map(|a| a.to_string())};
+        let join = |left: FromItem, right: FromItem, join_type: JoinType, condition: JoinCondition| FromItem::Join {
+            left: Box::new(left),
+            right: Box::new(right),
+            join_type,
+            condition,
+        };
+        let on = |l: &str, r: &str| JoinCondition::On(parse_expr(&format!("{} = {}", l, r)).unwrap());
+        let from = |query: &str| match parse_command(query) {
+            Ok(Command::Select {from: Some(from), ..}) => from,
+            c => panic!("unexpected {:?}", c),
+        };
+
+        assert_eq!(
+            from("select * from a, b as c;"),
+            vec![model("a", None), model("b", Some("c"))]
+        );
+        assert_eq!(
+            from("select * from a join b on a.x = b.x;"),
+            vec![join(model("a", None), model("b", None), JoinType::Inner, on("a.x", "b.x"))]
+        );
+
+        // joins are left-associative
+        assert_eq!(
+            from("select * from a as x INNER JOIN b USING (id, k) left outer join c on x.id = c.id right join d on d.id = c.id where a > 1;"),
+            vec![join(
+                join(
+                    join(model("a", Some("x")), model("b", None), JoinType::Inner, JoinCondition::Using(vec!["id".to_string(), "k".to_string()])),
+                    model("c", None),
+                    JoinType::Left,
+                    on("x.id", "c.id")
+                ),
+                model("d", None),
+                JoinType::Right,
+                on("d.id", "c.id")
+            )]
+        );
+
+        // unless they're grouped
+        assert_eq!(
+            from("select * from a full join (b cross join c) on a.id = b.id, d;"),
+            vec![
+                join(model("a", None), join(model("b", None), model("c", None), JoinType::Inner, JoinCondition::Cross), JoinType::Full, on("a.id", "b.id")),
+                model("d", None),
+            ]
+        );
+        assert_eq!(
+            from("select * from (a full outer join b using (id));"),
+            vec![join(model("a", None), model("b", None), JoinType::Full, JoinCondition::Using(vec!["id".to_string()]))]
+        );
+
+        for &(statement, token) in &[
+            ("select * from a join b;", "\";\" at offset 22"),
+            ("select * from a join b using ();", "\")\" at offset 30"),
+            ("select * from a join b on;", "\";\" at offset 25"),
+            ("select * from a left b on a.id = b.id;", "\"b\" at offset 21"),
+            ("select * from (a join b on a.id = b.id;", "\";\" at offset 38"),
+            ("select * from a join (b join c on b.id = c.id;", "\";\" at offset 45"),
+            ("select * from a cross join ;", "\";\" at offset 27"),
+        ] {
+            match parse_command(statement) {
+                Err(Error::ParseError(msg)) => assert!(msg.contains(token), "{}: {}", statement, msg),
+                c => panic!("unexpected {:?}", c),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_insert() {
+        let int = |v: &str| Expr::Literal {value_type: ValueType::Int, value: v.to_string()};
+        let id = |name: &str| Identifier {name: name.to_string(), qualifier: None};
+
+        assert_eq!(
+            parse_command("insert into abc values (1, \"x\");"),
+            Ok(Command::Insert {
+                model: id("abc"),
+                cols: None,
+                values: InsertValues::Rows(vec![
+                    vec![int("1"), Expr::Literal {value_type: ValueType::Str(0), value: "x".to_string()}],
+                ]),
+            })
+        );
+
+        assert_eq!(
+            parse_command("INSERT INTO abc ( b , a ) VALUES (1 + 2, null), (3, true) ;"),
+            Ok(Command::Insert {
+                model: id("abc"),
+                cols: Some(vec![id("b"), id("a")]),
+                values: InsertValues::Rows(vec![
+                    vec![
+                        Expr::BinOp {left: Box::new(int("1")), op: BinaryOperator::OpAdd, right: Box::new(int("2"))},
+                        Expr::Literal {value_type: ValueType::Unknown, value: "null".to_string()},
+                    ],
+                    vec![int("3"), Expr::Literal {value_type: ValueType::Bool, value: "true".to_string()}],
+                ]),
+            })
+        );
+
+        assert_eq!(
+            parse_command("insert into abc (a) select b from def where b = 2;"),
+            Ok(Command::Insert {
+                model: id("abc"),
+                cols: Some(vec![id("a")]),
+                values: InsertValues::Select(Box::new(Command::Select {
+                    cols: SelectColumns::Named(vec![(Expr::Id(id("b")), None)]),
+                    from: Some(vec![FromItem::Model {name: "def".to_string(), alias: None}]),
+                    where_expr: Some(Expr::BinOp {
+                        left: Box::new(Expr::Id(id("b"))),
+                        op: BinaryOperator::OpEq,
+                        right: Box::new(int("2")),
+                    }),
+                    group_by: None,
+                    having: None,
+                    order_by: None,
+                    limit: None,
+                })),
+            })
+        );
+
+        assert!(parse_command("insert into abc values;").is_err());
+        assert!(parse_command("insert into abc values ();").is_err());
+        assert!(parse_command("insert into abc () values (1);").is_err());
+        assert!(parse_command("insert abc values (1);").is_err());
+        assert!(parse_command("insert into abc values (1), ;").is_err());
+    }
+
+    #[test]
+    fn test_parse_update_delete() {
+        let int = |v: &str| Expr::Literal {value_type: ValueType::Int, value: v.to_string()};
+        let id = |name: &str| Identifier {name: name.to_string(), qualifier: None};
+
+        assert_eq!(
+            parse_command("update abc set a = 1;"),
+            Ok(Command::Update {model: id("abc"), where_expr: None, set: vec![(id("a"), int("1"))], order_by: None, limit: None})
+        );
+
+        assert_eq!(
+            parse_command("UPDATE abc SET a = a + 1 , b = 2 WHERE a > 2 ORDER BY b, a LIMIT 10 ;"),
+            Ok(Command::Update {
+                model: id("abc"),
+                where_expr: Some(Expr::BinOp {left: Box::new(Expr::Id(id("a"))), op: BinaryOperator::OpGt, right: Box::new(int("2"))}),
+                set: vec![
+                    (id("a"), Expr::BinOp {left: Box::new(Expr::Id(id("a"))), op: BinaryOperator::OpAdd, right: Box::new(int("1"))}),
+                    (id("b"), int("2")),
+                ],
+                order_by: Some(vec![OrderBy::new(Expr::Id(id("b")), false), OrderBy::new(Expr::Id(id("a")), false)]),
+                limit: Some(Limit {count: Some(10), offset: 0}),
+            })
+        );
+
+        assert_eq!(
+            parse_command("delete from abc;"),
+            Ok(Command::Delete {model: id("abc"), where_expr: None, order_by: None, limit: None})
+        );
+
+        assert_eq!(
+            parse_command("delete from abc where a = 1 limit 5;"),
+            Ok(Command::Delete {
+                model: id("abc"),
+                where_expr: Some(Expr::BinOp {left: Box::new(Expr::Id(id("a"))), op: BinaryOperator::OpEq, right: Box::new(int("1"))}),
+                order_by: None,
+                limit: Some(Limit {count: Some(5), offset: 0}),
+            })
+        );
+
+        // errors point at the token that couldn't be parsed
+        for &(statement, token) in &[
+            ("update abc set a = 1 wher a = 2;", "\"wher\" at offset 21"),
+            ("update abc a = 1;", "\"a\" at offset 11"),
+            ("update abc set a = 1, where a = 2;", "\",\" at offset 20"),
+            ("update abc set a = 1 order a;", "\"a\" at offset 27"),
+            ("delete from abc where;", "\";\" at offset 21"),
+            ("delete from abc limit -1;", "\"-\" at offset 22"),
+            ("delete abc;", "\"abc\" at offset 7"),
+            ("delete from abc limit 1 where a = 1;", "\"where\" at offset 24"),
+        ] {
+            match parse_command(statement) {
+                Err(Error::ParseError(msg)) => assert!(msg.contains(token), "{}: {}", statement, msg),
+                c => panic!("unexpected {:?}", c),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_show() {
+        assert_eq!(parse_command("show cache stats;"), Ok(Command::ShowCacheStats));
+        assert_eq!(parse_command("SHOW CACHE STATS ;"), Ok(Command::ShowCacheStats));
+        assert!(parse_command("show cache;").is_err());
+    }
 }
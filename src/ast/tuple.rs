@@ -1,7 +1,7 @@
 use std::ops::Index;
 use ::ast::value_type::ValueType;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct TupleEntry {
     pub name: String,
     pub value: ValueType,
@@ -13,7 +13,7 @@ impl TupleEntry {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct TupleDef(pub Vec<TupleEntry>);
 
 impl TupleDef {
@@ -29,6 +29,13 @@ impl TupleDef {
         }
     }
 
+    /// Returns the position of the named entry, if present.
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        match self {
+            &TupleDef(ref v) => v.iter().position(|e| e.name == name),
+        }
+    }
+
     pub fn to_ddl(&self) -> String {
         match self {
             &TupleDef(ref v) => {
@@ -13,8 +13,8 @@ pub enum ValueType {
     Uint,
     Int,
     Float,
-    // sized string type: a size of zero indicates variable size, which will
-    // be stored off page
+    // sized string type: a size of zero indicates variable size, which is
+    // stored off page, as are sized strings too long to store inline
     Str(u64),
     Nullable(Box<ValueType>),
     Vector(u64, Box<ValueType>),
@@ -31,7 +31,7 @@ impl ValueType {
             &ValueType::Int => 8,
             &ValueType::Float => 8,
             &ValueType::AutoId => 8,
-            // off-page storage is a page ID (u64) + offset (u16)
+            // off-page storage is a page ID (u64) + slot (u16) in the heap
             &ValueType::Str(0) => 10,
             &ValueType::Str(n) => if n > MAX_INLINE_STRING_LENGTH {MAX_INLINE_STRING_LENGTH} else {n},
             &ValueType::Nullable(ref v) => 1 + (*v).size_of(),
@@ -1,13 +1,46 @@
 use std::fs::create_dir_all;
 use std::path::PathBuf;
+use std::time::Duration;
+use ::io::eviction::EvictionPolicyType;
+use ::io::page::PAGE_SIZE;
+use ::io::pool::IoBackend;
+use ::io::wal::SyncPolicy;
 
 pub struct Config {
     pub data_dir: PathBuf,
+    /// Size in bytes of the page cache for each database. Databases opened
+    /// with a shared buffer pool use the pool's size instead.
+    pub working_memory: u64,
+    /// Which pages the page cache evicts first when it's full.
+    pub eviction_policy: EvictionPolicyType,
+    /// How long to wait for a lock on a cached page before failing with a
+    /// LockTimeout error; None waits forever.
+    pub lock_timeout: Option<Duration>,
+    /// Whether the database's pages are copied into the page cache or read
+    /// in place through memory maps.
+    pub io_backend: IoBackend,
+    /// How many pages ahead of a table scan to load in the background, at
+    /// most a quarter of working memory; 0 turns prefetching off.
+    pub prefetch_pages: usize,
+    /// How often each model's write-ahead log is synced to disk.
+    pub sync_policy: SyncPolicy,
 }
 
 impl Config {
     pub fn new() -> Config {
-         let new_config = Config {data_dir: ["/var", "soupdb"].iter().collect()};
+        Config::with_data_dir(["/var", "soupdb"].iter().collect())
+    }
+
+    pub fn with_data_dir(data_dir: PathBuf) -> Config {
+         let new_config = Config {
+             data_dir: data_dir,
+             working_memory: PAGE_SIZE * 0x1000,
+             eviction_policy: EvictionPolicyType::Lru,
+             lock_timeout: None,
+             io_backend: IoBackend::Buffered,
+             prefetch_pages: 8,
+             sync_policy: SyncPolicy::EveryCommit,
+         };
          new_config.create_directories();
          new_config
     }
@@ -1,57 +1,1043 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs::{File, create_dir_all, remove_file, rename};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use glob::glob;
 use ::{Error, Result};
-use ::ast::command::Command;
+use ::ast::Identifier;
+use ::ast::command::{Command, FromItem, InsertValues, JoinCondition, JoinType};
 use ::config::Config;
+use ::exec;
+use ::exec::ResultSet;
+use ::exec::join::{self, Relation};
+use ::exec::table::TableStore;
+use ::io::dbfile::{AccessMode, DbFile};
+use ::io::page::FileId;
+use ::io::pool::{BufferPool, IoBackend};
+use ::io::wal;
 use ::model::Model;
 
-struct Database {
+pub struct Database {
     pub name: String,
     pub config: Config,
     pub data_dir: PathBuf,
     pub schemas: HashMap<String, Model>,
+    /// working memory, which may be shared with other databases; model
+    /// files are opened in it as they're used
+    pool: Arc<BufferPool>,
 }
 
 impl Database {
-    pub fn new(name: String, config: Option<Config>) -> Database {
+    pub fn new(name: String, config: Option<Config>) -> Result<Database> {
+        let config = match config {
+            Some(c) => c,
+            None => Config::new(),
+        };
+        let pool = Arc::new(BufferPool::from_config(&config));
+        Database::with_pool(name, Some(config), pool)
+    }
+
+    /// Open a database whose pages are cached in a buffer pool shared with
+    /// other databases, so that they all share one memory budget. The
+    /// config's working memory settings are ignored.
+    pub fn with_pool(name: String, config: Option<Config>, pool: Arc<BufferPool>) -> Result<Database> {
         let config = match config {
             Some(c) => c,
             None => Config::new(),
         };
 
         let data_dir = config.data_dir.join(&name);
+        if let Err(e) = create_dir_all(&data_dir) {
+            return Err(Error::IoError(format!("failed to create {}: {}", data_dir.display(), e)));
+        }
 
         let mut schemas = HashMap::new();
-        for entry in glob(data_dir.join("*.schema").to_str().unwrap()) {
-            // TODO: read in schemas
+        let pattern = data_dir.join("*.schema");
+        let paths = match glob(&pattern.to_string_lossy()) {
+            Ok(paths) => paths,
+            Err(e) => return Err(Error::IoError(format!("invalid data directory {}: {}", data_dir.display(), e))),
+        };
+        for entry in paths {
+            let path = match entry {
+                Ok(path) => path,
+                Err(e) => return Err(Error::IoError(format!("failed to read schema: {}", e))),
+            };
+            let model = Database::read_schema(&path)?;
+            schemas.insert(model.name.clone(), model);
         }
 
-        Database {
+        Ok(Database {
             name: name,
             config: config,
             data_dir: data_dir,
             schemas: schemas,
-        }
+            pool: pool,
+        })
     }
 
-    pub fn run_command(command: Command) -> Result<()> {
+    pub fn run_command(&mut self, command: Command) -> Result<ResultSet> {
         match command {
+            Command::CreateModel {name, schema} => {
+                if self.schemas.contains_key(&name) {
+                    return Err(Error::Custom(format!("model {} already exists", name)));
+                }
+                let model = Model::new(name.clone(), schema);
+                if let Some(table) = model.schema.as_table() {
+                    TableStore::check_schema(&name, table)?;
+                }
+                // the schema is written last, so that a model isn't found on
+                // startup unless its file was created
+                let file_id = self.file_id(&name);
+                let created = DbFile::create(name.clone(), &self.model_path(&name))
+                    .and_then(|mut file| {
+                        file.set_sync_policy(self.config.sync_policy);
+                        self.pool.open(file_id.clone(), file, self.config.io_backend)
+                    })
+                    .and_then(|()| self.write_schema(&model));
+                if let Err(e) = created {
+                    if self.pool.is_open(&file_id) {
+                        let _ = self.pool.discard(&file_id);
+                    }
+                    let _ = self.remove_model_files(&name);
+                    return Err(e);
+                }
+                self.schemas.insert(name, model);
+                Ok(ResultSet::empty())
+            }
+            Command::DropModel {name} => {
+                if !self.schemas.contains_key(&name) {
+                    return Err(Error::Custom(format!("unknown model {}", name)));
+                }
+                let file_id = self.file_id(&name);
+                if self.pool.is_open(&file_id) {
+                    self.pool.discard(&file_id)?;
+                }
+                self.remove_model_files(&name)?;
+                if let Err(e) = remove_file(self.schema_path(&name)) {
+                    return Err(Error::IoError(format!("failed to remove schema for {}: {}", name, e)));
+                }
+                self.schemas.remove(&name);
+                Ok(ResultSet::empty())
+            }
+            Command::CreateIndex {model, index} => {
+                match self.schemas.get(&model) {
+                    Some(m) if m.indexes.iter().any(|i| i.name == index.name) => {
+                        return Err(Error::Custom(format!("index {} already exists on {}", index.name, model)));
+                    }
+                    Some(_) => {}
+                    None => return Err(Error::Custom(format!("unknown model {}", model))),
+                }
+                // the schema is written before the transaction commits, so
+                // that the index is rolled back if it can't be
+                let path = self.schema_path(&model);
+                let ddl = {
+                    let m = &self.schemas[&model];
+                    m.schema_ddl(m.indexes.iter().chain(Some(&index)))
+                };
+                self.in_transaction(&model, |store| {
+                    store.create_index(&index)?;
+                    Database::write_schema_file(&path, &ddl)?;
+                    Ok(ResultSet::empty())
+                }).map_err(|e| self.restore_schema(&model, e))?;
+                self.schemas.get_mut(&model).unwrap().indexes.push(index);
+                Ok(ResultSet::empty())
+            }
+            Command::DropIndex {name, model} => {
+                match self.schemas.get(&model) {
+                    Some(m) if m.indexes.iter().any(|i| i.name == name) => {}
+                    Some(_) => return Err(Error::Custom(format!("unknown index {} on {}", name, model))),
+                    None => return Err(Error::Custom(format!("unknown model {}", model))),
+                }
+                let path = self.schema_path(&model);
+                let ddl = {
+                    let m = &self.schemas[&model];
+                    m.schema_ddl(m.indexes.iter().filter(|i| i.name != name))
+                };
+                self.in_transaction(&model, |store| {
+                    store.drop_index(&name)?;
+                    Database::write_schema_file(&path, &ddl)?;
+                    Ok(ResultSet::empty())
+                }).map_err(|e| self.restore_schema(&model, e))?;
+                self.schemas.get_mut(&model).unwrap().indexes.retain(|i| i.name != name);
+                Ok(ResultSet::empty())
+            }
+            Command::Select {cols, from, where_expr, group_by, having, order_by, limit} => {
+                if group_by.is_some() || having.is_some() {
+                    return Err(Error::NotYetImplemented);
+                }
+                match from {
+                    None => exec::select(None, &cols, &where_expr, &order_by, &limit),
+                    Some(ref items) => match (items.len(), &items[0]) {
+                        (1, &FromItem::Model {ref name, ref alias}) => {
+                            let mut store = self.table_store(name)?;
+                            let qualifiers = Database::qualifiers(name, alias);
+                            exec::select(Some((&mut store, qualifiers)), &cols, &where_expr, &order_by, &limit)
+                        }
+                        // joins, where a comma-separated list is a cross join
+                        _ => {
+                            let mut relation = self.relation(&items[0])?;
+                            for item in &items[1 ..] {
+                                let right = self.relation(item)?;
+                                relation = join::join(relation, right, JoinType::Inner, &JoinCondition::Cross)?;
+                            }
+                            exec::select_joined(relation, &cols, &where_expr, &order_by, &limit)
+                        }
+                    },
+                }
+            }
+            Command::Insert {model, cols, values} => {
+                let rows = match values {
+                    InsertValues::Rows(exprs) => exec::evaluate_rows(&exprs)?,
+                    InsertValues::Select(select) => match *select {
+                        select @ Command::Select {..} => exec::typed_rows(self.run_command(select)?),
+                        _ => return Err(Error::Custom("INSERT can only add the rows of a SELECT".to_string())),
+                    },
+                };
+                self.in_transaction(&model.name, |store| exec::insert(store, &cols, &rows))
+            }
+            Command::Update {model, where_expr, set, order_by, limit} => {
+                let qualifiers = Database::qualifiers(&model.name, &None);
+                self.in_transaction(&model.name, |store| exec::update(store, qualifiers, &set, &where_expr, &order_by, &limit))
+            }
+            Command::Delete {model, where_expr, order_by, limit} => {
+                let qualifiers = Database::qualifiers(&model.name, &None);
+                self.in_transaction(&model.name, |store| exec::delete(store, qualifiers, &where_expr, &order_by, &limit))
+            }
+            Command::ShowCacheStats => Ok(exec::show_cache_stats(&self.pool.stats())),
+            // database commands are handled by the server, which owns the set
+            // of databases
             _ => {
                 Err(Error::NotYetImplemented)
             }
         }
     }
+
+    fn model_path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(format!("{}.db", name))
+    }
+
+    /// Identifies a model's file in the buffer pool, which may hold files
+    /// from other databases too.
+    fn file_id(&self, name: &str) -> FileId {
+        self.model_path(name).to_string_lossy().into_owned()
+    }
+
+    /// Remove a model's file and its write-ahead log, which may not have
+    /// been created yet.
+    fn remove_model_files(&self, name: &str) -> Result<()> {
+        for path in &[self.model_path(name), wal::log_path(&self.model_path(name))] {
+            match remove_file(path) {
+                Err(ref e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(Error::IoError(format!("failed to remove {}: {}", path.display(), e)));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn schema_path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(format!("{}.schema", name))
+    }
+
+    /// Parse a model from the DDL stored in a schema file. The model's name
+    /// must match the file name.
+    fn read_schema(path: &Path) -> Result<Model> {
+        let mut ddl = String::new();
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut ddl)) {
+            return Err(Error::IoError(format!("failed to read schema {}: {}", path.display(), e)));
+        }
+        let model = match Model::from_ddl(&ddl) {
+            Ok(model) => model,
+            Err(e) => return Err(Error::ParseError(format!("invalid schema {}: {:?}", path.display(), e))),
+        };
+        match path.file_stem() {
+            Some(stem) if stem.to_string_lossy() == model.name => Ok(model),
+            _ => Err(Error::ParseError(format!("schema {} defines model {}", path.display(), model.name))),
+        }
+    }
+
+    fn write_schema(&self, model: &Model) -> Result<()> {
+        Database::write_schema_file(&self.schema_path(&model.name), &model.schema_ddl(&model.indexes))
+    }
+
+    /// Replace a schema file. The schema is written to a temporary file
+    /// first, and renamed over the old one once it's on disk, so that the
+    /// database can't be left with a partly written schema it can't open.
+    fn write_schema_file(path: &Path, ddl: &str) -> Result<()> {
+        let temp_path = path.with_extension("schema.tmp");
+        let written = File::create(&temp_path)
+            .and_then(|mut f| f.write_all(ddl.as_bytes()).and_then(|()| f.sync_all()))
+            .and_then(|()| rename(&temp_path, path));
+        match written {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = remove_file(&temp_path);
+                Err(Error::IoError(format!("failed to write schema {}: {}", path.display(), e)))
+            }
+        }
+    }
+
+    /// Rewrite a model's schema file from its schema in memory, after a
+    /// transaction that changed it failed. If the transaction failed to
+    /// commit, the new schema may already have been written. Returns the
+    /// transaction's error.
+    fn restore_schema(&self, name: &str, error: Error) -> Error {
+        match self.write_schema(&self.schemas[name]) {
+            Ok(()) => error,
+            Err(e) => Error::IoError(format!("{:?}; failed to restore the schema of {}: {:?}", error, name, e)),
+        }
+    }
+
+    /// The names that qualify a model's columns: its alias if it has one,
+    /// which hides its own name, as it would otherwise clash in self-joins.
+    fn qualifiers<'a>(name: &'a str, alias: &'a Option<String>) -> Vec<&'a str> {
+        match alias {
+            &Some(ref a) => vec![a],
+            &None => vec![name],
+        }
+    }
+
+    /// Read the rows of an item in a FROM clause, joining models as needed.
+    fn relation(&mut self, item: &FromItem) -> Result<Relation> {
+        match item {
+            &FromItem::Model {ref name, ref alias} => {
+                let store = self.table_store(name)?;
+                Relation::scan(&store, Database::qualifiers(name, alias))
+            }
+            &FromItem::Join {ref left, ref right, join_type, ref condition} => {
+                let left = self.relation(left)?;
+                let right = self.relation(right)?;
+                join::join(left, right, join_type, condition)
+            }
+        }
+    }
+
+    /// Write `f`'s changes to a table's file in a single transaction, which is
+    /// rolled back if `f` fails. Pages modified in working memory are written
+    /// to the file before it's committed.
+    fn in_transaction<F: FnOnce(&mut TableStore) -> Result<ResultSet>>(&mut self, name: &str, f: F) -> Result<ResultSet> {
+        self.open_file(name)?;
+        let file_id = self.file_id(name);
+        self.pool.with_file(&file_id, |file| file.begin())?;
+        let result = match self.table_store(name) {
+            Ok(mut store) => f(&mut store),
+            Err(e) => Err(e),
+        };
+        let pool = &self.pool;
+        let result = result.and_then(|r| {
+            pool.flush(&file_id)?;
+            pool.with_file(&file_id, |file| file.commit())?;
+            Ok(r)
+        });
+        if result.is_err() {
+            // cached pages may hold the transaction's writes
+            pool.discard_dirty(&file_id);
+            let rolled_back = pool.with_file(&file_id, |file| Ok(file.rollback())).unwrap_or_default();
+            for page_id in rolled_back {
+                pool.invalidate(&(file_id.clone(), page_id));
+            }
+        }
+        result
+    }
+
+    fn open_file(&mut self, name: &str) -> Result<()> {
+        let file_id = self.file_id(name);
+        if !self.pool.is_open(&file_id) {
+            let mut file = DbFile::open(name.to_string(), &self.model_path(name), AccessMode::ReadWrite)?;
+            file.set_sync_policy(self.config.sync_policy);
+            self.pool.open(file_id, file, self.config.io_backend)?;
+        }
+        Ok(())
+    }
+
+    /// Open the row storage of a TABLE model.
+    fn table_store<'a>(&'a mut self, name: &'a str) -> Result<TableStore<'a>> {
+        self.open_file(name)?;
+        let model = match self.schemas.get(name) {
+            Some(model) => model,
+            None => return Err(Error::Custom(format!("unknown model {}", name))),
+        };
+        let table = match model.schema.as_table() {
+            Some(table) => table,
+            None => return Err(Error::NotYetImplemented),
+        };
+        TableStore::new(name, table, &model.indexes, self.file_id(name), &self.pool)
+    }
+}
+
+impl Drop for Database {
+    /// Close the database's files, so that their pages don't linger in a
+    /// shared buffer pool.
+    fn drop(&mut self) {
+        for name in self.schemas.keys() {
+            let file_id = self.file_id(name);
+            if self.pool.is_open(&file_id) {
+                self.pool.close(&file_id);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempdir::TempDir;
+    use ::ast::parse::{parse_command, parse_expr};
+    use ::ast::{Expr, BinaryOperator};
+    use ::ast::command::{OrderBy, Limit};
+    use ::ast::value_type::ValueType;
+    use std::io::Write;
+    use ::io::page::PAGE_SIZE;
+    use ::io::value::Value;
+
+    fn test_db(dir: &TempDir) -> Database {
+        let mut config = Config::with_data_dir(dir.path().to_path_buf());
+        config.working_memory = PAGE_SIZE * 16;
+        Database::new("test_db".to_string(), Some(config)).unwrap()
+    }
+
+    fn literal(value_type: ValueType, value: &str) -> Expr {
+        Expr::Literal {value_type, value: value.to_string()}
+    }
+
+    fn ints(result: &ResultSet) -> Vec<Vec<i64>> {
+        result.rows.iter().map(|r| r.iter().map(|v| match v {
+            &Value::Uint(n) => n as i64,
+            v => v.as_int(),
+        }).collect()).collect()
+    }
 
     #[test]
     fn test_database() {
         // make sure constructor works
-        let db = Database::new("test_db".to_string(), None);
-        assert_eq!(db.config.data_dir, Config::new().data_dir)
+        let dir = TempDir::new("soupdb").unwrap();
+        let db = Database::new("test_db".to_string(), Some(Config::with_data_dir(dir.path().to_path_buf()))).unwrap();
+        assert_eq!(db.data_dir, dir.path().join("test_db"));
+        assert!(db.data_dir.is_dir());
+    }
+
+    #[test]
+    fn test_run_command() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        let model = Identifier {name: "t".to_string(), qualifier: None};
+
+        db.run_command(parse_command("create table t (a int, b int);").unwrap()).unwrap();
+        assert!(db.run_command(parse_command("create table t (a int);").unwrap()).is_err());
+
+        // a model whose schema can't be written leaves no files behind
+        let temp_path = dir.path().join("test_db").join("v.schema.tmp");
+        create_dir_all(&temp_path).unwrap();
+        assert!(db.run_command(parse_command("create table v (a int);").unwrap()).is_err());
+        assert!(!db.schemas.contains_key("v"));
+        assert!(!db.model_path("v").exists());
+        assert!(!wal::log_path(&db.model_path("v")).exists());
+        ::std::fs::remove_dir(&temp_path).unwrap();
+        db.run_command(parse_command("create table v (a int);").unwrap()).unwrap();
+
+        let result = db.run_command(Command::Insert {
+            model: model.clone(),
+            cols: None,
+            values: InsertValues::Rows((1 .. 6).map(|i| vec![
+                literal(ValueType::Int, &format!("{}", i)),
+                literal(ValueType::Int, &format!("{}", i * 10)),
+            ]).collect()),
+        }).unwrap();
+        assert_eq!(result.rows_affected, 5);
+
+        let result = db.run_command(parse_command("select * from t;").unwrap()).unwrap();
+        assert_eq!(result.schema.to_ddl(), "(a int, b int)");
+        assert_eq!(ints(&result), vec![vec![1, 10], vec![2, 20], vec![3, 30], vec![4, 40], vec![5, 50]]);
+
+        let result = db.run_command(parse_command("select b - a as c from t as x where x.a > 3;").unwrap()).unwrap();
+        assert_eq!(result.schema.to_ddl(), "(c int)");
+        assert_eq!(ints(&result), vec![vec![36], vec![45]]);
+
+        let result = db.run_command(Command::Update {
+            model: model.clone(),
+            where_expr: Some(Expr::BinOp {
+                left: Box::new(Expr::Id(Identifier {name: "a".to_string(), qualifier: None})),
+                op: BinaryOperator::OpLt,
+                right: Box::new(literal(ValueType::Int, "3")),
+            }),
+            set: vec![(Identifier {name: "b".to_string(), qualifier: None}, literal(ValueType::Int, "0"))],
+            order_by: None,
+            limit: None,
+        }).unwrap();
+        assert_eq!(result.rows_affected, 2);
+
+        let result = db.run_command(Command::Delete {
+            model: model.clone(),
+            where_expr: None,
+            order_by: Some(vec![OrderBy::new(Expr::Id(Identifier {name: "b".to_string(), qualifier: None}), false)]),
+            limit: Some(Limit {count: Some(3), offset: 0}),
+        }).unwrap();
+        assert_eq!(result.rows_affected, 3);
+
+        let result = db.run_command(parse_command("select a, b, rowid from t;").unwrap()).unwrap();
+        assert_eq!(result.schema.to_ddl(), "(a int, b int, rowid autoid)");
+        assert_eq!(ints(&result), vec![vec![4, 40, 4], vec![5, 50, 5]]);
+
+        // rowids aren't reused after deletes
+        db.run_command(Command::Insert {
+            model: model.clone(),
+            cols: Some(vec![
+                Identifier {name: "b".to_string(), qualifier: None},
+                Identifier {name: "a".to_string(), qualifier: None},
+            ]),
+            values: InsertValues::Rows(vec![vec![literal(ValueType::Int, "60"), literal(ValueType::Int, "6")]]),
+        }).unwrap();
+        let result = db.run_command(parse_command("select rowid, a, b from t where b = 60;").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![6, 6, 60]]);
+
+        db.run_command(Command::DropModel {name: "t".to_string()}).unwrap();
+        assert!(db.run_command(parse_command("select * from t;").unwrap()).is_err());
+        assert!(!dir.path().join("test_db").join("t.db").exists());
+        assert!(!dir.path().join("test_db").join("t.schema").exists());
+
+        // a model whose files can't be removed isn't dropped
+        db.run_command(parse_command("create table u (a int);").unwrap()).unwrap();
+        let path = dir.path().join("test_db").join("u.db");
+        ::std::fs::remove_file(&path).unwrap();
+        ::std::fs::create_dir(&path).unwrap();
+        assert!(db.run_command(Command::DropModel {name: "u".to_string()}).is_err());
+        assert!(db.schemas.contains_key("u"));
+        assert!(dir.path().join("test_db").join("u.schema").exists());
+    }
+
+    #[test]
+    fn test_multiple_pages() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        let model = Identifier {name: "t".to_string(), qualifier: None};
+        let insert = |start: i64, end: i64| Command::Insert {
+            model: model.clone(),
+            cols: None,
+            values: InsertValues::Rows((start .. end).map(|i| vec![
+                literal(ValueType::Int, &format!("{}", i)),
+                literal(ValueType::Int, &format!("{}", i * 2)),
+            ]).collect()),
+        };
+        let file_size = || dir.path().join("test_db").join("t.db").metadata().unwrap().len();
+
+        // a few hundred rows fit on each page
+        db.run_command(parse_command("create table t (a int, b int);").unwrap()).unwrap();
+        db.run_command(insert(0, 1000)).unwrap();
+        assert_eq!(file_size(), PAGE_SIZE * 5);
+        let result = db.run_command(parse_command("select a from t where a > 995;").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![996], vec![997], vec![998], vec![999]]);
+
+        // emptied pages in the middle of the table are freed and reused
+        let result = db.run_command(Command::Delete {
+            model: model.clone(),
+            where_expr: Some(parse_expr("a >= 10 and a < 990").unwrap()),
+            order_by: None,
+            limit: None,
+        }).unwrap();
+        assert_eq!(result.rows_affected, 980);
+        db.run_command(insert(1000, 1500)).unwrap();
+        assert_eq!(file_size(), PAGE_SIZE * 5);
+
+        let result = db.run_command(parse_command("select rowid from t where a >= 5 and a < 15 or a > 1497;").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![6], vec![7], vec![8], vec![9], vec![10], vec![1499], vec![1500]]);
+    }
+
+    #[test]
+    fn test_strings() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        let model = Identifier {name: "t".to_string(), qualifier: None};
+        // strings of every size: inline, in the heap, and spanning several
+        // overflow pages
+        let long = |i: usize| "x".repeat(i * 1000) + &i.to_string();
+        let insert = || Command::Insert {
+            model: model.clone(),
+            cols: None,
+            values: InsertValues::Rows((0 .. 20).map(|i| vec![
+                literal(ValueType::Int, &format!("{}", i)),
+                literal(ValueType::Str(0), &format!("s{}", i)),
+                literal(ValueType::Str(0), &long(i)),
+            ]).collect()),
+        };
+        let strings = |result: &ResultSet| -> Vec<(String, String)> {
+            result.rows.iter().map(|r| match (&r[1], &r[2]) {
+                (&Value::Str(ref s), &Value::Str(ref l)) => (s.clone(), l.clone()),
+                r => panic!("expected strings, got {:?}", r),
+            }).collect()
+        };
+        let file_size = || dir.path().join("test_db").join("t.db").metadata().unwrap().len();
+
+        db.run_command(parse_command("create table t (a int, s str, l str(20000));").unwrap()).unwrap();
+        db.run_command(insert()).unwrap();
+        let result = db.run_command(parse_command("select * from t;").unwrap()).unwrap();
+        assert_eq!(strings(&result), (0 .. 20).map(|i| (format!("s{}", i), long(i))).collect::<Vec<_>>());
+        let result = db.run_command(parse_command("select a from t where s = \"s3\";").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![3]]);
+        assert!(db.run_command(Command::Insert {
+            model: model.clone(),
+            cols: None,
+            values: InsertValues::Rows(vec![vec![literal(ValueType::Int, "0"), literal(ValueType::Str(0), ""), literal(ValueType::Str(0), &"x".repeat(20001))]]),
+        }).is_err());
+
+        // updated and deleted rows free their strings, and the space is
+        // reused
+        let size = file_size();
+        db.run_command(Command::Update {
+            model: model.clone(),
+            where_expr: None,
+            set: vec![(Identifier {name: "l".to_string(), qualifier: None}, literal(ValueType::Str(0), "short"))],
+            order_by: None,
+            limit: None,
+        }).unwrap();
+        let result = db.run_command(parse_command("select * from t where a = 19;").unwrap()).unwrap();
+        assert_eq!(strings(&result), vec![("s19".to_string(), "short".to_string())]);
+        db.run_command(Command::Delete {model: model.clone(), where_expr: None, order_by: None, limit: None}).unwrap();
+        db.run_command(insert()).unwrap();
+        // deleted rows' slots aren't reused, so the new rows need another
+        // page of the table, but their strings fit in the freed space
+        assert_eq!(file_size(), size + PAGE_SIZE);
+        let result = db.run_command(parse_command("select * from t;").unwrap()).unwrap();
+        assert_eq!(strings(&result).len(), 20);
+    }
+
+    #[test]
+    fn test_indexes() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let model = Identifier {name: "t".to_string(), qualifier: None};
+        let insert = |range: ::std::ops::Range<i64>| Command::Insert {
+            model: model.clone(),
+            cols: None,
+            values: InsertValues::Rows(range.map(|i| vec![
+                literal(ValueType::Int, &format!("{}", i)),
+                literal(ValueType::Str(0), &format!("k{}", i % 10)),
+                literal(ValueType::Float, &format!("{}.0", i)),
+            ]).collect()),
+        };
+        let update = |where_expr: &str, set: &str| Command::Update {
+            model: model.clone(),
+            where_expr: Some(parse_expr(where_expr).unwrap()),
+            set: vec![(Identifier {name: "a".to_string(), qualifier: None}, parse_expr(set).unwrap())],
+            order_by: None,
+            limit: None,
+        };
+        let queries = [
+            "select a from t where b = \"k3\" and a >= 500 and a < 600;",
+            "select a from t where a = 42;",
+            "select a from t where 990 < a;",
+            "select a from t where a > 10.5 and a <= 20;",
+            "select a from t where b > \"k8\" and a > 900 or a = 7;",
+        ];
+        let run = |db: &mut Database, query: &str| ints(&db.run_command(parse_command(query).unwrap()).unwrap());
+
+        let mut db = test_db(&dir);
+        db.run_command(parse_command("create table t (a int, b str(10), c float);").unwrap()).unwrap();
+        db.run_command(insert(0 .. 1000)).unwrap();
+        let expected: Vec<Vec<Vec<i64>>> = queries.iter().map(|q| run(&mut db, q)).collect();
+        assert_eq!(expected[1], vec![vec![42]]);
+
+        db.run_command(parse_command("create unique index by_a on t (a);").unwrap()).unwrap();
+        db.run_command(parse_command("create index by_b_a on t (b, a);").unwrap()).unwrap();
+        assert!(db.run_command(parse_command("create index by_a on t (b);").unwrap()).is_err());
+        assert!(db.run_command(parse_command("create index by_d on t (d);").unwrap()).is_err());
+        assert!(db.run_command(parse_command("create unique index by_b on t (b);").unwrap()).is_err());
+        for (query, rows) in queries.iter().zip(&expected) {
+            assert_eq!(&run(&mut db, query), rows, "{}", query);
+        }
+
+        // unique indexes reject duplicates, rolling back the whole command,
+        // but rows can swap values
+        assert!(db.run_command(insert(999 .. 1001)).is_err());
+        assert_eq!(run(&mut db, "select a from t where a >= 999;"), vec![vec![999]]);
+        assert!(db.run_command(update("a = 0", "1")).is_err());
+        db.run_command(update("a < 2", "1 - a")).unwrap();
+        assert_eq!(run(&mut db, "select a from t where a < 2;"), vec![vec![1], vec![0]]);
+        db.run_command(update("a = 0 or a = 1", "1 - a")).unwrap();
+
+        // updated and deleted rows are found in their new place, or not at all
+        db.run_command(update("a >= 990", "a + 1000")).unwrap();
+        assert_eq!(run(&mut db, "select a from t where a >= 990 and a < 1995;"), (1990 .. 1995).map(|i| vec![i]).collect::<Vec<_>>());
+        db.run_command(update("a >= 1990", "a - 1000")).unwrap();
+        db.run_command(Command::Delete {model: model.clone(), where_expr: Some(parse_expr("b = \"k3\"").unwrap()), order_by: None, limit: None}).unwrap();
+        let remaining = |rows: &Vec<Vec<i64>>| rows.iter().filter(|r| r[0] % 10 != 3).cloned().collect::<Vec<_>>();
+        for (query, rows) in queries.iter().zip(&expected) {
+            assert_eq!(run(&mut db, query), remaining(rows), "{}", query);
+        }
+
+        // index definitions are reloaded, and their trees are found again
+        drop(db);
+        let mut db = test_db(&dir);
+        assert_eq!(db.schemas["t"].indexes.len(), 2);
+        for (query, rows) in queries.iter().zip(&expected) {
+            assert_eq!(run(&mut db, query), remaining(rows), "{}", query);
+        }
+        assert!(db.run_command(insert(0 .. 1)).is_err());
+
+        db.run_command(parse_command("drop index by_a on t;").unwrap()).unwrap();
+        assert!(db.run_command(parse_command("drop index by_a on t;").unwrap()).is_err());
+        db.run_command(insert(0 .. 1)).unwrap();
+        assert_eq!(run(&mut db, "select a from t where a = 0;"), vec![vec![0], vec![0]]);
+        drop(db);
+        let mut db = test_db(&dir);
+        assert_eq!(db.schemas["t"].indexes.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(), vec!["by_b_a"]);
+
+        // an index whose schema can't be written isn't kept in the file
+        let schema_path = dir.path().join("test_db").join("t.schema");
+        ::std::fs::remove_file(&schema_path).unwrap();
+        ::std::fs::create_dir(&schema_path).unwrap();
+        assert!(db.run_command(parse_command("create index by_c on t (c);").unwrap()).is_err());
+        assert!(db.run_command(parse_command("drop index by_b_a on t;").unwrap()).is_err());
+        ::std::fs::remove_dir(&schema_path).unwrap();
+        db.write_schema(&db.schemas["t"]).unwrap();
+        drop(db);
+        let mut db = test_db(&dir);
+        assert_eq!(db.schemas["t"].indexes.len(), 1);
+        db.run_command(parse_command("create index by_c on t (c);").unwrap()).unwrap();
+        assert_eq!(run(&mut db, "select a from t where b = \"k1\" and a < 20;"), vec![vec![1], vec![11]]);
+    }
+
+    #[test]
+    fn test_nullable_and_vector_columns() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        db.run_command(parse_command("create table t (a int, b nullable int, v vector(2) str, s nullable str);").unwrap()).unwrap();
+        let long = "x".repeat(300);
+        let row = |a: i64, b: Option<i64>, s: Option<&str>| vec![
+            Value::Int(a),
+            b.map_or(Value::Null, Value::Int),
+            Value::Vector(vec![Value::Str(format!("v{}", a)), Value::Str(long.clone())]),
+            s.map_or(Value::Null, |s| Value::Str(s.to_string())),
+        ];
+        let rows = vec![row(1, Some(10), Some("one")), row(2, None, None), row(3, Some(30), Some(&long))];
+        db.in_transaction("t", |store| Ok(ResultSet::affected(store.insert(rows)?))).unwrap();
+
+        // NULL can be given or left out for nullable columns only
+        db.run_command(parse_command("create table u (a int, b nullable int);").unwrap()).unwrap();
+        db.run_command(parse_command("insert into u values (1, null), (2, 20);").unwrap()).unwrap();
+        db.run_command(parse_command("insert into u (a) values (3);").unwrap()).unwrap();
+        assert!(db.run_command(parse_command("insert into u values (null, 1);").unwrap()).is_err());
+        assert!(db.run_command(parse_command("insert into u (b) values (4);").unwrap()).is_err());
+        db.run_command(parse_command("update u set b = null where a = 2;").unwrap()).unwrap();
+        db.run_command(parse_command("update u set b = a * 10 where a = 3;").unwrap()).unwrap();
+        let result = db.run_command(parse_command("select a, b from u;").unwrap()).unwrap();
+        assert_eq!(result.rows, vec![
+            vec![Value::Int(1), Value::Null],
+            vec![Value::Int(2), Value::Null],
+            vec![Value::Int(3), Value::Int(30)],
+        ]);
+        assert!(db.run_command(parse_command("update u set a = null;").unwrap()).is_err());
+
+        // tables that can't be stored aren't created
+        assert!(db.run_command(parse_command("create table big (a vector(100) str(100));").unwrap()).is_err());
+        assert!(!dir.path().join("test_db").join("big.db").exists());
+        assert!(!dir.path().join("test_db").join("big.schema").exists());
+
+        let result = db.run_command(parse_command("select a, b from t where b > 5;").unwrap()).unwrap();
+        assert_eq!(result.schema.to_ddl(), "(a int, b nullable int)");
+        assert_eq!(ints(&result), vec![vec![1, 10], vec![3, 30]]);
+        db.run_command(parse_command("update t set a = a + 10 where b > 20;").unwrap()).unwrap();
+        db.run_command(parse_command("create index by_a on t (a);").unwrap()).unwrap();
+        assert!(db.run_command(parse_command("create index by_b on t (b);").unwrap()).is_err());
+        db.run_command(parse_command("delete from t where a = 1;").unwrap()).unwrap();
+
+        // strings inside vectors and nullable columns are kept in the heap
+        drop(db);
+        let mut db = test_db(&dir);
+        let result = db.run_command(parse_command("select * from t order by a;").unwrap()).unwrap();
+        assert_eq!(result.rows, vec![
+            vec![Value::Int(2), Value::Null, Value::Vector(vec![Value::Str("v2".to_string()), Value::Str(long.clone())]), Value::Null],
+            vec![Value::Int(13), Value::Int(30), Value::Vector(vec![Value::Str("v3".to_string()), Value::Str(long.clone())]), Value::Str(long.clone())],
+        ]);
+    }
+
+    #[test]
+    fn test_insert() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        let mut run = |query: &str| db.run_command(parse_command(query).unwrap());
+        run("create table t (a int, b int);").unwrap();
+        run("create table u (a int, b float, c int);").unwrap();
+
+        assert_eq!(run("insert into t values (1, 10), (2, 20), (3, 30);").unwrap().rows_affected, 3);
+        assert_eq!(run("insert into t (b, a) values (40, 4);").unwrap().rows_affected, 1);
+        assert!(run("insert into t (a) values (5);").is_err());
+        assert!(run("insert into t values (5, 50, 500);").is_err());
+
+        // selected rows are converted to the target's column types
+        let result = run("insert into u (c, a, b) select a, b, a * 2 from t where a > 1;").unwrap();
+        assert_eq!(result.rows_affected, 3);
+        let result = run("select a, c from u;").unwrap();
+        assert_eq!(ints(&result), vec![vec![20, 2], vec![30, 3], vec![40, 4]]);
+        assert!(run("insert into u select a, b from t;").is_err());
+        assert!(run("insert into t select a, b from missing;").is_err());
+
+        // a table can be copied into itself
+        assert_eq!(run("insert into t select * from t;").unwrap().rows_affected, 4);
+        assert_eq!(ints(&run("select a from t where a = 1;").unwrap()), vec![vec![1], vec![1]]);
+    }
+
+    #[test]
+    fn test_update_delete() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        let mut run = |query: &str| db.run_command(parse_command(query).unwrap());
+        run("create table t (a int, b int);").unwrap();
+        run("insert into t values (1, 50), (2, 40), (3, 30), (4, 20), (5, 10);").unwrap();
+
+        assert_eq!(run("update t set b = b + 1, a = a * 10 where a > 3;").unwrap().rows_affected, 2);
+        assert_eq!(run("update t set b = 0 order by b limit 2;").unwrap().rows_affected, 2);
+        assert!(run("update t set c = 1;").is_err());
+        assert_eq!(ints(&run("select a, b from t;").unwrap()), vec![vec![1, 50], vec![2, 40], vec![3, 30], vec![40, 0], vec![50, 0]]);
+
+        assert_eq!(run("delete from t where b = 0 or a = 1 order by a limit 2;").unwrap().rows_affected, 2);
+        assert_eq!(ints(&run("select a from t;").unwrap()), vec![vec![2], vec![3], vec![50]]);
+        assert_eq!(run("delete from t;").unwrap().rows_affected, 3);
+        assert_eq!(run("select a from t;").unwrap().rows.len(), 0);
+    }
+
+    #[test]
+    fn test_pagination() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        let mut run = |query: &str| db.run_command(parse_command(query).unwrap());
+        run("create table t (a int, b int);").unwrap();
+        run("insert into t values (1, 3), (2, 1), (3, 2), (4, 1), (5, 3), (6, 2);").unwrap();
+
+        let pages: Vec<Vec<Vec<i64>>> = (0 .. 3)
+            .map(|page| ints(&run(&format!("select a from t order by b desc, a limit 2 offset {};", page * 2)).unwrap()))
+            .collect();
+        assert_eq!(pages, vec![vec![vec![1], vec![5]], vec![vec![3], vec![6]], vec![vec![2], vec![4]]]);
+        assert_eq!(ints(&run("select a from t order by a desc offset 4;").unwrap()), vec![vec![2], vec![1]]);
+        assert_eq!(run("select a from t limit 2 offset 6;").unwrap().rows.len(), 0);
+        assert_eq!(run("select 1 offset 1;").unwrap().rows.len(), 0);
+
+        // UPDATE and DELETE page the same way
+        assert_eq!(run("delete from t order by a desc limit 1 offset 1;").unwrap().rows_affected, 1);
+        assert_eq!(ints(&run("select a from t;").unwrap()), vec![vec![1], vec![2], vec![3], vec![4], vec![6]]);
+
+        match run("select b from t group by b having b > 1;") {
+            Err(Error::NotYetImplemented) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_joins() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        let mut run = |query: &str| db.run_command(parse_command(query).unwrap());
+        run("create table customers (id int, name str(10));").unwrap();
+        run("create table orders (id int, customer int, total int);").unwrap();
+        run("create table items (order_id int, qty int);").unwrap();
+        run("insert into customers values (1, \"ann\"), (2, \"bob\"), (3, \"cy\");").unwrap();
+        run("insert into orders values (10, 1, 100), (11, 1, 150), (12, 2, 80), (13, 4, 20);").unwrap();
+        run("insert into items values (10, 1), (10, 2), (12, 5);").unwrap();
+
+        let result = run("select c.name, o.total from customers as c join orders as o on o.customer = c.id order by o.total desc;").unwrap();
+        assert_eq!(result.schema.to_ddl(), "(name str(10), total int)");
+        assert_eq!(result.rows.iter().map(|r| r[1].as_int()).collect::<Vec<_>>(), vec![150, 100, 80]);
+
+        // unmatched rows are padded with nulls, which WHERE can filter on
+        let result = run("select c.id, o.id from customers as c left join orders as o on o.customer = c.id order by c.id, o.id;").unwrap();
+        assert_eq!(result.schema.to_ddl(), "(id int, id nullable int)");
+        assert_eq!(result.rows.len(), 4);
+        assert_eq!(result.rows[3], vec![Value::from_int(3), Value::Null]);
+        let result = run("select o.id from customers as c right join orders as o on o.customer = c.id where c.id > 0 or o.total < 50;").unwrap();
+        assert_eq!(ints(&result), vec![vec![10], vec![11], vec![12], vec![13]]);
+        let result = run("select c.id, o.id from customers as c full join orders as o on o.customer = c.id;").unwrap();
+        assert_eq!(result.rows.len(), 5);
+        assert_eq!(result.rows[3], vec![Value::from_int(3), Value::Null]);
+        assert_eq!(result.rows[4], vec![Value::Null, Value::from_int(13)]);
+
+        // nested joins, and USING columns merged into one
+        let result = run("select name, order_id, qty from customers as c join (orders join items on items.order_id = orders.id) on orders.customer = c.id where qty > 1;").unwrap();
+        assert_eq!(result.rows.iter().map(|r| (r[0].clone(), r[1].as_int(), r[2].as_int())).collect::<Vec<_>>(), vec![
+            (Value::Str("ann".to_string()), 10, 2),
+            (Value::Str("bob".to_string()), 12, 5),
+        ]);
+        run("create table notes (id int, text str(10));").unwrap();
+        run("insert into notes values (2, \"hi\"), (5, \"x\");").unwrap();
+        let result = run("select * from customers full join notes using (id);").unwrap();
+        assert_eq!(result.schema.to_ddl(), "(id nullable int, name nullable str(10), text nullable str(10))");
+        assert_eq!(result.rows.iter().map(|r| r[0].as_int()).collect::<Vec<_>>(), vec![1, 2, 3, 5]);
+
+        // a model can be joined to itself under an alias
+        let result = run("select t.id, x.id from customers as t join customers as x on t.id + 1 = x.id;").unwrap();
+        assert_eq!(ints(&result), vec![vec![1, 2], vec![2, 3]]);
+        assert!(run("select customers.id from customers as c;").is_err());
+
+        // a comma-separated FROM list is a cross join
+        assert_eq!(run("select * from customers, items;").unwrap().rows.len(), 9);
+        assert_eq!(run("select * from customers as a, customers as b where a.id < b.id;").unwrap().rows.len(), 3);
+        assert!(run("select id from customers join orders on customer = customers.id;").is_err());
+        assert!(run("select * from customers join missing on 1 = 1;").is_err());
+
+        // joined rows can be inserted
+        run("create table totals (name str(10), total int);").unwrap();
+        let result = run("insert into totals select name, total from customers join orders on customers.id = orders.customer;").unwrap();
+        assert_eq!(result.rows_affected, 3);
+    }
+
+    #[test]
+    fn test_show_cache_stats() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut db = test_db(&dir);
+        db.run_command(parse_command("create table t (a int);").unwrap()).unwrap();
+        db.run_command(parse_command("select a from t;").unwrap()).unwrap();
+        db.run_command(parse_command("select a from t;").unwrap()).unwrap();
+
+        let result = db.run_command(parse_command("show cache stats;").unwrap()).unwrap();
+        assert_eq!(result.schema.0[0].name, "pages");
+        assert_eq!(result.rows.len(), 1);
+        let stats: Vec<u64> = result.rows[0].iter().map(|v| v.as_uint()).collect();
+        // the table's first page is loaded once, then found in the cache
+        assert_eq!(&stats[.. 5], &[16, 1, 1, 1, 0]);
+        assert_eq!(&stats[5 .. 8], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn test_write_back() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut config = Config::with_data_dir(dir.path().to_path_buf());
+        // fewer pages than the table needs, so modified pages are evicted
+        // before the insert finishes
+        config.working_memory = PAGE_SIZE * 2;
+        let mut db = Database::new("test_db".to_string(), Some(config)).unwrap();
+        db.run_command(parse_command("create table t (a int);").unwrap()).unwrap();
+        db.run_command(Command::Insert {
+            model: Identifier {name: "t".to_string(), qualifier: None},
+            cols: None,
+            values: InsertValues::Rows((0 .. 2000).map(|i| vec![literal(ValueType::Int, &format!("{}", i))]).collect()),
+        }).unwrap();
+
+        let result = db.run_command(parse_command("select a from t where a >= 1998 or a < 2;").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![0], vec![1], vec![1998], vec![1999]]);
+        let result = db.run_command(parse_command("select a from t;").unwrap()).unwrap();
+        assert_eq!(result.rows.len(), 2000);
+    }
+
+    #[test]
+    fn test_shared_pool() {
+        let dir = TempDir::new("soupdb").unwrap();
+        // too small for either table, so each database's inserts evict the
+        // other's pages
+        let pool = Arc::new(BufferPool::new(PAGE_SIZE * 2));
+        let open = |name: &str| {
+            Database::with_pool(name.to_string(), Some(Config::with_data_dir(dir.path().to_path_buf())), pool.clone()).unwrap()
+        };
+        let mut dbs = vec![open("db1"), open("db2")];
+        for db in dbs.iter_mut() {
+            db.run_command(parse_command("create table t (a int);").unwrap()).unwrap();
+        }
+        for i in 0 .. 2 {
+            for (n, db) in dbs.iter_mut().enumerate() {
+                db.run_command(Command::Insert {
+                    model: Identifier {name: "t".to_string(), qualifier: None},
+                    cols: None,
+                    values: InsertValues::Rows((0 .. 1000).map(|j| vec![literal(ValueType::Int, &format!("{}", (n + 1) * 10000 + i * 1000 + j))]).collect()),
+                }).unwrap();
+            }
+        }
+        for (n, db) in dbs.iter_mut().enumerate() {
+            let result = db.run_command(parse_command("select a from t;").unwrap()).unwrap();
+            let expected: Vec<Vec<i64>> = (0 .. 2000).map(|j| vec![(n as i64 + 1) * 10000 + j]).collect();
+            assert_eq!(ints(&result), expected);
+        }
+        assert_eq!(pool.stats().pages, 2);
+
+        // the last pages read were db2's; dropping it closes its files and
+        // evicts them
+        assert_eq!(pool.stats().cached_pages, 2);
+        dbs.remove(1);
+        assert_eq!(pool.stats().cached_pages, 0);
+        let mut db = open("db2");
+        let result = db.run_command(parse_command("select a from t where a >= 21998;").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![21998], vec![21999]]);
+    }
+
+    #[test]
+    fn test_mapped_backend() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let open = || {
+            let mut config = Config::with_data_dir(dir.path().to_path_buf());
+            config.working_memory = PAGE_SIZE * 2;
+            config.io_backend = IoBackend::Mapped;
+            Database::new("test_db".to_string(), Some(config)).unwrap()
+        };
+        {
+            let mut db = open();
+            db.run_command(parse_command("create table t (a int);").unwrap()).unwrap();
+            db.run_command(Command::Insert {
+                model: Identifier {name: "t".to_string(), qualifier: None},
+                cols: None,
+                values: InsertValues::Rows((0 .. 2000).map(|i| vec![literal(ValueType::Int, &format!("{}", i))]).collect()),
+            }).unwrap();
+            let result = db.run_command(parse_command("select a from t where a >= 1998 or a < 2;").unwrap()).unwrap();
+            assert_eq!(ints(&result), vec![vec![0], vec![1], vec![1998], vec![1999]]);
+        }
+
+        // unmodified pages are read from the file's map, without going
+        // through the page cache
+        let mut db = open();
+        let result = db.run_command(parse_command("select a from t where a >= 1998 or a < 2;").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![0], vec![1], vec![1998], vec![1999]]);
+        let stats = db.pool.stats();
+        assert_eq!((stats.cached_pages, stats.hits, stats.misses), (0, 0, 0));
+
+        db.run_command(Command::Delete {
+            model: Identifier {name: "t".to_string(), qualifier: None},
+            where_expr: Some(parse_expr("a >= 2").unwrap()),
+            order_by: None,
+            limit: None,
+        }).unwrap();
+        let result = db.run_command(parse_command("select a from t;").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn test_reload_schemas() {
+        let dir = TempDir::new("soupdb").unwrap();
+        {
+            let mut db = test_db(&dir);
+            db.run_command(parse_command("create table t (a int, b float);").unwrap()).unwrap();
+            db.run_command(parse_command("create document d;").unwrap()).unwrap();
+            db.run_command(parse_command("create table u (a int);").unwrap()).unwrap();
+            db.run_command(Command::Insert {
+                model: Identifier {name: "t".to_string(), qualifier: None},
+                cols: None,
+                values: InsertValues::Rows(vec![vec![literal(ValueType::Int, "1"), literal(ValueType::Float, "2.5")]]),
+            }).unwrap();
+            db.run_command(Command::DropModel {name: "u".to_string()}).unwrap();
+
+            // a schema that fails to be written leaves the old one in place
+            let temp_path = dir.path().join("test_db").join("t.schema.tmp");
+            create_dir_all(&temp_path).unwrap();
+            assert!(db.run_command(parse_command("create index by_a on t (a);").unwrap()).is_err());
+            ::std::fs::remove_dir(&temp_path).unwrap();
+        }
+
+        let mut db = test_db(&dir);
+        let mut names: Vec<&String> = db.schemas.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["d", "t"]);
+        assert_eq!(db.schemas["t"].to_ddl(), "create table t (a int, b float);");
+        assert!(db.schemas["t"].indexes.is_empty());
+        let result = db.run_command(parse_command("select a from t;").unwrap()).unwrap();
+        assert_eq!(ints(&result), vec![vec![1]]);
+    }
+
+    #[test]
+    fn test_invalid_schema() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let data_dir = dir.path().join("test_db");
+        create_dir_all(&data_dir).unwrap();
+        File::create(data_dir.join("bad.schema")).unwrap().write_all(b"create table bad (").unwrap();
+
+        match Database::new("test_db".to_string(), Some(Config::with_data_dir(dir.path().to_path_buf()))) {
+            Err(Error::ParseError(msg)) => assert!(msg.contains("bad.schema")),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("loaded an invalid schema"),
+        }
     }
 }
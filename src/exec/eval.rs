@@ -0,0 +1,534 @@
+use std::cmp::Ordering;
+use std::ops::Range;
+use ::{Error, Result};
+use ::ast::{Expr, Identifier, BinaryOperator, UnaryOperator};
+use ::ast::tuple::TupleDef;
+use ::ast::value_type::ValueType;
+use ::io::value::Value;
+
+/// A value along with the type needed to interpret it.
+#[derive(Debug, Clone)]
+pub struct TypedValue {
+    pub value_type: ValueType,
+    pub value: Value,
+}
+
+impl TypedValue {
+    pub fn new(value_type: ValueType, value: Value) -> TypedValue {
+        TypedValue {value_type, value}
+    }
+
+    /// A null of the given type.
+    pub fn null(value_type: ValueType) -> TypedValue {
+        TypedValue::new(nullable(value_type), Value::Null)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self.value, Value::Null)
+    }
+
+    pub fn as_bool(&self) -> Result<bool> {
+        match self.value_type {
+            ValueType::Bool => Ok(self.value.as_bool()),
+            ref t => Err(Error::TypeError(format!("expected bool, found {}", t.to_ddl()))),
+        }
+    }
+
+    /// The value of a boolean which may be null, in which case it's neither
+    /// true nor false.
+    pub fn truth(&self) -> Result<Option<bool>> {
+        match (non_null(&self.value_type), self.is_null()) {
+            (&ValueType::Bool, true) | (&ValueType::Unknown, true) => Ok(None),
+            (&ValueType::Bool, false) => Ok(Some(self.value.as_bool())),
+            (t, _) => Err(Error::TypeError(format!("expected bool, found {}", t.to_ddl()))),
+        }
+    }
+}
+
+/// The type of a value that may also be null.
+pub fn nullable(value_type: ValueType) -> ValueType {
+    match value_type {
+        ValueType::Nullable(_) => value_type,
+        t => ValueType::Nullable(Box::new(t)),
+    }
+}
+
+/// The type of the values of a type that aren't null.
+pub fn non_null(value_type: &ValueType) -> &ValueType {
+    match value_type {
+        &ValueType::Nullable(ref t) => t,
+        t => t,
+    }
+}
+
+fn is_nullable(value_type: &ValueType) -> bool {
+    matches!(value_type, &ValueType::Nullable(_))
+}
+
+/// The columns of one model in a row, and the names (model name or alias)
+/// which can be used to qualify them.
+#[derive(Debug, Clone)]
+pub struct Scope {
+    pub qualifiers: Vec<String>,
+    pub columns: Range<usize>,
+    /// Columns which have to be qualified, because a join USING them merged
+    /// them into a single column.
+    pub qualified_only: Vec<String>,
+}
+
+impl Scope {
+    pub fn new(qualifiers: Vec<String>, columns: Range<usize>) -> Scope {
+        Scope {qualifiers, columns, qualified_only: vec![]}
+    }
+}
+
+/// The columns which are visible to an expression, from one model or from
+/// several joined together.
+pub struct Bindings<'a> {
+    pub scopes: Vec<Scope>,
+    pub schema: &'a TupleDef,
+}
+
+impl<'a> Bindings<'a> {
+    pub fn new(qualifiers: Vec<&str>, schema: &'a TupleDef) -> Bindings<'a> {
+        let qualifiers = qualifiers.into_iter().map(|q| q.to_string()).collect();
+        Bindings::with_scopes(vec![Scope::new(qualifiers, 0 .. schema.0.len())], schema)
+    }
+
+    pub fn with_scopes(scopes: Vec<Scope>, schema: &'a TupleDef) -> Bindings<'a> {
+        Bindings {scopes, schema}
+    }
+
+    /// Find the position of a column referenced by an identifier. An
+    /// unqualified name has to match a column in exactly one model.
+    pub fn resolve(&self, id: &Identifier) -> Result<usize> {
+        if let Some(ref q) = id.qualifier {
+            if !self.scopes.iter().any(|s| s.qualifiers.contains(q)) {
+                return Err(Error::Custom(format!("unknown model {}", q)));
+            }
+        }
+        let mut found = None;
+        for scope in &self.scopes {
+            let visible = match id.qualifier {
+                Some(ref q) => scope.qualifiers.contains(q),
+                None => !scope.qualified_only.contains(&id.name),
+            };
+            if !visible {
+                continue;
+            }
+            let position = self.schema.0[scope.columns.clone()].iter().position(|e| e.name == id.name);
+            if let Some(i) = position {
+                if found.is_some() {
+                    return Err(Error::Custom(format!("ambiguous column {}", id.name)));
+                }
+                found = Some(scope.columns.start + i);
+            }
+        }
+        match found {
+            Some(i) => Ok(i),
+            None => Err(Error::Custom(format!("unknown column {}", id.name))),
+        }
+    }
+}
+
+fn numeric_rank(value_type: &ValueType) -> Option<u8> {
+    match value_type {
+        &ValueType::Uint | &ValueType::AutoId => Some(0),
+        &ValueType::Int => Some(1),
+        &ValueType::Float => Some(2),
+        _ => None,
+    }
+}
+
+/// The common type that two numeric operands are promoted to.
+pub fn promote(left: &ValueType, right: &ValueType) -> Result<ValueType> {
+    match (left, right) {
+        // a NULL literal takes the type of the other operand
+        (&ValueType::Unknown, t) | (t, &ValueType::Unknown) => return Ok(t.clone()),
+        _ => {}
+    }
+    match (numeric_rank(left), numeric_rank(right)) {
+        (Some(l), Some(r)) => Ok(match ::std::cmp::max(l, r) {
+            0 => ValueType::Uint,
+            1 => ValueType::Int,
+            _ => ValueType::Float,
+        }),
+        _ => Err(Error::TypeError(format!("can't combine {} and {}", left.to_ddl(), right.to_ddl()))),
+    }
+}
+
+/// Determine the type an expression will produce without evaluating it.
+pub fn infer_type(expr: &Expr, bindings: Option<&Bindings>) -> Result<ValueType> {
+    use ::ast::BinaryOperator::*;
+    match expr {
+        &Expr::Id(ref id) => match bindings {
+            Some(b) => Ok(b.schema[b.resolve(id)?].value.clone()),
+            None => Err(Error::Custom(format!("unknown column {}", id.name))),
+        },
+        &Expr::Literal {value_type: ValueType::Unknown, ..} => Ok(nullable(ValueType::Unknown)),
+        &Expr::Literal {ref value_type, ..} => Ok(value_type.clone()),
+        &Expr::FunctionCall {..} => Err(Error::NotYetImplemented),
+        &Expr::UnOp {ref expr, op: UnaryOperator::OpNot} => {
+            if is_nullable(&infer_type(expr, bindings)?) {
+                Ok(nullable(ValueType::Bool))
+            } else {
+                Ok(ValueType::Bool)
+            }
+        }
+        &Expr::BinOp {ref left, ref right, ref op} => {
+            let (l, r) = (infer_type(left, bindings)?, infer_type(right, bindings)?);
+            // an operation on a nullable value may produce null
+            let t = match op {
+                &OpMul | &OpDiv | &OpAdd | &OpSub => promote(non_null(&l), non_null(&r))?,
+                &OpEq | &OpNeq | &OpLt | &OpGt | &OpLte | &OpGte | &OpAnd | &OpOr => ValueType::Bool,
+                &OpIs | &OpLike | &OpIn => return Err(Error::NotYetImplemented),
+            };
+            if is_nullable(&l) || is_nullable(&r) {
+                Ok(nullable(t))
+            } else {
+                Ok(t)
+            }
+        }
+    }
+}
+
+fn parse_literal(value_type: &ValueType, value: &str) -> Result<Value> {
+    let invalid = || Error::TypeError(format!("invalid {} literal {}", value_type.to_ddl(), value));
+    match value_type {
+        &ValueType::Bool => match value {
+            "true" => Ok(Value::from_bool(true)),
+            "false" => Ok(Value::from_bool(false)),
+            _ => Err(invalid()),
+        },
+        &ValueType::Uint => value.parse::<u64>().map(Value::from_uint).map_err(|_| invalid()),
+        &ValueType::Int => value.parse::<i64>().map(Value::from_int).map_err(|_| invalid()),
+        &ValueType::Float => value.parse::<f64>().map(Value::from_float).map_err(|_| invalid()),
+        &ValueType::Str(_) => Ok(Value::Str(value.to_string())),
+        _ => Err(Error::NotYetImplemented),
+    }
+}
+
+/// Convert a value to another type, failing if it can't be represented.
+pub fn coerce(value: &TypedValue, to: &ValueType) -> Result<Value> {
+    let v = &value.value;
+    let out_of_range = || Error::TypeError(format!("value out of range for {}", to.to_ddl()));
+    match (&value.value_type, to) {
+        (&ValueType::Bool, &ValueType::Bool) => Ok(v.clone()),
+        (&ValueType::Uint, &ValueType::Uint) |
+        (&ValueType::Uint, &ValueType::AutoId) |
+        (&ValueType::AutoId, &ValueType::Uint) |
+        (&ValueType::AutoId, &ValueType::AutoId) |
+        (&ValueType::Int, &ValueType::Int) |
+        (&ValueType::Float, &ValueType::Float) => Ok(v.clone()),
+        (&ValueType::Uint, &ValueType::Int) | (&ValueType::AutoId, &ValueType::Int) => {
+            if v.as_uint() > i64::MAX as u64 {
+                Err(out_of_range())
+            } else {
+                Ok(Value::from_int(v.as_uint() as i64))
+            }
+        }
+        (&ValueType::Int, &ValueType::Uint) | (&ValueType::Int, &ValueType::AutoId) => {
+            if v.as_int() < 0 {
+                Err(out_of_range())
+            } else {
+                Ok(Value::from_uint(v.as_int() as u64))
+            }
+        }
+        (&ValueType::Uint, &ValueType::Float) | (&ValueType::AutoId, &ValueType::Float) => Ok(Value::from_float(v.as_uint() as f64)),
+        (&ValueType::Int, &ValueType::Float) => Ok(Value::from_float(v.as_int() as f64)),
+        (&ValueType::Str(_), &ValueType::Str(n)) => match v {
+            &Value::Str(ref s) if n > 0 && s.len() as u64 > n => {
+                Err(Error::TypeError(format!("string of {} bytes is too long for {}", s.len(), to.to_ddl())))
+            }
+            _ => Ok(v.clone()),
+        },
+        (from, &ValueType::Nullable(ref t)) => match v {
+            &Value::Null => Ok(Value::Null),
+            _ => coerce(&TypedValue::new(non_null(from).clone(), v.clone()), t),
+        },
+        (&ValueType::Nullable(ref from), to) => match v {
+            &Value::Null => Err(Error::TypeError(format!("null value for {}", to.to_ddl()))),
+            _ => coerce(&TypedValue::new((**from).clone(), v.clone()), to),
+        },
+        (from, to) => Err(Error::TypeError(format!("can't convert {} to {}", from.to_ddl(), to.to_ddl()))),
+    }
+}
+
+/// Order two values of compatible types.
+pub fn compare(left: &TypedValue, right: &TypedValue) -> Result<Ordering> {
+    match (&left.value_type, &right.value_type) {
+        (&ValueType::Bool, &ValueType::Bool) => return Ok(left.value.as_bool().cmp(&right.value.as_bool())),
+        (&ValueType::Str(_), &ValueType::Str(_)) => match left.value.partial_cmp(&right.value) {
+            Some(o) => return Ok(o),
+            None => return Err(Error::TypeError("can't compare strings stored off page".to_string())),
+        },
+        _ => {}
+    }
+    let t = promote(&left.value_type, &right.value_type)?;
+    let (l, r) = (coerce(left, &t)?, coerce(right, &t)?);
+    Ok(match t {
+        ValueType::Uint => l.as_uint().cmp(&r.as_uint()),
+        ValueType::Int => l.as_int().cmp(&r.as_int()),
+        _ => match l.as_float().partial_cmp(&r.as_float()) {
+            Some(o) => o,
+            None => return Err(Error::TypeError("can't compare NaN".to_string())),
+        },
+    })
+}
+
+fn arithmetic(op: &BinaryOperator, left: &TypedValue, right: &TypedValue) -> Result<TypedValue> {
+    use ::ast::BinaryOperator::*;
+    let t = promote(&left.value_type, &right.value_type)?;
+    let (l, r) = (coerce(left, &t)?, coerce(right, &t)?);
+    let overflow = || Error::Custom(format!("{} overflow", t.to_ddl()));
+    let div_zero = || Error::Custom("division by zero".to_string());
+    let value = match t {
+        ValueType::Uint => {
+            let (l, r) = (l.as_uint(), r.as_uint());
+            Value::from_uint(match op {
+                &OpMul => l.checked_mul(r).ok_or_else(overflow)?,
+                &OpDiv => l.checked_div(r).ok_or_else(div_zero)?,
+                &OpAdd => l.checked_add(r).ok_or_else(overflow)?,
+                _ => l.checked_sub(r).ok_or_else(overflow)?,
+            })
+        }
+        ValueType::Int => {
+            let (l, r) = (l.as_int(), r.as_int());
+            Value::from_int(match op {
+                &OpMul => l.checked_mul(r).ok_or_else(overflow)?,
+                &OpDiv => if r == 0 {return Err(div_zero())} else {l.checked_div(r).ok_or_else(overflow)?},
+                &OpAdd => l.checked_add(r).ok_or_else(overflow)?,
+                _ => l.checked_sub(r).ok_or_else(overflow)?,
+            })
+        }
+        _ => {
+            let (l, r) = (l.as_float(), r.as_float());
+            Value::from_float(match op {
+                &OpMul => l * r,
+                &OpDiv => l / r,
+                &OpAdd => l + r,
+                _ => l - r,
+            })
+        }
+    };
+    Ok(TypedValue::new(t, value))
+}
+
+/// The value of a column in a row. Values of nullable columns which aren't
+/// null have the column's underlying type.
+pub fn column_value(schema: &TupleDef, row: &[Value], i: usize) -> TypedValue {
+    let value_type = match row[i] {
+        Value::Null => schema[i].value.clone(),
+        _ => non_null(&schema[i].value).clone(),
+    };
+    TypedValue::new(value_type, row[i].clone())
+}
+
+fn truth_value(value: Option<bool>) -> TypedValue {
+    match value {
+        Some(b) => TypedValue::new(ValueType::Bool, Value::from_bool(b)),
+        None => TypedValue::null(ValueType::Bool),
+    }
+}
+
+/// Evaluate an expression against a single row. `row` holds the values of
+/// the columns described by `bindings`.
+pub fn evaluate(expr: &Expr, bindings: Option<&Bindings>, row: &[Value]) -> Result<TypedValue> {
+    use ::ast::BinaryOperator::*;
+    match expr {
+        &Expr::Id(ref id) => match bindings {
+            Some(b) => {
+                let i = b.resolve(id)?;
+                Ok(column_value(b.schema, row, i))
+            }
+            None => Err(Error::Custom(format!("unknown column {}", id.name))),
+        },
+        &Expr::Literal {value_type: ValueType::Unknown, ..} => Ok(TypedValue::null(ValueType::Unknown)),
+        &Expr::Literal {ref value_type, ref value} => {
+            Ok(TypedValue::new(value_type.clone(), parse_literal(value_type, value)?))
+        }
+        &Expr::FunctionCall {..} => Err(Error::NotYetImplemented),
+        &Expr::UnOp {ref expr, op: UnaryOperator::OpNot} => {
+            let value = evaluate(expr, bindings, row)?.truth()?;
+            Ok(truth_value(value.map(|b| !b)))
+        }
+        &Expr::BinOp {ref left, ref right, ref op} => {
+            let l = evaluate(left, bindings, row)?;
+            match op {
+                // short-circuit boolean operators, where a null operand
+                // only makes the result null if the other doesn't decide it
+                &OpAnd | &OpOr => {
+                    let decisive = *op == OpOr;
+                    let l = l.truth()?;
+                    if l == Some(decisive) {
+                        return Ok(truth_value(l));
+                    }
+                    let result = match (l, evaluate(right, bindings, row)?.truth()?) {
+                        (_, Some(r)) if r == decisive => Some(decisive),
+                        (Some(_), Some(_)) => Some(!decisive),
+                        _ => None,
+                    };
+                    return Ok(truth_value(result));
+                }
+                _ => {}
+            }
+            let r = evaluate(right, bindings, row)?;
+            if l.is_null() || r.is_null() {
+                return match op {
+                    &OpMul | &OpDiv | &OpAdd | &OpSub => Ok(TypedValue::null(promote(non_null(&l.value_type), non_null(&r.value_type))?)),
+                    &OpIs | &OpLike | &OpIn => Err(Error::NotYetImplemented),
+                    _ => Ok(truth_value(None)),
+                };
+            }
+            match op {
+                &OpMul | &OpDiv | &OpAdd | &OpSub => arithmetic(op, &l, &r),
+                &OpIs | &OpLike | &OpIn => Err(Error::NotYetImplemented),
+                _ => {
+                    let ordering = compare(&l, &r)?;
+                    let result = match op {
+                        &OpEq => ordering == Ordering::Equal,
+                        &OpNeq => ordering != Ordering::Equal,
+                        &OpLt => ordering == Ordering::Less,
+                        &OpGt => ordering == Ordering::Greater,
+                        &OpLte => ordering != Ordering::Greater,
+                        _ => ordering != Ordering::Less,
+                    };
+                    Ok(TypedValue::new(ValueType::Bool, Value::from_bool(result)))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::ast::parse::parse_expr;
+    use ::ast::tuple::TupleEntry;
+
+    fn eval(s: &str) -> Result<TypedValue> {
+        evaluate(&parse_expr(s).unwrap(), None, &[])
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let v = eval("1 + 2 * 3").unwrap();
+        assert_eq!(v.value_type, ValueType::Int);
+        assert_eq!(v.value.as_int(), 7);
+
+        let v = eval("1 + 2.5").unwrap();
+        assert_eq!(v.value_type, ValueType::Float);
+        assert_eq!(v.value.as_float(), 3.5);
+
+        assert!(eval("1 < 2 and not (3 = 4)").unwrap().as_bool().unwrap());
+        assert!(!eval("2 >= 2.5 or false").unwrap().as_bool().unwrap());
+        assert!(eval("\"abc\" < \"abd\" and \"x\" = \"x\"").unwrap().as_bool().unwrap());
+        assert!(eval("\"1\" = 1").is_err());
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("1 and true").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_columns() {
+        let schema = TupleDef(vec![
+            TupleEntry {name: "a".to_string(), value: ValueType::Int},
+            TupleEntry {name: "b".to_string(), value: ValueType::Uint},
+        ]);
+        let bindings = Bindings::new(vec!["t"], &schema);
+        let row = [Value::from_int(-4), Value::from_uint(10)];
+
+        let expr = parse_expr("t.a + b").unwrap();
+        assert_eq!(infer_type(&expr, Some(&bindings)), Ok(ValueType::Int));
+        assert_eq!(evaluate(&expr, Some(&bindings), &row).unwrap().value.as_int(), 6);
+
+        assert!(evaluate(&parse_expr("c").unwrap(), Some(&bindings), &row).is_err());
+        assert!(evaluate(&parse_expr("u.a").unwrap(), Some(&bindings), &row).is_err());
+    }
+
+    #[test]
+    fn test_nulls() {
+        let schema = TupleDef(vec![
+            TupleEntry {name: "a".to_string(), value: ValueType::Nullable(Box::new(ValueType::Int))},
+            TupleEntry {name: "b".to_string(), value: ValueType::Nullable(Box::new(ValueType::Bool))},
+        ]);
+        let bindings = Bindings::new(vec!["t"], &schema);
+        let eval = |s: &str, row: &[Value]| evaluate(&parse_expr(s).unwrap(), Some(&bindings), row).unwrap();
+        let nulls = [Value::Null, Value::Null];
+        let values = [Value::from_int(2), Value::from_bool(false)];
+
+        // values of nullable columns have their underlying type
+        assert_eq!(eval("a", &values).value_type, ValueType::Int);
+        assert_eq!(eval("a * 3", &values).value.as_int(), 6);
+        assert!(eval("a + 1", &nulls).is_null());
+        assert_eq!(infer_type(&parse_expr("a + 1").unwrap(), Some(&bindings)), Ok(nullable(ValueType::Int)));
+        assert_eq!(eval("a = 1", &nulls).truth(), Ok(None));
+        assert_eq!(eval("not b", &nulls).truth(), Ok(None));
+
+        // AND and OR are only null when the other operand doesn't decide them
+        assert_eq!(eval("b and false", &nulls).truth(), Ok(Some(false)));
+        assert_eq!(eval("b and true", &nulls).truth(), Ok(None));
+        assert_eq!(eval("true or b", &nulls).truth(), Ok(Some(true)));
+        assert_eq!(eval("b or false", &nulls).truth(), Ok(None));
+        assert_eq!(eval("b or a > 1", &values).truth(), Ok(Some(true)));
+
+        let int = ValueType::Nullable(Box::new(ValueType::Int));
+        assert_eq!(coerce(&TypedValue::null(ValueType::Int), &int), Ok(Value::Null));
+        assert_eq!(coerce(&TypedValue::new(ValueType::Uint, Value::from_uint(3)), &int), Ok(Value::from_int(3)));
+        assert_eq!(coerce(&TypedValue::new(int.clone(), Value::from_int(3)), &ValueType::Float), Ok(Value::from_float(3.0)));
+        assert!(coerce(&TypedValue::null(ValueType::Int), &ValueType::Int).is_err());
+
+        // a NULL literal can be stored in any nullable column
+        assert_eq!(coerce(&eval("null", &values), &int), Ok(Value::Null));
+        assert!(coerce(&eval("null", &values), &ValueType::Int).is_err());
+        assert!(eval("a - null", &values).is_null());
+        assert_eq!(eval("null = 1", &values).truth(), Ok(None));
+        assert_eq!(eval("null or true", &values).truth(), Ok(Some(true)));
+    }
+
+    #[test]
+    fn test_scopes() {
+        let schema = TupleDef(vec![
+            TupleEntry {name: "id".to_string(), value: ValueType::Int},
+            TupleEntry {name: "a".to_string(), value: ValueType::Int},
+            TupleEntry {name: "id".to_string(), value: ValueType::Int},
+            TupleEntry {name: "b".to_string(), value: ValueType::Int},
+        ]);
+        let scopes = vec![
+            Scope::new(vec!["t".to_string(), "x".to_string()], 0 .. 2),
+            Scope::new(vec!["u".to_string()], 2 .. 4),
+        ];
+        let resolve = |scopes: &[Scope], s: &str| match parse_expr(s).unwrap() {
+            Expr::Id(id) => Bindings::with_scopes(scopes.to_vec(), &schema).resolve(&id),
+            e => panic!("unexpected {:?}", e),
+        };
+        assert_eq!(resolve(&scopes, "a"), Ok(1));
+        assert_eq!(resolve(&scopes, "x.id"), Ok(0));
+        assert_eq!(resolve(&scopes, "u.id"), Ok(2));
+        assert!(resolve(&scopes, "id").is_err());
+        assert!(resolve(&scopes, "t.b").is_err());
+        assert!(resolve(&scopes, "v.a").is_err());
+
+        // a column merged by USING hides the columns it came from
+        let mut merged = scopes.clone();
+        for scope in merged.iter_mut() {
+            scope.qualified_only.push("id".to_string());
+        }
+        merged.push(Scope::new(vec![], 2 .. 3));
+        assert_eq!(resolve(&merged, "id"), Ok(2));
+        assert_eq!(resolve(&merged, "t.id"), Ok(0));
+    }
+
+    #[test]
+    fn test_coerce() {
+        let v = TypedValue::new(ValueType::Int, Value::from_int(3));
+        assert_eq!(coerce(&v, &ValueType::Uint).unwrap().as_uint(), 3);
+        assert_eq!(coerce(&v, &ValueType::Float).unwrap().as_float(), 3.0);
+        assert!(coerce(&v, &ValueType::Bool).is_err());
+        let v = TypedValue::new(ValueType::Int, Value::from_int(-3));
+        assert!(coerce(&v, &ValueType::Uint).is_err());
+        let v = TypedValue::new(ValueType::Str(0), Value::Str("abc".to_string()));
+        assert_eq!(coerce(&v, &ValueType::Str(3)), Ok(Value::Str("abc".to_string())));
+        assert!(coerce(&v, &ValueType::Str(2)).is_err());
+        assert!(coerce(&v, &ValueType::Int).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use std::cmp::Ordering;
+use std::ops::Bound;
+use byteorder::{ByteOrder, LittleEndian};
+use ::{Error, Result};
+use ::ast::tuple::TupleDef;
+use ::ast::value_type::{ValueType, MAX_INLINE_STRING_LENGTH};
+use ::io::dbfile::{PageMetadata, PAGE_METADATA_SIZE};
+use ::io::page::{FileId, PageId, PAGE_SIZE};
+use ::io::pool::BufferPool;
+use ::io::row::RowCodec;
+use ::io::value::Value;
+use ::model::index::Index;
+
+/// A node starts after the page metadata with its type, and a u16 count of
+/// its entries.
+const NODE_TYPE_OFFSET: usize = PAGE_METADATA_SIZE;
+const ENTRY_COUNT_OFFSET: usize = NODE_TYPE_OFFSET + 1;
+const ENTRIES_OFFSET: usize = ENTRY_COUNT_OFFSET + 2;
+
+const NODE_LEAF: u8 = 0;
+const NODE_INTERNAL: u8 = 1;
+
+/// A leaf entry is a key followed by the u64 page and u16 slot of its row.
+const LOCATION_SIZE: usize = 10;
+/// An internal node starts with the u64 ID of its first child, and each of
+/// its entries is a key followed by the u64 ID of the child holding the keys
+/// from that one up to the next entry's.
+const CHILD_SIZE: usize = 8;
+
+/// Every node has to hold at least this many entries, so that both halves
+/// of a split node still have a few.
+const MIN_ENTRIES: usize = 4;
+
+/// The page and slot a row is stored in.
+pub type Location = (PageId, usize);
+
+enum Node {
+    Leaf {entries: Vec<(Vec<Value>, Location)>, next: PageId},
+    Internal {first: PageId, entries: Vec<(Vec<Value>, PageId)>},
+}
+
+/// Order keys, or a key and a bound covering a prefix of its values, by the
+/// values they have in common. NaN floats are treated as equal.
+fn compare_prefix(key: &[Value], other: &[Value]) -> Ordering {
+    for (x, y) in key.iter().zip(other) {
+        match x.partial_cmp(y).unwrap_or(Ordering::Equal) {
+            Ordering::Equal => continue,
+            o => return o,
+        }
+    }
+    Ordering::Equal
+}
+
+/// The number of entries, which are in key order, whose keys come before a
+/// position.
+fn count_before<T, F: Fn(&[Value]) -> bool>(entries: &[(Vec<Value>, T)], before: F) -> usize {
+    let (mut low, mut high) = (0, entries.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if before(&entries[mid].0) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+/// A B+tree over some of a table's columns, stored in pages of the table's
+/// file. Keys are the indexed columns followed by the rowid, so every key is
+/// distinct even when the indexed values aren't, and leaves map them to the
+/// location of their row. Leaves are linked in key order by their
+/// `next_page`, so that ranges can be read without going back up the tree.
+///
+/// The root stays in the page it was created in: when it splits, its
+/// entries move to a new page below it. Nodes aren't merged when entries are
+/// removed, so an empty leaf stays in the tree until the index is dropped.
+pub struct IndexStore<'a> {
+    name: String,
+    unique: bool,
+    file: FileId,
+    pool: &'a BufferPool,
+    root: PageId,
+    /// the positions of the indexed columns in a stored row
+    columns: Vec<usize>,
+    codec: RowCodec,
+}
+
+impl<'a> IndexStore<'a> {
+    /// Open an index whose root is in the given page. `schema` is the full
+    /// schema of the table's stored rows, starting with the rowid.
+    pub fn open(index: &Index, schema: &TupleDef, root: PageId, file: FileId, pool: &'a BufferPool) -> Result<IndexStore<'a>> {
+        let mut columns = Vec::with_capacity(index.columns.len());
+        let mut entries = Vec::with_capacity(index.columns.len() + 1);
+        for name in &index.columns {
+            let i = match schema.index_of(name) {
+                Some(i) if i > 0 => i,
+                _ => return Err(Error::Custom(format!("unknown column {}", name))),
+            };
+            if columns.contains(&i) {
+                return Err(Error::Custom(format!("column {} is in index {} more than once", name, index.name)));
+            }
+            match schema[i].value {
+                ValueType::Bool | ValueType::Uint | ValueType::Int | ValueType::Float | ValueType::AutoId => {}
+                // longer strings may be stored off page
+                ValueType::Str(n) if n > 0 && n <= MAX_INLINE_STRING_LENGTH => {}
+                ref t => return Err(Error::Custom(format!("can't index column {} of type {}", name, t.to_ddl()))),
+            }
+            columns.push(i);
+            entries.push(schema[i].clone());
+        }
+        entries.push(schema[0].clone());
+        let store = IndexStore {
+            name: index.name.clone(),
+            unique: index.unique,
+            file,
+            pool,
+            root,
+            columns,
+            codec: RowCodec::new(&TupleDef(entries)),
+        };
+        if store.leaf_capacity() < MIN_ENTRIES || store.internal_capacity() < MIN_ENTRIES {
+            return Err(Error::Custom(format!("the keys of index {} are too large", index.name)));
+        }
+        Ok(store)
+    }
+
+    /// Create an empty index, allocating its root page.
+    pub fn create(index: &Index, schema: &TupleDef, file: FileId, pool: &'a BufferPool) -> Result<IndexStore<'a>> {
+        let mut store = IndexStore::open(index, schema, 0, file, pool)?;
+        store.root = store.allocate()?;
+        store.write_node(store.root, &Node::Leaf {entries: vec![], next: 0})?;
+        Ok(store)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn root(&self) -> PageId {
+        self.root
+    }
+
+    /// The positions of the indexed columns in a stored row, in index order.
+    pub fn columns(&self) -> &[usize] {
+        &self.columns
+    }
+
+    /// Add a stored row to the index. Fails if the index is unique and
+    /// already holds a row with the same values.
+    pub fn insert(&mut self, row: &[Value], location: Location) -> Result<()> {
+        let key = self.key(row);
+        if self.unique {
+            let values = Bound::Included(key[.. self.columns.len()].to_vec());
+            if !self.range(&values, &values)?.is_empty() {
+                return Err(Error::Custom(format!("duplicate value {} in unique index {}", Value::Vector(key[.. self.columns.len()].to_vec()), self.name)));
+            }
+        }
+        let root = self.root;
+        if let Some((separator, right)) = self.insert_into(root, key, location)? {
+            let left = self.allocate()?;
+            let node = self.read_node(root)?;
+            self.write_node(left, &node)?;
+            self.write_node(root, &Node::Internal {first: left, entries: vec![(separator, right)]})?;
+        }
+        Ok(())
+    }
+
+    /// Remove a stored row from the index.
+    pub fn remove(&mut self, row: &[Value]) -> Result<()> {
+        let key = self.key(row);
+        let mut page_id = self.root;
+        loop {
+            let mut node = self.read_node(page_id)?;
+            match node {
+                Node::Internal {first, ref entries} => {
+                    page_id = self.child(first, entries, |k| compare_prefix(k, &key) != Ordering::Greater);
+                    continue;
+                }
+                Node::Leaf {ref mut entries, ..} => match entries.iter().position(|e| compare_prefix(&e.0, &key) == Ordering::Equal) {
+                    Some(i) => {
+                        entries.remove(i);
+                    }
+                    None => return Err(Error::Corruption(format!("row {} is missing from index {}", row[0], self.name))),
+                },
+            }
+            return self.write_node(page_id, &node);
+        }
+    }
+
+    /// Find the rows whose indexed values are between two bounds, in key
+    /// order. A bound may give values for only the first few columns.
+    pub fn range(&self, lower: &Bound<Vec<Value>>, upper: &Bound<Vec<Value>>) -> Result<Vec<Location>> {
+        let before = |key: &[Value]| match lower {
+            &Bound::Included(ref v) => compare_prefix(key, v) == Ordering::Less,
+            &Bound::Excluded(ref v) => compare_prefix(key, v) != Ordering::Greater,
+            &Bound::Unbounded => false,
+        };
+        let after = |key: &[Value]| match upper {
+            &Bound::Included(ref v) => compare_prefix(key, v) == Ordering::Greater,
+            &Bound::Excluded(ref v) => compare_prefix(key, v) != Ordering::Less,
+            &Bound::Unbounded => false,
+        };
+
+        // find the leaf that would hold the first key in range
+        let mut page_id = self.root;
+        while let Node::Internal {first, entries} = self.read_node(page_id)? {
+            page_id = self.child(first, &entries, before);
+        }
+        let mut locations = vec![];
+        while page_id != 0 {
+            let (entries, next) = match self.read_node(page_id)? {
+                Node::Leaf {entries, next} => (entries, next),
+                Node::Internal {..} => return Err(Error::Corruption(format!("page {} of index {} is linked to a leaf", page_id, self.name))),
+            };
+            for (key, location) in entries {
+                if after(&key) {
+                    return Ok(locations);
+                }
+                if !before(&key) {
+                    locations.push(location);
+                }
+            }
+            page_id = next;
+        }
+        Ok(locations)
+    }
+
+    /// Free every page of the index.
+    pub fn destroy(self) -> Result<()> {
+        self.free_node(self.root)
+    }
+
+    /// The key of a stored row: its indexed values followed by its rowid.
+    fn key(&self, row: &[Value]) -> Vec<Value> {
+        let mut key: Vec<Value> = self.columns.iter().map(|&i| row[i].clone()).collect();
+        key.push(row[0].clone());
+        key
+    }
+
+    fn leaf_capacity(&self) -> usize {
+        (PAGE_SIZE as usize - ENTRIES_OFFSET) / (self.codec.size() + LOCATION_SIZE)
+    }
+
+    fn internal_capacity(&self) -> usize {
+        (PAGE_SIZE as usize - ENTRIES_OFFSET - CHILD_SIZE) / (self.codec.size() + CHILD_SIZE)
+    }
+
+    /// The child of an internal node that holds the keys following the ones
+    /// `before` selects.
+    fn child<F: Fn(&[Value]) -> bool>(&self, first: PageId, entries: &[(Vec<Value>, PageId)], before: F) -> PageId {
+        match count_before(entries, before) {
+            0 => first,
+            i => entries[i - 1].1,
+        }
+    }
+
+    /// Insert a key into the subtree rooted at a node. If the node had to be
+    /// split, returns the first key of its new right half, and the page the
+    /// right half was written to.
+    fn insert_into(&mut self, page_id: PageId, key: Vec<Value>, location: Location) -> Result<Option<(Vec<Value>, PageId)>> {
+        let mut node = self.read_node(page_id)?;
+        let split = match node {
+            Node::Leaf {ref mut entries, ref mut next} => {
+                let i = count_before(entries, |k| compare_prefix(k, &key) == Ordering::Less);
+                entries.insert(i, (key, location));
+                if entries.len() > self.leaf_capacity() {
+                    let right_page = self.allocate()?;
+                    let half = entries.len() / 2;
+                    let right = entries.split_off(half);
+                    let separator = right[0].0.clone();
+                    self.write_node(right_page, &Node::Leaf {entries: right, next: *next})?;
+                    *next = right_page;
+                    Some((separator, right_page))
+                } else {
+                    None
+                }
+            }
+            Node::Internal {first, ref mut entries} => {
+                let i = count_before(entries, |k| compare_prefix(k, &key) != Ordering::Greater);
+                let child = if i == 0 {first} else {entries[i - 1].1};
+                match self.insert_into(child, key, location)? {
+                    Some(entry) => entries.insert(i, entry),
+                    // only the subtree changed
+                    None => return Ok(None),
+                }
+                if entries.len() > self.internal_capacity() {
+                    let right_page = self.allocate()?;
+                    let half = entries.len() / 2;
+                    let mut right = entries.split_off(half);
+                    // the middle key moves up, and its child becomes the
+                    // right half's first
+                    let (separator, right_first) = right.remove(0);
+                    self.write_node(right_page, &Node::Internal {first: right_first, entries: right})?;
+                    Some((separator, right_page))
+                } else {
+                    None
+                }
+            }
+        };
+        self.write_node(page_id, &node)?;
+        Ok(split)
+    }
+
+    fn free_node(&self, page_id: PageId) -> Result<()> {
+        if let Node::Internal {first, entries} = self.read_node(page_id)? {
+            self.free_node(first)?;
+            for (_, child) in entries {
+                self.free_node(child)?;
+            }
+        }
+        self.pool.invalidate(&(self.file.clone(), page_id));
+        self.pool.with_file(&self.file, |file| file.free_page(page_id))
+    }
+
+    fn allocate(&self) -> Result<PageId> {
+        self.pool.with_file(&self.file, |file| file.allocate_page())
+    }
+
+    fn read_node(&self, page_id: PageId) -> Result<Node> {
+        let page = self.pool.get_page((self.file.clone(), page_id))?;
+        let count = LittleEndian::read_u16(&page[ENTRY_COUNT_OFFSET ..]) as usize;
+        let key_size = self.codec.size();
+        match page[NODE_TYPE_OFFSET] {
+            NODE_LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for i in 0 .. count {
+                    let offset = ENTRIES_OFFSET + i * (key_size + LOCATION_SIZE);
+                    let key = self.codec.decode(&page[offset .. offset + key_size])?;
+                    let page_id = LittleEndian::read_u64(&page[offset + key_size ..]);
+                    let slot = LittleEndian::read_u16(&page[offset + key_size + 8 ..]) as usize;
+                    entries.push((key, (page_id, slot)));
+                }
+                Ok(Node::Leaf {entries, next: PageMetadata::from_page(&page).next_page})
+            }
+            NODE_INTERNAL => {
+                let mut entries = Vec::with_capacity(count);
+                for i in 0 .. count {
+                    let offset = ENTRIES_OFFSET + CHILD_SIZE + i * (key_size + CHILD_SIZE);
+                    let key = self.codec.decode(&page[offset .. offset + key_size])?;
+                    entries.push((key, LittleEndian::read_u64(&page[offset + key_size ..])));
+                }
+                Ok(Node::Internal {first: LittleEndian::read_u64(&page[ENTRIES_OFFSET ..]), entries})
+            }
+            t => Err(Error::Corruption(format!("page {} of index {} has invalid node type {}", page_id, self.name, t))),
+        }
+    }
+
+    /// Replace a page with a node, marking it dirty.
+    fn write_node(&self, page_id: PageId, node: &Node) -> Result<()> {
+        let mut page = vec![0; PAGE_SIZE as usize];
+        let key_size = self.codec.size();
+        match node {
+            &Node::Leaf {ref entries, next} => {
+                PageMetadata::new(0, next).write_to_page(&mut page);
+                page[NODE_TYPE_OFFSET] = NODE_LEAF;
+                LittleEndian::write_u16(&mut page[ENTRY_COUNT_OFFSET ..], entries.len() as u16);
+                for (i, &(ref key, (page_id, slot))) in entries.iter().enumerate() {
+                    let offset = ENTRIES_OFFSET + i * (key_size + LOCATION_SIZE);
+                    self.codec.encode(key, &mut page[offset .. offset + key_size])?;
+                    LittleEndian::write_u64(&mut page[offset + key_size ..], page_id);
+                    LittleEndian::write_u16(&mut page[offset + key_size + 8 ..], slot as u16);
+                }
+            }
+            &Node::Internal {first, ref entries} => {
+                PageMetadata::new(0, 0).write_to_page(&mut page);
+                page[NODE_TYPE_OFFSET] = NODE_INTERNAL;
+                LittleEndian::write_u16(&mut page[ENTRY_COUNT_OFFSET ..], entries.len() as u16);
+                LittleEndian::write_u64(&mut page[ENTRIES_OFFSET ..], first);
+                for (i, &(ref key, child)) in entries.iter().enumerate() {
+                    let offset = ENTRIES_OFFSET + CHILD_SIZE + i * (key_size + CHILD_SIZE);
+                    self.codec.encode(key, &mut page[offset .. offset + key_size])?;
+                    LittleEndian::write_u64(&mut page[offset + key_size ..], child);
+                }
+            }
+        }
+        let (_lock, mut buf) = self.pool.overwrite_page((self.file.clone(), page_id))?;
+        buf.copy_from_slice(&page);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use ::ast::tuple::TupleEntry;
+    use ::exec::table::test_pool;
+
+    fn schema() -> TupleDef {
+        TupleDef(vec![
+            TupleEntry {name: "rowid".to_string(), value: ValueType::AutoId},
+            TupleEntry {name: "a".to_string(), value: ValueType::Int},
+            // long keys, so that the tree is a few levels deep
+            TupleEntry {name: "b".to_string(), value: ValueType::Str(256)},
+            TupleEntry {name: "c".to_string(), value: ValueType::Str(0)},
+        ])
+    }
+
+    fn row(rowid: u64) -> Vec<Value> {
+        let a = (rowid * 7919 % 500) as i64;
+        vec![Value::Uint(rowid), Value::Int(a), Value::Str(format!("{}", a % 3)), Value::OffPage(0, 0)]
+    }
+
+    fn index(columns: &[&str], unique: bool) -> Index {
+        Index {name: "i".to_string(), columns: columns.iter().map(|c| c.to_string()).collect(), unique}
+    }
+
+    #[test]
+    fn test_index() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let pool = test_pool(&dir, 8);
+        let schema = schema();
+        assert!(IndexStore::create(&index(&["c"], false), &schema, "t".to_string(), &pool).is_err());
+        assert!(IndexStore::create(&index(&["a", "a"], false), &schema, "t".to_string(), &pool).is_err());
+        assert!(IndexStore::create(&index(&["d"], false), &schema, "t".to_string(), &pool).is_err());
+
+        let mut store = IndexStore::create(&index(&["b", "a"], false), &schema, "t".to_string(), &pool).unwrap();
+        let mut rows: Vec<Vec<Value>> = (1 .. 1001).map(row).collect();
+        for r in &rows {
+            store.insert(r, (r[0].as_uint(), 0)).unwrap();
+        }
+        let check = |store: &IndexStore, rows: &[Vec<Value>], lower: Bound<Vec<Value>>, upper: Bound<Vec<Value>>| {
+            let mut expected: Vec<&Vec<Value>> = rows.iter().filter(|r| {
+                let key = vec![r[2].clone(), r[1].clone()];
+                let above = match lower {
+                    Bound::Included(ref v) => compare_prefix(&key, v) != Ordering::Less,
+                    Bound::Excluded(ref v) => compare_prefix(&key, v) == Ordering::Greater,
+                    Bound::Unbounded => true,
+                };
+                let below = match upper {
+                    Bound::Included(ref v) => compare_prefix(&key, v) != Ordering::Greater,
+                    Bound::Excluded(ref v) => compare_prefix(&key, v) == Ordering::Less,
+                    Bound::Unbounded => true,
+                };
+                above && below
+            }).collect();
+            expected.sort_by(|x, y| compare_prefix(&[x[2].clone(), x[1].clone(), x[0].clone()], &[y[2].clone(), y[1].clone(), y[0].clone()]));
+            let found = store.range(&lower, &upper).unwrap();
+            assert_eq!(found, expected.iter().map(|r| (r[0].as_uint(), 0)).collect::<Vec<Location>>());
+        };
+        let one = Value::Str("1".to_string());
+        check(&store, &rows, Bound::Unbounded, Bound::Unbounded);
+        check(&store, &rows, Bound::Included(vec![one.clone()]), Bound::Included(vec![one.clone()]));
+        check(&store, &rows, Bound::Excluded(vec![one.clone()]), Bound::Unbounded);
+        check(&store, &rows, Bound::Included(vec![one.clone(), Value::Int(100)]), Bound::Excluded(vec![one.clone(), Value::Int(200)]));
+        check(&store, &rows, Bound::Excluded(vec![one.clone(), Value::Int(499)]), Bound::Included(vec![Value::Str("2".to_string()), Value::Int(2)]));
+
+        // removed keys are no longer found, even once leaves are empty
+        for r in rows.iter().filter(|r| r[0].as_uint() % 4 != 0) {
+            store.remove(r).unwrap();
+        }
+        assert!(store.remove(&rows[0]).is_err());
+        rows.retain(|r| r[0].as_uint() % 4 == 0);
+        check(&store, &rows, Bound::Unbounded, Bound::Unbounded);
+        check(&store, &rows, Bound::Included(vec![one.clone()]), Bound::Included(vec![one.clone()]));
+        for r in &rows {
+            store.remove(r).unwrap();
+        }
+        assert!(store.range(&Bound::Unbounded, &Bound::Unbounded).unwrap().is_empty());
+
+        // the root is freed last, so it's the first page to be reused
+        let root = store.root();
+        store.destroy().unwrap();
+        let store = IndexStore::create(&index(&["a"], true), &schema, "t".to_string(), &pool).unwrap();
+        assert_eq!(store.root(), root);
+    }
+
+    #[test]
+    fn test_unique() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let pool = test_pool(&dir, 8);
+        let mut store = IndexStore::create(&index(&["a"], true), &schema(), "t".to_string(), &pool).unwrap();
+        for rowid in 1 .. 501 {
+            store.insert(&row(rowid), (rowid, 0)).unwrap();
+        }
+        // 7919 is prime, so the next row repeats the first value
+        assert!(store.insert(&row(501), (501, 0)).is_err());
+        store.remove(&row(1)).unwrap();
+        store.insert(&row(501), (501, 0)).unwrap();
+        let value = Bound::Included(vec![row(1)[1].clone()]);
+        assert_eq!(store.range(&value, &value).unwrap(), vec![(501, 0)]);
+    }
+}
@@ -0,0 +1,364 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use ::{Error, Result};
+use ::ast::{BinaryOperator, Expr, Identifier};
+use ::ast::command::{JoinCondition, JoinType};
+use ::ast::tuple::{TupleDef, TupleEntry};
+use ::ast::value_type::ValueType;
+use ::io::value::Value;
+use ::exec::conjuncts;
+use ::exec::eval::{Bindings, Scope, coerce, column_value, compare, evaluate, non_null, nullable, promote};
+use ::exec::table::TableStore;
+
+/// The rows of a FROM clause, along with the models their columns came from.
+pub struct Relation {
+    pub schema: TupleDef,
+    pub scopes: Vec<Scope>,
+    /// The columns that SELECT * returns, in order.
+    pub visible: Vec<usize>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Relation {
+    /// Read every row of a table, whose columns can be qualified by any of
+    /// `qualifiers`.
+    pub fn scan(store: &TableStore, qualifiers: Vec<&str>) -> Result<Relation> {
+        let schema = store.schema().clone();
+        let mut rows = Vec::new();
+        for row in store.rows() {
+            rows.push(row?.values);
+        }
+        let width = schema.0.len();
+        Ok(Relation {
+            scopes: vec![Scope::new(qualifiers.into_iter().map(|q| q.to_string()).collect(), 0 .. width)],
+            // skip the hidden rowid column
+            visible: (1 .. width).collect(),
+            schema,
+            rows,
+        })
+    }
+
+    fn width(&self) -> usize {
+        self.schema.0.len()
+    }
+}
+
+/// A key value that can be hashed, for matching rows on equal columns.
+#[derive(PartialEq, Eq, Hash)]
+enum HashKey {
+    Bool(bool),
+    Uint(u64),
+    Int(i64),
+    Str(String),
+}
+
+/// The type to hash a pair of key columns as, or None if they can't be
+/// hashed, like floats, which compare equal with different representations.
+fn hash_type(left: &ValueType, right: &ValueType) -> Option<ValueType> {
+    match (non_null(left), non_null(right)) {
+        (&ValueType::Bool, &ValueType::Bool) => Some(ValueType::Bool),
+        (&ValueType::Str(_), &ValueType::Str(_)) => Some(ValueType::Str(0)),
+        (l, r) => match promote(l, r) {
+            Ok(ValueType::Float) | Err(_) => None,
+            Ok(t) => Some(t),
+        },
+    }
+}
+
+/// The hash keys of a row, or None if any of them is null, since a null
+/// doesn't equal anything.
+fn hash_keys(schema: &TupleDef, row: &[Value], columns: &[(usize, ValueType)]) -> Result<Option<Vec<HashKey>>> {
+    let mut keys = Vec::with_capacity(columns.len());
+    for &(i, ref t) in columns {
+        let value = column_value(schema, row, i);
+        if value.is_null() {
+            return Ok(None);
+        }
+        keys.push(match coerce(&value, t)? {
+            Value::Bool(b) => HashKey::Bool(b),
+            Value::Uint(n) => HashKey::Uint(n),
+            Value::Int(n) => HashKey::Int(n),
+            Value::Str(s) => HashKey::Str(s),
+            v => return Err(Error::TypeError(format!("can't join on {:?}", v))),
+        });
+    }
+    Ok(Some(keys))
+}
+
+/// Find the columns that a join condition requires to be equal, as pairs of
+/// positions in the left and right sides' rows and the type to hash them as.
+fn equi_keys(condition: &Expr, bindings: &Bindings, left_width: usize) -> Vec<(usize, usize, ValueType)> {
+    let mut terms = Vec::new();
+    conjuncts(condition, &mut terms);
+    let mut keys = Vec::new();
+    for term in terms {
+        let (a, b) = match term {
+            &Expr::BinOp {ref left, op: BinaryOperator::OpEq, ref right} => match (&**left, &**right) {
+                (&Expr::Id(ref a), &Expr::Id(ref b)) => match (bindings.resolve(a), bindings.resolve(b)) {
+                    (Ok(a), Ok(b)) => (a, b),
+                    _ => continue,
+                },
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let (l, r) = match (a < left_width, b < left_width) {
+            (true, false) => (a, b),
+            (false, true) => (b, a),
+            _ => continue,
+        };
+        if let Some(t) = hash_type(&bindings.schema[l].value, &bindings.schema[r].value) {
+            keys.push((l, r - left_width, t));
+        }
+    }
+    keys
+}
+
+/// Join the rows of two relations. Rows are matched with a hash join when
+/// the condition requires some of their columns to be equal, and by
+/// comparing every pair of rows otherwise.
+///
+/// The joined rows hold the left side's columns followed by the right
+/// side's, and then the merged columns of a join USING them.
+pub fn join(left: Relation, right: Relation, join_type: JoinType, condition: &JoinCondition) -> Result<Relation> {
+    let left_width = left.width();
+    let keep_left = join_type == JoinType::Left || join_type == JoinType::Full;
+    let keep_right = join_type == JoinType::Right || join_type == JoinType::Full;
+
+    // the columns of a side can be null when the other side's unmatched rows
+    // are kept
+    let padded = |schema: &TupleDef, nulls: bool| -> Vec<TupleEntry> {
+        schema.0.iter().map(|e| TupleEntry {
+            name: e.name.clone(),
+            value: if nulls {nullable(e.value.clone())} else {e.value.clone()},
+        }).collect()
+    };
+    let mut entries = padded(&left.schema, keep_right);
+    entries.extend(padded(&right.schema, keep_left));
+    let mut scopes = left.scopes.clone();
+    scopes.extend(right.scopes.iter().map(|s| Scope {
+        columns: s.columns.start + left_width .. s.columns.end + left_width,
+        ..s.clone()
+    }));
+    let paired = TupleDef(entries);
+
+    // the positions of USING columns on each side, and their merged type
+    let mut using = Vec::new();
+    if let &JoinCondition::Using(ref names) = condition {
+        let left_bindings = Bindings::with_scopes(left.scopes.clone(), &left.schema);
+        let right_bindings = Bindings::with_scopes(right.scopes.clone(), &right.schema);
+        for name in names {
+            let id = Identifier {name: name.clone(), qualifier: None};
+            let (l, r) = (left_bindings.resolve(&id)?, right_bindings.resolve(&id)?);
+            let merged_type = match join_type {
+                JoinType::Inner | JoinType::Left => left.schema[l].value.clone(),
+                JoinType::Right => right.schema[r].value.clone(),
+                JoinType::Full => nullable(left.schema[l].value.clone()),
+            };
+            using.push((l, r, merged_type));
+        }
+    }
+
+    let on = match condition {
+        &JoinCondition::On(ref e) => Some(e),
+        _ => None,
+    };
+    let keys = {
+        let bindings = Bindings::with_scopes(scopes.clone(), &paired);
+        match on {
+            Some(e) => equi_keys(e, &bindings, left_width),
+            None => using.iter()
+                .filter_map(|&(l, r, _)| hash_type(&left.schema[l].value, &right.schema[r].value).map(|t| (l, r, t)))
+                .collect(),
+        }
+    };
+    let left_keys: Vec<(usize, ValueType)> = keys.iter().map(|&(l, _, ref t)| (l, t.clone())).collect();
+    let right_keys: Vec<(usize, ValueType)> = keys.iter().map(|&(_, r, ref t)| (r, t.clone())).collect();
+    let mut table: HashMap<Vec<HashKey>, Vec<usize>> = HashMap::new();
+    if !keys.is_empty() {
+        for (i, row) in right.rows.iter().enumerate() {
+            if let Some(key) = hash_keys(&right.schema, row, &right_keys)? {
+                table.entry(key).or_default().push(i);
+            }
+        }
+    }
+    let all_rows: Vec<usize> = (0 .. right.rows.len()).collect();
+
+    let bindings = Bindings::with_scopes(scopes.clone(), &paired);
+    let matches = |l: &[Value], r: &[Value]| -> Result<bool> {
+        for &(lp, rp, _) in &using {
+            let (x, y) = (column_value(&left.schema, l, lp), column_value(&right.schema, r, rp));
+            if x.is_null() || y.is_null() || compare(&x, &y)? != Ordering::Equal {
+                return Ok(false);
+            }
+        }
+        match on {
+            Some(e) => {
+                let row: Vec<Value> = l.iter().chain(r).cloned().collect();
+                Ok(evaluate(e, Some(&bindings), &row)?.truth()? == Some(true))
+            }
+            None => Ok(true),
+        }
+    };
+    let combine = |l: Option<&Vec<Value>>, r: Option<&Vec<Value>>| -> Result<Vec<Value>> {
+        let mut row = match l {
+            Some(l) => l.clone(),
+            None => vec![Value::Null; left_width],
+        };
+        match r {
+            Some(r) => row.extend(r.iter().cloned()),
+            None => row.extend((0 .. right.width()).map(|_| Value::Null)),
+        }
+        for &(lp, rp, ref t) in &using {
+            let value = match (l, r) {
+                (Some(l), _) if !column_value(&left.schema, l, lp).is_null() => coerce(&column_value(&left.schema, l, lp), t)?,
+                (_, Some(r)) => coerce(&column_value(&right.schema, r, rp), t)?,
+                _ => Value::Null,
+            };
+            row.push(value);
+        }
+        Ok(row)
+    };
+
+    let mut rows = Vec::new();
+    let mut right_matched = vec![false; right.rows.len()];
+    for l in &left.rows {
+        let candidates = if keys.is_empty() {
+            &all_rows[..]
+        } else {
+            match hash_keys(&left.schema, l, &left_keys)? {
+                Some(key) => table.get(&key).map_or(&[][..], |rows| &rows[..]),
+                None => &[][..],
+            }
+        };
+        let mut matched = false;
+        for &i in candidates {
+            let r = &right.rows[i];
+            if matches(l, r)? {
+                rows.push(combine(Some(l), Some(r))?);
+                matched = true;
+                right_matched[i] = true;
+            }
+        }
+        if !matched && keep_left {
+            rows.push(combine(Some(l), None)?);
+        }
+    }
+    if keep_right {
+        for (r, _) in right.rows.iter().zip(&right_matched).filter(|&(_, &m)| !m) {
+            rows.push(combine(None, Some(r))?);
+        }
+    }
+
+    // USING columns can only be referenced unqualified by their merged
+    // column, which SELECT * returns in place of both sides' columns
+    let mut schema = paired;
+    let mut visible: Vec<usize> = (0 .. using.len()).map(|i| schema.0.len() + i).collect();
+    for &(lp, _, ref t) in &using {
+        schema.0.push(TupleEntry {name: left.schema[lp].name.clone(), value: t.clone()});
+    }
+    let names: Vec<String> = using.iter().map(|&(lp, _, _)| left.schema[lp].name.clone()).collect();
+    visible.extend(left.visible.iter().cloned().filter(|&i| !names.contains(&left.schema[i].name)));
+    visible.extend(right.visible.iter().cloned().filter(|&i| !names.contains(&right.schema[i].name)).map(|i| i + left_width));
+    if !using.is_empty() {
+        for scope in scopes.iter_mut() {
+            scope.qualified_only.extend(names.iter().cloned());
+        }
+        scopes.push(Scope::new(vec![], schema.0.len() - using.len() .. schema.0.len()));
+    }
+
+    Ok(Relation {schema, scopes, visible, rows})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::ast::parse::parse_expr;
+
+    /// A relation of (id, value) rows, without a rowid column.
+    fn relation(name: &str, rows: &[(i64, i64)]) -> Relation {
+        Relation {
+            schema: TupleDef(vec![
+                TupleEntry {name: "id".to_string(), value: ValueType::Int},
+                TupleEntry {name: format!("{}_value", name), value: ValueType::Int},
+            ]),
+            scopes: vec![Scope::new(vec![name.to_string()], 0 .. 2)],
+            visible: vec![0, 1],
+            rows: rows.iter().map(|&(id, v)| vec![Value::from_int(id), Value::from_int(v)]).collect(),
+        }
+    }
+
+    fn ints(relation: &Relation) -> Vec<Vec<Option<i64>>> {
+        relation.rows.iter()
+            .map(|row| relation.visible.iter().map(|&i| match row[i] {
+                Value::Null => None,
+                ref v => Some(v.as_int()),
+            }).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_join() {
+        let left = || relation("l", &[(1, 10), (2, 20), (2, 21), (3, 30)]);
+        let right = || relation("r", &[(2, 200), (3, 300), (3, 301), (4, 400)]);
+        let on = |s: &str| JoinCondition::On(parse_expr(s).unwrap());
+        let (n, s) = (None, Some);
+
+        let inner = vec![
+            vec![s(2), s(20), s(2), s(200)],
+            vec![s(2), s(21), s(2), s(200)],
+            vec![s(3), s(30), s(3), s(300)],
+            vec![s(3), s(30), s(3), s(301)],
+        ];
+        let left_only = [vec![s(1), s(10), n, n]];
+        let right_only = [vec![n, n, s(4), s(400)]];
+        let cases = vec![
+            (JoinType::Inner, inner.clone()),
+            (JoinType::Left, [&left_only[..], &inner[..]].concat()),
+            (JoinType::Right, [&inner[..], &right_only[..]].concat()),
+            (JoinType::Full, [&left_only[..], &inner[..], &right_only[..]].concat()),
+        ];
+        // rows are in the left side's order, followed by the right side's
+        // unmatched rows
+        for (join_type, expected) in cases {
+            // a hash join and a nested loop join find the same rows
+            let hashed = join(left(), right(), join_type, &on("l.id = r.id")).unwrap();
+            let looped = join(left(), right(), join_type, &on("l.id + 0 = r.id")).unwrap();
+            assert_eq!(ints(&hashed), expected, "{:?}", join_type);
+            assert_eq!(ints(&looped), expected, "{:?}", join_type);
+        }
+
+        // conditions beyond the equal columns are still checked
+        let joined = join(left(), right(), JoinType::Inner, &on("r.id = l.id and r_value > 300")).unwrap();
+        assert_eq!(ints(&joined), vec![vec![s(3), s(30), s(3), s(301)]]);
+
+        let joined = join(left(), right(), JoinType::Inner, &JoinCondition::Cross).unwrap();
+        assert_eq!(joined.rows.len(), 16);
+        assert!(join(left(), right(), JoinType::Inner, &on("id = 1")).is_err());
+        assert!(join(left(), right(), JoinType::Inner, &on("l.id")).is_err());
+    }
+
+    #[test]
+    fn test_join_using() {
+        let left = relation("l", &[(1, 10), (2, 20)]);
+        let right = relation("r", &[(2, 200), (3, 300)]);
+        let joined = join(left, right, JoinType::Full, &JoinCondition::Using(vec!["id".to_string()])).unwrap();
+        let (n, s) = (None, Some);
+        assert_eq!(
+            joined.visible.iter().map(|&i| joined.schema[i].name.as_str()).collect::<Vec<_>>(),
+            vec!["id", "l_value", "r_value"]
+        );
+        assert_eq!(ints(&joined), vec![vec![s(1), s(10), n], vec![s(2), s(20), s(200)], vec![s(3), n, s(300)]]);
+
+        // the merged column is the one that's unqualified
+        let bindings = Bindings::with_scopes(joined.scopes.clone(), &joined.schema);
+        let id = |s: &str| match parse_expr(s).unwrap() {
+            Expr::Id(id) => bindings.resolve(&id).unwrap(),
+            e => panic!("unexpected {:?}", e),
+        };
+        assert_eq!((id("id"), id("l.id"), id("r.id")), (4, 0, 2));
+
+        let left = relation("l", &[]);
+        assert!(join(left, relation("r", &[]), JoinType::Inner, &JoinCondition::Using(vec!["x".to_string()])).is_err());
+    }
+}
@@ -0,0 +1,494 @@
+pub mod eval;
+pub mod index;
+pub mod join;
+pub mod table;
+
+use std::cmp::Ordering;
+use std::ops::Bound;
+use ::{Error, Result};
+use ::ast::{BinaryOperator, Expr, Identifier};
+use ::ast::command::{SelectColumns, OrderBy, OrderByClause, LimitClause};
+use ::ast::tuple::{TupleDef, TupleEntry};
+use ::ast::value_type::ValueType;
+use ::io::cache::CacheStats;
+use ::io::page::PageId;
+use ::io::value::Value;
+use ::exec::eval::{Bindings, TypedValue, coerce, compare, evaluate, infer_type};
+use ::exec::join::Relation;
+use ::exec::table::{StoredRow, TableStore};
+
+/// The outcome of running a command: the typed rows produced by a query, and
+/// the number of rows changed by a data modification command.
+#[derive(Debug, PartialEq)]
+pub struct ResultSet {
+    pub schema: TupleDef,
+    pub rows: Vec<Vec<Value>>,
+    pub rows_affected: u64,
+}
+
+impl ResultSet {
+    pub fn new(schema: TupleDef, rows: Vec<Vec<Value>>) -> ResultSet {
+        ResultSet {schema, rows, rows_affected: 0}
+    }
+
+    pub fn empty() -> ResultSet {
+        ResultSet::affected(0)
+    }
+
+    pub fn affected(rows_affected: u64) -> ResultSet {
+        ResultSet {schema: TupleDef(vec![]), rows: vec![], rows_affected}
+    }
+}
+
+/// A range of one of a table's indexes which holds every row that a WHERE
+/// clause can match.
+struct IndexRange {
+    index: usize,
+    lower: Bound<Vec<Value>>,
+    upper: Bound<Vec<Value>>,
+}
+
+/// Split a condition into the terms that are ANDed together.
+fn conjuncts<'e>(expr: &'e Expr, terms: &mut Vec<&'e Expr>) {
+    match expr {
+        &Expr::BinOp {ref left, ref right, op: BinaryOperator::OpAnd} => {
+            conjuncts(left, terms);
+            conjuncts(right, terms);
+        }
+        e => terms.push(e),
+    }
+}
+
+/// Match a comparison between a column and a literal, returning the
+/// column's position, the operator with the column on its left, and the
+/// literal's value.
+fn column_comparison(expr: &Expr, bindings: &Bindings) -> Option<(usize, BinaryOperator, TypedValue)> {
+    use ::ast::BinaryOperator::*;
+    let (id, literal, op) = match expr {
+        &Expr::BinOp {ref left, ref right, ref op} => match (&**left, &**right) {
+            (&Expr::Id(ref id), &Expr::Literal {..}) => (id, right, op.clone()),
+            (&Expr::Literal {..}, &Expr::Id(ref id)) => (id, left, match op {
+                &OpLt => OpGt,
+                &OpGt => OpLt,
+                &OpLte => OpGte,
+                &OpGte => OpLte,
+                op => op.clone(),
+            }),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match op {
+        OpEq | OpLt | OpGt | OpLte | OpGte => {}
+        _ => return None,
+    }
+    let column = bindings.resolve(id).ok()?;
+    Some((column, op, evaluate(literal, None, &[]).ok()?))
+}
+
+/// Choose the index that narrows a WHERE clause down the most: the one whose
+/// leading columns are compared for equality with the most literals, and
+/// whose next column is bounded by others. Comparisons with literals that
+/// can't be converted to their column's type are left to the WHERE clause,
+/// which is still checked against every row read from the index.
+fn index_range(store: &TableStore, bindings: &Bindings, where_expr: &Expr) -> Option<IndexRange> {
+    use ::ast::BinaryOperator::*;
+    let mut terms = vec![];
+    conjuncts(where_expr, &mut terms);
+    let comparisons: Vec<(usize, BinaryOperator, Value)> = terms.into_iter()
+        .filter_map(|t| column_comparison(t, bindings))
+        .filter_map(|(c, op, v)| coerce(&v, &bindings.schema[c].value).ok().map(|v| (c, op, v)))
+        .collect();
+
+    let mut best: Option<(usize, IndexRange)> = None;
+    for (i, index) in store.indexes().iter().enumerate() {
+        let mut prefix = vec![];
+        for &column in index.columns() {
+            match comparisons.iter().find(|c| c.0 == column && c.1 == OpEq) {
+                Some(c) => prefix.push(c.2.clone()),
+                None => break,
+            }
+        }
+        let (mut lower, mut upper) = (None, None);
+        if let Some(&column) = index.columns().get(prefix.len()) {
+            for &(c, ref op, ref v) in &comparisons {
+                match op {
+                    &OpGt | &OpGte if c == column && lower.is_none() => lower = Some((op, v)),
+                    &OpLt | &OpLte if c == column && upper.is_none() => upper = Some((op, v)),
+                    _ => {}
+                }
+            }
+        }
+        let score = prefix.len() * 2 + lower.iter().count() + upper.iter().count();
+        match best {
+            Some((best_score, _)) if best_score >= score => continue,
+            _ if score == 0 => continue,
+            _ => {}
+        }
+        let bound = |end: Option<(&BinaryOperator, &Value)>| match end {
+            Some((op, v)) => {
+                let mut values = prefix.clone();
+                values.push(v.clone());
+                match op {
+                    &OpGte | &OpLte => Bound::Included(values),
+                    _ => Bound::Excluded(values),
+                }
+            }
+            None if prefix.is_empty() => Bound::Unbounded,
+            None => Bound::Included(prefix.clone()),
+        };
+        best = Some((score, IndexRange {index: i, lower: bound(lower), upper: bound(upper)}));
+    }
+    best.map(|(_, range)| range)
+}
+
+/// Find the rows of a table matching a WHERE clause, sorted and paged
+/// according to ORDER BY and LIMIT/OFFSET. The table is scanned unless one
+/// of its indexes can narrow down the rows to check.
+fn matching_rows(store: &mut TableStore, qualifiers: Vec<&str>, where_expr: &Option<Expr>, order_by: &OrderByClause, limit: &LimitClause) -> Result<Vec<StoredRow>> {
+    let schema = store.schema().clone();
+    let bindings = Bindings::new(qualifiers, &schema);
+    let candidates: Box<dyn Iterator<Item = Result<StoredRow>>> = match where_expr.as_ref().and_then(|e| index_range(store, &bindings, e)) {
+        Some(range) => Box::new(store.lookup(range.index, &range.lower, &range.upper)?.into_iter().map(Ok)),
+        None => Box::new(store.rows()),
+    };
+    let mut rows = Vec::new();
+    for row in candidates {
+        let row = row?;
+        if is_match(where_expr, &bindings, &row.values)? {
+            rows.push(row);
+        }
+    }
+    let rows = sort_rows(rows, &bindings, order_by, |row| &row.values)?;
+    Ok(apply_limit(rows, limit))
+}
+
+/// Whether a row satisfies a WHERE clause. Rows for which the condition is
+/// null don't.
+fn is_match(where_expr: &Option<Expr>, bindings: &Bindings, row: &[Value]) -> Result<bool> {
+    match where_expr {
+        &Some(ref e) => Ok(evaluate(e, Some(bindings), row)?.truth()? == Some(true)),
+        &None => Ok(true),
+    }
+}
+
+/// Sort rows according to ORDER BY, where `values` gets the column values of
+/// a row.
+fn sort_rows<T, F: Fn(&T) -> &[Value]>(rows: Vec<T>, bindings: &Bindings, order_by: &OrderByClause, values: F) -> Result<Vec<T>> {
+    let keys = match order_by {
+        &Some(ref keys) => keys,
+        &None => return Ok(rows),
+    };
+    let mut keyed = Vec::with_capacity(rows.len());
+    for row in rows {
+        let mut key_values = Vec::with_capacity(keys.len());
+        for key in keys {
+            key_values.push(evaluate(&key.expr, Some(bindings), values(&row))?);
+        }
+        keyed.push((key_values, row));
+    }
+    keyed.sort_by(|a, b| compare_keys(keys, &a.0, &b.0));
+    Ok(keyed.into_iter().map(|(_, row)| row).collect())
+}
+
+/// Order rows by their ORDER BY keys. Each key expression has a single type,
+/// so keys are always comparable; NaN floats are treated as equal.
+fn compare_keys(keys: &[OrderBy], a: &[TypedValue], b: &[TypedValue]) -> Ordering {
+    for ((key, x), y) in keys.iter().zip(a).zip(b) {
+        let nulls = if key.nulls_first {Ordering::Less} else {Ordering::Greater};
+        let o = match (&x.value, &y.value) {
+            (&Value::Null, &Value::Null) => Ordering::Equal,
+            (&Value::Null, _) => nulls,
+            (_, &Value::Null) => nulls.reverse(),
+            _ if key.descending => compare(y, x).unwrap_or(Ordering::Equal),
+            _ => compare(x, y).unwrap_or(Ordering::Equal),
+        };
+        if o != Ordering::Equal {
+            return o;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Skip and truncate rows according to LIMIT/OFFSET.
+fn apply_limit<T>(rows: Vec<T>, limit: &LimitClause) -> Vec<T> {
+    match limit {
+        &Some(ref l) => rows.into_iter()
+            .skip(l.offset as usize)
+            .take(l.count.map_or(usize::MAX, |n| n as usize))
+            .collect(),
+        &None => rows,
+    }
+}
+
+/// Run a SELECT against a single table, or against no table at all, in which
+/// case the column expressions are evaluated once.
+pub fn select(source: Option<(&mut TableStore, Vec<&str>)>, cols: &SelectColumns, where_expr: &Option<Expr>, order_by: &OrderByClause, limit: &LimitClause) -> Result<ResultSet> {
+    let (schema, qualifiers, rows) = match source {
+        Some((store, qualifiers)) => {
+            let schema = store.schema().clone();
+            let rows = matching_rows(store, qualifiers.clone(), where_expr, order_by, limit)?;
+            (schema, qualifiers, rows.into_iter().map(|r| r.values).collect())
+        }
+        None => {
+            if where_expr.is_some() || order_by.is_some() {
+                return Err(Error::Custom("SELECT without FROM can't filter or sort".to_string()));
+            }
+            (TupleDef(vec![]), vec![], apply_limit(vec![vec![]], limit))
+        }
+    };
+    // skip the hidden rowid column
+    let visible: Vec<usize> = (1 .. schema.0.len()).collect();
+    project(&Bindings::new(qualifiers, &schema), &visible, rows, cols)
+}
+
+/// Run a SELECT against the joined rows of several models.
+pub fn select_joined(relation: Relation, cols: &SelectColumns, where_expr: &Option<Expr>, order_by: &OrderByClause, limit: &LimitClause) -> Result<ResultSet> {
+    let Relation {schema, scopes, visible, rows} = relation;
+    let bindings = Bindings::with_scopes(scopes, &schema);
+    let mut matched = Vec::new();
+    for row in rows {
+        if is_match(where_expr, &bindings, &row)? {
+            matched.push(row);
+        }
+    }
+    let rows = sort_rows(matched, &bindings, order_by, |row| &row[..])?;
+    project(&bindings, &visible, apply_limit(rows, limit), cols)
+}
+
+/// Evaluate a SELECT's columns for each row. SELECT * returns the `visible`
+/// columns.
+fn project(bindings: &Bindings, visible: &[usize], rows: Vec<Vec<Value>>, cols: &SelectColumns) -> Result<ResultSet> {
+    match cols {
+        &SelectColumns::All => {
+            let schema = TupleDef(visible.iter().map(|&i| bindings.schema[i].clone()).collect());
+            let rows = rows.into_iter().map(|r| visible.iter().map(|&i| r[i].clone()).collect()).collect();
+            Ok(ResultSet::new(schema, rows))
+        }
+        &SelectColumns::Named(ref exprs) => {
+            let mut entries = Vec::with_capacity(exprs.len());
+            for (i, &(ref expr, ref alias)) in exprs.iter().enumerate() {
+                let name = match (alias, expr) {
+                    (&Some(ref a), _) => a.clone(),
+                    (&None, &Expr::Id(Identifier {ref name, ..})) => name.clone(),
+                    _ => format!("col_{}", i + 1),
+                };
+                entries.push(TupleEntry {name, value: infer_type(expr, Some(bindings))?});
+            }
+            let mut output = Vec::with_capacity(rows.len());
+            for row in rows {
+                let mut values = Vec::with_capacity(exprs.len());
+                for (&(ref expr, _), entry) in exprs.iter().zip(&entries) {
+                    values.push(coerce(&evaluate(expr, Some(bindings), &row)?, &entry.value)?);
+                }
+                output.push(values);
+            }
+            Ok(ResultSet::new(TupleDef(entries), output))
+        }
+    }
+}
+
+/// Evaluate the literal rows of an INSERT ... VALUES.
+pub fn evaluate_rows(values: &[Vec<Expr>]) -> Result<Vec<Vec<TypedValue>>> {
+    let mut rows = Vec::with_capacity(values.len());
+    for exprs in values {
+        let mut row = Vec::with_capacity(exprs.len());
+        for expr in exprs {
+            row.push(evaluate(expr, None, &[])?);
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// The rows of a result paired with their types, to be inserted by an
+/// INSERT ... SELECT.
+pub fn typed_rows(result: ResultSet) -> Vec<Vec<TypedValue>> {
+    let schema = result.schema;
+    result.rows.into_iter()
+        .map(|row| row.into_iter().zip(&schema.0).map(|(v, entry)| TypedValue::new(entry.value.clone(), v)).collect())
+        .collect()
+}
+
+/// Insert rows of values into a table, converting each to its column's type.
+/// If columns are named, values are matched to them by position; otherwise
+/// every column must be given in schema order.
+pub fn insert(store: &mut TableStore, cols: &Option<Vec<Identifier>>, values: &[Vec<TypedValue>]) -> Result<ResultSet> {
+    // the first schema entry is the hidden rowid
+    let schema = TupleDef(store.schema().0.iter().skip(1).cloned().collect());
+    let positions: Vec<usize> = match cols {
+        &Some(ref ids) => {
+            let mut positions = Vec::with_capacity(ids.len());
+            for id in ids {
+                match schema.index_of(&id.name) {
+                    Some(i) => positions.push(i),
+                    None => return Err(Error::Custom(format!("unknown column {}", id.name))),
+                }
+            }
+            positions
+        }
+        &None => (0 .. schema.0.len()).collect(),
+    };
+
+    let mut rows = Vec::with_capacity(values.len());
+    for given in values {
+        if given.len() != positions.len() {
+            return Err(Error::Custom(format!("expected {} values, found {}", positions.len(), given.len())));
+        }
+        let mut row: Vec<Option<Value>> = vec![None; schema.0.len()];
+        for (value, &i) in given.iter().zip(&positions) {
+            row[i] = Some(coerce(value, &schema[i].value)?);
+        }
+        let mut complete = Vec::with_capacity(row.len());
+        for (value, entry) in row.into_iter().zip(&schema.0) {
+            match (value, &entry.value) {
+                (Some(v), _) => complete.push(v),
+                (None, &ValueType::Nullable(_)) => complete.push(Value::Null),
+                (None, _) => return Err(Error::Custom(format!("no value given for column {}", entry.name))),
+            }
+        }
+        rows.push(complete);
+    }
+    Ok(ResultSet::affected(store.insert(rows)?))
+}
+
+/// Update the rows of a table matching a WHERE clause, or the first of them
+/// in ORDER BY order if there's a LIMIT, setting columns to expressions of
+/// each row's old values.
+pub fn update(store: &mut TableStore, qualifiers: Vec<&str>, set: &[(Identifier, Expr)], where_expr: &Option<Expr>, order_by: &OrderByClause, limit: &LimitClause) -> Result<ResultSet> {
+    let schema = store.schema().clone();
+    let rows = matching_rows(store, qualifiers.clone(), where_expr, order_by, limit)?;
+    let bindings = Bindings::new(qualifiers, &schema);
+    let mut targets = Vec::with_capacity(set.len());
+    for &(ref id, ref expr) in set {
+        let i = bindings.resolve(id)?;
+        if i == 0 {
+            return Err(Error::Custom(format!("can't update {}", id.name)));
+        }
+        targets.push((i, expr));
+    }
+
+    let mut updated = Vec::with_capacity(rows.len());
+    for row in rows {
+        // every assignment sees the values from before the update
+        let mut values = row.values.clone();
+        for &(i, expr) in &targets {
+            values[i] = coerce(&evaluate(expr, Some(&bindings), &row.values)?, &schema[i].value)?;
+        }
+        updated.push(StoredRow {page: row.page, slot: row.slot, values});
+    }
+    Ok(ResultSet::affected(store.update(updated)?))
+}
+
+/// Delete the rows of a table matching a WHERE clause, or the first of them
+/// in ORDER BY order if there's a LIMIT.
+pub fn delete(store: &mut TableStore, qualifiers: Vec<&str>, where_expr: &Option<Expr>, order_by: &OrderByClause, limit: &LimitClause) -> Result<ResultSet> {
+    let rows = matching_rows(store, qualifiers, where_expr, order_by, limit)?;
+    let locations: Vec<(PageId, usize)> = rows.iter().map(|r| (r.page, r.slot)).collect();
+    Ok(ResultSet::affected(store.delete(&locations)?))
+}
+
+/// Report working memory statistics as a single row, with the longest lock
+/// wait in microseconds.
+pub fn show_cache_stats(stats: &CacheStats) -> ResultSet {
+    let wait = stats.longest_lock_wait;
+    let columns = vec![
+        ("pages", stats.pages),
+        ("cached_pages", stats.cached_pages),
+        ("hits", stats.hits),
+        ("misses", stats.misses),
+        ("evictions", stats.evictions),
+        ("dirty_pages", stats.dirty_pages),
+        ("pinned_pages", stats.pinned_pages),
+        ("queued_requests", stats.queued_requests),
+        ("longest_lock_wait_us", wait.as_secs() * 1_000_000 + wait.subsec_micros() as u64),
+        ("prefetched_pages", stats.prefetched_pages),
+        ("prefetch_hits", stats.prefetch_hits),
+    ];
+    let schema = TupleDef(columns.iter().map(|&(name, _)| TupleEntry {name: name.to_string(), value: ValueType::Uint}).collect());
+    let row = columns.iter().map(|&(_, n)| Value::from_uint(n)).collect();
+    ResultSet::new(schema, vec![row])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use ::ast::parse::{parse_command, parse_expr};
+    use ::ast::command::{Command, Limit};
+    use ::exec::table::test_pool;
+    use ::model::index::Index;
+
+    #[test]
+    fn test_index_range() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let pool = test_pool(&dir, 4);
+        let table = match parse_command("create table t (a int, b str(10), c float);").unwrap() {
+            Command::CreateModel {schema, ..} => schema,
+            c => panic!("unexpected {:?}", c),
+        };
+        let indexes = vec![
+            Index {name: "by_a".to_string(), columns: vec!["a".to_string()], unique: true},
+            Index {name: "by_b_a".to_string(), columns: vec!["b".to_string(), "a".to_string()], unique: false},
+        ];
+        let mut store = TableStore::new("t", table.as_table().unwrap(), &[], "t".to_string(), &pool).unwrap();
+        for index in &indexes {
+            store.create_index(index).unwrap();
+        }
+        let store = TableStore::new("t", table.as_table().unwrap(), &indexes, "t".to_string(), &pool).unwrap();
+        let schema = store.schema().clone();
+        let bindings = Bindings::new(vec!["t"], &schema);
+        let range = |expr: &str| index_range(&store, &bindings, &parse_expr(expr).unwrap()).map(|r| (r.index, r.lower, r.upper));
+
+        let k3 = || Value::Str("k3".to_string());
+        assert_eq!(range("a = 42"), Some((0, Bound::Included(vec![Value::Int(42)]), Bound::Included(vec![Value::Int(42)]))));
+        assert_eq!(range("3 < t.a"), Some((0, Bound::Excluded(vec![Value::Int(3)]), Bound::Unbounded)));
+        assert_eq!(range("c > 1.0 and a <= 7 and a > 3"), Some((0, Bound::Excluded(vec![Value::Int(3)]), Bound::Included(vec![Value::Int(7)]))));
+        assert_eq!(range("b = \"k3\""), Some((1, Bound::Included(vec![k3()]), Bound::Included(vec![k3()]))));
+        assert_eq!(
+            range("a >= 5 and b = \"k3\" and a < 9"),
+            Some((1, Bound::Included(vec![k3(), Value::Int(5)]), Bound::Excluded(vec![k3(), Value::Int(9)])))
+        );
+        // comparisons an index can't narrow down
+        assert_eq!(range("a > 10.5"), None);
+        assert_eq!(range("c = 1.0"), None);
+        assert_eq!(range("a = 1 or a = 2"), None);
+        assert_eq!(range("a + 1 = 2"), None);
+        assert_eq!(range("b = \"longer than ten\""), None);
+    }
+
+    #[test]
+    fn test_compare_keys() {
+        let int = |i: i64| TypedValue::new(ValueType::Int, Value::Int(i));
+        let null = || TypedValue::new(ValueType::Nullable(Box::new(ValueType::Int)), Value::Null);
+        let key = |descending: bool, nulls_first: bool| OrderBy {expr: parse_expr("a").unwrap(), descending, nulls_first};
+        let sorted = |keys: &[OrderBy], rows: &[Vec<TypedValue>]| {
+            let mut rows = rows.to_vec();
+            rows.sort_by(|a, b| compare_keys(keys, a, b));
+            rows.into_iter().map(|r| r.into_iter().map(|v| v.value).collect()).collect::<Vec<Vec<Value>>>()
+        };
+
+        let rows = vec![vec![int(2)], vec![null()], vec![int(1)]];
+        assert_eq!(sorted(&[OrderBy::new(parse_expr("a").unwrap(), false)], &rows), vec![vec![Value::Null], vec![Value::Int(1)], vec![Value::Int(2)]]);
+        assert_eq!(sorted(&[OrderBy::new(parse_expr("a").unwrap(), true)], &rows), vec![vec![Value::Int(2)], vec![Value::Int(1)], vec![Value::Null]]);
+        assert_eq!(sorted(&[key(false, false)], &rows), vec![vec![Value::Int(1)], vec![Value::Int(2)], vec![Value::Null]]);
+        assert_eq!(sorted(&[key(true, true)], &rows), vec![vec![Value::Null], vec![Value::Int(2)], vec![Value::Int(1)]]);
+
+        // later keys break ties
+        let rows = vec![vec![int(1), int(1)], vec![int(2), int(1)], vec![int(1), int(2)]];
+        assert_eq!(
+            sorted(&[key(false, true), key(true, true)], &rows),
+            vec![vec![Value::Int(1), Value::Int(2)], vec![Value::Int(1), Value::Int(1)], vec![Value::Int(2), Value::Int(1)]]
+        );
+    }
+
+    #[test]
+    fn test_apply_limit() {
+        let rows: Vec<u64> = (0 .. 10).collect();
+        assert_eq!(apply_limit(rows.clone(), &None), rows);
+        assert_eq!(apply_limit(rows.clone(), &Some(Limit {count: Some(3), offset: 0})), vec![0, 1, 2]);
+        assert_eq!(apply_limit(rows.clone(), &Some(Limit {count: Some(3), offset: 8})), vec![8, 9]);
+        assert_eq!(apply_limit(rows.clone(), &Some(Limit {count: None, offset: 7})), vec![7, 8, 9]);
+        assert_eq!(apply_limit(rows.clone(), &Some(Limit {count: Some(0), offset: 0})), vec![]);
+    }
+}
@@ -0,0 +1,688 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use byteorder::{ByteOrder, LittleEndian};
+use ::{Error, Result};
+use ::ast::tuple::TupleDef;
+use ::ast::value_type::ValueType;
+use ::exec::index::IndexStore;
+use ::io::dbfile::{PageMetadata, FIRST_DATA_PAGE, PAGE_METADATA_SIZE};
+use ::io::heap;
+use ::io::page::{FileId, PageId, PAGE_SIZE};
+use ::io::pool::{BufferPool, PageRef};
+use ::io::row::RowCodec;
+use ::io::value::Value;
+use ::model::ModelType;
+use ::model::index::Index;
+use ::model::table::Table;
+
+/// The first page of a table starts with a header: the last rowid assigned
+/// (u64), the ID of the last page in the chain (u64), where zero stands for
+/// the first page, and the heap page (u64) and slot (u16) of the table's
+/// index directory, followed by 6 reserved bytes. A heap page of zero means
+/// the table has no indexes.
+const HEADER_OFFSET: usize = PAGE_METADATA_SIZE;
+const HEADER_SIZE: usize = 32;
+
+/// Rows start after the page metadata (and header, on the first page) and a
+/// u16 count of row slots.
+const SLOT_COUNT_SIZE: usize = 2;
+
+const SLOT_EMPTY: u8 = 0;
+const SLOT_LIVE: u8 = 1;
+
+struct TableHeader {
+    last_rowid: u64,
+    last_page: PageId,
+    indexes: Option<(PageId, u16)>,
+}
+
+impl TableHeader {
+    fn from_page(page: &[u8]) -> TableHeader {
+        let last_page = LittleEndian::read_u64(&page[HEADER_OFFSET + 8 ..]);
+        let index_page = LittleEndian::read_u64(&page[HEADER_OFFSET + 16 ..]);
+        TableHeader {
+            last_rowid: LittleEndian::read_u64(&page[HEADER_OFFSET ..]),
+            last_page: if last_page == 0 {FIRST_DATA_PAGE} else {last_page},
+            indexes: match index_page {
+                0 => None,
+                p => Some((p, LittleEndian::read_u16(&page[HEADER_OFFSET + 24 ..]))),
+            },
+        }
+    }
+
+    fn write_to_page(&self, page: &mut [u8]) {
+        LittleEndian::write_u64(&mut page[HEADER_OFFSET ..], self.last_rowid);
+        LittleEndian::write_u64(&mut page[HEADER_OFFSET + 8 ..], self.last_page);
+        let (index_page, index_slot) = self.indexes.unwrap_or((0, 0));
+        LittleEndian::write_u64(&mut page[HEADER_OFFSET + 16 ..], index_page);
+        LittleEndian::write_u16(&mut page[HEADER_OFFSET + 24 ..], index_slot);
+    }
+}
+
+/// The root page of each of a table's indexes, stored as a record in the
+/// file's heap: for each index, the u8 length of its name, the name, and the
+/// u64 ID of its root.
+fn decode_index_roots(record: &[u8]) -> Result<Vec<(String, PageId)>> {
+    let mut roots = vec![];
+    let mut offset = 0;
+    while offset < record.len() {
+        let len = record[offset] as usize;
+        let end = offset + 1 + len;
+        if end + 8 > record.len() {
+            return Err(Error::Corruption("truncated index directory".to_string()));
+        }
+        let name = match String::from_utf8(record[offset + 1 .. end].to_vec()) {
+            Ok(name) => name,
+            Err(e) => return Err(Error::Corruption(format!("invalid index name: {}", e))),
+        };
+        roots.push((name, LittleEndian::read_u64(&record[end ..])));
+        offset = end + 8;
+    }
+    Ok(roots)
+}
+
+fn encode_index_roots(roots: &[(String, PageId)]) -> Result<Vec<u8>> {
+    let mut record = vec![];
+    for &(ref name, root) in roots {
+        if name.len() > u8::MAX as usize {
+            return Err(Error::Custom(format!("index name {} is too long", name)));
+        }
+        record.push(name.len() as u8);
+        record.extend(name.as_bytes());
+        let mut buf = [0; 8];
+        LittleEndian::write_u64(&mut buf, root);
+        record.extend(&buf);
+    }
+    Ok(record)
+}
+
+/// A row read from a table, identified by the page and slot it was found in.
+#[derive(Debug)]
+pub struct StoredRow {
+    pub page: PageId,
+    pub slot: usize,
+    pub values: Vec<Value>,
+}
+
+/// Fixed-width row storage for a TABLE model. Rows are stored in a chain of
+/// pages linked by their metadata, starting at the file's first data page.
+/// Each slot holds a flag byte followed by the hidden rowid and the table's
+/// columns; deleted rows are left in place as tombstones with their flag
+/// cleared, and pages other than the first are freed once all of their rows
+/// are deleted. Strings that don't fit in their column are kept in the
+/// file's heap, and freed along with the row that refers to them.
+///
+/// Rows are only ever appended to the last page, and rowids are assigned
+/// in order from a counter in the first page, so rowids increase along the
+/// chain and are never reused.
+///
+/// The table's indexes are kept up to date as rows change, and their roots
+/// are listed in a directory in the heap, so that they can be found by name.
+///
+/// Pages are modified in a buffer pool, and only reach the table's file when
+/// they're evicted or flushed; the pool writes pages of other files that it
+/// evicts along the way back to their own files.
+pub struct TableStore<'a> {
+    name: &'a str,
+    file: FileId,
+    pool: &'a BufferPool,
+    schema: TupleDef,
+    codec: RowCodec,
+    /// whether any column can hold a string stored in the heap
+    strings: bool,
+    indexes: Vec<IndexStore<'a>>,
+}
+
+impl<'a> TableStore<'a> {
+    pub fn new(name: &'a str, table: &Table, indexes: &[Index], file: FileId, pool: &'a BufferPool) -> Result<TableStore<'a>> {
+        if !pool.is_open(&file) {
+            return Err(Error::Custom(format!("the file for table {} isn't open", name)));
+        }
+        TableStore::check_schema(name, table)?;
+        let schema = TableStore::row_schema(table);
+        let codec = RowCodec::new(&schema);
+        let strings = schema.0.iter().any(|e| has_strings(&e.value));
+        let mut store = TableStore {name, file, pool, schema, codec, strings, indexes: vec![]};
+        if !indexes.is_empty() {
+            let roots = store.index_roots()?;
+            for index in indexes {
+                let root = match roots.iter().find(|r| r.0 == index.name) {
+                    Some(&(_, root)) => root,
+                    None => return Err(Error::Corruption(format!("index {} of table {} has no root", index.name, name))),
+                };
+                let index = IndexStore::open(index, &store.schema, root, store.file.clone(), pool)?;
+                store.indexes.push(index);
+            }
+        }
+        Ok(store)
+    }
+
+    /// Check that a table's rows can be stored, before any file is created
+    /// for it.
+    pub fn check_schema(name: &str, table: &Table) -> Result<()> {
+        let schema = TableStore::row_schema(table);
+        if let Some(entry) = schema.0.iter().find(|e| !is_storable(&e.value)) {
+            return Err(Error::TypeError(format!("column {} of table {} has no storable type", entry.name, name)));
+        }
+        if (HEADER_OFFSET + HEADER_SIZE + SLOT_COUNT_SIZE) as u64 + 1 + schema.size_of() > PAGE_SIZE {
+            return Err(Error::Custom(format!("rows of table {} don't fit in a page", name)));
+        }
+        Ok(())
+    }
+
+    /// The full schema of a stored row: the hidden rowid followed by the
+    /// table's own columns.
+    pub fn row_schema(table: &Table) -> TupleDef {
+        let mut entries = match table.rowid_schema() {
+            Some(TupleDef(v)) => v,
+            None => vec![],
+        };
+        entries.extend(table.schema.0.iter().cloned());
+        TupleDef(entries)
+    }
+
+    pub fn schema(&self) -> &TupleDef {
+        &self.schema
+    }
+
+    pub fn indexes(&self) -> &[IndexStore<'a>] {
+        &self.indexes
+    }
+
+    /// Build a new index over the table's rows.
+    pub fn create_index(&mut self, index: &Index) -> Result<()> {
+        let mut roots = self.index_roots()?;
+        if roots.iter().any(|r| r.0 == index.name) {
+            return Err(Error::Custom(format!("index {} already exists", index.name)));
+        }
+        let mut store = IndexStore::create(index, &self.schema, self.file.clone(), self.pool)?;
+        for row in self.rows() {
+            let row = row?;
+            store.insert(&row.values, (row.page, row.slot))?;
+        }
+        roots.push((index.name.clone(), store.root()));
+        self.write_index_roots(&roots)?;
+        self.indexes.push(store);
+        Ok(())
+    }
+
+    /// Drop one of the table's indexes, freeing its pages.
+    pub fn drop_index(&mut self, name: &str) -> Result<()> {
+        let index = match self.indexes.iter().position(|i| i.name() == name) {
+            Some(i) => self.indexes.remove(i),
+            None => return Err(Error::Custom(format!("unknown index {}", name))),
+        };
+        let roots: Vec<(String, PageId)> = self.index_roots()?.into_iter().filter(|r| r.0 != name).collect();
+        self.write_index_roots(&roots)?;
+        index.destroy()
+    }
+
+    /// The live rows that one of the table's indexes holds between two
+    /// bounds, in rowid order.
+    pub fn lookup(&self, index: usize, lower: &Bound<Vec<Value>>, upper: &Bound<Vec<Value>>) -> Result<Vec<StoredRow>> {
+        let mut rows = vec![];
+        for (page_id, slot) in self.indexes[index].range(lower, upper)? {
+            let page = self.read(page_id)?;
+            let offset = self.slot_offset(page_id, slot);
+            if page[offset] != SLOT_LIVE {
+                return Err(Error::Corruption(format!("index {} refers to a deleted row", self.indexes[index].name())));
+            }
+            let mut values = self.codec.decode(&page[offset + 1 .. offset + self.slot_size()])?;
+            self.load_strings(&mut values)?;
+            rows.push(StoredRow {page: page_id, slot, values});
+        }
+        rows.sort_by_key(|r| r.values[0].as_uint());
+        Ok(rows)
+    }
+
+    /// Returns every live row in the table.
+    pub fn scan(&mut self) -> Result<Vec<StoredRow>> {
+        self.rows().collect()
+    }
+
+    /// Iterate over the live rows in the table, in rowid order.
+    pub fn rows<'s>(&'s self) -> Rows<'s, 'a> {
+        Rows {store: self, next_page: FIRST_DATA_PAGE, rows: Vec::new().into_iter()}
+    }
+
+    /// Find a row by its rowid.
+    pub fn fetch(&mut self, rowid: u64) -> Result<Option<StoredRow>> {
+        let mut page_id = FIRST_DATA_PAGE;
+        while page_id != 0 {
+            let page = self.read(page_id)?;
+            let count = self.slot_count(page_id, &page);
+            // rowids increase along the chain, so the row can only be on the
+            // first page that ends with a rowid at least as high
+            if count > 0 && self.rowid(page_id, &page, count - 1)? >= rowid {
+                let (mut low, mut high) = (0, count);
+                while low < high {
+                    let mid = (low + high) / 2;
+                    if self.rowid(page_id, &page, mid)? < rowid {
+                        low = mid + 1;
+                    } else {
+                        high = mid;
+                    }
+                }
+                let offset = self.slot_offset(page_id, low);
+                if self.rowid(page_id, &page, low)? != rowid || page[offset] != SLOT_LIVE {
+                    return Ok(None);
+                }
+                let mut values = self.codec.decode(&page[offset + 1 .. offset + self.slot_size()])?;
+                self.load_strings(&mut values)?;
+                return Ok(Some(StoredRow {page: page_id, slot: low, values}));
+            }
+            page_id = PageMetadata::from_page(&page).next_page;
+        }
+        Ok(None)
+    }
+
+    /// Append rows, which contain values for every column except the rowid.
+    /// Returns the number of rows inserted.
+    pub fn insert(&mut self, rows: Vec<Vec<Value>>) -> Result<u64> {
+        let mut header = TableHeader::from_page(&self.read(FIRST_DATA_PAGE)?);
+        let mut page_id = header.last_page;
+        let mut page = self.load(page_id)?;
+
+        let inserted = rows.len() as u64;
+        for row in rows {
+            let slot = self.slot_count(page_id, &page);
+            if self.slot_offset(page_id, slot + 1) > PAGE_SIZE as usize {
+                // link a new page onto the end of the chain
+                let new_page_id = self.pool.with_file(&self.file, |file| file.allocate_page())?;
+                PageMetadata::new(PageMetadata::from_page(&page).prev_page, new_page_id).write_to_page(&mut page);
+                self.store(page_id, &page)?;
+                page = vec![0; PAGE_SIZE as usize];
+                PageMetadata::new(page_id, 0).write_to_page(&mut page);
+                page_id = new_page_id;
+            }
+            let slot = self.slot_count(page_id, &page);
+            let offset = self.slot_offset(page_id, slot);
+            header.last_rowid += 1;
+            let mut values = vec![Value::Uint(header.last_rowid)];
+            values.extend(row);
+            for index in &mut self.indexes {
+                index.insert(&values, (page_id, slot))?;
+            }
+            self.store_strings(&mut values)?;
+            page[offset] = SLOT_LIVE;
+            self.codec.encode(&values, &mut page[offset + 1 .. offset + self.slot_size()])?;
+            LittleEndian::write_u16(&mut page[self.slot_count_offset(page_id) ..], (slot + 1) as u16);
+        }
+        self.store(page_id, &page)?;
+        header.last_page = page_id;
+        self.write_header(&header)?;
+        Ok(inserted)
+    }
+
+    /// Overwrite previously scanned rows in place. The rowid of each row is
+    /// preserved, and the strings it stored in the heap are replaced.
+    pub fn update(&mut self, mut rows: Vec<StoredRow>) -> Result<u64> {
+        let updated = rows.len() as u64;
+        if !self.indexes.is_empty() {
+            // every changed key is removed before any is added, so that rows
+            // can swap values in a unique index
+            let mut old_rows = Vec::with_capacity(rows.len());
+            for row in &mut rows {
+                let old = self.stored_values(row.page, row.slot)?;
+                row.values[0] = old[0].clone();
+                old_rows.push(old);
+            }
+            for index in &mut self.indexes {
+                let changed: Vec<usize> = (0 .. rows.len())
+                    .filter(|&i| index.columns().iter().any(|&c| rows[i].values[c] != old_rows[i][c]))
+                    .collect();
+                for &i in &changed {
+                    index.remove(&old_rows[i])?;
+                }
+                for &i in &changed {
+                    index.insert(&rows[i].values, (rows[i].page, rows[i].slot))?;
+                }
+            }
+        }
+        let mut by_page: BTreeMap<PageId, Vec<StoredRow>> = BTreeMap::new();
+        for row in rows {
+            by_page.entry(row.page).or_default().push(row);
+        }
+        for (page_id, rows) in by_page {
+            let mut page = self.load(page_id)?;
+            for row in rows {
+                let offset = self.slot_offset(page_id, row.slot);
+                let mut values = row.values;
+                values[0] = Value::Uint(self.rowid(page_id, &page, row.slot)?);
+                self.free_strings(page_id, &page, row.slot)?;
+                self.store_strings(&mut values)?;
+                self.codec.encode(&values, &mut page[offset + 1 .. offset + self.slot_size()])?;
+            }
+            self.store(page_id, &page)?;
+        }
+        Ok(updated)
+    }
+
+    /// Mark the rows in the given (page, slot) locations as deleted.
+    pub fn delete(&mut self, locations: &[(PageId, usize)]) -> Result<u64> {
+        let mut by_page: BTreeMap<PageId, Vec<usize>> = BTreeMap::new();
+        for &(page_id, slot) in locations {
+            by_page.entry(page_id).or_default().push(slot);
+        }
+        for (page_id, slots) in by_page {
+            let mut page = self.load(page_id)?;
+            for slot in slots {
+                if !self.indexes.is_empty() {
+                    let values = self.stored_values(page_id, slot)?;
+                    for index in &mut self.indexes {
+                        index.remove(&values)?;
+                    }
+                }
+                self.free_strings(page_id, &page, slot)?;
+                let offset = self.slot_offset(page_id, slot);
+                page[offset] = SLOT_EMPTY;
+            }
+            let meta = PageMetadata::from_page(&page);
+            let live = (0 .. self.slot_count(page_id, &page)).any(|slot| page[self.slot_offset(page_id, slot)] == SLOT_LIVE);
+            // the first page holds the header, so it's kept even when empty
+            if !live && page_id != FIRST_DATA_PAGE {
+                self.unlink(page_id, &meta)?;
+            } else {
+                self.store(page_id, &page)?;
+            }
+        }
+        Ok(locations.len() as u64)
+    }
+
+    /// Remove a page other than the first from the chain and free it.
+    fn unlink(&mut self, page_id: PageId, meta: &PageMetadata) -> Result<()> {
+        let mut prev = self.load(meta.prev_page)?;
+        let prev_meta = PageMetadata::from_page(&prev);
+        PageMetadata::new(prev_meta.prev_page, meta.next_page).write_to_page(&mut prev);
+        self.store(meta.prev_page, &prev)?;
+
+        if meta.next_page == 0 {
+            let mut header = TableHeader::from_page(&self.read(FIRST_DATA_PAGE)?);
+            header.last_page = meta.prev_page;
+            self.write_header(&header)?;
+        } else {
+            let mut next = self.load(meta.next_page)?;
+            let next_meta = PageMetadata::from_page(&next);
+            PageMetadata::new(meta.prev_page, next_meta.next_page).write_to_page(&mut next);
+            self.store(meta.next_page, &next)?;
+        }
+
+        self.pool.invalidate(&(self.file.clone(), page_id));
+        self.pool.with_file(&self.file, |file| file.free_page(page_id))
+    }
+
+    fn slot_size(&self) -> usize {
+        1 + self.codec.size()
+    }
+
+    fn slot_count_offset(&self, page_id: PageId) -> usize {
+        if page_id == FIRST_DATA_PAGE {
+            HEADER_OFFSET + HEADER_SIZE
+        } else {
+            PAGE_METADATA_SIZE
+        }
+    }
+
+    fn slot_offset(&self, page_id: PageId, slot: usize) -> usize {
+        self.slot_count_offset(page_id) + SLOT_COUNT_SIZE + slot * self.slot_size()
+    }
+
+    fn slot_count(&self, page_id: PageId, page: &[u8]) -> usize {
+        LittleEndian::read_u16(&page[self.slot_count_offset(page_id) ..]) as usize
+    }
+
+    /// Read the rowid of a slot, without decoding the rest of its row.
+    fn rowid(&self, page_id: PageId, page: &[u8], slot: usize) -> Result<u64> {
+        let offset = self.slot_offset(page_id, slot) + 1;
+        Ok(self.codec.read_field(&page[offset .. offset + self.codec.size()], 0)?.as_uint())
+    }
+
+    /// Decode a row as it's stored, without loading its strings from the
+    /// heap.
+    fn stored_values(&self, page_id: PageId, slot: usize) -> Result<Vec<Value>> {
+        let page = self.read(page_id)?;
+        let offset = self.slot_offset(page_id, slot);
+        self.codec.decode(&page[offset + 1 .. offset + self.slot_size()])
+    }
+
+    /// Read the names and root pages of the table's indexes.
+    fn index_roots(&self) -> Result<Vec<(String, PageId)>> {
+        let header = TableHeader::from_page(&self.read(FIRST_DATA_PAGE)?);
+        match header.indexes {
+            Some((page_id, slot)) => decode_index_roots(&self.pool.with_file(&self.file, |file| file.heap_read(page_id, slot))?),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Replace the table's index directory.
+    fn write_index_roots(&mut self, roots: &[(String, PageId)]) -> Result<()> {
+        let record = encode_index_roots(roots)?;
+        let mut header = TableHeader::from_page(&self.read(FIRST_DATA_PAGE)?);
+        if let Some((page_id, slot)) = header.indexes {
+            self.pool.with_file(&self.file, |file| file.heap_free(page_id, slot))?;
+        }
+        header.indexes = match roots.is_empty() {
+            true => None,
+            false => Some(self.pool.with_file(&self.file, |file| file.heap_insert(&record))?),
+        };
+        self.write_header(&header)
+    }
+
+    /// Update the header in the first page.
+    fn write_header(&mut self, header: &TableHeader) -> Result<()> {
+        let mut first = self.load(FIRST_DATA_PAGE)?;
+        header.write_to_page(&mut first);
+        self.store(FIRST_DATA_PAGE, &first)
+    }
+
+    /// Read the live rows in a page, and the ID of the next page.
+    fn read_rows(&self, page_id: PageId) -> Result<(Vec<StoredRow>, PageId)> {
+        let page = self.read(page_id)?;
+        let next_page = PageMetadata::from_page(&page).next_page;
+        if page_id == FIRST_DATA_PAGE && next_page != 0 {
+            // the rest of the chain is about to be read
+            self.pool.prefetch((self.file.clone(), next_page));
+        }
+        let mut rows = Vec::new();
+        for slot in 0 .. self.slot_count(page_id, &page) {
+            let offset = self.slot_offset(page_id, slot);
+            if page[offset] == SLOT_LIVE {
+                let mut values = self.codec.decode(&page[offset + 1 .. offset + self.slot_size()])?;
+                self.load_strings(&mut values)?;
+                rows.push(StoredRow {page: page_id, slot, values});
+            }
+        }
+        Ok((rows, next_page))
+    }
+
+    /// Move the strings in a row that don't fit in their columns to the heap.
+    fn store_strings(&self, values: &mut [Value]) -> Result<()> {
+        if !self.strings {
+            return Ok(());
+        }
+        let schema = &self.schema;
+        self.pool.with_file(&self.file, |file| {
+            for (value, entry) in values.iter_mut().zip(&schema.0) {
+                heap::store_strings(file, value, &entry.value)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Read the strings a row keeps in the heap.
+    fn load_strings(&self, values: &mut [Value]) -> Result<()> {
+        if !self.strings {
+            return Ok(());
+        }
+        self.pool.with_file(&self.file, |file| {
+            for value in values.iter_mut() {
+                heap::load_strings(file, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Free the heap space used by the row stored in a slot.
+    fn free_strings(&self, page_id: PageId, page: &[u8], slot: usize) -> Result<()> {
+        if !self.strings {
+            return Ok(());
+        }
+        let offset = self.slot_offset(page_id, slot);
+        let values = self.codec.decode(&page[offset + 1 .. offset + self.slot_size()])?;
+        self.pool.with_file(&self.file, |file| {
+            for value in &values {
+                heap::free_strings(file, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Get a page from the buffer pool, loading it from disk if it isn't
+    /// cached. The page is locked until it's dropped.
+    fn read(&self, page_id: PageId) -> Result<PageRef<'a>> {
+        self.pool.get_page((self.file.clone(), page_id))
+    }
+
+    /// Copy a page out of the buffer pool, so that it can be modified.
+    fn load(&mut self, page_id: PageId) -> Result<Vec<u8>> {
+        Ok(self.read(page_id)?.to_vec())
+    }
+
+    /// Replace a page in working memory, marking it dirty.
+    fn store(&mut self, page_id: PageId, page: &[u8]) -> Result<()> {
+        // the whole page is overwritten, so there's no need to read it
+        let (_lock, mut buf) = self.pool.overwrite_page((self.file.clone(), page_id))?;
+        buf.copy_from_slice(page);
+        Ok(())
+    }
+}
+
+/// Iterates over the live rows of a table, reading one page of the chain at
+/// a time.
+pub struct Rows<'s, 'a: 's> {
+    store: &'s TableStore<'a>,
+    next_page: PageId,
+    rows: ::std::vec::IntoIter<StoredRow>,
+}
+
+impl<'s, 'a> Iterator for Rows<'s, 'a> {
+    type Item = Result<StoredRow>;
+
+    fn next(&mut self) -> Option<Result<StoredRow>> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Some(Ok(row));
+            }
+            if self.next_page == 0 {
+                return None;
+            }
+            match self.store.read_rows(self.next_page) {
+                Ok((rows, next_page)) => {
+                    self.rows = rows.into_iter();
+                    self.next_page = next_page;
+                }
+                Err(e) => {
+                    self.next_page = 0;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn is_storable(value_type: &ValueType) -> bool {
+    match value_type {
+        &ValueType::Unknown => false,
+        &ValueType::Nullable(ref t) | &ValueType::Vector(_, ref t) => is_storable(t),
+        _ => true,
+    }
+}
+
+/// Whether values of a type may hold strings that are kept in the heap.
+fn has_strings(value_type: &ValueType) -> bool {
+    match value_type {
+        &ValueType::Str(_) => true,
+        &ValueType::Nullable(ref t) | &ValueType::Vector(_, ref t) => has_strings(t),
+        _ => false,
+    }
+}
+
+/// A buffer pool of the given number of pages, with an empty file open in
+/// it as "t", for testing the stores built on it.
+#[cfg(test)]
+pub fn test_pool(dir: &::tempdir::TempDir, pages: u64) -> BufferPool {
+    use ::io::dbfile::DbFile;
+    use ::io::pool::IoBackend;
+    let pool = BufferPool::new(PAGE_SIZE * pages);
+    let file = DbFile::create("t".to_string(), &dir.path().join("t")).unwrap();
+    pool.open("t".to_string(), file, IoBackend::Buffered).unwrap();
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use ::ast::tuple::TupleEntry;
+    use ::io::dbfile::{AccessMode, DbFile};
+    use ::io::pool::IoBackend;
+
+    fn table() -> Table {
+        Table {schema: TupleDef(vec![
+            TupleEntry {name: "a".to_string(), value: ValueType::Int},
+            TupleEntry {name: "b".to_string(), value: ValueType::Str(0)},
+        ])}
+    }
+
+    fn row(n: i64) -> Vec<Value> {
+        vec![Value::Int(n), Value::Str(format!("row {}", n))]
+    }
+
+    #[test]
+    fn test_rows() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let pool = test_pool(&dir, 4);
+        let table = table();
+        let mut store = TableStore::new("t", &table, &[], "t".to_string(), &pool).unwrap();
+
+        // enough rows to span several pages
+        store.insert((0 .. 1000).map(row).collect()).unwrap();
+        let rows = store.scan().unwrap();
+        assert_eq!(rows.len(), 1000);
+        assert!(rows.last().unwrap().page != FIRST_DATA_PAGE);
+        for (i, r) in rows.iter().enumerate() {
+            assert_eq!(r.values[0], Value::Uint(i as u64 + 1));
+            assert_eq!(&r.values[1 ..], &row(i as i64)[..]);
+        }
+        let fetched = store.fetch(600).unwrap().unwrap();
+        assert_eq!(&fetched.values[1 ..], &row(599)[..]);
+        assert!(store.fetch(0).unwrap().is_none());
+        assert!(store.fetch(1001).unwrap().is_none());
+
+        let mut updated = store.fetch(2).unwrap().unwrap();
+        updated.values[1] = Value::Int(-2);
+        store.update(vec![updated]).unwrap();
+        assert_eq!(store.fetch(2).unwrap().unwrap().values, vec![Value::Uint(2), Value::Int(-2), Value::Str("row 1".to_string())]);
+
+        // deleted rows leave tombstones that fetch skips over, and rowids
+        // aren't reused even once the last pages are freed
+        let deleted: Vec<(PageId, usize)> = rows[500 ..].iter().map(|r| (r.page, r.slot)).collect();
+        store.delete(&deleted).unwrap();
+        store.delete(&[(rows[1].page, rows[1].slot)]).unwrap();
+        assert!(store.fetch(2).unwrap().is_none());
+        assert!(store.fetch(600).unwrap().is_none());
+        assert_eq!(store.fetch(3).unwrap().unwrap().values[0], Value::Uint(3));
+        assert_eq!(store.rows().count(), 499);
+
+        // the rowid counter survives reopening the file
+        pool.close("t").unwrap();
+        let file = DbFile::open("t".to_string(), &dir.path().join("t"), AccessMode::ReadWrite).unwrap();
+        pool.open("t".to_string(), file, IoBackend::Buffered).unwrap();
+        let mut store = TableStore::new("t", &table, &[], "t".to_string(), &pool).unwrap();
+        store.insert(vec![row(1000)]).unwrap();
+        let last = store.rows().last().unwrap().unwrap();
+        assert_eq!(last.values[0], Value::Uint(1001));
+        assert_eq!(store.fetch(1001).unwrap().unwrap().values, last.values);
+        assert_eq!(store.rows().count(), 500);
+    }
+}
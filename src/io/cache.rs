@@ -1,12 +1,14 @@
-use std::collections::{HashMap, LinkedList, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::io::Read;
 use std::ops::{Deref, DerefMut, Drop};
-use std::sync::mpsc::{channel, Sender, Receiver};
-use lru_cache::LruCache;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
 use ::{Result, Error};
-use ::io::page::{PAGE_SIZE, PageId};
+use ::io::eviction::{EvictionPolicy, EvictionPolicyType};
+use ::io::page::PAGE_SIZE;
 
 /// Many read locks, or a single write lock, can be held against a single page
 /// at one time.
@@ -16,60 +18,91 @@ pub enum LockType {
     Write,
 }
 
+/// Identifies whoever holds or is waiting for a lock, so that the page table
+/// can tell when waiting for a lock would deadlock. WorkingMemory uses a
+/// separate owner for each thread.
+pub type LockOwner = u64;
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(1);
+
+thread_local!(static THREAD_OWNER: LockOwner = NEXT_OWNER.fetch_add(1, Ordering::SeqCst));
+
+/// The lock owner representing the current thread.
+pub fn current_owner() -> LockOwner {
+    THREAD_OWNER.with(|owner| *owner)
+}
+
 /// Represents a request for a lock that we couldn't yet satisfy. Try again
-/// later in the order it was received.
+/// later in the order it was received. When the lock is granted, it's sent
+/// through the channel; `None` is sent instead if the requester should retry,
+/// because the only way to make room for the page is to evict a dirty page,
+/// which the requester has to write back itself.
 #[derive(Debug)]
 pub struct LockRequest<T: Hash + Debug + Eq + Clone> {
+    id: u64,
     lock_type: LockType,
-    channel: Sender<PageLock<T>>,
+    owner: LockOwner,
+    /// True if the requester holds a read lock on the page, which will be
+    /// turned into the write lock it's asking for.
+    upgrade: bool,
+    channel: Sender<Option<PageLock<T>>>,
 }
 
 impl<T: Hash + Debug + Eq + Clone> LockRequest<T> {
-    pub fn new(lock_type: LockType, channel: Sender<PageLock<T>>) -> LockRequest<T> {
-        LockRequest {lock_type, channel}
+    pub fn new(id: u64, lock_type: LockType, owner: LockOwner, channel: Sender<Option<PageLock<T>>>) -> LockRequest<T> {
+        LockRequest {id, lock_type, owner, upgrade: false, channel}
     }
 }
 
 /// Prevents a page from being overwritten while in use. The PageTable uses
-/// these objects to count active references to a given page in working memory;
-/// once all active references are dropped, the page will re-enter the LRU
-/// cache and may be overwritten.
+/// these objects to count active references to a given page in working
+/// memory; once all active references are dropped, the page is unpinned and
+/// may be overwritten. Dropping a lock releases it immediately, granting any
+/// requests that were waiting for it.
 #[derive(Debug)]
 pub struct PageLock<T: Hash + Debug + Eq + Clone> {
-    channel: Sender<T>,
+    table: Weak<Mutex<PageTableState<T>>>,
     page_id: T,
     index: u64,
     lock_type: LockType,
+    owner: LockOwner,
+    /// True if the page was assigned a new slot for this lock, and the
+    /// holder is responsible for loading it.
+    load: bool,
 }
 
 impl<T: Hash + Debug + Eq + Clone> PageLock<T> {
-    pub fn new(channel: Sender<T>, page_id: T, index: u64, lock_type: LockType) -> PageLock<T> {
-        PageLock {channel, page_id, index, lock_type}
+    fn new(table: Weak<Mutex<PageTableState<T>>>, page_id: T, index: u64, lock_type: LockType, owner: LockOwner, load: bool) -> PageLock<T> {
+        PageLock {table, page_id, index, lock_type, owner, load}
+    }
+
+    /// Release the lock into a table that's already locked by the caller,
+    /// instead of waiting for it to be dropped.
+    fn release_into(mut self, table: &mut PageTableState<T>) {
+        self.table = Weak::new();
+        table.release(&self.page_id, &self.lock_type, self.owner, self.load);
     }
 }
 
 impl<T: Hash + Debug + Eq + Clone> Drop for PageLock<T> {
     fn drop (&mut self) {
-        self.channel.send(self.page_id.clone());
+        if let Some(table) = self.table.upgrade() {
+            let mut table = table.lock().unwrap_or_else(|e| e.into_inner());
+            table.release(&self.page_id, &self.lock_type, self.owner, self.load);
+        }
     }
 }
 
-/// A counter of active references to a page with a bidirectional channel for
-/// notification of expired references.
+/// A counter of active references to a page.
 #[derive(Debug)]
 pub struct ActiveRefCount<T: Hash + Debug + Eq + Clone> {
     active_count: HashMap<T, u64>,
-    sender: Sender<T>,
-    receiver: Receiver<T>,
 }
 
 impl<T: Hash + Debug + Eq + Clone> ActiveRefCount<T> {
     pub fn new(size: usize) -> ActiveRefCount<T> {
-        let (sender, receiver) = channel();
         ActiveRefCount {
             active_count: HashMap::with_capacity(size),
-            sender,
-            receiver,
         }
     }
 
@@ -94,14 +127,35 @@ impl<T: Hash + Debug + Eq + Clone> DerefMut for ActiveRefCount<T> {
     }
 }
 
-/// An LRU cache which maps page IDs to segments of working memory.
+/// A cache which maps page IDs to segments of working memory, evicting pages
+/// according to an EvictionPolicy.
+///
+/// Lock requests for a page are granted strictly in the order they were
+/// queued, and a lock is only granted without queueing if nothing is queued
+/// for its page. Once a writer is waiting, later readers wait behind it
+/// instead of joining the readers that hold the page, and a reader queued
+/// behind one writer is granted its lock before the next writer; so a
+/// steady stream of readers can't starve a writer, or vice versa. The one
+/// exception is an upgrade from a read lock to a write lock, which goes to
+/// the front of the queue, since everyone behind it would otherwise be
+/// waiting for the upgrader's read lock.
+///
+/// The table can be shared between threads; its state is kept behind a mutex, which
+/// every PageLock refers back to so that it can release itself.
 #[derive(Debug)]
 pub struct PageTable<T: Hash + Debug + Eq + Clone> {
+    state: Arc<Mutex<PageTableState<T>>>,
+}
+
+#[derive(Debug)]
+struct PageTableState<T: Hash + Debug + Eq + Clone> {
+    /// the state's own mutex, which locks are created with
+    handle: Weak<Mutex<PageTableState<T>>>,
     page_count: u64,
     /// map of pages currently cached -> index
     page_map: HashMap<T, u64>,
-    /// LRU of page -> index
-    page_lru: LruCache<T, u64>,
+    /// decides which unlocked page to evict next
+    eviction: Box<dyn EvictionPolicy<T> + Send>,
     /// count of currently active readers
     reader_count: ActiveRefCount<T>,
     /// count of currently active writers (max 1 per page)
@@ -113,103 +167,449 @@ pub struct PageTable<T: Hash + Debug + Eq + Clone> {
     pending_pages: VecDeque<T>,
     /// cache indexes that are as of yet unused
     available_slots: Vec<u64>,
+    /// whether the page in each slot has been modified since it was loaded
+    dirty: Vec<bool>,
+    /// pages that have been assigned a slot but haven't been loaded into it
+    /// yet; no further locks are granted on them until they are
+    loading: HashSet<T>,
+    /// locks granted on pages that were already cached
+    hits: u64,
+    /// locks granted on pages that had to be loaded
+    misses: u64,
+    /// pages evicted to make room for others
+    evictions: u64,
+    /// the longest time a lock request has been queued
+    longest_wait: Duration,
+    /// owners of the active locks on each page, once per lock
+    holders: HashMap<T, Vec<LockOwner>>,
+    /// the ID of the next lock request
+    next_request: u64,
+    /// whether the page in each slot was loaded by a prefetch and hasn't
+    /// been used since
+    prefetched: Vec<bool>,
+    /// pages loaded by a prefetch
+    prefetches: u64,
+    /// prefetched pages that were used before being evicted
+    prefetch_hits: u64,
+}
+
+/// A snapshot of how working memory is being used. Hits, misses and
+/// evictions are counted from the time working memory was created.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheStats {
+    /// the number of pages working memory can hold
+    pub pages: u64,
+    /// pages currently in working memory
+    pub cached_pages: u64,
+    /// locks granted on pages that were already cached
+    pub hits: u64,
+    /// locks granted on pages that had to be loaded
+    pub misses: u64,
+    /// pages evicted to make room for others
+    pub evictions: u64,
+    /// cached pages modified since they were loaded or last written
+    pub dirty_pages: u64,
+    /// cached pages that are locked, and can't be evicted
+    pub pinned_pages: u64,
+    /// lock requests waiting to be granted
+    pub queued_requests: u64,
+    /// the longest time any lock request has waited to be granted
+    pub longest_lock_wait: Duration,
+    /// pages loaded ahead of their first use by a prefetch
+    pub prefetched_pages: u64,
+    /// prefetched pages that were used before being evicted
+    pub prefetch_hits: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
 }
 
 impl<T: Hash + Debug + Eq + Clone> PageTable<T> {
-    pub fn new(size: u64) -> PageTable<T> {
+    pub fn new(size: u64) -> PageTable<T> where T: Send + 'static {
+        PageTable::with_policy(size, EvictionPolicyType::Lru)
+    }
+
+    pub fn with_policy(size: u64, policy: EvictionPolicyType) -> PageTable<T> where T: Send + 'static {
         let pages = (size / PAGE_SIZE) as usize;
-        let actual_size = (pages as u64) * PAGE_SIZE;
-        let page_lru = LruCache::new(pages);
         let available_slots = (0..pages as u64).rev().collect();
 
-        PageTable {
+        let state = Arc::new_cyclic(|handle| Mutex::new(PageTableState {
+            handle: handle.clone(),
             page_count: pages as u64,
             page_map: HashMap::new(),
-            page_lru: page_lru,
+            eviction: policy.create(pages),
             reader_count: ActiveRefCount::new(pages),
             writer_count: ActiveRefCount::new(pages),
             pending_requests: HashMap::with_capacity(0x100),
             pending_pages: VecDeque::with_capacity(0x100),
             available_slots: available_slots,
+            dirty: vec![false; pages],
+            loading: HashSet::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            longest_wait: Duration::from_secs(0),
+            holders: HashMap::new(),
+            next_request: 0,
+            prefetched: vec![false; pages],
+            prefetches: 0,
+            prefetch_hits: 0,
+        }));
+        PageTable {state}
+    }
+
+    /// Returns true if working memory already contains this page. This also
+    /// counts as a use of the page, making it less likely to be evicted.
+    pub fn contains_page(&self, id: &T) -> bool {
+        let mut state = self.state();
+        let cached = state._contains_page(id);
+        if cached {
+            state.eviction.access(id);
+        }
+        cached
+    }
+
+    /// Replay a trace of page accesses, locking and immediately releasing
+    /// each page in turn, and count how many of them were already cached.
+    /// Nothing is actually loaded, so this is only useful for comparing
+    /// eviction policies.
+    pub fn replay(&self, trace: &[T]) -> TraceResult {
+        let mut result = TraceResult {hits: 0, misses: 0};
+        for page_id in trace {
+            match self.try_lock(page_id, &LockType::Read, 0) {
+                Some(ref lock) if !lock.load => result.hits += 1,
+                _ => result.misses += 1,
+            }
         }
+        result
+    }
+
+    /// Queue a request for a lock. The lock is sent through the channel as
+    /// soon as it can be granted, which may be immediately. Returns an ID
+    /// for the request, which can be used to cancel it; if the request would
+    /// have to wait for an owner that's already waiting for this one, it's
+    /// refused instead, with a Deadlock error.
+    pub fn request_lock(&self, page_id: &T, lock_type: &LockType, owner: LockOwner, channel: &Sender<Option<PageLock<T>>>) -> Result<u64> {
+        self.state().request_lock(page_id, lock_type, owner, channel)
+    }
+
+    /// Queue a request to turn a read lock into a write lock, which is sent
+    /// through the channel as soon as every other lock on the page has been
+    /// released. The read lock is held until then, so no writer can modify
+    /// the page in between. If the upgrade would deadlock, which happens
+    /// when two holders of a page both try to upgrade, the read lock is
+    /// released and a Deadlock error is returned. Any other kind of lock is
+    /// released with an error.
+    pub fn request_upgrade(&self, lock: PageLock<T>, channel: &Sender<Option<PageLock<T>>>) -> Result<u64> {
+        self.state().request_upgrade(lock, channel)
+    }
+
+    /// Withdraw a request that hasn't been granted yet. Returns false if the
+    /// request is no longer queued. A withdrawn upgrade releases the read
+    /// lock it was made with.
+    pub fn cancel_request(&self, page_id: &T, request: u64) -> bool {
+        self.state().cancel_request(page_id, request)
+    }
+
+    /// Get a lock without waiting, if it can be granted immediately and no
+    /// other requests for the page are waiting.
+    pub fn try_lock(&self, page_id: &T, lock_type: &LockType, owner: LockOwner) -> Option<PageLock<T>> {
+        self.state().try_lock(page_id, lock_type, owner)
+    }
+
+    /// Get the page index of a specified page ID. If this page is currently
+    /// loaded, returns the index at which its data resides (in units of
+    /// pages); otherwise, assigns the page a slot, evicting another page if
+    /// necessary, and returns the new index into which it can be loaded.
+    /// Doesn't check for conflicting locks.
+    pub(self) fn page_index(&self, page_id: T, lock_type: LockType, owner: LockOwner) -> Option<PageLock<T>> {
+        self.state().page_index(page_id, lock_type, owner)
+    }
+
+    /// Drops an unlocked page from the cache so that the next request for it
+    /// reloads it, discarding any changes to it. Returns false if the page is
+    /// still locked.
+    pub fn remove_page(&self, page_id: &T) -> bool {
+        self.state().remove_page(page_id)
     }
 
-    /// Returns true if working memory already contains this page. This method
-    /// is mutable because contains_key pushes the key to the front of the LRU
-    /// if it exists.
-    pub fn contains_page(&mut self, id: &T) -> bool {
-        self.check_messages();
-        self._contains_page(&id)
+    pub fn stats(&self) -> CacheStats {
+        self.state().stats()
     }
 
-    pub fn request_lock(&mut self, page_id: &T, lock_type: &LockType, channel: &mut Sender<PageLock<T>>) {
-        self.check_messages();
+    fn state(&self) -> MutexGuard<'_, PageTableState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T: Hash + Debug + Eq + Clone> PageTableState<T> {
+    fn request_lock(&mut self, page_id: &T, lock_type: &LockType, owner: LockOwner, channel: &Sender<Option<PageLock<T>>>) -> Result<u64> {
         if !self.pending_requests.contains_key(&page_id) {
             self.pending_requests.insert(page_id.clone(), VecDeque::with_capacity(0x100));
         }
+        let id = self.next_request;
+        self.next_request += 1;
         self.pending_requests.get_mut(&page_id).unwrap().push_back(
-            LockRequest::new(lock_type.clone(), channel.clone())
+            LockRequest::new(id, lock_type.clone(), owner, channel.clone())
         );
         self.handle_pending_requests(&page_id);
+        // a cycle can only be closed by a request that has to wait
+        if self.is_queued(page_id, id) && self.deadlocked(owner) {
+            self.cancel_request(page_id, id);
+            return Err(Error::Deadlock(format!("waiting for a lock on page {:?} would deadlock", page_id)));
+        }
+        Ok(id)
     }
 
-    fn incr_ref_count(&mut self, page_id: &T, lock_type: &LockType) -> Sender<T> {
-        if self.page_lru.contains_key(page_id) {
-            self.page_lru.remove(page_id);
+    fn request_upgrade(&mut self, mut lock: PageLock<T>, channel: &Sender<Option<PageLock<T>>>) -> Result<u64> {
+        if lock.lock_type != LockType::Read {
+            let message = format!("can't upgrade a {:?} lock on page {:?}", lock.lock_type, lock.page_id);
+            lock.release_into(self);
+            return Err(Error::Custom(message));
+        }
+        // the read lock now belongs to the request
+        lock.table = Weak::new();
+        let page_id = lock.page_id.clone();
+        if lock.load {
+            // the page was loaded without anyone calling finish_load
+            self.loading.remove(&page_id);
         }
-        let mut ref_count = match lock_type {
+        let id = self.next_request;
+        self.next_request += 1;
+        let mut request = LockRequest::new(id, LockType::Write, lock.owner, channel.clone());
+        request.upgrade = true;
+        self.pending_requests.entry(page_id.clone()).or_default().push_front(request);
+        self.handle_pending_requests(&page_id);
+        if self.is_queued(&page_id, id) && self.deadlocked(lock.owner) {
+            self.cancel_request(&page_id, id);
+            return Err(Error::Deadlock(format!("upgrading a lock on page {:?} would deadlock", page_id)));
+        }
+        Ok(id)
+    }
+
+    fn cancel_request(&mut self, page_id: &T, request: u64) -> bool {
+        let position = match self.pending_requests.get(page_id) {
+            Some(v) => v.iter().position(|r| r.id == request),
+            None => None,
+        };
+        match position {
+            Some(i) => {
+                let request = self.pending_requests.get_mut(page_id).unwrap().remove(i).unwrap();
+                if request.upgrade {
+                    self.release(page_id, &LockType::Read, request.owner, false);
+                }
+                // the requests behind it may be grantable now
+                self.handle_pending_requests(page_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Turn an upgrader's read lock into a write lock.
+    fn upgrade_lock(&mut self, page_id: &T, owner: LockOwner) -> PageLock<T> {
+        *self.reader_count.get_mut(page_id).unwrap() -= 1;
+        *self.writer_count.entry(page_id.clone()).or_insert(0) += 1;
+        let index = self.page_map[page_id];
+        PageLock::new(self.handle.clone(), page_id.clone(), index, LockType::Write, owner, false)
+    }
+
+    fn is_queued(&self, page_id: &T, request: u64) -> bool {
+        match self.pending_requests.get(page_id) {
+            Some(v) => v.iter().any(|r| r.id == request),
+            None => false,
+        }
+    }
+
+    /// The owners that an owner's queued requests are waiting for: those
+    /// holding locks on the requested pages, and those queued ahead of it.
+    fn waits_for(&self, owner: LockOwner) -> Vec<LockOwner> {
+        let mut owners = Vec::new();
+        for (page_id, queue) in &self.pending_requests {
+            if let Some(i) = queue.iter().position(|r| r.owner == owner) {
+                if let Some(holders) = self.holders.get(page_id) {
+                    let mut holders = holders.clone();
+                    if queue[i].upgrade {
+                        // an upgrader doesn't wait for its own read lock
+                        let own = holders.iter().position(|&o| o == owner).unwrap();
+                        holders.swap_remove(own);
+                    }
+                    owners.extend(holders);
+                }
+                owners.extend(queue.iter().take(i).map(|r| r.owner));
+            }
+        }
+        owners
+    }
+
+    /// Returns true if there's a cycle in the wait-for graph through this
+    /// owner, meaning that none of the owners in it will ever be granted
+    /// their locks.
+    fn deadlocked(&self, owner: LockOwner) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = self.waits_for(owner);
+        while let Some(next) = stack.pop() {
+            if next == owner {
+                return true;
+            }
+            if visited.insert(next) {
+                stack.extend(self.waits_for(next));
+            }
+        }
+        false
+    }
+
+    fn try_lock(&mut self, page_id: &T, lock_type: &LockType, owner: LockOwner) -> Option<PageLock<T>> {
+        if !self.has_queued_requests(page_id) && self.can_grant_lock(page_id, lock_type) {
+            self.page_index(page_id.clone(), lock_type.clone(), owner)
+        } else {
+            None
+        }
+    }
+
+    /// A read lock on a cached page which doesn't count as a use of it, or
+    /// None if the page isn't cached or can't be locked right away.
+    fn peek(&mut self, page_id: &T, owner: LockOwner) -> Option<PageLock<T>> {
+        if !self._contains_page(page_id) || self.has_queued_requests(page_id) || !self.can_grant_lock(page_id, &LockType::Read) {
+            return None;
+        }
+        let index = self.page_map[page_id];
+        Some(self.create_lock(page_id.clone(), index, LockType::Read, owner, false))
+    }
+
+    fn has_queued_requests(&self, page_id: &T) -> bool {
+        match self.pending_requests.get(page_id) {
+            Some(v) => !v.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Record that a page was loaded by a prefetch.
+    fn mark_prefetched(&mut self, index: u64) {
+        self.prefetched[index as usize] = true;
+        self.prefetches += 1;
+        // the page's first use counts as a hit instead
+        self.misses -= 1;
+    }
+
+    fn incr_ref_count(&mut self, page_id: &T, lock_type: &LockType, owner: LockOwner) {
+        self.eviction.pin(page_id);
+        self.holders.entry(page_id.clone()).or_default().push(owner);
+        let ref_count = match lock_type {
             &LockType::Read => &mut self.reader_count,
             &LockType::Write => &mut self.writer_count,
         };
-        {
-            let entry = ref_count.entry(page_id.clone()).or_insert(0);
-            *entry += 1;
+        let entry = ref_count.entry(page_id.clone()).or_insert(0);
+        *entry += 1;
+    }
+
+    /// Returns the number of locks of this type left on the page.
+    fn decr_ref_count(&mut self, page_id: &T, lock_type: &LockType, owner: LockOwner) -> u64 {
+        let emptied = match self.holders.get_mut(page_id) {
+            Some(holders) => {
+                if let Some(i) = holders.iter().position(|&o| o == owner) {
+                    holders.swap_remove(i);
+                }
+                holders.is_empty()
+            }
+            None => false,
+        };
+        if emptied {
+            self.holders.remove(page_id);
         }
-        ref_count.sender.clone()
+        let ref_count = match lock_type {
+            &LockType::Read => &mut self.reader_count,
+            &LockType::Write => &mut self.writer_count,
+        };
+        let entry = ref_count.entry(page_id.clone()).or_insert(0);
+        *entry -= 1;
+        *entry
     }
 
-    fn decr_ref_count(&mut self, page_id: &T, lock_type: &LockType) {
-        let rc = {
-            let mut ref_count = match lock_type {
-                &LockType::Read => &mut self.reader_count,
-                &LockType::Write => &mut self.writer_count,
-            };
-            let entry = ref_count.entry(page_id.clone()).or_insert(0);
-            *entry -= 1;
-            entry.clone()
+    /// Release a lock. If it was the last lock on the page, the page is
+    /// unpinned, and waiting requests are granted if possible.
+    fn release(&mut self, page_id: &T, lock_type: &LockType, owner: LockOwner, loaded: bool) {
+        if loaded && self.loading.remove(page_id) {
+            // the page was loaded without anyone calling finish_load
+            self.handle_pending_requests(page_id);
+        }
+        let rc = self.decr_ref_count(page_id, lock_type, owner);
+        // an upgrade can be granted while its own read lock is still held
+        let upgrading = match self.pending_requests.get(&page_id) {
+            Some(v) => !v.is_empty() && v[0].upgrade,
+            None => false,
         };
-        if rc == 0 {
+        if rc == 0 || upgrading {
             if self.pending_requests.contains_key(&page_id) && self.pending_requests.get(&page_id).unwrap().len() > 0 {
                 self.handle_pending_requests(&page_id);
             }
-            if self.reader_count.active(&page_id) == 0 && self.writer_count.active(&page_id) == 0 {
-                self.page_lru.insert(page_id.clone(), self.page_map.get(page_id).unwrap().clone());
+            if self.reader_count.active(page_id) == 0 && self.writer_count.active(page_id) == 0 {
+                self.eviction.unpin(page_id);
+                self.handle_pending_pages();
             }
         }
     }
 
-    /// Get the page index of a specified page ID. If this page is currently
-    /// loaded, returns the index at which its data resides (in units of
-    /// pages); otherwise, adds the ID to the LRU cache first, returning a new
-    /// index into which it can be loaded.
-    pub(self) fn page_index(&mut self, page_id: T, lock_type: LockType) -> Option<PageLock<T>> {
+    /// Mark a page as loaded, allowing other locks on it to be granted.
+    fn finish_load(&mut self, page_id: &T) {
+        self.loading.remove(page_id);
+        self.handle_pending_requests(page_id);
+    }
+
+    /// Release the lock held by a page's loader after loading failed, and
+    /// free the page's slot.
+    fn abort_load(&mut self, mut lock: PageLock<T>) {
+        lock.table = Weak::new();
+        self.loading.remove(&lock.page_id);
+        self.decr_ref_count(&lock.page_id, &lock.lock_type, lock.owner);
+        // nobody else can have locked the page while it was loading
+        self.page_map.remove(&lock.page_id);
+        self.eviction.remove(&lock.page_id);
+        self.dirty[lock.index as usize] = false;
+        self.available_slots.push(lock.index);
+        self.handle_pending_requests(&lock.page_id);
+        self.handle_pending_pages();
+    }
+
+    fn page_index(&mut self, page_id: T, lock_type: LockType, owner: LockOwner) -> Option<PageLock<T>> {
         if self._contains_page(&page_id) {
             // no active references to this page, but it's still in working
             // memory; reuse it
             let index = self.get_index_for_lock(&page_id).unwrap();
-            return Some(self.create_lock(page_id, index, lock_type));
+            self.hits += 1;
+            if self.prefetched[index as usize] {
+                self.prefetched[index as usize] = false;
+                self.prefetch_hits += 1;
+            }
+            return Some(self.create_lock(page_id, index, lock_type, owner, false));
         }
         match self.available_slots.pop() {
             Some(index) => {
                 // fill a previously empty block of working memory
                 self.page_map.insert(page_id.clone(), index);
-                Some(self.create_lock(page_id, index, lock_type))
+                self.eviction.insert(&page_id);
+                self.dirty[index as usize] = false;
+                self.loading.insert(page_id.clone());
+                self.misses += 1;
+                Some(self.create_lock(page_id, index, lock_type, owner, true))
             },
-            None => match self.page_lru.remove_lru() {
-                Some((_, index)) => {
+            None => match self.clean_victim() {
+                Some(expired) => {
                     // expire a block of working memory and overwrite it
+                    let index = self.page_map.remove(&expired).unwrap();
+                    self.eviction.remove(&expired);
                     self.page_map.insert(page_id.clone(), index);
-                    Some(self.create_lock(page_id, index, lock_type))
+                    self.eviction.insert(&page_id);
+                    self.loading.insert(page_id.clone());
+                    self.misses += 1;
+                    self.evictions += 1;
+                    Some(self.create_lock(page_id, index, lock_type, owner, true))
                 },
                 // working memory is completely full
                 _ => None
@@ -217,19 +617,144 @@ impl<T: Hash + Debug + Eq + Clone> PageTable<T> {
         }
     }
 
-    pub fn tick(&mut self) {
-        self.check_messages();
+    fn remove_page(&mut self, page_id: &T) -> bool {
+        if self.reader_count.active(page_id) > 0 || self.writer_count.active(page_id) > 0 {
+            return false;
+        }
+        if let Some(index) = self.page_map.remove(page_id) {
+            self.eviction.remove(page_id);
+            self.dirty[index as usize] = false;
+            self.available_slots.push(index);
+            self.handle_pending_pages();
+        }
+        true
+    }
+
+    /// The next page to be evicted, if it can be evicted without being
+    /// written back.
+    fn clean_victim(&mut self) -> Option<T> {
+        match self.eviction.victim() {
+            Some(ref id) if self.dirty[self.page_map[id] as usize] => None,
+            victim => victim,
+        }
+    }
+
+    /// If making room for a page means evicting a dirty page, returns that
+    /// page along with a read lock on it, which keeps it cached while it's
+    /// written back.
+    fn dirty_victim(&mut self, page_id: &T, owner: LockOwner) -> Option<(T, PageLock<T>)> {
+        if self._contains_page(page_id) || self.can_load_page() {
+            return None;
+        }
+        let victim = self.eviction.victim()?;
+        // writing the page back doesn't count as a use of it
+        let index = self.page_map[&victim];
+        let lock = self.create_lock(victim.clone(), index, LockType::Read, owner, false);
+        Some((victim, lock))
+    }
+
+    /// After a dirty victim has been written back, evict it and give its slot
+    /// to another page, which has to be loaded. Returns None, and releases the
+    /// victim normally, if it has been locked or modified in the meantime.
+    fn replace_victim(&mut self, mut victim: PageLock<T>, page_id: &T, lock_type: &LockType) -> Option<PageLock<T>> {
+        victim.table = Weak::new();
+        let victim_id = victim.page_id.clone();
+        let index = victim.index;
+        self.decr_ref_count(&victim_id, &LockType::Read, victim.owner);
+        let unlocked = self.reader_count.active(&victim_id) == 0 && self.writer_count.active(&victim_id) == 0;
+        if !unlocked || self.dirty[index as usize] || self._contains_page(page_id) {
+            if unlocked {
+                self.eviction.unpin(&victim_id);
+                self.handle_pending_requests(&victim_id);
+                self.handle_pending_pages();
+            }
+            return None;
+        }
+        self.page_map.remove(&victim_id);
+        self.eviction.remove(&victim_id);
+        self.page_map.insert(page_id.clone(), index);
+        self.eviction.insert(page_id);
+        self.loading.insert(page_id.clone());
+        self.misses += 1;
+        self.evictions += 1;
+        let lock = self.create_lock(page_id.clone(), index, lock_type.clone(), victim.owner, true);
+        // anyone waiting for the victim will have to reload it
+        self.handle_pending_requests(&victim_id);
+        Some(lock)
+    }
+
+    fn mark_clean(&mut self, index: u64) {
+        self.dirty[index as usize] = false;
+        self.handle_pending_pages();
+    }
+
+    /// Pages that have been modified since they were loaded, with their
+    /// indexes.
+    fn dirty_pages(&self) -> Vec<(T, u64)> {
+        self.page_map.iter()
+            .filter(|&(_, index)| self.dirty[*index as usize])
+            .map(|(id, index)| (id.clone(), *index))
+            .collect()
+    }
+
+    fn record_wait(&mut self, wait: Duration) {
+        if wait > self.longest_wait {
+            self.longest_wait = wait;
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        let pinned = self.page_map.keys()
+            .filter(|id| self.reader_count.active(id) > 0 || self.writer_count.active(id) > 0)
+            .count();
+        CacheStats {
+            pages: self.page_count,
+            cached_pages: self.page_map.len() as u64,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            dirty_pages: self.dirty_pages().len() as u64,
+            pinned_pages: pinned as u64,
+            queued_requests: self.pending_requests.values().map(|v| v.len() as u64).sum(),
+            longest_lock_wait: self.longest_wait,
+            prefetched_pages: self.prefetches,
+            prefetch_hits: self.prefetch_hits,
+        }
     }
 
     fn handle_pending_requests(&mut self, page_id: &T) {
         if self.pending_requests.contains_key(&page_id) {
             let mut pending = self.pending_requests.remove(&page_id).unwrap();
             while pending.len() > 0 {
-                if self.can_grant_lock(&page_id, &pending[0].lock_type) {
-                    let mut request = pending.pop_front().unwrap();
-                    let lock = self.page_index(page_id.clone(), request.lock_type.clone()).unwrap();
-                    request.channel.send(lock);
+                let grantable = if pending[0].upgrade {
+                    self.can_upgrade(page_id)
+                } else {
+                    self.can_grant_lock(&page_id, &pending[0].lock_type)
+                };
+                if grantable {
+                    let request = pending.pop_front().unwrap();
+                    let lock = if request.upgrade {
+                        self.upgrade_lock(page_id, request.owner)
+                    } else {
+                        self.page_index(page_id.clone(), request.lock_type.clone(), request.owner).unwrap()
+                    };
+                    if let Err(e) = request.channel.send(Some(lock)) {
+                        // the requester has gone away
+                        if let Some(lock) = e.0 {
+                            lock.release_into(self);
+                        }
+                    }
                 } else {
+                    if !self._contains_page(page_id) && !self.loading.contains(page_id) {
+                        if self.eviction.victim().is_some() {
+                            // the next page to be evicted is dirty, and the
+                            // requester has to write it back
+                            let request = pending.pop_front().unwrap();
+                            request.channel.send(None);
+                        } else if !self.pending_pages.contains(page_id) {
+                            self.pending_pages.push_back(page_id.clone());
+                        }
+                    }
                     break;
                 }
             }
@@ -237,18 +762,24 @@ impl<T: Hash + Debug + Eq + Clone> PageTable<T> {
         }
     }
 
+    /// Retry requests that were waiting for space in working memory.
+    fn handle_pending_pages(&mut self) {
+        let pages: Vec<T> = self.pending_pages.drain(..).collect();
+        for page_id in pages {
+            self.handle_pending_requests(&page_id);
+        }
+    }
+
     fn can_grant_lock(&mut self, page_id: &T, lock_type: &LockType) -> bool {
+        if self.loading.contains(page_id) {
+            return false;
+        }
+        // queued requests are checked by the caller, since they're granted
+        // in order
         (self._contains_page(&page_id) || self.can_load_page()) && match lock_type {
             &LockType::Read => match self.writer_count.get(&page_id) {
                 Some(n) if *n > 0 => false,
-                _ => match self.pending_requests.get(&page_id) {
-                    Some(v) => {
-                        // if the next request is a write request, don't accept any
-                        // more read requests
-                        v.len() == 0 || v[0].lock_type == LockType::Write
-                    },
-                    _ => true,
-                },
+                _ => true,
             },
             &LockType::Write => match self.writer_count.get(&page_id) {
                 Some(n) if *n > 0 => false,
@@ -260,116 +791,384 @@ impl<T: Hash + Debug + Eq + Clone> PageTable<T> {
         }
     }
 
+    /// An upgrade can be granted once the upgrader's read lock is the only
+    /// lock left on the page.
+    fn can_upgrade(&self, page_id: &T) -> bool {
+        self.writer_count.active(page_id) == 0 && self.reader_count.active(page_id) == 1
+    }
+
     fn get_index_for_lock(&mut self, page_id: &T) -> Option<u64> {
-        if self.page_lru.contains_key(&page_id) {
-            // no active references to this page, but it's still in working
-            // memory; reuse it
-            Some(self.page_lru.remove(&page_id).unwrap())
-        }
-        else if self.reader_count.contains_key(&page_id) {
-            // there are other active references to this page already
-            Some(self.page_map.get(&page_id).unwrap().clone())
-        }
-        else {
-            None
+        // whether or not there are other active references to this page, it's
+        // still in working memory; reuse it
+        let index = self.page_map.get(page_id).cloned();
+        if index.is_some() {
+            self.eviction.access(page_id);
         }
+        index
     }
 
-    fn create_lock(&mut self, page_id: T, index: u64, lock_type: LockType) -> PageLock<T> {
-        let channel = self.incr_ref_count(&page_id, &lock_type);
-        PageLock::new(channel, page_id, index, lock_type)
+    fn create_lock(&mut self, page_id: T, index: u64, lock_type: LockType, owner: LockOwner, load: bool) -> PageLock<T> {
+        if load {
+            self.prefetched[index as usize] = false;
+        }
+        self.incr_ref_count(&page_id, &lock_type, owner);
+        PageLock::new(self.handle.clone(), page_id, index, lock_type, owner, load)
     }
 
     fn can_load_page(&mut self) -> bool {
-        !(self.available_slots.is_empty() && self.page_lru.len() == 0)
+        !self.available_slots.is_empty() || self.clean_victim().is_some()
     }
 
-    fn _contains_page(&mut self, id: &T) -> bool {
-        self.page_lru.contains_key(id) ||
-            self.reader_count.active(id) > 0 ||
-            self.writer_count.active(id) > 0
+    fn _contains_page(&self, id: &T) -> bool {
+        self.page_map.contains_key(id)
     }
+}
 
-    fn check_messages(&mut self) {
-        loop {
-            match self.reader_count.receiver.try_recv() {
-                Ok(page_id) => {
-                    self.decr_ref_count(&page_id, &LockType::Read);
-                }
-                _ => {
-                    break;
-                }
-            }
-        }
-        loop {
-            match self.writer_count.receiver.try_recv() {
-                Ok(page_id) => {
-                    self.decr_ref_count(&page_id, &LockType::Write);
-                }
-                _ => {
-                    break;
-                }
-            }
+/// The outcome of replaying a trace of page accesses against a PageTable.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TraceResult {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl TraceResult {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
         }
     }
 }
 
-/// A block of memory for caching pages from database files.
+/// A page's data, readable while its guard is held.
+pub type PageReadGuard<'a> = RwLockReadGuard<'a, Box<[u8]>>;
+/// A page's data, writable while its guard is held.
+pub type PageWriteGuard<'a> = RwLockWriteGuard<'a, Box<[u8]>>;
+
+/// A block of memory for caching pages from database files. Working memory
+/// can be shared between threads behind an Arc; each page's data is only
+/// accessible while holding a PageLock on it. When both are needed, a slot's
+/// data is always locked before the page table's state.
 pub struct WorkingMemory<T: Hash + Debug + Eq + Clone> {
-    page_data: Box<[u8]>,
+    slots: Vec<RwLock<Box<[u8]>>>,
     page_table: PageTable<T>,
+    /// how long to wait for a lock before giving up; None waits forever
+    lock_timeout: Option<Duration>,
 }
 
 impl<T: Hash + Debug + Eq + Clone> WorkingMemory<T> {
-    pub fn new(size: u64) -> WorkingMemory<T> {
+    pub fn new(size: u64) -> WorkingMemory<T> where T: Send + 'static {
+        WorkingMemory::with_policy(size, EvictionPolicyType::Lru)
+    }
+
+    pub fn with_policy(size: u64, policy: EvictionPolicyType) -> WorkingMemory<T> where T: Send + 'static {
         let pages = size / PAGE_SIZE;
-        let actual_size = pages * PAGE_SIZE;
-        let mut zero_data = Vec::with_capacity(actual_size as usize);
-        for i in 0..actual_size {
-            zero_data.push(0);
-        }
-        let page_data = zero_data.into_boxed_slice();
-        let page_table = PageTable::new(actual_size);
+        let slots = (0 .. pages).map(|_| RwLock::new(vec![0; PAGE_SIZE as usize].into_boxed_slice())).collect();
+        let page_table = PageTable::with_policy(pages * PAGE_SIZE, policy);
 
         WorkingMemory {
-            page_data: page_data,
-            page_table: page_table,
+            slots,
+            page_table,
+            lock_timeout: None,
         }
     }
 
-    /// Get a page from working memory. If the page is not present in memory,
-    /// it will be loaded first, replacing the least recently used page if
-    /// working memory is full.
-    pub fn get_page<'a, R: FnOnce(&mut [u8]) -> ()>(&'a mut self, page_id: T, reader: R) -> Result<Option<(PageLock<T>, &'a [u8])>> {
-        let load = !self.page_table.contains_page(&page_id);
-        let result = self.page_table.page_index(page_id, LockType::Read);
-        match result {
+    /// Limit how long a request waits for a lock, after which it fails with
+    /// a LockTimeout error. Requests that would deadlock fail immediately,
+    /// with a Deadlock error, regardless of the timeout.
+    pub fn set_lock_timeout(&mut self, timeout: Option<Duration>) {
+        self.lock_timeout = timeout;
+    }
+
+    /// Get a page from working memory, waiting until no other thread holds a
+    /// write lock on it. If the page is not present in memory, it will be
+    /// loaded first, replacing the page chosen by the eviction policy if
+    /// working memory is full; if that page is dirty, it's passed to the
+    /// writer before being replaced. If loading fails, the page is left
+    /// uncached and the reader's error is returned.
+    pub fn get_page<'a, R, W>(&'a self, page_id: T, reader: R, writer: W) -> Result<(PageLock<T>, PageReadGuard<'a>)>
+            where R: FnOnce(&mut [u8]) -> Result<()>, W: FnMut(&T, &[u8]) -> Result<()> {
+        let lock = self.lock_page(page_id, LockType::Read, true, reader, writer)?.unwrap();
+        let data = self.read_slot(lock.index);
+        Ok((lock, data))
+    }
+
+    /// Like get_page, but returns None instead of waiting if the page is
+    /// locked for writing or working memory is full of locked pages.
+    pub fn try_get_page<'a, R, W>(&'a self, page_id: T, reader: R, writer: W) -> Result<Option<(PageLock<T>, PageReadGuard<'a>)>>
+            where R: FnOnce(&mut [u8]) -> Result<()>, W: FnMut(&T, &[u8]) -> Result<()> {
+        match self.lock_page(page_id, LockType::Read, false, reader, writer)? {
             Some(lock) => {
-                let mut index = match &lock {
-                    &PageLock {index, ..} => {
-                        let buf = &mut self.page_data[(index*PAGE_SIZE) as usize .. ((index+1)*PAGE_SIZE) as usize];
-                        if load {
-                            // load from the provided buffer
-                            reader(buf);
+                let data = self.read_slot(lock.index);
+                Ok(Some((lock, data)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Like get_page, but waits for a write lock and returns a mutable buffer.
+    /// The page is marked dirty, and will be passed to a writer when it's
+    /// evicted or flushed.
+    pub fn get_page_mut<'a, R, W>(&'a self, page_id: T, reader: R, writer: W) -> Result<(PageLock<T>, PageWriteGuard<'a>)>
+            where R: FnOnce(&mut [u8]) -> Result<()>, W: FnMut(&T, &[u8]) -> Result<()> {
+        let lock = self.lock_page(page_id, LockType::Write, true, reader, writer)?.unwrap();
+        let data = self.write_slot(lock.index);
+        self.page_table.state().dirty[lock.index as usize] = true;
+        Ok((lock, data))
+    }
+
+    /// Like get_page_mut, but returns None instead of waiting.
+    pub fn try_get_page_mut<'a, R, W>(&'a self, page_id: T, reader: R, writer: W) -> Result<Option<(PageLock<T>, PageWriteGuard<'a>)>>
+            where R: FnOnce(&mut [u8]) -> Result<()>, W: FnMut(&T, &[u8]) -> Result<()> {
+        match self.lock_page(page_id, LockType::Write, false, reader, writer)? {
+            Some(lock) => {
+                let data = self.write_slot(lock.index);
+                self.page_table.state().dirty[lock.index as usize] = true;
+                Ok(Some((lock, data)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Turn a read lock from get_page into a write lock, waiting until every
+    /// other lock on the page has been released. No other writer can modify
+    /// the page in the meantime, so the data read under the read lock is
+    /// still current once the upgrade is granted. The page is marked dirty.
+    /// If the upgrade fails, the read lock is released.
+    pub fn upgrade<'a>(&'a self, lock: PageLock<T>, data: PageReadGuard<'a>) -> Result<(PageLock<T>, PageWriteGuard<'a>)> {
+        // the slot has to be free for the writer once the upgrade is granted
+        ::std::mem::drop(data);
+        let page_id = lock.page_id.clone();
+        let deadline = self.lock_timeout.map(|timeout| Instant::now() + timeout);
+        let start = Instant::now();
+        let (sender, receiver) = channel();
+        let request = self.page_table.request_upgrade(lock, &sender)?;
+        let result = self.wait_for_lock(&page_id, request, &receiver, deadline);
+        self.page_table.state().record_wait(start.elapsed());
+        match result? {
+            Some(lock) => {
+                let data = self.write_slot(lock.index);
+                self.page_table.state().dirty[lock.index as usize] = true;
+                Ok((lock, data))
+            }
+            None => Err(Error::Custom(format!("upgrade of page {:?} was refused", page_id))),
+        }
+    }
+
+    /// Load a page ahead of its first use, if that can be done without
+    /// waiting, and pass its data to `inspect`, e.g. to find out which page
+    /// to prefetch next. Returns None if the page is locked for writing or
+    /// there's no room for it. A page that's already cached is only
+    /// inspected, and neither prefetching nor inspecting a page counts as a
+    /// use of it.
+    pub fn prefetch<R, W, F, U>(&self, page_id: T, reader: R, writer: W, inspect: F) -> Result<Option<U>>
+            where R: FnOnce(&mut [u8]) -> Result<()>, W: FnMut(&T, &[u8]) -> Result<()>, F: FnOnce(&[u8]) -> U {
+        let cached = self.page_table.state().peek(&page_id, current_owner());
+        let lock = match cached {
+            Some(lock) => lock,
+            None => match self.lock_page(page_id, LockType::Read, false, reader, writer)? {
+                Some(lock) => {
+                    if lock.load {
+                        self.page_table.state().mark_prefetched(lock.index);
+                    }
+                    lock
+                }
+                None => return Ok(None),
+            },
+        };
+        let data = self.read_slot(lock.index);
+        Ok(Some(inspect(&data)))
+    }
+
+    /// Pass every dirty page to the writer, marking each one clean once it's
+    /// written. Pages that are locked for writing are skipped, since they're
+    /// still being modified. Stops at the first error.
+    pub fn flush_all<W: FnMut(&T, &[u8]) -> Result<()>>(&self, writer: W) -> Result<()> {
+        self.flush_pages(|_| true, writer)
+    }
+
+    /// Like flush_all, but only flushes the dirty pages accepted by `filter`.
+    pub fn flush_pages<F, W>(&self, filter: F, mut writer: W) -> Result<()>
+            where F: Fn(&T) -> bool, W: FnMut(&T, &[u8]) -> Result<()> {
+        let locks: Vec<PageLock<T>> = {
+            let mut table = self.page_table.state();
+            let dirty = table.dirty_pages();
+            dirty.into_iter()
+                .filter(|&(ref page_id, _)| filter(page_id))
+                .filter_map(|(page_id, _)| table.try_lock(&page_id, &LockType::Read, current_owner()))
+                .collect()
+        };
+        for lock in locks {
+            let data = self.read_slot(lock.index);
+            writer(&lock.page_id, &data)?;
+            self.page_table.state().mark_clean(lock.index);
+        }
+        Ok(())
+    }
+
+    /// Whether a page is cached. Doesn't load or lock it.
+    pub fn contains_page(&self, page_id: &T) -> bool {
+        self.page_table.contains_page(page_id)
+    }
+
+    /// Report how working memory is being used.
+    pub fn stats(&self) -> CacheStats {
+        self.page_table.stats()
+    }
+
+    /// Drop every unlocked dirty page without writing it, so that the next
+    /// request reloads it.
+    pub fn discard_dirty(&self) {
+        self.discard_dirty_pages(|_| true)
+    }
+
+    /// Like discard_dirty, but only drops the pages accepted by `filter`.
+    pub fn discard_dirty_pages<F: Fn(&T) -> bool>(&self, filter: F) {
+        let mut table = self.page_table.state();
+        for (page_id, _) in table.dirty_pages() {
+            if filter(&page_id) {
+                table.remove_page(&page_id);
+            }
+        }
+    }
+
+    /// Discard a cached copy of a page after it has been modified on disk.
+    /// Any unwritten changes to it are lost. Returns false if the page is
+    /// locked and couldn't be discarded.
+    pub fn invalidate(&self, page_id: &T) -> bool {
+        self.page_table.remove_page(page_id)
+    }
+
+    /// Discard every cached page accepted by `filter`. Returns false if any of
+    /// them were locked and couldn't be discarded.
+    pub fn invalidate_pages<F: Fn(&T) -> bool>(&self, filter: F) -> bool {
+        let mut table = self.page_table.state();
+        let pages: Vec<T> = table.page_map.keys().filter(|id| filter(id)).cloned().collect();
+        let mut removed = true;
+        for page_id in pages {
+            removed &= table.remove_page(&page_id);
+        }
+        removed
+    }
+
+    /// Lock a page, loading it if necessary and writing back the page it
+    /// replaces if that page is dirty. Returns None if the lock can't be
+    /// granted immediately and `wait` is false.
+    fn lock_page<R, W>(&self, page_id: T, lock_type: LockType, wait: bool, reader: R, mut writer: W) -> Result<Option<PageLock<T>>>
+            where R: FnOnce(&mut [u8]) -> Result<()>, W: FnMut(&T, &[u8]) -> Result<()> {
+        if self.slots.is_empty() {
+            return Err(Error::Custom("working memory is too small to hold a page".to_string()));
+        }
+        let owner = current_owner();
+        let deadline = self.lock_timeout.map(|timeout| Instant::now() + timeout);
+        let mut queued: Option<Instant> = None;
+        let lock = loop {
+            let (sender, receiver) = channel();
+            let (victim, request) = {
+                let mut table = self.page_table.state();
+                match table.dirty_victim(&page_id, owner) {
+                    Some(victim) => (Some(victim), 0),
+                    None => {
+                        if let Some(lock) = table.try_lock(&page_id, &lock_type, owner) {
+                            break lock;
                         }
-                        index
+                        if !wait {
+                            return Ok(None);
+                        }
+                        let request = table.request_lock(&page_id, &lock_type, owner, &sender)?;
+                        if queued.is_none() {
+                            queued = Some(Instant::now());
+                        }
+                        (None, request)
                     }
-                };
-                let buf = &mut self.page_data[(index*PAGE_SIZE) as usize .. ((index+1)*PAGE_SIZE) as usize];
-                Ok(Some((lock, buf)))
+                }
+            };
+            match victim {
+                Some((expired, victim)) => {
+                    // write back the dirty page, then take its place
+                    let data = self.read_slot(victim.index);
+                    writer(&expired, &data)?;
+                    let mut table = self.page_table.state();
+                    table.dirty[victim.index as usize] = false;
+                    if let Some(lock) = table.replace_victim(victim, &page_id, &lock_type) {
+                        break lock;
+                    }
+                }
+                None => match self.wait_for_lock(&page_id, request, &receiver, deadline) {
+                    Ok(Some(lock)) => break lock,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        if let Some(start) = queued {
+                            self.page_table.state().record_wait(start.elapsed());
+                        }
+                        return Err(e);
+                    }
+                },
             }
-            None => Ok(None),
+        };
+        if let Some(start) = queued {
+            self.page_table.state().record_wait(start.elapsed());
+        }
+
+        if lock.load {
+            // load from the provided buffer
+            let result = reader(&mut self.write_slot(lock.index));
+            if let Err(e) = result {
+                self.page_table.state().abort_load(lock);
+                return Err(e);
+            }
+            self.page_table.state().finish_load(&page_id);
         }
+        Ok(Some(lock))
+    }
+
+    /// Wait for the response to a queued lock request. If the deadline passes
+    /// first, the request is cancelled and a LockTimeout error is returned.
+    fn wait_for_lock(&self, page_id: &T, request: u64, receiver: &Receiver<Option<PageLock<T>>>, deadline: Option<Instant>) -> Result<Option<PageLock<T>>> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return receiver.recv()
+                .map_err(|_| Error::Custom("page table was dropped while waiting for a lock".to_string())),
+        };
+        let now = Instant::now();
+        let remaining = if deadline > now {deadline - now} else {Duration::from_secs(0)};
+        match receiver.recv_timeout(remaining) {
+            Ok(response) => Ok(response),
+            Err(RecvTimeoutError::Timeout) => {
+                self.page_table.cancel_request(page_id, request);
+                // the lock may have been granted just before the request was
+                // cancelled
+                match receiver.try_recv() {
+                    Ok(Some(lock)) => Ok(Some(lock)),
+                    _ => Err(Error::LockTimeout(format!("timed out waiting for a lock on page {:?}", page_id))),
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Custom("page table was dropped while waiting for a lock".to_string())),
+        }
+    }
+
+    fn read_slot(&self, index: u64) -> PageReadGuard<'_> {
+        self.slots[index as usize].read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_slot(&self, index: u64) -> PageWriteGuard<'_> {
+        self.slots[index as usize].write().unwrap_or_else(|e| e.into_inner())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    fn no_writer(page_id: &u8, buf: &[u8]) -> Result<()> {
+        panic!("wrote page {} when nothing should be dirty", page_id);
+    }
 
     fn check_page_index(cache: &mut PageTable<u8>, id: u8, expected_index: u64) -> Option<PageLock<u8>> {
-        cache.tick();
-        let result = cache.page_index(id, LockType::Read);
+        let result = cache.page_index(id, LockType::Read, 0);
         match result {
             Some(PageLock {index, ..}) => {
                 assert_eq!(expected_index, index);
@@ -451,20 +1250,40 @@ mod tests {
         assert!(!cache.contains_page(&5));
     }
 
+    #[test]
+    fn test_eviction_policies() {
+        // a few hot pages are used over and over, then a scan passes through
+        // far more pages than fit in memory
+        let mut trace: Vec<u8> = vec![];
+        for _ in 0 .. 10 {
+            trace.extend(0 .. 4);
+        }
+        trace.extend(100 .. 200);
+        let hot_hits = |policy| {
+            let cache = PageTable::with_policy(PAGE_SIZE * 8, policy);
+            assert_eq!(cache.replay(&trace), TraceResult {hits: 36, misses: 104});
+            cache.replay(&[0, 1, 2, 3]).hits
+        };
+        assert_eq!(hot_hits(EvictionPolicyType::Lru), 0);
+        assert_eq!(hot_hits(EvictionPolicyType::Clock), 0);
+        // only the scan-resistant policy keeps the hot pages cached
+        assert_eq!(hot_hits(EvictionPolicyType::LruK(2)), 4);
+    }
+
     #[test]
     fn test_get_page() {
         let mut working_memory = WorkingMemory::new(PAGE_SIZE * 3);
 
         // check that the result buffer includes data from the reader
         {
-            let reader = |buf: &mut [u8]| buf[0] = 5;
-            let (lock, buf) = working_memory.get_page(0_u8, reader).unwrap().unwrap();
+            let reader = |buf: &mut [u8]| {buf[0] = 5; Ok(())};
+            let (lock, buf) = working_memory.get_page(0_u8, reader, no_writer).unwrap();
             assert_eq!(buf[0], 5);
             assert_eq!(buf[1], 0);
         }
         {
-            let reader = |buf: &mut [u8]| buf[0] = 8;
-            let (lock, buf) = working_memory.get_page(1_u8, reader).unwrap().unwrap();
+            let reader = |buf: &mut [u8]| {buf[0] = 8; Ok(())};
+            let (lock, buf) = working_memory.get_page(1_u8, reader, no_writer).unwrap();
             assert_eq!(buf[0], 8);
             assert_eq!(buf[1], 0);
         }
@@ -472,60 +1291,457 @@ mod tests {
         // these readers shouldn't be used because the page is still cached
         {
             let reader = |buf: &mut [u8]| panic!("reading when page should be cached");
-            let (lock, buf) = working_memory.get_page(0_u8, reader).unwrap().unwrap();
+            let (lock, buf) = working_memory.get_page(0_u8, reader, no_writer).unwrap();
             assert_eq!(buf[0], 5);
             assert_eq!(buf[1], 0);
         }
         {
             let reader = |buf: &mut [u8]| panic!("reading when page should be cached");
-            let (lock, buf) = working_memory.get_page(1_u8, reader).unwrap().unwrap();
+            let (lock, buf) = working_memory.get_page(1_u8, reader, no_writer).unwrap();
             assert_eq!(buf[0], 8);
             assert_eq!(buf[1], 0);
         }
     }
 
+    #[test]
+    fn test_invalidate() {
+        let mut working_memory = WorkingMemory::new(PAGE_SIZE * 3);
+        let lock = {
+            let (lock, buf) = working_memory.get_page(0_u8, |buf: &mut [u8]| {buf[0] = 5; Ok(())}, no_writer).unwrap();
+            assert_eq!(buf[0], 5);
+            lock
+        };
+        // locked pages can't be discarded
+        assert!(!working_memory.invalidate(&0));
+        ::std::mem::drop(lock);
+        assert!(working_memory.invalidate(&0));
+        // the next request reloads the page
+        let (lock, buf) = working_memory.get_page(0_u8, |buf: &mut [u8]| {buf[0] = 6; Ok(())}, no_writer).unwrap();
+        assert_eq!(buf[0], 6);
+    }
+
+    #[test]
+    fn test_get_page_error() {
+        let mut working_memory = WorkingMemory::new(PAGE_SIZE * 3);
+        let result = working_memory.get_page(0_u8, |buf: &mut [u8]| Err(Error::IoError("bad page".to_string())), no_writer);
+        assert_eq!(result.err(), Some(Error::IoError("bad page".to_string())));
+        // the failed page isn't cached, so the next request tries again
+        let (lock, buf) = working_memory.get_page(0_u8, |buf: &mut [u8]| {buf[0] = 1; Ok(())}, no_writer).unwrap();
+        assert_eq!(buf[0], 1);
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        let mut working_memory = WorkingMemory::new(PAGE_SIZE * 2);
+        let mut written = vec![];
+        {
+            let (lock, mut buf) = working_memory.get_page_mut(0_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+            assert_eq!(lock.lock_type, LockType::Write);
+            buf[0] = 1;
+        }
+        working_memory.get_page(1_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+
+        // a failed write-back leaves the dirty page cached
+        let fail = |page_id: &u8, buf: &[u8]| Err(Error::IoError("disk full".to_string()));
+        assert!(working_memory.get_page(2_u8, |buf: &mut [u8]| panic!("loaded page 2"), fail).is_err());
+        {
+            let (lock, buf) = working_memory.get_page(0_u8, |buf: &mut [u8]| panic!("reloaded page 0"), no_writer).unwrap();
+            assert_eq!(buf[0], 1);
+        }
+
+        // evicting the dirty page writes it back; page 1 is now the least
+        // recently used, and is clean
+        working_memory.get_page(2_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+        working_memory.get_page(3_u8, |buf: &mut [u8]| Ok(()), |page_id: &u8, buf: &[u8]| {
+            written.push((*page_id, buf[0]));
+            Ok(())
+        }).unwrap();
+        assert_eq!(written, vec![(0, 1)]);
+
+        // flushing writes every dirty page once
+        written.clear();
+        for &page_id in &[2_u8, 3] {
+            let (lock, mut buf) = working_memory.get_page_mut(page_id, |buf: &mut [u8]| panic!("page should be cached"), no_writer).unwrap();
+            buf[0] = page_id * 10;
+        }
+        working_memory.flush_all(|page_id: &u8, buf: &[u8]| {
+            written.push((*page_id, buf[0]));
+            Ok(())
+        }).unwrap();
+        written.sort();
+        assert_eq!(written, vec![(2, 20), (3, 30)]);
+        working_memory.flush_all(no_writer).unwrap();
+
+        // discarded changes are reloaded
+        {
+            let (lock, mut buf) = working_memory.get_page_mut(2_u8, |buf: &mut [u8]| panic!("page should be cached"), no_writer).unwrap();
+            buf[0] = 99;
+        }
+        working_memory.discard_dirty();
+        let (lock, buf) = working_memory.get_page(2_u8, |buf: &mut [u8]| {buf[0] = 20; Ok(())}, no_writer).unwrap();
+        assert_eq!(buf[0], 20);
+    }
+
+    #[test]
+    fn test_shared_memory() {
+        let memory = Arc::new(WorkingMemory::new(PAGE_SIZE));
+        let (lock, mut buf) = memory.get_page_mut(0_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+        buf[0] = 1;
+        assert!(memory.try_get_page(0_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap().is_none());
+
+        // a blocked reader is woken as soon as the write lock is dropped
+        let reader = {
+            let memory = memory.clone();
+            thread::spawn(move || {
+                let (lock, buf) = memory.get_page(0_u8, |buf: &mut [u8]| panic!("page should be cached"), no_writer).unwrap();
+                buf[0]
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        buf[0] = 2;
+        ::std::mem::drop(buf);
+        ::std::mem::drop(lock);
+        assert_eq!(reader.join().unwrap(), 2);
+
+        // with the only slot locked, loading another page waits for it
+        let (lock, buf) = memory.get_page(0_u8, |buf: &mut [u8]| panic!("page should be cached"), no_writer).unwrap();
+        assert!(memory.try_get_page(1_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap().is_none());
+        let loader = {
+            let memory = memory.clone();
+            thread::spawn(move || {
+                let written = Mutex::new(vec![]);
+                let (lock, buf) = memory.get_page(1_u8, |buf: &mut [u8]| {buf[0] = 5; Ok(())}, |page_id: &u8, buf: &[u8]| {
+                    written.lock().unwrap().push((*page_id, buf[0]));
+                    Ok(())
+                }).unwrap();
+                (buf[0], written.into_inner().unwrap())
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        ::std::mem::drop(buf);
+        ::std::mem::drop(lock);
+        assert_eq!(loader.join().unwrap(), (5, vec![(0, 2)]));
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        // more pages than slots, so pages are constantly written back and
+        // reloaded while other threads are using them
+        let memory = Arc::new(WorkingMemory::new(PAGE_SIZE * 2));
+        let disk = Arc::new(Mutex::new(HashMap::new()));
+        let threads: Vec<_> = (0 .. 4).map(|t| {
+            let memory = memory.clone();
+            let disk = disk.clone();
+            thread::spawn(move || {
+                for i in 0 .. 200 {
+                    let page_id = ((t + i) % 4) as u8;
+                    let reader = |buf: &mut [u8]| {
+                        let count = disk.lock().unwrap().get(&page_id).cloned().unwrap_or(0);
+                        LittleEndian::write_u64(buf, count);
+                        Ok(())
+                    };
+                    let writer = |page_id: &u8, buf: &[u8]| {
+                        disk.lock().unwrap().insert(*page_id, LittleEndian::read_u64(buf));
+                        Ok(())
+                    };
+                    let (lock, mut buf) = memory.get_page_mut(page_id, reader, writer).unwrap();
+                    let count = LittleEndian::read_u64(&buf);
+                    LittleEndian::write_u64(&mut buf, count + 1);
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        memory.flush_all(|page_id: &u8, buf: &[u8]| {
+            disk.lock().unwrap().insert(*page_id, LittleEndian::read_u64(buf));
+            Ok(())
+        }).unwrap();
+        let disk = disk.lock().unwrap();
+        assert_eq!((0 .. 4).map(|p| disk[&p]).collect::<Vec<u64>>(), vec![200, 200, 200, 200]);
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let memory = Arc::new(WorkingMemory::new(PAGE_SIZE * 2));
+        memory.get_page(0_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+        memory.get_page(1_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+        memory.get_page(0_u8, |buf: &mut [u8]| panic!("page should be cached"), no_writer).unwrap();
+        memory.get_page(2_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+        let stats = memory.stats();
+        assert_eq!((stats.pages, stats.cached_pages), (2, 2));
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 3, 1));
+        assert_eq!(stats.hit_rate(), 0.25);
+
+        // a locked, modified page, and a reader queued behind it
+        let (lock, buf) = memory.get_page_mut(0_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+        let reader = {
+            let memory = memory.clone();
+            thread::spawn(move || {
+                memory.get_page(0_u8, |buf: &mut [u8]| panic!("page should be cached"), no_writer).unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        let stats = memory.stats();
+        assert_eq!((stats.dirty_pages, stats.pinned_pages, stats.queued_requests), (1, 1, 1));
+        assert_eq!(stats.longest_lock_wait, Duration::from_secs(0));
+
+        ::std::mem::drop(buf);
+        ::std::mem::drop(lock);
+        reader.join().unwrap();
+        let stats = memory.stats();
+        assert_eq!((stats.pinned_pages, stats.queued_requests), (0, 0));
+        assert!(stats.longest_lock_wait >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let memory = WorkingMemory::new(PAGE_SIZE * 2);
+        let fill = |buf: &mut [u8]| {
+            buf[0] = 7;
+            Ok(())
+        };
+        assert_eq!(memory.prefetch(0_u8, fill, no_writer, |buf| buf[0]), Ok(Some(7)));
+        // prefetching a cached page doesn't reload it, and doesn't count as
+        // a use of it
+        let cached = |buf: &mut [u8]| panic!("page should be cached");
+        assert_eq!(memory.prefetch(0_u8, cached, no_writer, |buf| buf[0]), Ok(Some(7)));
+        let stats = memory.stats();
+        assert_eq!((stats.hits, stats.misses, stats.prefetched_pages, stats.prefetch_hits), (0, 0, 1, 0));
+
+        // the first use of a prefetched page is a hit
+        memory.get_page(0_u8, cached, no_writer).unwrap();
+        memory.get_page(0_u8, cached, no_writer).unwrap();
+        let stats = memory.stats();
+        assert_eq!((stats.hits, stats.misses, stats.prefetched_pages, stats.prefetch_hits), (2, 0, 1, 1));
+
+        // prefetches don't wait for locks or for room
+        let (lock, buf) = memory.get_page_mut(0_u8, cached, |_: &u8, _: &[u8]| Ok(())).unwrap();
+        assert_eq!(memory.prefetch(0_u8, cached, no_writer, |buf| buf[0]), Ok(None));
+        let _page1 = memory.get_page(1_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+        assert_eq!(memory.prefetch(2_u8, cached, no_writer, |buf| buf[0]), Ok(None));
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let cache = PageTable::new(PAGE_SIZE * 4);
+        let (sender, receiver) = channel();
+
+        // owners 1 and 2 each hold a page the other wants
+        let page0 = cache.try_lock(&0_u8, &LockType::Write, 1).unwrap();
+        let page1 = cache.try_lock(&1, &LockType::Write, 2).unwrap();
+        assert!(cache.request_lock(&1, &LockType::Write, 1, &sender).is_ok());
+        match cache.request_lock(&0, &LockType::Write, 2, &sender) {
+            Err(Error::Deadlock(_)) => {}
+            r => panic!("expected a deadlock, got {:?}", r),
+        }
+        // the victim's request was never queued; once it gives up its lock,
+        // the other owner's request is granted
+        assert_eq!(cache.stats().queued_requests, 1);
+        ::std::mem::drop(page1);
+        let page1 = receiver.try_recv().unwrap().unwrap();
+        assert_eq!((page1.page_id, page1.owner), (1, 1));
+        ::std::mem::drop(page1);
+
+        // a cycle through a queue: 4 waits behind a writer for 5's page, and
+        // 5 asks for a page that 4 holds
+        let page2 = cache.try_lock(&2, &LockType::Read, 4).unwrap();
+        let page3 = cache.try_lock(&3, &LockType::Read, 5).unwrap();
+        assert!(cache.request_lock(&3, &LockType::Write, 6, &sender).is_ok());
+        assert!(cache.request_lock(&3, &LockType::Read, 4, &sender).is_ok());
+        match cache.request_lock(&2, &LockType::Write, 5, &sender) {
+            Err(Error::Deadlock(_)) => {}
+            r => panic!("expected a deadlock, got {:?}", r),
+        }
+        // several owners waiting for one page isn't a deadlock
+        assert!(cache.request_lock(&2, &LockType::Write, 7, &sender).is_ok());
+        assert!(cache.request_lock(&2, &LockType::Read, 8, &sender).is_ok());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_deadlocked_threads() {
+        let memory = Arc::new(WorkingMemory::new(PAGE_SIZE * 4));
+        let barrier = Arc::new(Barrier::new(2));
+        let threads: Vec<_> = (0 .. 2).map(|t| {
+            let memory = memory.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let first = memory.get_page_mut(t as u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+                barrier.wait();
+                let second = memory.get_page_mut(1 - t as u8, |buf: &mut [u8]| Ok(()), no_writer);
+                second.map(|_| ())
+            })
+        }).collect();
+        let mut results: Vec<Result<()>> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        // exactly one thread is chosen as the victim, letting the other finish
+        results.sort_by_key(|r| r.is_ok());
+        match results[0] {
+            Err(Error::Deadlock(_)) => {}
+            ref r => panic!("expected a deadlock, got {:?}", r),
+        }
+        assert_eq!(results[1], Ok(()));
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let mut memory = WorkingMemory::new(PAGE_SIZE * 2);
+        memory.set_lock_timeout(Some(Duration::from_millis(20)));
+        let memory = Arc::new(memory);
+        let (lock, buf) = memory.get_page_mut(0_u8, |buf: &mut [u8]| Ok(()), no_writer).unwrap();
+        let waiter = {
+            let memory = memory.clone();
+            thread::spawn(move || memory.get_page(0_u8, |buf: &mut [u8]| Ok(()), no_writer).map(|_| ()))
+        };
+        match waiter.join().unwrap() {
+            Err(Error::LockTimeout(_)) => {}
+            r => panic!("expected a timeout, got {:?}", r),
+        }
+        // the request was withdrawn
+        assert_eq!(memory.stats().queued_requests, 0);
+        assert!(memory.stats().longest_lock_wait >= Duration::from_millis(20));
+        ::std::mem::drop(buf);
+        ::std::mem::drop(lock);
+        assert!(memory.get_page(0_u8, |buf: &mut [u8]| panic!("page should be cached"), no_writer).is_ok());
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let cache = PageTable::new(PAGE_SIZE * 2);
+        let (sender, receiver) = channel();
+
+        // the only reader is upgraded immediately
+        let lock = cache.try_lock(&0_u8, &LockType::Read, 1).unwrap();
+        cache.request_upgrade(lock, &sender).unwrap();
+        let lock = receiver.try_recv().unwrap().unwrap();
+        assert_eq!(lock.lock_type, LockType::Write);
+        assert_eq!(cache.state().reader_count.active(&0), 0);
+        assert_eq!(cache.state().writer_count.active(&0), 1);
+        ::std::mem::drop(lock);
+
+        // otherwise it waits for the other readers, ahead of anyone queued
+        let read1 = cache.try_lock(&0, &LockType::Read, 1).unwrap();
+        let read2 = cache.try_lock(&0, &LockType::Read, 2).unwrap();
+        let (sender3, receiver3) = channel();
+        cache.request_lock(&0, &LockType::Write, 3, &sender3).unwrap();
+        cache.request_upgrade(read1, &sender).unwrap();
+        assert!(receiver.try_recv().is_err());
+        ::std::mem::drop(read2);
+        let upgraded = receiver.try_recv().unwrap().unwrap();
+        assert_eq!(upgraded.owner, 1);
+        assert!(receiver3.try_recv().is_err());
+        ::std::mem::drop(upgraded);
+        let write3 = receiver3.try_recv().unwrap().unwrap();
+        ::std::mem::drop(write3);
+
+        // two readers can't both upgrade; the second is refused, and its
+        // read lock released, so the first can go ahead
+        let read1 = cache.try_lock(&0, &LockType::Read, 1).unwrap();
+        let read2 = cache.try_lock(&0, &LockType::Read, 2).unwrap();
+        cache.request_upgrade(read1, &sender).unwrap();
+        match cache.request_upgrade(read2, &sender3) {
+            Err(Error::Deadlock(_)) => {}
+            r => panic!("expected a deadlock, got {:?}", r),
+        }
+        assert_eq!(receiver.try_recv().unwrap().unwrap().owner, 1);
+        assert!(receiver3.try_recv().is_err());
+
+        // only read locks can be upgraded
+        let write = cache.try_lock(&1, &LockType::Write, 1).unwrap();
+        assert!(cache.request_upgrade(write, &sender).is_err());
+    }
+
+    #[test]
+    fn test_fairness() {
+        let cache = PageTable::new(PAGE_SIZE * 2);
+        let (sender, receiver) = channel();
+
+        // once a writer is waiting, new readers queue behind it rather than
+        // sharing the page with the current readers
+        let read = cache.try_lock(&0_u8, &LockType::Read, 1).unwrap();
+        cache.request_lock(&0, &LockType::Write, 2, &sender).unwrap();
+        for owner in 3 .. 6 {
+            assert!(cache.try_lock(&0, &LockType::Read, owner).is_none());
+            cache.request_lock(&0, &LockType::Read, owner, &sender).unwrap();
+        }
+        assert!(receiver.try_recv().is_err());
+        ::std::mem::drop(read);
+        let write = receiver.try_recv().unwrap().unwrap();
+        assert_eq!((write.owner, write.lock_type.clone()), (2, LockType::Write));
+        assert!(receiver.try_recv().is_err());
+
+        // readers queued behind one writer are all let in before the next
+        let (sender2, receiver2) = channel();
+        cache.request_lock(&0, &LockType::Write, 6, &sender2).unwrap();
+        cache.request_lock(&0, &LockType::Read, 7, &sender).unwrap();
+        ::std::mem::drop(write);
+        let readers: Vec<PageLock<u8>> = receiver.try_iter().map(|l| l.unwrap()).collect();
+        assert_eq!(readers.iter().map(|l| l.owner).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert!(receiver2.try_recv().is_err());
+        ::std::mem::drop(readers);
+        let write = receiver2.try_recv().unwrap().unwrap();
+        assert_eq!(write.owner, 6);
+        assert!(receiver.try_recv().is_err());
+        ::std::mem::drop(write);
+        assert_eq!(receiver.try_recv().unwrap().unwrap().owner, 7);
+    }
+
+    #[test]
+    fn test_upgrade_page() {
+        let memory = WorkingMemory::new(PAGE_SIZE * 2);
+        let (lock, buf) = memory.get_page(0_u8, |buf: &mut [u8]| {buf[0] = 1; Ok(())}, no_writer).unwrap();
+        let value = buf[0];
+        let (lock, mut buf) = memory.upgrade(lock, buf).unwrap();
+        buf[0] = value + 1;
+        ::std::mem::drop(buf);
+        ::std::mem::drop(lock);
+        assert_eq!(memory.stats().dirty_pages, 1);
+        let (lock, buf) = memory.get_page(0_u8, |buf: &mut [u8]| panic!("page should be cached"), no_writer).unwrap();
+        assert_eq!(buf[0], 2);
+    }
+
     #[test]
     fn test_get_lock() {
         let mut cache = PageTable::new(PAGE_SIZE * 3);
-        let (mut sender, mut receiver) = channel();
-        let (mut sender2, mut receiver2) = channel();
-        let (mut sender3, mut receiver3) = channel();
+        let (sender, receiver) = channel();
+        let (sender2, receiver2) = channel();
+        let (sender3, receiver3) = channel();
 
         {
-            cache.request_lock(&0, &LockType::Read, &mut sender);
+            cache.request_lock(&0, &LockType::Read, 1, &sender);
             let result = receiver.try_recv();
             assert!(result.is_ok());
         }
 
         {
-            cache.request_lock(&0, &LockType::Read, &mut sender);
+            cache.request_lock(&0, &LockType::Read, 1, &sender);
             let page0_1 = receiver.try_recv();
             assert!(page0_1.is_ok());
 
-            cache.request_lock(&0, &LockType::Read, &mut sender);
+            cache.request_lock(&0, &LockType::Read, 1, &sender);
             let page0_2 = receiver.try_recv();
             assert!(page0_2.is_ok());
 
-            cache.request_lock(&1, &LockType::Read, &mut sender);
+            cache.request_lock(&1, &LockType::Read, 1, &sender);
             let page1_1 = receiver.try_recv();
             assert!(page1_1.is_ok());
 
-            cache.request_lock(&2, &LockType::Read, &mut sender);
+            cache.request_lock(&2, &LockType::Read, 1, &sender);
             let page2_1 = receiver.try_recv();
             assert!(page2_1.is_ok());
 
             // too many cached pages; can't get this one immediately
-            cache.request_lock(&3, &LockType::Read, &mut sender2);
+            cache.request_lock(&3, &LockType::Read, 4, &sender2);
             let r = receiver2.try_recv();
             assert!(r.is_err());
 
             // this one is still cached so we can get additional read locks
-            cache.request_lock(&0, &LockType::Read, &mut sender);
+            cache.request_lock(&0, &LockType::Read, 1, &sender);
             let page0_3 = receiver.try_recv();
             assert!(page0_3.is_ok());
 
             // can't get a write lock while there are active read locks
-            cache.request_lock(&0, &LockType::Write, &mut sender);
+            cache.request_lock(&0, &LockType::Write, 2, &sender);
             let result = receiver.try_recv();
             assert!(result.is_err());
 
@@ -538,16 +1754,16 @@ mod tests {
             ::std::mem::drop(page1_1);
 
             // first writer succeeds; can't get a second concurrent writer
-            cache.request_lock(&0, &LockType::Write, &mut sender3);
-            assert_eq!(cache.reader_count.active(&0), 0);
-            assert_eq!(cache.writer_count.active(&0), 1);
+            cache.request_lock(&0, &LockType::Write, 3, &sender3);
+            assert_eq!(cache.state().reader_count.active(&0), 0);
+            assert_eq!(cache.state().writer_count.active(&0), 1);
             let result = receiver3.try_recv();
             assert!(result.is_err());
             let page0_w1 = receiver.try_recv();
             assert!(page0_w1.is_ok());
 
             // can't get a concurrent read lock
-            cache.request_lock(&0, &LockType::Read, &mut sender);
+            cache.request_lock(&0, &LockType::Read, 1, &sender);
             let page0_4 = receiver.try_recv();
             assert!(page0_4.is_err());
         }
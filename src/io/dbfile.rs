@@ -1,144 +1,1074 @@
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use memmap::Mmap;
+use ::io::heap::{HeapPage, MAX_RECORD_SIZE, OVERFLOW_DATA_SIZE, OVERFLOW_RECORD_SIZE, RECORD_INLINE, RECORD_OVERFLOW};
 use ::io::page::{Page, PageId, PAGE_SIZE};
+use ::io::wal::{self, Recovered, SyncPolicy, WriteAheadLog, CHECKPOINT_SIZE};
 use ::{Result, Error};
 
-trait BinaryStruct: Sized {
-    fn from_bytes(data: &[u8]) -> Self {
-        unsafe {
-            ::std::ptr::read(data.as_ptr() as *const _)
-        }
+/// Identifies a soupdb file; stored at the start of the DbMetadata.
+pub const MAGIC: &[u8; 8] = b"SOUPDB\0\0";
+/// Incremented whenever the on-disk layout changes incompatibly.
+pub const FORMAT_VERSION: u32 = 4;
+
+pub const PAGE_METADATA_SIZE: usize = 24;
+/// Offset of the checksum within the PageMetadata.
+const CHECKSUM_OFFSET: usize = 16;
+pub const DB_METADATA_SIZE: usize = 48;
+/// Size of the header at the start of page 0.
+pub const HEADER_SIZE: usize = PAGE_METADATA_SIZE + DB_METADATA_SIZE;
+
+/// A segment of metadata contained on every page, stored as little-endian
+/// page IDs followed by a CRC32 of the rest of the page and 4 reserved bytes.
+/// The checksum is filled in by DbFile when the page is written.
+#[derive(Debug, PartialEq)]
+pub struct PageMetadata {
+    pub prev_page: PageId,
+    pub next_page: PageId,
+    pub checksum: u32,
+}
+
+impl PageMetadata {
+    pub fn new(prev_page: PageId, next_page: PageId) -> PageMetadata {
+        PageMetadata {prev_page, next_page, checksum: 0}
     }
 
-    fn to_bytes<'a>(&'a self) -> &'a [u8] {
-        unsafe {
-            ::std::slice::from_raw_parts(
-                (self as *const Self) as *const u8,
-                ::std::mem::size_of::<Self>(),
-            )
+    pub fn from_bytes(data: &[u8]) -> PageMetadata {
+        PageMetadata {
+            prev_page: LittleEndian::read_u64(&data[0 ..]),
+            next_page: LittleEndian::read_u64(&data[8 ..]),
+            checksum: LittleEndian::read_u32(&data[CHECKSUM_OFFSET ..]),
         }
     }
 
-    fn write_to_buf(&self, mut buf: &mut [u8]) {
-        buf.write(self.to_bytes());
+    pub fn write_to_buf(&self, buf: &mut [u8]) {
+        LittleEndian::write_u64(&mut buf[0 ..], self.prev_page);
+        LittleEndian::write_u64(&mut buf[8 ..], self.next_page);
+        LittleEndian::write_u32(&mut buf[CHECKSUM_OFFSET ..], self.checksum);
+        LittleEndian::write_u32(&mut buf[CHECKSUM_OFFSET + 4 ..], 0);
     }
-}
-
-/// A segment of metadata contained on every page.
-#[repr(C)]
-pub struct PageMetadata {
-    prev_page: PageId,
-    next_page: PageId,
-}
 
-impl PageMetadata {
     pub fn from_page(page_data: &Page) -> PageMetadata {
-        let meta: PageMetadata = unsafe {
-            ::std::ptr::read(page_data.as_ptr() as *const _)
-        };
-        return meta;
+        PageMetadata::from_bytes(page_data)
     }
-}
 
-impl BinaryStruct for PageMetadata {}
+    pub fn write_to_page(&self, page_data: &mut Page) {
+        self.write_to_buf(page_data);
+    }
+}
 
 /// A segment of DB metadata that follows the PageMetadata of the first page in
-/// a DB file.
-#[repr(C)]
+/// a DB file. Freed pages form a list linked by their `next_page`, starting
+/// at `first_free_page`; page 0 is never free, so it marks the end of the
+/// list. The pages of the file's heap form a list starting at `heap_page`,
+/// linked in both directions.
+///
+/// Layout (little-endian):
+///
+/// | offset | size | field           |
+/// |--------|------|-----------------|
+/// | 0      | 8    | magic           |
+/// | 8      | 4    | format version  |
+/// | 12     | 4    | reserved        |
+/// | 16     | 8    | page size       |
+/// | 24     | 8    | first free page |
+/// | 32     | 8    | last page       |
+/// | 40     | 8    | first heap page |
+#[derive(Debug, PartialEq, Clone)]
 pub struct DbMetadata {
+    magic: [u8; 8],
+    version: u32,
+    page_size: u64,
     first_free_page: PageId,
     last_page: PageId,
+    heap_page: PageId,
+}
+
+impl DbMetadata {
+    pub fn new(first_free_page: PageId, last_page: PageId) -> DbMetadata {
+        DbMetadata {
+            magic: *MAGIC,
+            version: FORMAT_VERSION,
+            page_size: PAGE_SIZE,
+            first_free_page,
+            last_page,
+            heap_page: 0,
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> DbMetadata {
+        let mut magic = [0; 8];
+        magic.copy_from_slice(&data[0 .. 8]);
+        DbMetadata {
+            magic,
+            version: LittleEndian::read_u32(&data[8 ..]),
+            page_size: LittleEndian::read_u64(&data[16 ..]),
+            first_free_page: LittleEndian::read_u64(&data[24 ..]),
+            last_page: LittleEndian::read_u64(&data[32 ..]),
+            heap_page: LittleEndian::read_u64(&data[40 ..]),
+        }
+    }
+
+    pub fn write_to_buf(&self, buf: &mut [u8]) {
+        buf[0 .. 8].copy_from_slice(&self.magic);
+        LittleEndian::write_u32(&mut buf[8 ..], self.version);
+        LittleEndian::write_u32(&mut buf[12 ..], 0);
+        LittleEndian::write_u64(&mut buf[16 ..], self.page_size);
+        LittleEndian::write_u64(&mut buf[24 ..], self.first_free_page);
+        LittleEndian::write_u64(&mut buf[32 ..], self.last_page);
+        LittleEndian::write_u64(&mut buf[40 ..], self.heap_page);
+    }
+
+    /// Check that a file was written in a format this build can read.
+    fn validate(&self) -> ::std::result::Result<(), String> {
+        if &self.magic != MAGIC {
+            Err("not a soupdb file".to_string())
+        } else if self.version != FORMAT_VERSION {
+            Err(format!("unsupported format version {} (expected {})", self.version, FORMAT_VERSION))
+        } else if self.page_size != PAGE_SIZE {
+            Err(format!("file uses a page size of {} bytes (expected {})", self.page_size, PAGE_SIZE))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Computes the CRC32 of a page, skipping the checksum stored in its
+/// metadata.
+pub fn page_checksum(page_data: &Page) -> u32 {
+    let crc = crc32::update(0, &crc32::IEEE_TABLE, &page_data[.. CHECKSUM_OFFSET]);
+    crc32::update(crc, &crc32::IEEE_TABLE, &page_data[CHECKSUM_OFFSET + 4 ..])
 }
 
-impl BinaryStruct for DbMetadata {}
+/// The first page after the header, which is allocated when the file is
+/// created and holds the start of a model's data.
+pub const FIRST_DATA_PAGE: PageId = 1;
+
+/// Whether a DbFile can be modified.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AccessMode {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Pages written since a transaction began, and the metadata to restore if
+/// it's rolled back.
+struct Transaction {
+    pages: BTreeMap<PageId, Vec<u8>>,
+    meta: DbMetadata,
+}
 
+/// A file of pages. Writes are grouped into transactions, which are recorded
+/// in a write-ahead log before any of their pages are written to the file;
+/// pages that have been committed but not yet applied are read from memory.
+/// Opening a file replays every committed transaction in its log.
 pub struct DbFile {
     name: String,
+    path: PathBuf,
+    mode: AccessMode,
     meta: DbMetadata,
     handle: File,
+    /// None when the file is read-only.
+    wal: Option<WriteAheadLog>,
+    txn: Option<Transaction>,
+    committed: BTreeMap<PageId, Vec<u8>>,
+    /// a read-only map of the file, created by the first read_mapped and
+    /// replaced when the file grows past it
+    map: Option<Mmap>,
 }
 
 impl DbFile {
     pub fn create(name: String, path: &Path) -> Result<DbFile> {
-        let meta = DbMetadata {
-            first_free_page: 1,
-            last_page: 1,
+        let meta = DbMetadata::new(0, FIRST_DATA_PAGE);
+        let page_meta = PageMetadata::new(0, 0);
+
+        let mut header = vec![0; PAGE_SIZE as usize];
+        page_meta.write_to_buf(&mut header);
+        meta.write_to_buf(&mut header[PAGE_METADATA_SIZE .. HEADER_SIZE]);
+        let mut first_page = vec![0; PAGE_SIZE as usize];
+        page_meta.write_to_page(&mut first_page);
+
+        let file = match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(Error::IoError(format!("{}: failed to create: {}", path.display(), e))),
         };
-        let page_meta = PageMetadata {
-            prev_page: 0,
-            next_page: 0,
+        // a log left behind by an earlier file at this path no longer applies
+        let (mut log, _) = WriteAheadLog::open(&wal::log_path(path), SyncPolicy::EveryCommit)?;
+        log.truncate(0)?;
+        let mut db_file = DbFile {
+            name,
+            path: path.to_path_buf(),
+            mode: AccessMode::ReadWrite,
+            meta,
+            handle: file,
+            wal: Some(log),
+            txn: None,
+            committed: BTreeMap::new(),
+            map: None,
         };
+        db_file.begin()?;
+        db_file.write(0, &header)?;
+        db_file.write(FIRST_DATA_PAGE, &first_page)?;
+        db_file.commit()?;
+        db_file.checkpoint()?;
+        Ok(db_file)
+    }
 
-        let buf_size = PAGE_SIZE * 2;
-        let mut buf = Vec::with_capacity(buf_size as usize);
-        for _ in 0 .. buf_size {
-            buf.push(0);
+    /// Open an existing file, failing if it wasn't created by a compatible
+    /// version of soupdb. Committed transactions in the file's log are
+    /// recovered; a read-only file keeps them in memory instead of applying
+    /// them.
+    pub fn open(name: String, path: &Path, mode: AccessMode) -> Result<DbFile> {
+        let file = match OpenOptions::new().read(true).write(mode == AccessMode::ReadWrite).open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(Error::IoError(format!("{}: failed to open: {}", path.display(), e))),
+        };
+        let (log, recovered) = match mode {
+            AccessMode::ReadWrite => {
+                let (log, recovered) = WriteAheadLog::open(&wal::log_path(path), SyncPolicy::EveryCommit)?;
+                (Some(log), recovered)
+            }
+            AccessMode::ReadOnly => (None, Recovered::from_file(&wal::log_path(path))?),
+        };
+        let mut db_file = DbFile {
+            name,
+            path: path.to_path_buf(),
+            mode,
+            meta: DbMetadata::new(0, 0),
+            handle: file,
+            wal: log,
+            txn: None,
+            committed: recovered.pages.into_iter().collect(),
+            map: None,
+        };
+
+        // check the header before the checksum, so that files from other
+        // versions aren't reported as corrupt
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        db_file.read_unverified(0, &mut buf)?;
+        let meta = DbMetadata::from_bytes(&buf[PAGE_METADATA_SIZE ..]);
+        if let Err(e) = meta.validate() {
+            return Err(Error::IoError(format!("{}: {}", path.display(), e)));
+        }
+        db_file.verify(0, &buf)?;
+        db_file.meta = meta;
+        if mode == AccessMode::ReadWrite {
+            db_file.checkpoint()?;
         }
+        Ok(db_file)
+    }
 
-        let page_header_len = size_of::<PageMetadata>();
-        let db_header_len = size_of::<DbMetadata>();
-        page_meta.write_to_buf(&mut buf);
-        meta.write_to_buf(&mut buf[page_header_len .. page_header_len + db_header_len]);
-        page_meta.write_to_buf(&mut buf[PAGE_SIZE as usize .. (PAGE_SIZE * 2) as usize]);
+    /// Set how often the log is synced; new files sync on every commit.
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        if let Some(ref mut log) = self.wal {
+            log.set_policy(policy);
+        }
+    }
 
-        let mut file = File::create(path).unwrap();
-        file.write(&buf);
-        file.flush();
+    /// Start a transaction. Until it's committed, writes are only visible
+    /// through this DbFile, and are lost if it's dropped.
+    pub fn begin(&mut self) -> Result<()> {
+        if self.txn.is_some() {
+            return Err(Error::Custom(format!("{}: a transaction is already in progress", self.name)));
+        }
+        self.txn = Some(Transaction {pages: BTreeMap::new(), meta: self.meta.clone()});
+        Ok(())
+    }
 
-        Ok(DbFile {
-            name,
-            meta,
-            handle: file,
-        })
+    /// Log the current transaction's pages. They're written to the file once
+    /// the log has been synced, according to its sync policy. If this fails,
+    /// the transaction is still in progress and should be rolled back.
+    pub fn commit(&mut self) -> Result<()> {
+        let synced = match self.txn {
+            None => return Err(Error::Custom(format!("{}: no transaction is in progress", self.name))),
+            Some(ref txn) if txn.pages.is_empty() => false,
+            Some(ref txn) => match self.wal {
+                Some(ref mut log) => log.append(&txn.pages)?,
+                None => return Err(Error::IoError(format!("{}: can't commit; file was opened read-only", self.path.display()))),
+            },
+        };
+        let txn = self.txn.take().unwrap();
+        self.committed.extend(txn.pages);
+        if synced {
+            self.apply()?;
+            if self.wal.as_ref().is_some_and(|log| log.size() >= CHECKPOINT_SIZE) {
+                self.checkpoint()?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn open(name: String, path: &Path) -> Result<DbFile> {
-        let mut file = File::open(path).unwrap();
-        let buf_size = size_of::<DbMetadata>();
-        let mut buf = Vec::with_capacity(buf_size);
-        for i in 0 .. buf_size {
-            buf.push(0);
+    /// Discard the current transaction, returning the IDs of the pages it
+    /// wrote.
+    pub fn rollback(&mut self) -> Vec<PageId> {
+        match self.txn.take() {
+            Some(txn) => {
+                self.meta = txn.meta;
+                txn.pages.keys().cloned().collect()
+            }
+            None => vec![],
         }
-        DbFile::read_page(&mut file, 0, &mut buf);
-        let page_header_len = size_of::<PageMetadata>();
-        let db_header_len = size_of::<DbMetadata>();
-        let meta = DbMetadata::from_bytes(&buf[page_header_len .. page_header_len + db_header_len]);
+    }
 
-        Ok(DbFile {
-            name,
-            meta,
-            handle: file,
-        })
+    /// Sync the log, write every committed page to the file and sync it, then
+    /// empty the log.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if let Some(ref mut log) = self.wal {
+            log.sync()?;
+        }
+        self.apply()?;
+        if let Some(ref mut log) = self.wal {
+            if log.policy() != SyncPolicy::Off {
+                if let Err(e) = self.handle.sync_all() {
+                    return Err(Error::IoError(format!("{}: failed to sync: {}", self.path.display(), e)));
+                }
+            }
+            log.truncate(0)?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Allocate a page, reusing the most recently freed page if there is one
+    /// or growing the file otherwise. The page's metadata is reset; its
+    /// remaining contents are unspecified.
+    pub fn allocate_page(&mut self) -> Result<PageId> {
+        self.atomically(DbFile::allocate_page_in_txn)
+    }
+
+    fn allocate_page_in_txn(&mut self) -> Result<PageId> {
+        let (page_id, first_free_page) = if self.meta.first_free_page != 0 {
+            let page_id = self.meta.first_free_page;
+            let mut buf = vec![0; PAGE_SIZE as usize];
+            self.read(page_id, &mut buf)?;
+            (page_id, PageMetadata::from_page(&buf).next_page)
+        } else {
+            (self.meta.last_page + 1, 0)
+        };
+
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        PageMetadata::new(0, 0).write_to_page(&mut buf);
+        self.write(page_id, &buf)?;
+        self.meta.first_free_page = first_free_page;
+        if page_id > self.meta.last_page {
+            self.meta.last_page = page_id;
+        }
+        self.write_meta()?;
+        Ok(page_id)
+    }
+
+    /// Return a page to the free list so that it can be reused by a later
+    /// allocation.
+    pub fn free_page(&mut self, page_id: PageId) -> Result<()> {
+        self.atomically(|file| file.free_page_in_txn(page_id))
+    }
+
+    fn free_page_in_txn(&mut self, page_id: PageId) -> Result<()> {
+        // linking a page into the free list twice would hand it out twice
+        if page_id <= FIRST_DATA_PAGE || page_id > self.meta.last_page || self.is_free(page_id)? {
+            return Err(Error::Custom(format!("{}: can't free page {}", self.name, page_id)));
+        }
+        let page_meta = PageMetadata::new(0, self.meta.first_free_page);
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        page_meta.write_to_page(&mut buf);
+        self.write(page_id, &buf)?;
+        self.meta.first_free_page = page_id;
+        self.write_meta()
+    }
+
+    /// Whether a page is on the free list.
+    fn is_free(&mut self, page_id: PageId) -> Result<bool> {
+        let mut free_page = self.meta.first_free_page;
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        while free_page != 0 {
+            if free_page == page_id {
+                return Ok(true);
+            }
+            self.read(free_page, &mut buf)?;
+            free_page = PageMetadata::from_page(&buf).next_page;
+        }
+        Ok(false)
     }
 
-    fn read_page<R: Read + Seek>(handle: &mut R, page_index: u64, buffer: &mut [u8]) {
-        handle.seek(SeekFrom::Start(page_index * PAGE_SIZE));
-        handle.read_exact(buffer);
+    /// Store a value in the file's heap, returning the page and slot it can
+    /// be read from. Values too large to share a heap page are stored in a
+    /// chain of overflow pages instead, with only their location in the
+    /// heap.
+    pub fn heap_insert(&mut self, data: &[u8]) -> Result<(PageId, u16)> {
+        self.atomically(|file| file.heap_insert_in_txn(data))
     }
 
-    fn write_to_page<W: Write + Seek>(handle: &mut W, page_index: u64, bytes: &[u8]) {
-        handle.seek(SeekFrom::Start(page_index * PAGE_SIZE));
-        handle.write(bytes);
+    fn heap_insert_in_txn(&mut self, data: &[u8]) -> Result<(PageId, u16)> {
+        let mut record = Vec::with_capacity(OVERFLOW_RECORD_SIZE);
+        if data.len() + 1 > MAX_RECORD_SIZE {
+            let first_page = self.write_overflow(data)?;
+            record.push(RECORD_OVERFLOW);
+            record.extend(&[0; 16]);
+            LittleEndian::write_u64(&mut record[1 ..], data.len() as u64);
+            LittleEndian::write_u64(&mut record[9 ..], first_page);
+        } else {
+            record.push(RECORD_INLINE);
+            record.extend(data);
+        }
+
+        // only the first heap page is searched for space; pages that have
+        // space freed are moved to the front of the list
+        let head = self.meta.heap_page;
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        if head != 0 {
+            self.read(head, &mut buf)?;
+            if let Some(slot) = HeapPage::new(&mut buf).insert(&record) {
+                self.write(head, &buf)?;
+                return Ok((head, slot));
+            }
+        }
+        let page_id = self.allocate_page_in_txn()?;
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        PageMetadata::new(0, head).write_to_page(&mut buf);
+        let slot = HeapPage::init(&mut buf).insert(&record).unwrap();
+        self.write(page_id, &buf)?;
+        if head != 0 {
+            self.set_links(head, Some(page_id), None)?;
+        }
+        self.meta.heap_page = page_id;
+        self.write_meta()?;
+        Ok((page_id, slot))
+    }
+
+    /// Read a value stored with heap_insert.
+    pub fn heap_read(&mut self, page_id: PageId, slot: u16) -> Result<Vec<u8>> {
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        self.read(page_id, &mut buf)?;
+        let record = self.heap_record(page_id, slot, &mut buf)?;
+        if record[0] == RECORD_INLINE {
+            return Ok(record[1 ..].to_vec());
+        }
+        let len = LittleEndian::read_u64(&record[1 ..]) as usize;
+        let mut page_id = LittleEndian::read_u64(&record[9 ..]);
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            if page_id == 0 {
+                return Err(Error::Corruption(format!("{}: overflow chain ends early", self.path.display())));
+            }
+            self.read(page_id, &mut buf)?;
+            let n = ::std::cmp::min(len - data.len(), OVERFLOW_DATA_SIZE);
+            data.extend(&buf[PAGE_METADATA_SIZE .. PAGE_METADATA_SIZE + n]);
+            page_id = PageMetadata::from_page(&buf).next_page;
+        }
+        Ok(data)
+    }
+
+    /// Free a value stored with heap_insert, along with its overflow pages.
+    /// Heap pages are freed once they're empty.
+    pub fn heap_free(&mut self, page_id: PageId, slot: u16) -> Result<()> {
+        self.atomically(|file| file.heap_free_in_txn(page_id, slot))
+    }
+
+    fn heap_free_in_txn(&mut self, page_id: PageId, slot: u16) -> Result<()> {
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        self.read(page_id, &mut buf)?;
+        let record = self.heap_record(page_id, slot, &mut buf)?;
+        if record[0] == RECORD_OVERFLOW {
+            let mut overflow_page = LittleEndian::read_u64(&record[9 ..]);
+            let mut overflow = vec![0; PAGE_SIZE as usize];
+            while overflow_page != 0 {
+                self.read(overflow_page, &mut overflow)?;
+                self.free_page_in_txn(overflow_page)?;
+                overflow_page = PageMetadata::from_page(&overflow).next_page;
+            }
+        }
+
+        let (empty, free_space) = {
+            let mut heap = HeapPage::new(&mut buf);
+            heap.remove(slot);
+            (heap.is_empty(), heap.free_space())
+        };
+        let meta = PageMetadata::from_page(&buf);
+        let head = self.meta.heap_page;
+        if empty {
+            self.unlink_heap_page(page_id, &meta)?;
+            self.free_page_in_txn(page_id)
+        } else if page_id != head && free_space >= PAGE_SIZE as usize / 2 {
+            // move the page to the front of the list, so that its space is
+            // used by the next insert
+            PageMetadata::new(0, head).write_to_page(&mut buf);
+            self.write(page_id, &buf)?;
+            self.unlink_heap_page(page_id, &meta)?;
+            self.set_links(self.meta.heap_page, Some(page_id), None)?;
+            self.meta.heap_page = page_id;
+            self.write_meta()
+        } else {
+            self.write(page_id, &buf)
+        }
+    }
+
+    /// The record in a slot of a heap page, failing if there isn't one.
+    fn heap_record(&self, page_id: PageId, slot: u16, buf: &mut [u8]) -> Result<Vec<u8>> {
+        match HeapPage::new(buf).get(slot) {
+            Some(record) => Ok(record.to_vec()),
+            None => Err(Error::Corruption(format!("{}: page {} has no value in slot {}", self.path.display(), page_id, slot))),
+        }
+    }
+
+    /// Remove a page from the heap list, given its old links.
+    fn unlink_heap_page(&mut self, page_id: PageId, meta: &PageMetadata) -> Result<()> {
+        if meta.prev_page == 0 {
+            self.meta.heap_page = meta.next_page;
+            self.write_meta()?;
+        } else {
+            self.set_links(meta.prev_page, None, Some(meta.next_page))?;
+        }
+        if meta.next_page != 0 {
+            self.set_links(meta.next_page, Some(meta.prev_page), None)?;
+        }
+        Ok(())
+    }
+
+    /// Update the links in a page's metadata, keeping the rest of the page.
+    fn set_links(&mut self, page_id: PageId, prev_page: Option<PageId>, next_page: Option<PageId>) -> Result<()> {
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        self.read(page_id, &mut buf)?;
+        let meta = PageMetadata::from_page(&buf);
+        PageMetadata::new(prev_page.unwrap_or(meta.prev_page), next_page.unwrap_or(meta.next_page)).write_to_page(&mut buf);
+        self.write(page_id, &buf)
+    }
+
+    /// Write data to a chain of newly allocated overflow pages, returning the
+    /// first.
+    fn write_overflow(&mut self, data: &[u8]) -> Result<PageId> {
+        let mut pages = Vec::new();
+        for _ in data.chunks(OVERFLOW_DATA_SIZE) {
+            pages.push(self.allocate_page_in_txn()?);
+        }
+        for (i, chunk) in data.chunks(OVERFLOW_DATA_SIZE).enumerate() {
+            let prev_page = if i == 0 {0} else {pages[i - 1]};
+            let next_page = pages.get(i + 1).cloned().unwrap_or(0);
+            let mut buf = vec![0; PAGE_SIZE as usize];
+            PageMetadata::new(prev_page, next_page).write_to_page(&mut buf);
+            buf[PAGE_METADATA_SIZE .. PAGE_METADATA_SIZE + chunk.len()].copy_from_slice(chunk);
+            self.write(pages[i], &buf)?;
+        }
+        Ok(pages[0])
+    }
+
+    /// Persist the DbMetadata to the header page.
+    fn write_meta(&mut self) -> Result<()> {
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        PageMetadata::new(0, 0).write_to_buf(&mut buf);
+        self.meta.write_to_buf(&mut buf[PAGE_METADATA_SIZE .. HEADER_SIZE]);
+        self.write(0, &buf)
+    }
+
+    /// Read a page into a buffer, which must be exactly one page long. Fails
+    /// if the page doesn't exist, can't be read in full, or doesn't match its
+    /// checksum.
+    pub fn read(&mut self, page_id: PageId, buffer: &mut [u8]) -> Result<()> {
+        self.read_unverified(page_id, buffer)?;
+        self.verify(page_id, buffer)
+    }
+
+    /// Like read, but reads the page through a memory map of the file
+    /// instead of a system call. The page is copied out of the map, since a
+    /// checkpoint may rewrite it in place once the file is no longer
+    /// borrowed.
+    pub fn read_mapped(&mut self, page_id: PageId) -> Result<Vec<u8>> {
+        let pending = self.txn.as_ref().and_then(|txn| txn.pages.get(&page_id)).or_else(|| self.committed.get(&page_id));
+        let page = match pending {
+            Some(page) => page.clone(),
+            None => {
+                let end = (page_id + 1) * PAGE_SIZE;
+                if self.map.as_ref().is_none_or(|map| (map.len() as u64) < end) {
+                    self.remap()?;
+                }
+                let map = self.map.as_ref().unwrap();
+                if (map.len() as u64) < end {
+                    return Err(self.io_error("read", page_id, "short read; the file ends before this page".to_string()));
+                }
+                map[(page_id * PAGE_SIZE) as usize .. end as usize].to_vec()
+            }
+        };
+        self.verify(page_id, &page)?;
+        Ok(page)
+    }
+
+    /// Write a full page, filling in the checksum in its metadata.
+    pub fn write(&mut self, page_id: PageId, bytes: &[u8]) -> Result<()> {
+        if self.mode == AccessMode::ReadOnly {
+            return Err(self.io_error("write", page_id, "file was opened read-only".to_string()));
+        }
+        if bytes.len() as u64 != PAGE_SIZE {
+            return Err(self.io_error("write", page_id, format!("expected {} bytes, got {}", PAGE_SIZE, bytes.len())));
+        }
+        let mut page = bytes.to_vec();
+        let checksum = page_checksum(&page);
+        LittleEndian::write_u32(&mut page[CHECKSUM_OFFSET ..], checksum);
+        match self.txn {
+            Some(ref mut txn) => {
+                txn.pages.insert(page_id, page);
+                Ok(())
+            }
+            None => self.atomically(|file| file.write(page_id, &page)),
+        }
+    }
+
+    /// Run `f` in the current transaction, or in a transaction of its own if
+    /// none is in progress.
+    fn atomically<T, F: FnOnce(&mut DbFile) -> Result<T>>(&mut self, f: F) -> Result<T> {
+        if self.txn.is_some() {
+            return f(self);
+        }
+        self.begin()?;
+        let result = f(self).and_then(|value| self.commit().map(|_| value));
+        if result.is_err() {
+            self.rollback();
+        }
+        result
+    }
+
+    /// Write committed pages to the file.
+    fn apply(&mut self) -> Result<()> {
+        while let Some(page_id) = self.committed.keys().next().cloned() {
+            let written = DbFile::write_to_page(&mut self.handle, page_id, &self.committed[&page_id]);
+            if let Err(e) = written {
+                return Err(self.io_error("write", page_id, e.to_string()));
+            }
+            self.committed.remove(&page_id);
+        }
+        Ok(())
+    }
+
+    fn remap(&mut self) -> Result<()> {
+        // the file is only modified through this DbFile, which never
+        // shrinks it, so the map can't end up past the end of the file
+        match unsafe { Mmap::map(&self.handle) } {
+            Ok(map) => {
+                self.map = Some(map);
+                Ok(())
+            }
+            Err(e) => Err(Error::IoError(format!("{}: failed to map: {}", self.path.display(), e))),
+        }
+    }
+
+    fn read_unverified(&mut self, page_id: PageId, buffer: &mut [u8]) -> Result<()> {
+        if buffer.len() as u64 != PAGE_SIZE {
+            return Err(self.io_error("read", page_id, format!("expected a {} byte buffer, got {}", PAGE_SIZE, buffer.len())));
+        }
+        // pages that haven't reached the file yet
+        let pending = self.txn.as_ref().and_then(|txn| txn.pages.get(&page_id)).or_else(|| self.committed.get(&page_id));
+        if let Some(page) = pending {
+            buffer.copy_from_slice(page);
+            return Ok(());
+        }
+        match DbFile::read_page(&mut self.handle, page_id, buffer) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                Err(self.io_error("read", page_id, "short read; the file ends before this page".to_string()))
+            }
+            Err(e) => Err(self.io_error("read", page_id, e.to_string())),
+        }
+    }
+
+    fn verify(&self, page_id: PageId, page: &Page) -> Result<()> {
+        let expected = PageMetadata::from_page(page).checksum;
+        let actual = page_checksum(page);
+        if expected == actual {
+            Ok(())
+        } else {
+            Err(Error::Corruption(format!(
+                "{}: page {} is corrupt (checksum {:08x}, expected {:08x})",
+                self.path.display(), page_id, actual, expected
+            )))
+        }
+    }
+
+    fn io_error(&self, action: &str, page_id: PageId, message: String) -> Error {
+        Error::IoError(format!("{}: failed to {} page {}: {}", self.path.display(), action, page_id, message))
+    }
+
+    fn read_page<R: Read + Seek>(handle: &mut R, page_index: u64, buffer: &mut [u8]) -> io::Result<()> {
+        handle.seek(SeekFrom::Start(page_index * PAGE_SIZE))?;
+        handle.read_exact(buffer)
+    }
+
+    fn write_to_page<W: Write + Seek>(handle: &mut W, page_index: u64, bytes: &[u8]) -> io::Result<()> {
+        handle.seek(SeekFrom::Start(page_index * PAGE_SIZE))?;
+        handle.write_all(bytes)?;
+        handle.flush()
+    }
+}
+
+impl Drop for DbFile {
+    /// Apply committed pages and empty the log, so that a file that was closed
+    /// cleanly doesn't need recovery. An uncommitted transaction is discarded.
+    fn drop(&mut self) {
+        if self.mode == AccessMode::ReadWrite {
+            let _ = self.checkpoint();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempdir::TempDir;
 
     #[test]
     fn test_page() {
         let page_data = [
             0xa, 0, 0, 0, 0, 0, 0, 0, 0x14, 0, 0, 0, 0, 0, 0, 0,
-            0xb, 0, 0, 0, 0, 0, 0, 0, 0x15, 0, 0, 0, 0, 0, 0, 0
+            0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0,
         ];
         let page = PageMetadata::from_bytes(&page_data);
         assert_eq!(page.prev_page, 10);
         assert_eq!(page.next_page, 20);
-        let db = DbMetadata::from_bytes(&page_data[size_of::<PageMetadata>() .. page_data.len()]);
-        assert_eq!(db.first_free_page, 11);
-        assert_eq!(db.last_page, 21);
+        assert_eq!(page.checksum, 0x12345678);
+        let mut buf = [0; PAGE_METADATA_SIZE];
+        page.write_to_buf(&mut buf);
+        assert_eq!(buf, page_data);
+    }
+
+    #[test]
+    fn test_db_metadata() {
+        let mut buf = [0; DB_METADATA_SIZE];
+        DbMetadata::new(11, 21).write_to_buf(&mut buf);
+        assert_eq!(&buf[0 .. 8], MAGIC);
+        assert_eq!(&buf[8 .. 12], &[FORMAT_VERSION as u8, 0, 0, 0]);
+        assert_eq!(&buf[16 .. 24], &[0, 0x20, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[24 .. 32], &[0xb, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[32 .. 40], &[0x15, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&buf[40 .. 48], &[0; 8]);
+
+        let db = DbMetadata::from_bytes(&buf);
+        assert_eq!(db, DbMetadata::new(11, 21));
+        assert!(db.validate().is_ok());
+    }
+
+    #[test]
+    fn test_incompatible_header() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.db");
+        let rewrite_header = |meta: DbMetadata| {
+            let mut buf = vec![0; DB_METADATA_SIZE];
+            meta.write_to_buf(&mut buf);
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(PAGE_METADATA_SIZE as u64)).unwrap();
+            file.write_all(&buf).unwrap();
+        };
+        let open_error = || match DbFile::open("test".to_string(), &path, AccessMode::ReadOnly) {
+            Err(Error::IoError(msg)) => msg,
+            _ => panic!("opened an incompatible file"),
+        };
+
+        DbFile::create("test".to_string(), &path).unwrap();
+        assert!(DbFile::open("test".to_string(), &path, AccessMode::ReadOnly).is_ok());
+
+        let mut meta = DbMetadata::new(0, FIRST_DATA_PAGE);
+        meta.version = FORMAT_VERSION + 1;
+        rewrite_header(meta);
+        assert!(open_error().contains(&format!("unsupported format version {}", FORMAT_VERSION + 1)));
+
+        let mut meta = DbMetadata::new(0, FIRST_DATA_PAGE);
+        meta.page_size = PAGE_SIZE * 2;
+        rewrite_header(meta);
+        assert!(open_error().contains("page size"));
+
+        let mut meta = DbMetadata::new(0, FIRST_DATA_PAGE);
+        meta.magic = *b"NOTSOUP!";
+        rewrite_header(meta);
+        assert!(open_error().contains("not a soupdb file"));
+    }
+
+    #[test]
+    fn test_allocate_page() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.db");
+        {
+            let mut file = DbFile::create("test".to_string(), &path).unwrap();
+            // the free list starts empty, so the file grows
+            assert_eq!(file.allocate_page(), Ok(2));
+            assert_eq!(file.allocate_page(), Ok(3));
+            assert_eq!(file.allocate_page(), Ok(4));
+            assert_eq!(path.metadata().unwrap().len(), PAGE_SIZE * 5);
+
+            // freed pages are reused, most recently freed first
+            file.free_page(2).unwrap();
+            file.free_page(4).unwrap();
+            assert_eq!(file.allocate_page(), Ok(4));
+            file.free_page(3).unwrap();
+            assert!(file.free_page(0).is_err());
+            assert!(file.free_page(FIRST_DATA_PAGE).is_err());
+            assert!(file.free_page(5).is_err());
+
+            // pages that are already free can't be freed again
+            assert!(file.free_page(3).is_err());
+            assert!(file.free_page(2).is_err());
+        }
+
+        // the free list survives reopening the file
+        let mut file = DbFile::open("test".to_string(), &path, AccessMode::ReadOnly).unwrap();
+        assert_eq!(file.meta.first_free_page, 3);
+        assert_eq!(file.meta.last_page, 4);
+        drop(file);
+        let mut file = DbFile::open("test".to_string(), &path, AccessMode::ReadWrite).unwrap();
+        assert_eq!(file.allocate_page(), Ok(3));
+        assert_eq!(file.allocate_page(), Ok(2));
+        assert_eq!(file.allocate_page(), Ok(5));
+    }
+
+    #[test]
+    fn test_io_errors() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.db");
+
+        match DbFile::open("test".to_string(), &path, AccessMode::ReadWrite) {
+            Err(Error::IoError(msg)) => assert!(msg.contains("test.db")),
+            _ => panic!("opened a missing file"),
+        }
+
+        DbFile::create("test".to_string(), &path).unwrap();
+        let mut file = DbFile::open("test".to_string(), &path, AccessMode::ReadOnly).unwrap();
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        assert!(file.read(1, &mut buf).is_ok());
+        match file.read(2, &mut buf) {
+            Err(Error::IoError(msg)) => assert!(msg.contains("page 2") && msg.contains("short read")),
+            _ => panic!("read past the end of the file"),
+        }
+        match file.write(1, &buf) {
+            Err(Error::IoError(msg)) => assert!(msg.contains("page 1") && msg.contains("read-only")),
+            _ => panic!("wrote to a read-only file"),
+        }
+        assert!(file.allocate_page().is_err());
+
+        let mut file = DbFile::open("test".to_string(), &path, AccessMode::ReadWrite).unwrap();
+        buf[100] = 7;
+        file.write(1, &buf).unwrap();
+        let mut check = vec![0; PAGE_SIZE as usize];
+        file.read(1, &mut check).unwrap();
+        assert_eq!(check[100], 7);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.db");
+        let mut file = DbFile::create("test".to_string(), &path).unwrap();
+        let page_id = file.allocate_page().unwrap();
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        buf[1000] = 42;
+        file.write(page_id, &buf).unwrap();
+        file.read(page_id, &mut buf).unwrap();
+        assert_eq!(PageMetadata::from_page(&buf).checksum, page_checksum(&buf));
+
+        // flip a single bit on disk
+        {
+            let mut handle = OpenOptions::new().write(true).open(&path).unwrap();
+            handle.seek(SeekFrom::Start(page_id * PAGE_SIZE + 1000)).unwrap();
+            handle.write_all(&[43]).unwrap();
+        }
+        match file.read(page_id, &mut buf) {
+            Err(Error::Corruption(msg)) => {
+                assert!(msg.contains("test.db"));
+                assert!(msg.contains(&format!("page {}", page_id)));
+            }
+            r => panic!("expected a corruption error, got {:?}", r),
+        }
+        // other pages are unaffected
+        assert!(file.read(FIRST_DATA_PAGE, &mut buf).is_ok());
+    }
+
+    #[test]
+    fn test_read_mapped() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.db");
+        let mut file = DbFile::create("test".to_string(), &path).unwrap();
+        let page = file.read_mapped(FIRST_DATA_PAGE).unwrap();
+        assert_eq!(page.len(), PAGE_SIZE as usize);
+        match file.read_mapped(2) {
+            Err(Error::IoError(msg)) => assert!(msg.contains("page 2") && msg.contains("short read")),
+            _ => panic!("read past the end of the file"),
+        }
+
+        // uncommitted pages are read from memory
+        file.begin().unwrap();
+        let page_id = file.allocate_page().unwrap();
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        buf[1000] = 42;
+        file.write(page_id, &buf).unwrap();
+        assert_eq!(file.read_mapped(page_id).unwrap()[1000], 42);
+
+        // once they reach the file, the file is remapped to include them;
+        // pages read earlier keep their contents when the file is rewritten
+        file.commit().unwrap();
+        file.checkpoint().unwrap();
+        let mapped = file.read_mapped(page_id).unwrap();
+        assert_eq!(mapped[1000], 42);
+        assert_eq!(&page[.. PAGE_METADATA_SIZE], &file.read_mapped(FIRST_DATA_PAGE).unwrap()[.. PAGE_METADATA_SIZE]);
+        buf[1000] = 43;
+        file.begin().unwrap();
+        file.write(page_id, &buf).unwrap();
+        file.commit().unwrap();
+        file.checkpoint().unwrap();
+        assert_eq!(mapped[1000], 42);
+        assert_eq!(file.read_mapped(page_id).unwrap()[1000], 43);
+    }
+
+    #[test]
+    fn test_heap() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.db");
+        let value = |i: usize, len: usize| vec![i as u8; len];
+        let mut stored = Vec::new();
+        {
+            let mut file = DbFile::create("test".to_string(), &path).unwrap();
+            // values that fill several heap pages, with a few too large for
+            // a heap page
+            for i in 0 .. 40 {
+                let len = if i % 10 == 9 {3 * PAGE_SIZE as usize} else {1000};
+                stored.push((file.heap_insert(&value(i, len)).unwrap(), len));
+            }
+            let heap_pages: Vec<PageId> = {
+                let mut pages: Vec<PageId> = stored.iter().map(|&((page_id, _), _)| page_id).collect();
+                pages.dedup();
+                pages
+            };
+            assert_eq!(heap_pages.len(), 5);
+            assert_eq!(file.meta.heap_page, heap_pages[4]);
+            for (i, &((page_id, slot), len)) in stored.iter().enumerate() {
+                assert_eq!(file.heap_read(page_id, slot).unwrap(), value(i, len));
+            }
+
+            // freeing half of a page moves it to the front of the list, so
+            // that the next value reuses the space
+            for &((page_id, slot), _) in &stored[.. 4] {
+                file.heap_free(page_id, slot).unwrap();
+            }
+            assert_eq!(file.meta.heap_page, heap_pages[0]);
+            let location = file.heap_insert(&value(100, 1000)).unwrap();
+            assert_eq!(location, stored[0].0);
+            stored[0] = (location, 1000);
+            let (freed_page, freed_slot) = stored[1].0;
+            assert!(file.heap_read(freed_page, freed_slot).is_err());
+            stored.drain(1 .. 4);
+        }
+
+        // the heap survives reopening the file, and every page is freed
+        // once its values are
+        let mut file = DbFile::open("test".to_string(), &path, AccessMode::ReadWrite).unwrap();
+        let (page_id, slot) = stored[0].0;
+        assert_eq!(file.heap_read(page_id, slot).unwrap(), value(100, 1000));
+        let last_page = file.meta.last_page;
+        for &((page_id, slot), _) in &stored {
+            file.heap_free(page_id, slot).unwrap();
+        }
+        assert_eq!(file.meta.heap_page, 0);
+        let mut free_pages = 0;
+        let mut page_id = file.meta.first_free_page;
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        while page_id != 0 {
+            free_pages += 1;
+            file.read(page_id, &mut buf).unwrap();
+            page_id = PageMetadata::from_page(&buf).next_page;
+        }
+        assert_eq!(free_pages, last_page - FIRST_DATA_PAGE);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.db");
+        let mut file = DbFile::create("test".to_string(), &path).unwrap();
+        assert!(file.commit().is_err());
+
+        file.begin().unwrap();
+        assert!(file.begin().is_err());
+        let page_id = file.allocate_page().unwrap();
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        buf[1000] = 1;
+        file.write(FIRST_DATA_PAGE, &buf).unwrap();
+        // the transaction sees its own writes
+        let mut check = vec![0; PAGE_SIZE as usize];
+        file.read(FIRST_DATA_PAGE, &mut check).unwrap();
+        assert_eq!(check[1000], 1);
+
+        let mut discarded = file.rollback();
+        discarded.sort();
+        assert_eq!(discarded, vec![0, FIRST_DATA_PAGE, page_id]);
+        file.read(FIRST_DATA_PAGE, &mut check).unwrap();
+        assert_eq!(check[1000], 0);
+        assert_eq!(file.meta.last_page, FIRST_DATA_PAGE);
+        assert_eq!(file.allocate_page(), Ok(page_id));
+    }
+
+    #[test]
+    fn test_crash_recovery() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.db");
+        let log_path = wal::log_path(&path);
+
+        // commit transactions that each allocate a page and update the first
+        // page, without syncing the log, then crash
+        let mut file = DbFile::create("test".to_string(), &path).unwrap();
+        file.set_sync_policy(SyncPolicy::Grouped(100));
+        let mut commit_ends = vec![0];
+        for i in 1 .. 6 {
+            file.begin().unwrap();
+            let page_id = file.allocate_page().unwrap();
+            let mut buf = vec![0; PAGE_SIZE as usize];
+            buf[1000] = i as u8;
+            file.write(page_id, &buf).unwrap();
+            file.write(FIRST_DATA_PAGE, &buf).unwrap();
+            file.commit().unwrap();
+            commit_ends.push(log_path.metadata().unwrap().len());
+        }
+        // this transaction is never committed
+        file.begin().unwrap();
+        file.allocate_page().unwrap();
+        ::std::mem::forget(file);
+        assert_eq!(path.metadata().unwrap().len(), PAGE_SIZE * 2);
+
+        let mut log = Vec::new();
+        File::open(&log_path).unwrap().read_to_end(&mut log).unwrap();
+        assert_eq!(log.len() as u64, commit_ends[5]);
+
+        // a read-only file sees committed pages without modifying anything
+        {
+            let mut file = DbFile::open("test".to_string(), &path, AccessMode::ReadOnly).unwrap();
+            assert_eq!(file.meta.last_page, 6);
+            let mut buf = vec![0; PAGE_SIZE as usize];
+            file.read(6, &mut buf).unwrap();
+            assert_eq!(buf[1000], 5);
+        }
+        assert_eq!(log_path.metadata().unwrap().len(), commit_ends[5]);
+
+        let cuts = (0 .. log.len()).step_by(4099).chain(commit_ends.iter().flat_map(|&e| vec![e as usize, e as usize + 1]));
+        for cut in cuts.filter(|&c| c <= log.len()) {
+            let committed = commit_ends.iter().filter(|&&e| e <= cut as u64).count() as u64 - 1;
+            let crash_dir = TempDir::new("soupdb").unwrap();
+            let crash_path = crash_dir.path().join("test.db");
+            ::std::fs::copy(&path, &crash_path).unwrap();
+            File::create(wal::log_path(&crash_path)).unwrap().write_all(&log[.. cut]).unwrap();
+
+            {
+                let mut file = DbFile::open("test".to_string(), &crash_path, AccessMode::ReadWrite).unwrap();
+                assert_eq!(file.meta.last_page, FIRST_DATA_PAGE + committed, "cut at {}", cut);
+                let mut buf = vec![0; PAGE_SIZE as usize];
+                file.read(FIRST_DATA_PAGE, &mut buf).unwrap();
+                assert_eq!(buf[1000] as u64, committed);
+                for i in 1 .. committed + 1 {
+                    file.read(FIRST_DATA_PAGE + i, &mut buf).unwrap();
+                    assert_eq!(buf[1000] as u64, i);
+                }
+                // recovery applies the log and empties it
+                assert_eq!(crash_path.metadata().unwrap().len(), PAGE_SIZE * (2 + committed));
+                assert_eq!(wal::log_path(&crash_path).metadata().unwrap().len(), 0);
+            }
+        }
     }
 }
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Debug;
+use std::hash::Hash;
+use lru_cache::LruCache;
+
+/// Decides which page to evict when working memory is full. The page table
+/// reports every page that's loaded, used and evicted; pages are pinned while
+/// they're locked, and only unpinned pages can be chosen as victims.
+pub trait EvictionPolicy<T>: Debug {
+    /// A page has been loaded into working memory. New pages start out
+    /// pinned.
+    fn insert(&mut self, page_id: &T);
+    /// A cached page has been used again.
+    fn access(&mut self, page_id: &T);
+    /// A page has been locked, and can't be evicted until it's unpinned.
+    fn pin(&mut self, page_id: &T);
+    /// The last lock on a page has been released.
+    fn unpin(&mut self, page_id: &T);
+    /// A page has been evicted or discarded.
+    fn remove(&mut self, page_id: &T);
+    /// The unpinned page that should be evicted next, if there is one. The
+    /// page isn't removed; until something else changes, the same page is
+    /// returned again.
+    fn victim(&mut self) -> Option<T>;
+}
+
+/// The eviction policies that working memory can be configured with.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EvictionPolicyType {
+    /// Evict the page that was least recently unlocked.
+    Lru,
+    /// Sweep a clock hand over the cached pages, giving each page that was
+    /// used since the last sweep a second chance.
+    Clock,
+    /// Evict the page whose kth most recent use is oldest. Pages that have
+    /// been used fewer than k times go first, so a single scan can't push
+    /// out frequently used pages.
+    LruK(usize),
+}
+
+impl EvictionPolicyType {
+    /// Create a policy for a cache of the given number of pages.
+    pub fn create<T: Hash + Debug + Eq + Clone + Send + 'static>(&self, pages: usize) -> Box<dyn EvictionPolicy<T> + Send> {
+        match self {
+            &EvictionPolicyType::Lru => Box::new(Lru::new(pages)),
+            &EvictionPolicyType::Clock => Box::new(Clock::new(pages)),
+            &EvictionPolicyType::LruK(k) => Box::new(LruK::new(k, pages)),
+        }
+    }
+}
+
+/// Least recently used: unpinned pages are queued in the order they were
+/// unlocked, and using an unpinned page moves it to the back of the queue.
+#[derive(Debug)]
+pub struct Lru<T: Hash + Debug + Eq + Clone> {
+    unpinned: LruCache<T, ()>,
+}
+
+impl<T: Hash + Debug + Eq + Clone> Lru<T> {
+    pub fn new(pages: usize) -> Lru<T> {
+        Lru {unpinned: LruCache::new(pages)}
+    }
+}
+
+impl<T: Hash + Debug + Eq + Clone> EvictionPolicy<T> for Lru<T> {
+    fn insert(&mut self, _page_id: &T) {}
+
+    fn access(&mut self, page_id: &T) {
+        // refreshes the page if it's queued
+        self.unpinned.contains_key(page_id);
+    }
+
+    fn pin(&mut self, page_id: &T) {
+        self.unpinned.remove(page_id);
+    }
+
+    fn unpin(&mut self, page_id: &T) {
+        self.unpinned.insert(page_id.clone(), ());
+    }
+
+    fn remove(&mut self, page_id: &T) {
+        self.unpinned.remove(page_id);
+    }
+
+    fn victim(&mut self) -> Option<T> {
+        self.unpinned.iter().next().map(|(id, _)| id.clone())
+    }
+}
+
+#[derive(Debug)]
+struct ClockFrame<T> {
+    page_id: T,
+    referenced: bool,
+    pinned: bool,
+}
+
+/// CLOCK, an approximation of LRU which only sets a flag when a page is
+/// used. The hand sweeps over every cached page, clearing the flags it finds
+/// set, and stops at the first unpinned page whose flag is already clear.
+#[derive(Debug)]
+pub struct Clock<T: Hash + Debug + Eq + Clone> {
+    /// the ring of cached pages; an evicted page leaves a hole, which the
+    /// next page loaded fills
+    frames: Vec<Option<ClockFrame<T>>>,
+    /// map of page -> position in frames
+    positions: HashMap<T, usize>,
+    holes: Vec<usize>,
+    hand: usize,
+}
+
+impl<T: Hash + Debug + Eq + Clone> Clock<T> {
+    pub fn new(pages: usize) -> Clock<T> {
+        Clock {
+            frames: Vec::with_capacity(pages),
+            positions: HashMap::with_capacity(pages),
+            holes: Vec::new(),
+            hand: 0,
+        }
+    }
+
+    fn frame(&mut self, page_id: &T) -> Option<&mut ClockFrame<T>> {
+        match self.positions.get(page_id) {
+            Some(&i) => self.frames[i].as_mut(),
+            None => None,
+        }
+    }
+}
+
+impl<T: Hash + Debug + Eq + Clone> EvictionPolicy<T> for Clock<T> {
+    fn insert(&mut self, page_id: &T) {
+        if self.positions.contains_key(page_id) {
+            return;
+        }
+        let frame = ClockFrame {page_id: page_id.clone(), referenced: false, pinned: true};
+        let i = match self.holes.pop() {
+            Some(i) => {
+                self.frames[i] = Some(frame);
+                i
+            }
+            None => {
+                self.frames.push(Some(frame));
+                self.frames.len() - 1
+            }
+        };
+        self.positions.insert(page_id.clone(), i);
+        // a page loaded in place of the victim is passed over by the hand
+        // until its next turn
+        if i == self.hand {
+            self.hand = (self.hand + 1) % self.frames.len();
+        }
+    }
+
+    fn access(&mut self, page_id: &T) {
+        if let Some(frame) = self.frame(page_id) {
+            frame.referenced = true;
+        }
+    }
+
+    fn pin(&mut self, page_id: &T) {
+        if let Some(frame) = self.frame(page_id) {
+            frame.pinned = true;
+        }
+    }
+
+    fn unpin(&mut self, page_id: &T) {
+        if let Some(frame) = self.frame(page_id) {
+            frame.pinned = false;
+        }
+    }
+
+    fn remove(&mut self, page_id: &T) {
+        if let Some(i) = self.positions.remove(page_id) {
+            self.frames[i] = None;
+            self.holes.push(i);
+        }
+    }
+
+    fn victim(&mut self) -> Option<T> {
+        let n = self.frames.len();
+        // after one full turn every flag is clear, so a second turn either
+        // finds a victim or proves every page is pinned
+        for _ in 0 .. 2 * n {
+            if let Some(ref mut frame) = self.frames[self.hand] {
+                if !frame.pinned {
+                    if frame.referenced {
+                        frame.referenced = false;
+                    } else {
+                        return Some(frame.page_id.clone());
+                    }
+                }
+            }
+            self.hand = (self.hand + 1) % n;
+        }
+        None
+    }
+}
+
+/// LRU-K, which ranks pages by the time of their kth most recent use. Pages
+/// with fewer than k uses are evicted first, least recently used first. The
+/// history of recently evicted pages is kept for a while, so that a page
+/// that's reloaded soon after being evicted is still recognized.
+#[derive(Debug)]
+pub struct LruK<T: Hash + Debug + Eq + Clone> {
+    k: usize,
+    /// ticks once for every use of a page
+    clock: u64,
+    /// the last k uses of each cached page, most recent last
+    history: HashMap<T, VecDeque<u64>>,
+    /// unpinned pages, by (kth most recent use, most recent use); the first
+    /// is zero for pages with fewer than k uses
+    unpinned: BTreeMap<(u64, u64), T>,
+    /// history of evicted pages, along with the time they were evicted
+    retained: HashMap<T, (u64, VecDeque<u64>)>,
+    /// evicted pages in the order they were evicted
+    retained_order: VecDeque<(T, u64)>,
+    /// how many evicted pages to remember
+    retained_size: usize,
+}
+
+impl<T: Hash + Debug + Eq + Clone> LruK<T> {
+    pub fn new(k: usize, pages: usize) -> LruK<T> {
+        LruK {
+            k: ::std::cmp::max(k, 1),
+            clock: 0,
+            history: HashMap::with_capacity(pages),
+            unpinned: BTreeMap::new(),
+            retained: HashMap::with_capacity(pages),
+            retained_order: VecDeque::with_capacity(pages),
+            retained_size: pages,
+        }
+    }
+
+    fn rank(&self, history: &VecDeque<u64>) -> (u64, u64) {
+        let kth = if history.len() >= self.k {history[history.len() - self.k]} else {0};
+        (kth, *history.back().unwrap())
+    }
+
+    /// Record a use of a page, given the history of its earlier uses.
+    fn record(&mut self, page_id: &T, mut history: VecDeque<u64>) {
+        self.clock += 1;
+        history.push_back(self.clock);
+        if history.len() > self.k {
+            history.pop_front();
+        }
+        self.history.insert(page_id.clone(), history);
+    }
+}
+
+impl<T: Hash + Debug + Eq + Clone> EvictionPolicy<T> for LruK<T> {
+    fn insert(&mut self, page_id: &T) {
+        let history = match self.retained.remove(page_id) {
+            Some((_, history)) => history,
+            None => VecDeque::with_capacity(self.k + 1),
+        };
+        self.record(page_id, history);
+    }
+
+    fn access(&mut self, page_id: &T) {
+        let history = match self.history.remove(page_id) {
+            Some(history) => history,
+            None => return,
+        };
+        // ranks are unique, since no two uses happen at the same time
+        let unpinned = self.unpinned.remove(&self.rank(&history)).is_some();
+        self.record(page_id, history);
+        if unpinned {
+            self.unpin(page_id);
+        }
+    }
+
+    fn pin(&mut self, page_id: &T) {
+        if let Some(rank) = self.history.get(page_id).map(|h| self.rank(h)) {
+            self.unpinned.remove(&rank);
+        }
+    }
+
+    fn unpin(&mut self, page_id: &T) {
+        if let Some(rank) = self.history.get(page_id).map(|h| self.rank(h)) {
+            self.unpinned.insert(rank, page_id.clone());
+        }
+    }
+
+    fn remove(&mut self, page_id: &T) {
+        if let Some(history) = self.history.remove(page_id) {
+            self.unpinned.remove(&self.rank(&history));
+            self.retained.insert(page_id.clone(), (self.clock, history));
+            self.retained_order.push_back((page_id.clone(), self.clock));
+            while self.retained_order.len() > self.retained_size {
+                let (id, evicted) = self.retained_order.pop_front().unwrap();
+                // the page may have been reloaded and evicted again since
+                if self.retained.get(&id).map(|r| r.0) == Some(evicted) {
+                    self.retained.remove(&id);
+                }
+            }
+        }
+    }
+
+    fn victim(&mut self) -> Option<T> {
+        self.unpinned.values().next().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(policy: &mut dyn EvictionPolicy<u8>, page_id: u8) {
+        policy.insert(&page_id);
+        policy.unpin(&page_id);
+    }
+
+    fn reuse(policy: &mut dyn EvictionPolicy<u8>, page_id: u8) {
+        policy.access(&page_id);
+        policy.pin(&page_id);
+        policy.unpin(&page_id);
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut clock = Clock::new(3);
+        for page_id in 0 .. 3 {
+            load(&mut clock, page_id);
+        }
+        assert_eq!(clock.victim(), Some(0));
+        assert_eq!(clock.victim(), Some(0));
+
+        // used pages get a second chance, and pinned pages are skipped
+        reuse(&mut clock, 0);
+        clock.pin(&1);
+        assert_eq!(clock.victim(), Some(2));
+        clock.remove(&2);
+        assert_eq!(clock.victim(), Some(0));
+        clock.pin(&0);
+        assert_eq!(clock.victim(), None);
+        clock.unpin(&1);
+        assert_eq!(clock.victim(), Some(1));
+    }
+
+    #[test]
+    fn test_lru_k() {
+        let mut lru_k = LruK::new(2, 4);
+        for page_id in 0 .. 3 {
+            load(&mut lru_k, page_id);
+        }
+        reuse(&mut lru_k, 0);
+        reuse(&mut lru_k, 1);
+
+        // page 2 has only been used once
+        assert_eq!(lru_k.victim(), Some(2));
+        lru_k.remove(&2);
+        assert_eq!(lru_k.victim(), Some(0));
+        lru_k.pin(&0);
+        assert_eq!(lru_k.victim(), Some(1));
+
+        // an evicted page's history is remembered when it's reloaded
+        lru_k.remove(&1);
+        load(&mut lru_k, 3);
+        load(&mut lru_k, 1);
+        assert_eq!(lru_k.victim(), Some(3));
+    }
+}
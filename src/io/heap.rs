@@ -0,0 +1,295 @@
+use byteorder::{ByteOrder, LittleEndian};
+use ::{Error, Result};
+use ::ast::value_type::{ValueType, MAX_INLINE_STRING_LENGTH};
+use ::io::dbfile::{DbFile, PAGE_METADATA_SIZE};
+use ::io::page::{Page, PAGE_SIZE};
+use ::io::value::Value;
+
+/// A heap page starts with the page metadata, a u16 count of slots and the
+/// u16 offset of the start of its records, followed by the slots. Records
+/// are packed at the end of the page, growing towards the slots.
+const SLOT_COUNT_OFFSET: usize = PAGE_METADATA_SIZE;
+const DATA_START_OFFSET: usize = SLOT_COUNT_OFFSET + 2;
+const SLOTS_OFFSET: usize = DATA_START_OFFSET + 2;
+/// Each slot is the u16 offset and u16 length of a record; an offset of
+/// zero marks an empty slot.
+const SLOT_SIZE: usize = 4;
+
+/// Records are tagged with whether they hold a value, or the location of
+/// the overflow pages that do.
+pub const RECORD_INLINE: u8 = 0;
+pub const RECORD_OVERFLOW: u8 = 1;
+/// An overflow record is its tag, the u64 length of the value and the u64 ID
+/// of the first page in its chain.
+pub const OVERFLOW_RECORD_SIZE: usize = 1 + 8 + 8;
+/// Values whose records would be larger than this are moved to overflow
+/// pages, so that every heap page is shared by at least a few values.
+pub const MAX_RECORD_SIZE: usize = (PAGE_SIZE as usize - SLOTS_OFFSET) / 4;
+/// Overflow pages are linked by their metadata, and hold data in the rest
+/// of the page.
+pub const OVERFLOW_DATA_SIZE: usize = PAGE_SIZE as usize - PAGE_METADATA_SIZE;
+
+/// A view of a page in a file's heap, which stores variable-length records
+/// in numbered slots. A record keeps its slot for as long as it's stored,
+/// even when the page is compacted to reclaim the space of removed records.
+pub struct HeapPage<'a> {
+    page: &'a mut Page,
+}
+
+impl<'a> HeapPage<'a> {
+    pub fn new(page: &'a mut Page) -> HeapPage<'a> {
+        HeapPage {page}
+    }
+
+    /// Empty a page's slots, leaving its metadata as is.
+    pub fn init(page: &'a mut Page) -> HeapPage<'a> {
+        LittleEndian::write_u16(&mut page[SLOT_COUNT_OFFSET ..], 0);
+        LittleEndian::write_u16(&mut page[DATA_START_OFFSET ..], PAGE_SIZE as u16);
+        HeapPage {page}
+    }
+
+    fn slot_count(&self) -> usize {
+        LittleEndian::read_u16(&self.page[SLOT_COUNT_OFFSET ..]) as usize
+    }
+
+    fn data_start(&self) -> usize {
+        LittleEndian::read_u16(&self.page[DATA_START_OFFSET ..]) as usize
+    }
+
+    fn slot(&self, slot: usize) -> (usize, usize) {
+        let offset = SLOTS_OFFSET + slot * SLOT_SIZE;
+        (LittleEndian::read_u16(&self.page[offset ..]) as usize, LittleEndian::read_u16(&self.page[offset + 2 ..]) as usize)
+    }
+
+    fn set_slot(&mut self, slot: usize, offset: usize, len: usize) {
+        let at = SLOTS_OFFSET + slot * SLOT_SIZE;
+        LittleEndian::write_u16(&mut self.page[at ..], offset as u16);
+        LittleEndian::write_u16(&mut self.page[at + 2 ..], len as u16);
+    }
+
+    /// The record in a slot, if there is one.
+    pub fn get(&self, slot: u16) -> Option<&[u8]> {
+        let slot = slot as usize;
+        if slot >= self.slot_count() {
+            return None;
+        }
+        match self.slot(slot) {
+            (0, _) => None,
+            (offset, len) => self.page.get(offset .. offset + len),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slot_count() == 0
+    }
+
+    /// The space available for new records, including the space of removed
+    /// records that hasn't been reclaimed yet.
+    pub fn free_space(&self) -> usize {
+        let live: usize = (0 .. self.slot_count()).map(|i| self.slot(i).1).sum();
+        PAGE_SIZE as usize - SLOTS_OFFSET - self.slot_count() * SLOT_SIZE - live
+    }
+
+    /// Store a record, returning its slot, or None if it doesn't fit.
+    pub fn insert(&mut self, record: &[u8]) -> Option<u16> {
+        let count = self.slot_count();
+        let empty_slot = (0 .. count).find(|&i| self.slot(i).0 == 0);
+        let needed = record.len() + if empty_slot.is_some() {0} else {SLOT_SIZE};
+        if record.is_empty() || self.free_space() < needed {
+            return None;
+        }
+        let slots_end = SLOTS_OFFSET + count * SLOT_SIZE;
+        if self.data_start() - slots_end < needed {
+            self.compact();
+        }
+        let slot = match empty_slot {
+            Some(slot) => slot,
+            None => {
+                LittleEndian::write_u16(&mut self.page[SLOT_COUNT_OFFSET ..], (count + 1) as u16);
+                count
+            }
+        };
+        let offset = self.data_start() - record.len();
+        self.page[offset .. offset + record.len()].copy_from_slice(record);
+        LittleEndian::write_u16(&mut self.page[DATA_START_OFFSET ..], offset as u16);
+        self.set_slot(slot, offset, record.len());
+        Some(slot as u16)
+    }
+
+    /// Remove the record in a slot. Its space is reclaimed when the page is
+    /// next compacted.
+    pub fn remove(&mut self, slot: u16) -> bool {
+        if self.get(slot).is_none() {
+            return false;
+        }
+        self.set_slot(slot as usize, 0, 0);
+        // empty slots at the end of the directory can be dropped without
+        // moving any other record's slot
+        let mut count = self.slot_count();
+        while count > 0 && self.slot(count - 1).0 == 0 {
+            count -= 1;
+        }
+        LittleEndian::write_u16(&mut self.page[SLOT_COUNT_OFFSET ..], count as u16);
+        if count == 0 {
+            LittleEndian::write_u16(&mut self.page[DATA_START_OFFSET ..], PAGE_SIZE as u16);
+        }
+        true
+    }
+
+    /// Move every record to the end of the page, so that the free space is
+    /// contiguous.
+    fn compact(&mut self) {
+        let records: Vec<(usize, Vec<u8>)> = (0 .. self.slot_count())
+            .filter(|&i| self.slot(i).0 != 0)
+            .map(|i| {
+                let (offset, len) = self.slot(i);
+                (i, self.page[offset .. offset + len].to_vec())
+            })
+            .collect();
+        let mut offset = PAGE_SIZE as usize;
+        for (slot, record) in records {
+            offset -= record.len();
+            self.page[offset .. offset + record.len()].copy_from_slice(&record);
+            self.set_slot(slot, offset, record.len());
+        }
+        LittleEndian::write_u16(&mut self.page[DATA_START_OFFSET ..], offset as u16);
+    }
+}
+
+/// Whether a string value of the given type has to be stored in the heap.
+fn is_off_page(value_type: &ValueType, s: &str) -> bool {
+    match value_type {
+        &ValueType::Str(0) => true,
+        &ValueType::Str(_) => s.len() as u64 > MAX_INLINE_STRING_LENGTH,
+        _ => false,
+    }
+}
+
+/// Move the strings in a value that don't fit in a row to the file's heap,
+/// replacing them with their location.
+pub fn store_strings(file: &mut DbFile, value: &mut Value, value_type: &ValueType) -> Result<()> {
+    let location = match (value_type, &mut *value) {
+        (&ValueType::Str(n), &mut Value::Str(ref s)) => {
+            if n > 0 && s.len() as u64 > n {
+                return Err(Error::TypeError(format!("string of {} bytes is too long for {}", s.len(), value_type.to_ddl())));
+            }
+            if !is_off_page(value_type, s) {
+                return Ok(());
+            }
+            file.heap_insert(s.as_bytes())?
+        }
+        (&ValueType::Nullable(ref t), v) => return store_strings(file, v, t),
+        (&ValueType::Vector(_, ref t), &mut Value::Vector(ref mut values)) => {
+            for v in values.iter_mut() {
+                store_strings(file, v, t)?;
+            }
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+    *value = Value::OffPage(location.0, location.1);
+    Ok(())
+}
+
+/// Replace the locations of strings stored in the file's heap with the
+/// strings themselves.
+pub fn load_strings(file: &mut DbFile, value: &mut Value) -> Result<()> {
+    let s = match value {
+        &mut Value::OffPage(page_id, slot) => {
+            let bytes = file.heap_read(page_id, slot)?;
+            match String::from_utf8(bytes) {
+                Ok(s) => s,
+                Err(e) => return Err(Error::Corruption(format!("invalid string in page {} slot {}: {}", page_id, slot, e))),
+            }
+        }
+        &mut Value::Vector(ref mut values) => {
+            for v in values.iter_mut() {
+                load_strings(file, v)?;
+            }
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+    *value = Value::Str(s);
+    Ok(())
+}
+
+/// Free the heap space used by the strings in a stored value.
+pub fn free_strings(file: &mut DbFile, value: &Value) -> Result<()> {
+    match value {
+        &Value::OffPage(page_id, slot) => file.heap_free(page_id, slot),
+        &Value::Vector(ref values) => {
+            for v in values {
+                free_strings(file, v)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_heap_page() {
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        let mut page = HeapPage::init(&mut buf);
+        assert!(page.is_empty());
+        let a = page.insert(&[1; 3000]).unwrap();
+        let b = page.insert(&[2; 3000]).unwrap();
+        assert!(page.insert(&[3; 3000]).is_none());
+        assert_eq!(page.get(a), Some(&[1; 3000][..]));
+
+        // removed space is reclaimed by compacting the page, without moving
+        // records to other slots
+        assert!(page.remove(a));
+        assert!(!page.remove(a));
+        assert_eq!(page.get(a), None);
+        let c = page.insert(&[3; 4000]).unwrap();
+        assert_eq!(c, a);
+        assert_eq!(page.get(b), Some(&[2; 3000][..]));
+        assert_eq!(page.get(c), Some(&[3; 4000][..]));
+
+        // trailing empty slots are dropped
+        assert!(page.remove(b));
+        assert_eq!(page.slot_count(), 1);
+        assert!(page.remove(c));
+        assert!(page.is_empty());
+        assert_eq!(page.free_space(), PAGE_SIZE as usize - SLOTS_OFFSET);
+    }
+
+    #[test]
+    fn test_strings() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut file = DbFile::create("test".to_string(), &dir.path().join("test.db")).unwrap();
+
+        let long = "x".repeat(300);
+        let vector = ValueType::Vector(2, Box::new(ValueType::Str(0)));
+        let cases = vec![
+            (ValueType::Str(10), Value::Str("short".to_string()), false),
+            (ValueType::Str(1000), Value::Str("short".to_string()), false),
+            (ValueType::Str(1000), Value::Str(long.clone()), true),
+            (ValueType::Str(0), Value::Str("".to_string()), true),
+            (ValueType::Nullable(Box::new(ValueType::Str(0))), Value::Null, false),
+            (vector.clone(), Value::Vector(vec![Value::Str("a".to_string()), Value::Str(long.clone())]), true),
+        ];
+        for (value_type, value, spilled) in cases {
+            let mut stored = value.clone();
+            store_strings(&mut file, &mut stored, &value_type).unwrap();
+            assert_eq!(stored != value, spilled, "{:?}", value);
+            // the stored value fits in the row
+            let mut bytes = vec![0; value_type.size_of() as usize];
+            stored.to_bytes(&mut bytes, &value_type).unwrap();
+            let mut loaded = Value::from_bytes(&bytes, &value_type).unwrap();
+            load_strings(&mut file, &mut loaded).unwrap();
+            assert_eq!(loaded, value);
+            free_strings(&mut file, &stored).unwrap();
+        }
+
+        let mut value = Value::Str(long.clone());
+        assert!(store_strings(&mut file, &mut value, &ValueType::Str(299)).is_err());
+    }
+}
@@ -1,4 +1,9 @@
 pub mod cache;
 pub mod dbfile;
+pub mod eviction;
+pub mod heap;
 pub mod page;
+pub mod pool;
+pub mod row;
 pub mod value;
+pub mod wal;
@@ -2,4 +2,7 @@ pub const PAGE_SIZE: u64 = 0x2000;
 
 pub type Page = [u8];
 pub type PageId = u64;
-pub type ScopedPageId<'a> = (&'a str, PageId);
+/// Identifies an open file in a buffer pool shared between files.
+pub type FileId = String;
+/// A page of a particular file.
+pub type ScopedPageId = (FileId, PageId);
@@ -0,0 +1,497 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use ::{Error, Result};
+use ::config::Config;
+use ::io::cache::{CacheStats, PageLock, PageReadGuard, PageWriteGuard, WorkingMemory};
+use ::io::dbfile::{DbFile, PageMetadata};
+use ::io::eviction::EvictionPolicyType;
+use ::io::page::{FileId, PageId, ScopedPageId, PAGE_SIZE};
+
+/// How pages are read from a file in a buffer pool.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IoBackend {
+    /// Pages are copied into working memory, and cached there.
+    Buffered,
+    /// Pages are read from a memory map of the file, leaving caching to the
+    /// operating system. Modified pages are still held in working memory
+    /// until they're written back, and are read from there while they're
+    /// cached. Mapped pages are copied out of the map as they're read, so
+    /// later writes to the file can't change them; a page that's being
+    /// modified by another thread may be read as it was before.
+    Mapped,
+}
+
+/// A page returned by BufferPool::get_page.
+pub enum PageRef<'a> {
+    /// A page in working memory, which stays locked while it's held.
+    Cached(PageLock<ScopedPageId>, PageReadGuard<'a>),
+    /// A copy of a page read from a file's memory map.
+    Mapped(Vec<u8>),
+}
+
+impl<'a> Deref for PageRef<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            &PageRef::Cached(_, ref data) => data,
+            &PageRef::Mapped(ref page) => page,
+        }
+    }
+}
+
+struct PooledFile {
+    file: DbFile,
+    backend: IoBackend,
+    /// the page that comes next if the file's pages are being read in the
+    /// order of their chain
+    next_page: PageId,
+    /// how many pages in a row have been read in chain order
+    walk_length: usize,
+}
+
+/// The parts of a buffer pool that its prefetch thread uses.
+struct SharedPool {
+    memory: WorkingMemory<ScopedPageId>,
+    files: Mutex<HashMap<FileId, PooledFile>>,
+    /// how far ahead of a chain walk to prefetch
+    prefetch_pages: usize,
+    /// held by the prefetch thread while it follows a chain, and by anything
+    /// that drops pages from the pool, which mustn't be locked by a prefetch
+    /// in the meantime
+    prefetching: Mutex<()>,
+}
+
+/// Working memory shared by a set of open files, so that they can share a
+/// single memory budget. Cached pages are keyed by file as well as page ID,
+/// and pages are read from and written back to the file they belong to,
+/// including pages of one file evicted to make room for another's.
+///
+/// Reading pages in the order they're chained together by their metadata,
+/// as a table scan does, is detected, and a background thread loads the next
+/// few pages of the chain before they're needed.
+///
+/// The pool can be shared between threads behind an Arc. Files are only
+/// locked while they're being read or written, never while waiting for a
+/// page, so the closure passed to with_file must not use the pool's pages.
+pub struct BufferPool {
+    shared: Arc<SharedPool>,
+    /// chains for the prefetch thread to follow, by their first page; None
+    /// if prefetching is off
+    prefetches: Option<Mutex<Sender<ScopedPageId>>>,
+    prefetch_thread: Option<JoinHandle<()>>,
+}
+
+impl BufferPool {
+    pub fn new(size: u64) -> BufferPool {
+        BufferPool::with_policy(size, EvictionPolicyType::Lru)
+    }
+
+    /// Create a pool that doesn't prefetch.
+    pub fn with_policy(size: u64, policy: EvictionPolicyType) -> BufferPool {
+        BufferPool::create(WorkingMemory::with_policy(size, policy), 0)
+    }
+
+    /// Create a pool using a config's working memory settings.
+    pub fn from_config(config: &Config) -> BufferPool {
+        let mut memory = WorkingMemory::with_policy(config.working_memory, config.eviction_policy);
+        memory.set_lock_timeout(config.lock_timeout);
+        // prefetching much of the pool would evict prefetched pages before
+        // they're used
+        let prefetch_pages = min(config.prefetch_pages, (config.working_memory / PAGE_SIZE) as usize / 4);
+        BufferPool::create(memory, prefetch_pages)
+    }
+
+    fn create(memory: WorkingMemory<ScopedPageId>, prefetch_pages: usize) -> BufferPool {
+        let shared = Arc::new(SharedPool {
+            memory,
+            files: Mutex::new(HashMap::new()),
+            prefetch_pages,
+            prefetching: Mutex::new(()),
+        });
+        let (prefetches, prefetch_thread) = if prefetch_pages > 0 {
+            let (sender, receiver) = channel();
+            let thread_shared = shared.clone();
+            let thread = thread::spawn(move || thread_shared.run_prefetcher(receiver));
+            (Some(Mutex::new(sender)), Some(thread))
+        } else {
+            (None, None)
+        };
+        BufferPool {shared, prefetches, prefetch_thread}
+    }
+
+    /// Add a file to the pool. The ID has to be unique among every file
+    /// sharing the pool; a file's path is a good choice.
+    pub fn open(&self, file_id: FileId, file: DbFile, backend: IoBackend) -> Result<()> {
+        let mut files = self.shared.files();
+        if files.contains_key(&file_id) {
+            return Err(Error::Custom(format!("{} is already open", file_id)));
+        }
+        files.insert(file_id, PooledFile {file, backend, next_page: 0, walk_length: 0});
+        Ok(())
+    }
+
+    pub fn is_open(&self, file_id: &str) -> bool {
+        self.shared.files().contains_key(file_id)
+    }
+
+    /// Write a file's dirty pages back to it, drop its pages from working
+    /// memory and close it. Other files' pages are left cached. Fails if any
+    /// of the file's pages are still locked, in which case the file is left
+    /// open.
+    pub fn close(&self, file_id: &str) -> Result<()> {
+        let _paused = self.shared.pause_prefetching();
+        self.flush(file_id)?;
+        if !self.shared.memory.invalidate_pages(|id| id.0 == file_id) {
+            return Err(Error::Custom(format!("can't close {}; some of its pages are locked", file_id)));
+        }
+        match self.shared.files().remove(file_id) {
+            Some(_) => Ok(()),
+            None => Err(Error::Custom(format!("{} isn't open", file_id))),
+        }
+    }
+
+    /// Close a file without writing its dirty pages back first, since the
+    /// file is about to be deleted.
+    pub fn discard(&self, file_id: &str) -> Result<()> {
+        let _paused = self.shared.pause_prefetching();
+        if !self.shared.memory.invalidate_pages(|id| id.0 == file_id) {
+            return Err(Error::Custom(format!("can't close {}; some of its pages are locked", file_id)));
+        }
+        self.shared.files().remove(file_id);
+        Ok(())
+    }
+
+    /// Run `f` on an open file, e.g. to start a transaction or allocate a
+    /// page.
+    pub fn with_file<R, F: FnOnce(&mut DbFile) -> Result<R>>(&self, file_id: &str, f: F) -> Result<R> {
+        self.shared.with_file(file_id, f)
+    }
+
+    /// Get a page from working memory, reading it from its file if it isn't
+    /// cached. Pages of mapped files are only taken from working memory if
+    /// they're already there.
+    pub fn get_page(&self, page_id: ScopedPageId) -> Result<PageRef<'_>> {
+        let (file_id, id) = page_id.clone();
+        let mapped = match self.shared.files().get(&file_id) {
+            Some(pooled) => pooled.backend == IoBackend::Mapped,
+            None => return Err(Error::Custom(format!("{} isn't open", file_id))),
+        };
+        if mapped && !self.shared.memory.contains_page(&page_id) {
+            // if the page is loaded and modified in the meantime, it's
+            // read as it was before
+            return Ok(PageRef::Mapped(self.with_file(&file_id, |file| file.read_mapped(id))?));
+        }
+        let shared = &*self.shared;
+        let reader = |buf: &mut [u8]| shared.with_file(&file_id, |file| file.read(id, buf));
+        let writer = |key: &ScopedPageId, page: &[u8]| shared.write_back(key, page);
+        let (lock, data) = shared.memory.get_page(page_id, reader, writer)?;
+        if !mapped {
+            self.follow_walk(&file_id, id, &data);
+        }
+        Ok(PageRef::Cached(lock, data))
+    }
+
+    /// Like get_page, but returns a mutable buffer and marks the page dirty.
+    pub fn get_page_mut(&self, page_id: ScopedPageId) -> Result<(PageLock<ScopedPageId>, PageWriteGuard<'_>)> {
+        let (file_id, id) = page_id.clone();
+        let shared = &*self.shared;
+        let reader = |buf: &mut [u8]| shared.with_file(&file_id, |file| file.read(id, buf));
+        let writer = |key: &ScopedPageId, page: &[u8]| shared.write_back(key, page);
+        shared.memory.get_page_mut(page_id, reader, writer)
+    }
+
+    /// Like get_page_mut, but doesn't read the page if it isn't cached, for
+    /// callers that are about to overwrite all of it.
+    pub fn overwrite_page(&self, page_id: ScopedPageId) -> Result<(PageLock<ScopedPageId>, PageWriteGuard<'_>)> {
+        let shared = &*self.shared;
+        let reader = |_: &mut [u8]| Ok(());
+        let writer = |key: &ScopedPageId, page: &[u8]| shared.write_back(key, page);
+        shared.memory.get_page_mut(page_id, reader, writer)
+    }
+
+    /// Hint that the chain of pages starting at this one is about to be
+    /// read, so that the prefetch thread can start loading them. Does nothing
+    /// if prefetching is off.
+    pub fn prefetch(&self, page_id: ScopedPageId) {
+        if let Some(ref prefetches) = self.prefetches {
+            // the thread only stops once the pool is dropped
+            prefetches.lock().unwrap_or_else(|e| e.into_inner()).send(page_id);
+        }
+    }
+
+    /// Write a file's dirty pages back to it.
+    pub fn flush(&self, file_id: &str) -> Result<()> {
+        let shared = &*self.shared;
+        shared.memory.flush_pages(|id| id.0 == file_id, |key, page| shared.write_back(key, page))
+    }
+
+    /// Drop a file's unlocked dirty pages without writing them.
+    pub fn discard_dirty(&self, file_id: &str) {
+        let _paused = self.shared.pause_prefetching();
+        self.shared.memory.discard_dirty_pages(|id| id.0 == file_id)
+    }
+
+    /// Discard the cached copy of a page. Returns false if it's locked.
+    pub fn invalidate(&self, page_id: &ScopedPageId) -> bool {
+        let _paused = self.shared.pause_prefetching();
+        self.shared.memory.invalidate(page_id)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.shared.memory.stats()
+    }
+
+    /// Keep track of whether a file's pages are being read in chain order,
+    /// and once two have been, prefetch the rest of the chain.
+    fn follow_walk(&self, file_id: &str, page_id: PageId, page: &[u8]) {
+        if self.prefetches.is_none() {
+            return;
+        }
+        let next_page = PageMetadata::from_page(page).next_page;
+        let walking = match self.shared.files().get_mut(file_id) {
+            Some(pooled) => {
+                pooled.walk_length = if page_id == pooled.next_page {pooled.walk_length + 1} else {0};
+                pooled.next_page = next_page;
+                pooled.walk_length > 0
+            }
+            None => false,
+        };
+        if walking && next_page != 0 {
+            self.prefetch((file_id.to_string(), next_page));
+        }
+    }
+}
+
+impl Drop for BufferPool {
+    /// Stop the prefetch thread. Open files are closed once it has stopped,
+    /// without writing back their dirty pages.
+    fn drop(&mut self) {
+        self.prefetches = None;
+        if let Some(thread) = self.prefetch_thread.take() {
+            thread.join();
+        }
+    }
+}
+
+impl SharedPool {
+    fn with_file<R, F: FnOnce(&mut DbFile) -> Result<R>>(&self, file_id: &str, f: F) -> Result<R> {
+        match self.files().get_mut(file_id) {
+            Some(pooled) => f(&mut pooled.file),
+            None => Err(Error::Custom(format!("{} isn't open", file_id))),
+        }
+    }
+
+    /// Write a modified page back to the file it belongs to.
+    fn write_back(&self, key: &ScopedPageId, page: &[u8]) -> Result<()> {
+        match self.files().get_mut(&key.0) {
+            Some(pooled) => pooled.file.write(key.1, page),
+            None => Err(Error::Custom(format!("can't write page {} of {}; its file isn't open", key.1, key.0))),
+        }
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<FileId, PooledFile>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until the prefetch thread has no pages locked, and keep it from
+    /// locking any until the guard is dropped.
+    fn pause_prefetching(&self) -> MutexGuard<'_, ()> {
+        self.prefetching.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run_prefetcher(&self, chains: Receiver<ScopedPageId>) {
+        while let Ok(chain) = chains.recv() {
+            // a scan sends a chain for every page it reads, so only the
+            // latest chain of each file is worth following
+            let mut latest = vec![chain];
+            while let Ok(chain) = chains.try_recv() {
+                latest.retain(|c| c.0 != chain.0);
+                latest.push(chain);
+            }
+            let _busy = self.pause_prefetching();
+            for (file_id, page_id) in latest {
+                self.prefetch_chain(&file_id, page_id);
+            }
+        }
+    }
+
+    /// Load up to prefetch_pages pages of a chain that aren't cached yet.
+    fn prefetch_chain(&self, file_id: &str, first_page: PageId) {
+        match self.files().get(file_id) {
+            // the operating system does its own read-ahead of mapped files
+            Some(pooled) if pooled.backend == IoBackend::Buffered => {}
+            _ => return,
+        }
+        let mut page_id = first_page;
+        for _ in 0 .. self.prefetch_pages {
+            if page_id == 0 {
+                break;
+            }
+            let id = page_id;
+            let reader = |buf: &mut [u8]| self.with_file(file_id, |file| file.read(id, buf));
+            let writer = |key: &ScopedPageId, page: &[u8]| self.write_back(key, page);
+            let next_page = |page: &[u8]| PageMetadata::from_page(page).next_page;
+            match self.memory.prefetch((file_id.to_string(), id), reader, writer, next_page) {
+                Ok(Some(next)) => page_id = next,
+                // the page is busy or couldn't be read; the walk will get to
+                // it on its own
+                _ => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    fn fill(pool: &BufferPool, page_id: ScopedPageId, byte: u8) {
+        let (_lock, mut buf) = pool.overwrite_page(page_id).unwrap();
+        for b in buf.iter_mut() {
+            *b = byte;
+        }
+    }
+
+    fn first_byte(pool: &BufferPool, page_id: ScopedPageId) -> u8 {
+        pool.get_page(page_id).unwrap()[0]
+    }
+
+    #[test]
+    fn test_shared_files() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let pool = BufferPool::new(PAGE_SIZE * 2);
+        let mut ids: Vec<PageId> = vec![];
+        for name in &["a", "b"] {
+            let mut file = DbFile::create(name.to_string(), &dir.path().join(name)).unwrap();
+            for _ in 0 .. 3 {
+                ids.push(file.allocate_page().unwrap());
+            }
+            pool.open(name.to_string(), file, IoBackend::Buffered).unwrap();
+        }
+        assert!(pool.open("a".to_string(), DbFile::create("a".to_string(), &dir.path().join("c")).unwrap(), IoBackend::Buffered).is_err());
+
+        // both files use the same page IDs, and every page is written back
+        // to its own file when it's evicted
+        assert_eq!(&ids[.. 3], &ids[3 ..]);
+        for &id in &ids[.. 3] {
+            fill(&pool, ("a".to_string(), id), id as u8);
+            fill(&pool, ("b".to_string(), id), id as u8 + 100);
+        }
+        for &id in &ids[.. 3] {
+            assert_eq!(first_byte(&pool, ("a".to_string(), id)), id as u8);
+            assert_eq!(first_byte(&pool, ("b".to_string(), id)), id as u8 + 100);
+        }
+        assert_eq!(pool.stats().cached_pages, 2);
+    }
+
+    #[test]
+    fn test_close() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let pool = BufferPool::new(PAGE_SIZE * 4);
+        let mut id = 0;
+        for name in &["a", "b"] {
+            let mut file = DbFile::create(name.to_string(), &dir.path().join(name)).unwrap();
+            id = file.allocate_page().unwrap();
+            pool.open(name.to_string(), file, IoBackend::Buffered).unwrap();
+        }
+        fill(&pool, ("a".to_string(), id), 1);
+        fill(&pool, ("b".to_string(), id), 2);
+
+        // a file can't be closed while its pages are locked
+        {
+            let _page = pool.get_page(("a".to_string(), id)).unwrap();
+            assert!(pool.close("a").is_err());
+            assert!(pool.is_open("a"));
+        }
+
+        // closing a file only flushes and evicts its own pages
+        pool.close("a").unwrap();
+        assert!(!pool.is_open("a"));
+        assert!(pool.close("a").is_err());
+        let stats = pool.stats();
+        assert_eq!((stats.cached_pages, stats.dirty_pages), (1, 1));
+        assert!(pool.get_page(("a".to_string(), id)).is_err());
+
+        let mut file = DbFile::open("a".to_string(), &dir.path().join("a"), ::io::dbfile::AccessMode::ReadOnly).unwrap();
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        file.read(id, &mut buf).unwrap();
+        assert_eq!(buf[0], 1);
+
+        // discarded files lose their changes
+        pool.discard("b").unwrap();
+        assert_eq!(pool.stats().cached_pages, 0);
+        let mut file = DbFile::open("b".to_string(), &dir.path().join("b"), ::io::dbfile::AccessMode::ReadOnly).unwrap();
+        file.read(id, &mut buf).unwrap();
+        assert_eq!(buf[0], 0);
+    }
+
+    /// Create a file whose data pages are chained in order, with each
+    /// page's ID in byte 100.
+    fn chained_file(dir: &TempDir, pages: u64) -> DbFile {
+        let mut file = DbFile::create("chain".to_string(), &dir.path().join("chain")).unwrap();
+        for _ in 1 .. pages {
+            file.allocate_page().unwrap();
+        }
+        for page_id in 1 .. pages + 1 {
+            let mut buf = vec![0; PAGE_SIZE as usize];
+            let next_page = if page_id == pages {0} else {page_id + 1};
+            PageMetadata::new(page_id - 1, next_page).write_to_page(&mut buf);
+            buf[100] = page_id as u8;
+            file.write(page_id, &buf).unwrap();
+        }
+        file
+    }
+
+    fn wait_for_prefetches(pool: &BufferPool, pages: u64) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.stats().prefetched_pages < pages {
+            assert!(Instant::now() < deadline, "pages weren't prefetched: {:?}", pool.stats());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let mut config = Config::with_data_dir(dir.path().to_path_buf());
+        config.working_memory = PAGE_SIZE * 32;
+        config.prefetch_pages = 4;
+        let pool = BufferPool::from_config(&config);
+        pool.open("chain".to_string(), chained_file(&dir, 20), IoBackend::Buffered).unwrap();
+        let page = |page_id: PageId| pool.get_page(("chain".to_string(), page_id)).unwrap()[100];
+
+        // reading pages out of order doesn't prefetch anything
+        assert_eq!(page(12), 12);
+        assert_eq!(page(1), 1);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(pool.stats().prefetched_pages, 0);
+
+        // once two pages have been read in chain order, the next few are
+        // loaded in the background
+        assert_eq!(page(2), 2);
+        wait_for_prefetches(&pool, 4);
+        let stats = pool.stats();
+        assert_eq!((stats.cached_pages, stats.misses, stats.prefetch_hits), (7, 3, 0));
+        for page_id in 3 .. 7 {
+            assert_eq!(page(page_id), page_id as u8);
+        }
+        // the prefetch thread keeps four pages ahead
+        wait_for_prefetches(&pool, 8);
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses, stats.prefetch_hits), (4, 3, 4));
+
+        // a hint starts prefetching a chain right away
+        pool.prefetch(("chain".to_string(), 15));
+        wait_for_prefetches(&pool, 12);
+        assert_eq!(page(16), 16);
+        let stats = pool.stats();
+        assert_eq!((stats.cached_pages, stats.misses, stats.prefetch_hits), (15, 3, 5));
+    }
+}
@@ -0,0 +1,224 @@
+use ::{Error, Result};
+use ::ast::tuple::TupleDef;
+use ::ast::value_type::ValueType;
+use ::io::value::Value;
+
+/// Packs rows into the fixed-width layout described by a TupleDef, and reads
+/// them back. Fields are stored one after another with no padding, each
+/// taking `size_of` bytes of its type, so any single field can be read or
+/// written in place without touching the rest of the row.
+#[derive(Debug, Clone)]
+pub struct RowCodec {
+    types: Vec<ValueType>,
+    /// The offset of each field, followed by the size of the row.
+    offsets: Vec<usize>,
+}
+
+impl RowCodec {
+    pub fn new(schema: &TupleDef) -> RowCodec {
+        let types: Vec<ValueType> = schema.0.iter().map(|e| e.value.clone()).collect();
+        let mut offsets = Vec::with_capacity(types.len() + 1);
+        let mut offset = 0;
+        for t in &types {
+            offsets.push(offset);
+            offset += t.size_of() as usize;
+        }
+        offsets.push(offset);
+        RowCodec {types, offsets}
+    }
+
+    /// The number of bytes in an encoded row.
+    pub fn size(&self) -> usize {
+        self.offsets[self.types.len()]
+    }
+
+    pub fn field_count(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn field_type(&self, index: usize) -> &ValueType {
+        &self.types[index]
+    }
+
+    /// Encode a full row into `bytes`, which must be exactly `size()` long.
+    pub fn encode(&self, values: &[Value], bytes: &mut [u8]) -> Result<()> {
+        if values.len() != self.types.len() {
+            return Err(Error::TypeError(format!("expected {} values, got {}", self.types.len(), values.len())));
+        }
+        self.check_size(bytes)?;
+        for (i, value) in values.iter().enumerate() {
+            self.write_field(bytes, i, value)?;
+        }
+        Ok(())
+    }
+
+    /// Decode a full row from `bytes`, which must be exactly `size()` long.
+    pub fn decode(&self, bytes: &[u8]) -> Result<Vec<Value>> {
+        self.check_size(bytes)?;
+        (0 .. self.types.len()).map(|i| self.read_field(bytes, i)).collect()
+    }
+
+    /// The encoded bytes of a single field.
+    pub fn field<'a>(&self, row: &'a [u8], index: usize) -> &'a [u8] {
+        &row[self.offsets[index] .. self.offsets[index + 1]]
+    }
+
+    /// Decode a single field, without decoding the rest of the row.
+    pub fn read_field(&self, row: &[u8], index: usize) -> Result<Value> {
+        Value::from_bytes(self.field(row, index), &self.types[index])
+    }
+
+    /// Overwrite a single field in place.
+    pub fn write_field(&self, row: &mut [u8], index: usize, value: &Value) -> Result<()> {
+        value.to_bytes(&mut row[self.offsets[index] .. self.offsets[index + 1]], &self.types[index])
+    }
+
+    /// Check whether a field is null by reading its null flag alone. Fields
+    /// that aren't nullable are never null.
+    pub fn is_null(&self, row: &[u8], index: usize) -> bool {
+        match self.types[index] {
+            ValueType::Nullable(_) => row[self.offsets[index]] == 0,
+            _ => false,
+        }
+    }
+
+    fn check_size(&self, bytes: &[u8]) -> Result<()> {
+        if bytes.len() == self.size() {
+            Ok(())
+        } else {
+            Err(Error::Custom(format!("expected a row of {} bytes, got {}", self.size(), bytes.len())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, Gen, QuickCheck};
+    use ::ast::tuple::TupleEntry;
+
+    /// A random schema, with a row that fits it.
+    #[derive(Debug, Clone)]
+    struct SchemaRow(TupleDef, Vec<Value>);
+
+    fn arbitrary_type(g: &mut Gen, depth: u32) -> ValueType {
+        let choices: &[u8] = if depth == 0 { &[0, 1, 2, 3, 4, 5] } else { &[0, 1, 2, 3, 4, 5, 6, 7] };
+        match *g.choose(choices).unwrap() {
+            0 => ValueType::AutoId,
+            1 => ValueType::Bool,
+            2 => ValueType::Uint,
+            3 => ValueType::Int,
+            4 => ValueType::Float,
+            5 => ValueType::Str(u64::from(u8::arbitrary(g) % 40)),
+            6 => ValueType::Nullable(Box::new(arbitrary_type(g, depth - 1))),
+            _ => ValueType::Vector(u64::from(u8::arbitrary(g) % 5), Box::new(arbitrary_type(g, depth - 1))),
+        }
+    }
+
+    fn arbitrary_value(g: &mut Gen, value_type: &ValueType) -> Value {
+        match value_type {
+            &ValueType::Bool => Value::Bool(bool::arbitrary(g)),
+            &ValueType::Uint | &ValueType::AutoId => Value::Uint(u64::arbitrary(g)),
+            &ValueType::Int => Value::Int(i64::arbitrary(g)),
+            // NaN never equals itself, so it can't be compared after a
+            // round trip
+            &ValueType::Float => Value::Float(match f64::arbitrary(g) {
+                n if n.is_nan() => 0.0,
+                n => n,
+            }),
+            &ValueType::Str(0) => Value::OffPage(u64::arbitrary(g), u16::arbitrary(g)),
+            &ValueType::Str(n) => {
+                let mut s = String::new();
+                for c in String::arbitrary(g).chars().filter(|&c| c != '\0') {
+                    if s.len() + c.len_utf8() > n as usize {
+                        break;
+                    }
+                    s.push(c);
+                }
+                Value::Str(s)
+            }
+            &ValueType::Nullable(ref t) => match bool::arbitrary(g) {
+                true => Value::Null,
+                false => arbitrary_value(g, t),
+            },
+            &ValueType::Vector(n, ref t) => Value::Vector((0 .. n).map(|_| arbitrary_value(g, t)).collect()),
+            &ValueType::Unknown => unreachable!(),
+        }
+    }
+
+    impl Arbitrary for SchemaRow {
+        fn arbitrary(g: &mut Gen) -> SchemaRow {
+            let fields = u8::arbitrary(g) % 8;
+            let schema = TupleDef((0 .. fields).map(|i| TupleEntry {
+                name: format!("col_{}", i),
+                value: arbitrary_type(g, 2),
+            }).collect());
+            let row = schema.0.iter().map(|e| arbitrary_value(g, &e.value)).collect();
+            SchemaRow(schema, row)
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        fn round_trip(input: SchemaRow) -> bool {
+            let SchemaRow(schema, row) = input;
+            let codec = RowCodec::new(&schema);
+            assert_eq!(codec.size() as u64, schema.size_of());
+            let mut bytes = vec![0xaa; codec.size()];
+            codec.encode(&row, &mut bytes).unwrap();
+            codec.decode(&bytes).unwrap() == row
+        }
+        QuickCheck::new().tests(500).quickcheck(round_trip as fn(SchemaRow) -> bool);
+    }
+
+    #[test]
+    fn test_field_access() {
+        fn field_access(input: SchemaRow, replacement: SchemaRow) -> bool {
+            let SchemaRow(schema, mut row) = input;
+            let codec = RowCodec::new(&schema);
+            let mut bytes = vec![0; codec.size()];
+            codec.encode(&row, &mut bytes).unwrap();
+            for (i, value) in row.iter().enumerate() {
+                if codec.read_field(&bytes, i).unwrap() != *value || codec.is_null(&bytes, i) != value.is_null() {
+                    return false;
+                }
+            }
+            // overwrite the fields that have the same type in both rows
+            let SchemaRow(other_schema, other_row) = replacement;
+            for (i, (entry, value)) in other_schema.0.iter().zip(other_row).enumerate() {
+                if i < row.len() && entry.value == schema[i].value {
+                    codec.write_field(&mut bytes, i, &value).unwrap();
+                    row[i] = value;
+                }
+            }
+            codec.decode(&bytes).unwrap() == row
+        }
+        QuickCheck::new().tests(500).quickcheck(field_access as fn(SchemaRow, SchemaRow) -> bool);
+    }
+
+    #[test]
+    fn test_layout() {
+        let schema = TupleDef(vec![
+            TupleEntry {name: "a".to_string(), value: ValueType::Nullable(Box::new(ValueType::Int))},
+            TupleEntry {name: "b".to_string(), value: ValueType::Str(0)},
+            TupleEntry {name: "c".to_string(), value: ValueType::Vector(2, Box::new(ValueType::Bool))},
+            TupleEntry {name: "d".to_string(), value: ValueType::Str(3)},
+        ]);
+        let codec = RowCodec::new(&schema);
+        assert_eq!(codec.size(), 9 + 10 + 2 + 3);
+        let mut bytes = vec![0; codec.size()];
+        let row = vec![Value::Null, Value::OffPage(2, 5), Value::Vector(vec![Value::Bool(true), Value::Bool(false)]), Value::Str("ok".to_string())];
+        codec.encode(&row, &mut bytes).unwrap();
+        assert_eq!(bytes, vec![
+            0, 0, 0, 0, 0, 0, 0, 0, 0,
+            2, 0, 0, 0, 0, 0, 0, 0, 5, 0,
+            1, 0,
+            b'o', b'k', 0,
+        ]);
+        assert!(codec.is_null(&bytes, 0));
+        assert_eq!(codec.field(&bytes, 2), &[1, 0]);
+
+        assert!(codec.encode(&row[.. 3], &mut bytes).is_err());
+        assert!(codec.decode(&bytes[1 ..]).is_err());
+    }
+}
@@ -1,50 +1,265 @@
-use std::fmt::{Debug, Formatter, Result};
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::str;
 use byteorder::{ByteOrder, LittleEndian};
-use ::ast::value_type::ValueType;
+use ::{Error, Result};
+use ::ast::value_type::{ValueType, MAX_INLINE_STRING_LENGTH};
+use ::io::page::PageId;
 
-pub union Value {
-    bool_value: bool,
-    uint_value: u64,
-    int_value: i64,
-    float_value: f64,
+/// Marks a sized string that was too long to store inline, and was stored
+/// off page instead; the byte never occurs in UTF-8.
+const SPILLED: u8 = 0xff;
+
+/// A single value of any ValueType. AutoIds are stored as Uints.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Uint(u64),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    /// The location of a string which is stored off page: the ID of a page
+    /// in the file's heap, and its slot within that page.
+    OffPage(PageId, u16),
+    Vector(Vec<Value>),
 }
 
 impl Value {
-    pub fn from_bytes(bytes: &[u8], value_type: &ValueType) -> Option<Value> {
-        match value_type {
-            &ValueType::Bool => Some(Value {uint_value: if bytes[0] != 0 {1} else {0}}),
-            &ValueType::Uint => Some(Value {uint_value: LittleEndian::read_u64(&bytes)}),
-            &ValueType::Int => Some(Value {int_value: LittleEndian::read_i64(&bytes)}),
-            &ValueType::Float => Some(Value {float_value: LittleEndian::read_f64(&bytes)}),
-            _ => None
+    pub fn from_bool(value: bool) -> Value {
+        Value::Bool(value)
+    }
+
+    pub fn from_uint(value: u64) -> Value {
+        Value::Uint(value)
+    }
+
+    pub fn from_int(value: i64) -> Value {
+        Value::Int(value)
+    }
+
+    pub fn from_float(value: f64) -> Value {
+        Value::Float(value)
+    }
+
+    /// Panics if the value isn't a bool.
+    pub fn as_bool(&self) -> bool {
+        match self {
+            &Value::Bool(b) => b,
+            v => panic!("expected a bool, found {:?}", v),
         }
     }
 
-    pub fn to_bytes(&self, mut bytes: &mut [u8], value_type: &ValueType) {
-        unsafe {
-            match value_type {
-                &ValueType::Bool => bytes[0] = if self.bool_value {1} else {0},
-                &ValueType::Uint => LittleEndian::write_u64(&mut bytes, self.uint_value),
-                &ValueType::Int => LittleEndian::write_i64(&mut bytes, self.int_value),
-                &ValueType::Float => LittleEndian::write_f64(&mut bytes, self.float_value),
-                _ => ()
+    /// Panics if the value isn't a uint.
+    pub fn as_uint(&self) -> u64 {
+        match self {
+            &Value::Uint(n) => n,
+            v => panic!("expected an unsigned int, found {:?}", v),
+        }
+    }
+
+    /// Panics if the value isn't an int.
+    pub fn as_int(&self) -> i64 {
+        match self {
+            &Value::Int(n) => n,
+            v => panic!("expected an int, found {:?}", v),
+        }
+    }
+
+    /// Panics if the value isn't a float.
+    pub fn as_float(&self) -> f64 {
+        match self {
+            &Value::Float(n) => n,
+            v => panic!("expected a float, found {:?}", v),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, &Value::Null)
+    }
+
+    /// Decode a value of the given type, which takes up exactly
+    /// `value_type.size_of()` bytes.
+    pub fn from_bytes(bytes: &[u8], value_type: &ValueType) -> Result<Value> {
+        let size = value_type.size_of() as usize;
+        if bytes.len() != size {
+            return Err(Error::Custom(format!("expected {} bytes for {}, got {}", size, value_type.to_ddl(), bytes.len())));
+        }
+        Ok(match value_type {
+            &ValueType::Unknown => unreachable!(),
+            &ValueType::Bool => match bytes[0] {
+                0 => Value::Bool(false),
+                1 => Value::Bool(true),
+                b => return Err(Error::Corruption(format!("invalid bool {}", b))),
+            },
+            &ValueType::Uint | &ValueType::AutoId => Value::Uint(LittleEndian::read_u64(bytes)),
+            &ValueType::Int => Value::Int(LittleEndian::read_i64(bytes)),
+            &ValueType::Float => Value::Float(LittleEndian::read_f64(bytes)),
+            &ValueType::Str(0) => Value::OffPage(LittleEndian::read_u64(bytes), LittleEndian::read_u16(&bytes[8 ..])),
+            &ValueType::Str(n) if n > MAX_INLINE_STRING_LENGTH && bytes[0] == SPILLED => {
+                Value::OffPage(LittleEndian::read_u64(&bytes[1 ..]), LittleEndian::read_u16(&bytes[9 ..]))
+            }
+            // inline strings are padded with zeros
+            &ValueType::Str(_) => {
+                let len = bytes.iter().position(|&b| b == 0).unwrap_or(size);
+                match str::from_utf8(&bytes[.. len]) {
+                    Ok(s) => Value::Str(s.to_string()),
+                    Err(e) => return Err(Error::Corruption(format!("invalid string: {}", e))),
+                }
+            }
+            &ValueType::Nullable(ref t) => match bytes[0] {
+                0 => Value::Null,
+                1 => Value::from_bytes(&bytes[1 ..], t)?,
+                b => return Err(Error::Corruption(format!("invalid null flag {}", b))),
+            },
+            &ValueType::Vector(n, ref t) => {
+                let element_size = t.size_of() as usize;
+                let mut values = Vec::with_capacity(n as usize);
+                for i in 0 .. n as usize {
+                    values.push(Value::from_bytes(&bytes[i * element_size .. (i + 1) * element_size], t)?);
+                }
+                Value::Vector(values)
+            }
+        })
+    }
+
+    /// Encode a value as the given type, filling exactly
+    /// `value_type.size_of()` bytes. Fails if the value isn't of that type,
+    /// or doesn't fit.
+    pub fn to_bytes(&self, bytes: &mut [u8], value_type: &ValueType) -> Result<()> {
+        let size = value_type.size_of() as usize;
+        if bytes.len() != size {
+            return Err(Error::Custom(format!("expected {} bytes for {}, got {}", size, value_type.to_ddl(), bytes.len())));
+        }
+        match (value_type, self) {
+            (&ValueType::Bool, &Value::Bool(b)) => bytes[0] = if b {1} else {0},
+            (&ValueType::Uint, &Value::Uint(n)) | (&ValueType::AutoId, &Value::Uint(n)) => LittleEndian::write_u64(bytes, n),
+            (&ValueType::Int, &Value::Int(n)) => LittleEndian::write_i64(bytes, n),
+            (&ValueType::Float, &Value::Float(n)) => LittleEndian::write_f64(bytes, n),
+            (&ValueType::Str(0), &Value::OffPage(page_id, slot)) => {
+                LittleEndian::write_u64(bytes, page_id);
+                LittleEndian::write_u16(&mut bytes[8 ..], slot);
             }
+            (&ValueType::Str(n), &Value::OffPage(page_id, slot)) if n > MAX_INLINE_STRING_LENGTH => {
+                for b in bytes.iter_mut() {
+                    *b = 0;
+                }
+                bytes[0] = SPILLED;
+                LittleEndian::write_u64(&mut bytes[1 ..], page_id);
+                LittleEndian::write_u16(&mut bytes[9 ..], slot);
+            }
+            (&ValueType::Str(0), &Value::Str(_)) => {
+                return Err(Error::Custom("strings of unlimited length have to be stored off page".to_string()));
+            }
+            (&ValueType::Str(n), &Value::Str(ref s)) if n > MAX_INLINE_STRING_LENGTH && s.len() > size => {
+                return Err(Error::Custom(format!("strings longer than {} bytes have to be stored off page", size)));
+            }
+            (&ValueType::Str(_), &Value::Str(ref s)) => {
+                if s.len() > size {
+                    return Err(Error::TypeError(format!("string of {} bytes is too long for {}", s.len(), value_type.to_ddl())));
+                }
+                if s.bytes().any(|b| b == 0) {
+                    return Err(Error::TypeError("strings can't contain NUL characters".to_string()));
+                }
+                bytes[.. s.len()].copy_from_slice(s.as_bytes());
+                for b in &mut bytes[s.len() ..] {
+                    *b = 0;
+                }
+            }
+            (&ValueType::Nullable(_), &Value::Null) => {
+                for b in bytes.iter_mut() {
+                    *b = 0;
+                }
+            }
+            (&ValueType::Nullable(ref t), v) => {
+                bytes[0] = 1;
+                v.to_bytes(&mut bytes[1 ..], t)?;
+            }
+            (&ValueType::Vector(n, ref t), &Value::Vector(ref values)) => {
+                if values.len() as u64 != n {
+                    return Err(Error::TypeError(format!("expected {} elements for {}, got {}", n, value_type.to_ddl(), values.len())));
+                }
+                let element_size = t.size_of() as usize;
+                for (i, value) in values.iter().enumerate() {
+                    value.to_bytes(&mut bytes[i * element_size .. (i + 1) * element_size], t)?;
+                }
+            }
+            (t, v) => return Err(Error::TypeError(format!("can't store {} as {}", v, t.to_ddl()))),
         }
+        Ok(())
     }
 }
 
 impl PartialEq for Value {
+    /// Numbers are equal if they have the same value, whatever their types.
     fn eq(&self, rhs: &Self) -> bool {
-        unsafe {
-            self.uint_value == rhs.uint_value
+        self.partial_cmp(rhs) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Value {
+    /// Nulls come before every other value. Numbers of different types are
+    /// compared by value; other values can only be compared with values of
+    /// the same kind, and vectors are compared element by element.
+    fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
+        match (self, rhs) {
+            (&Value::Null, &Value::Null) => Some(Ordering::Equal),
+            (&Value::Null, _) => Some(Ordering::Less),
+            (_, &Value::Null) => Some(Ordering::Greater),
+            (&Value::Bool(l), &Value::Bool(r)) => l.partial_cmp(&r),
+            (&Value::Uint(l), &Value::Uint(r)) => l.partial_cmp(&r),
+            (&Value::Int(l), &Value::Int(r)) => l.partial_cmp(&r),
+            (&Value::Uint(l), &Value::Int(r)) => Some(compare_uint_int(l, r)),
+            (&Value::Int(l), &Value::Uint(r)) => Some(compare_uint_int(r, l).reverse()),
+            (&Value::Float(l), &Value::Float(r)) => l.partial_cmp(&r),
+            (&Value::Float(l), &Value::Uint(r)) => l.partial_cmp(&(r as f64)),
+            (&Value::Float(l), &Value::Int(r)) => l.partial_cmp(&(r as f64)),
+            (&Value::Uint(l), &Value::Float(r)) => (l as f64).partial_cmp(&r),
+            (&Value::Int(l), &Value::Float(r)) => (l as f64).partial_cmp(&r),
+            (&Value::Str(ref l), &Value::Str(ref r)) => l.partial_cmp(r),
+            (&Value::OffPage(lp, lo), &Value::OffPage(rp, ro)) => (lp, lo).partial_cmp(&(rp, ro)),
+            (&Value::Vector(ref l), &Value::Vector(ref r)) => {
+                for (l, r) in l.iter().zip(r) {
+                    match l.partial_cmp(r) {
+                        Some(Ordering::Equal) => {}
+                        o => return o,
+                    }
+                }
+                l.len().partial_cmp(&r.len())
+            }
+            _ => None,
         }
     }
 }
 
-impl Debug for Value {
-    fn fmt(&self, f: &mut Formatter) -> Result {
-        unsafe {
-            write!(f, "0x{:x}", self.uint_value)
+fn compare_uint_int(l: u64, r: i64) -> Ordering {
+    if r < 0 {
+        Ordering::Greater
+    } else {
+        l.cmp(&(r as u64))
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            &Value::Null => write!(f, "null"),
+            &Value::Bool(b) => write!(f, "{}", b),
+            &Value::Uint(n) => write!(f, "{}", n),
+            &Value::Int(n) => write!(f, "{}", n),
+            &Value::Float(n) => write!(f, "{}", n),
+            &Value::Str(ref s) => write!(f, "{:?}", s),
+            &Value::OffPage(page_id, slot) => write!(f, "<string at page {} slot {}>", page_id, slot),
+            &Value::Vector(ref values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
@@ -53,16 +268,84 @@ impl Debug for Value {
 mod tests {
     use super::*;
 
+    fn round_trip(value: Value, value_type: ValueType) {
+        let mut bytes = vec![0xaa; value_type.size_of() as usize];
+        value.to_bytes(&mut bytes, &value_type).unwrap();
+        assert_eq!(Value::from_bytes(&bytes, &value_type).unwrap(), value);
+    }
+
     #[test]
     fn test_value_from_bytes() {
-        assert_eq!(Some(Value {bool_value: true}), Value::from_bytes(&[1], &ValueType::Bool));
-        assert_eq!(Some(Value {bool_value: false}), Value::from_bytes(&[0], &ValueType::Bool));
-        assert_eq!(Some(Value {int_value: 0}), Value::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0], &ValueType::Int));
-        assert_eq!(Some(Value {int_value: 1}), Value::from_bytes(&[1, 0, 0, 0, 0, 0, 0, 0], &ValueType::Int));
-        assert_eq!(Some(Value {int_value: -1}), Value::from_bytes(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], &ValueType::Int));
-        assert_eq!(Some(Value {uint_value: 0}), Value::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0], &ValueType::Uint));
-        assert_eq!(Some(Value {uint_value: 1}), Value::from_bytes(&[1, 0, 0, 0, 0, 0, 0, 0], &ValueType::Uint));
-        assert_eq!(Some(Value {uint_value: 18446744073709551615}), Value::from_bytes(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], &ValueType::Uint));
-        assert_eq!(Some(Value {float_value: 0.12345}), Value::from_bytes(&[0x7c, 0xf2, 0xb0, 0x50, 0x6b, 0x9a, 0xbf, 0x3f], &ValueType::Float));
+        assert_eq!(Ok(Value::Bool(true)), Value::from_bytes(&[1], &ValueType::Bool));
+        assert_eq!(Ok(Value::Bool(false)), Value::from_bytes(&[0], &ValueType::Bool));
+        assert_eq!(Ok(Value::Int(0)), Value::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0], &ValueType::Int));
+        assert_eq!(Ok(Value::Int(1)), Value::from_bytes(&[1, 0, 0, 0, 0, 0, 0, 0], &ValueType::Int));
+        assert_eq!(Ok(Value::Int(-1)), Value::from_bytes(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], &ValueType::Int));
+        assert_eq!(Ok(Value::Uint(0)), Value::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0], &ValueType::Uint));
+        assert_eq!(Ok(Value::Uint(1)), Value::from_bytes(&[1, 0, 0, 0, 0, 0, 0, 0], &ValueType::Uint));
+        assert_eq!(Ok(Value::Uint(18446744073709551615)), Value::from_bytes(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], &ValueType::Uint));
+        assert_eq!(Ok(Value::Float(0.12345)), Value::from_bytes(&[0x7c, 0xf2, 0xb0, 0x50, 0x6b, 0x9a, 0xbf, 0x3f], &ValueType::Float));
+        assert_eq!(Ok(Value::Uint(7)), Value::from_bytes(&[7, 0, 0, 0, 0, 0, 0, 0], &ValueType::AutoId));
+        assert_eq!(Ok(Value::Str("ab".to_string())), Value::from_bytes(b"ab\0\0", &ValueType::Str(4)));
+        assert_eq!(Ok(Value::OffPage(3, 16)), Value::from_bytes(&[3, 0, 0, 0, 0, 0, 0, 0, 16, 0], &ValueType::Str(0)));
+        assert_eq!(Ok(Value::Null), Value::from_bytes(&[0, 0xff], &ValueType::Nullable(Box::new(ValueType::Bool))));
+        assert!(Value::from_bytes(&[2], &ValueType::Bool).is_err());
+        assert!(Value::from_bytes(&[0, 0], &ValueType::Bool).is_err());
+        assert!(Value::from_bytes(&[0xff, 0], &ValueType::Str(2)).is_err());
+    }
+
+    #[test]
+    fn test_value_to_bytes() {
+        round_trip(Value::Bool(true), ValueType::Bool);
+        round_trip(Value::Uint(1 << 40), ValueType::AutoId);
+        round_trip(Value::Int(-12), ValueType::Int);
+        round_trip(Value::Float(-0.5), ValueType::Float);
+        round_trip(Value::Str("".to_string()), ValueType::Str(3));
+        round_trip(Value::Str("héllo".to_string()), ValueType::Str(6));
+        round_trip(Value::OffPage(1 << 33, 0x2000 - 1), ValueType::Str(0));
+        round_trip(Value::Str("y".repeat(256)), ValueType::Str(1000));
+        round_trip(Value::OffPage(7, 2), ValueType::Str(1000));
+        round_trip(Value::Null, ValueType::Nullable(Box::new(ValueType::Str(8))));
+        round_trip(Value::Int(3), ValueType::Nullable(Box::new(ValueType::Int)));
+        let vector = ValueType::Vector(3, Box::new(ValueType::Nullable(Box::new(ValueType::Float))));
+        round_trip(Value::Vector(vec![Value::Float(1.0), Value::Null, Value::Float(2.5)]), vector.clone());
+        round_trip(Value::Vector(vec![Value::Vector(vec![]), Value::Vector(vec![])]), ValueType::Vector(2, Box::new(ValueType::Vector(0, Box::new(ValueType::Int)))));
+
+        let mut bytes = [0; 10];
+        assert!(Value::Int(1).to_bytes(&mut bytes[.. 8], &ValueType::Uint).is_err());
+        assert!(Value::Null.to_bytes(&mut bytes[.. 8], &ValueType::Int).is_err());
+        assert!(Value::Str("toolong".to_string()).to_bytes(&mut bytes[.. 4], &ValueType::Str(4)).is_err());
+        assert!(Value::Str("a".to_string()).to_bytes(&mut bytes[.. 10], &ValueType::Str(0)).is_err());
+        assert!(Value::OffPage(7, 2).to_bytes(&mut bytes[.. 10], &ValueType::Str(10)).is_err());
+        let mut bytes = vec![0; 256];
+        assert!(Value::Str("y".repeat(257)).to_bytes(&mut bytes, &ValueType::Str(1000)).is_err());
+        let mut bytes = vec![0; vector.size_of() as usize];
+        assert!(Value::Vector(vec![Value::Null]).to_bytes(&mut bytes, &vector).is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(Value::Uint(3), Value::Int(3));
+        assert_eq!(Value::Float(3.0), Value::Int(3));
+        assert!(Value::Int(-1) < Value::Uint(0));
+        assert!(Value::Uint(u64::MAX) > Value::Int(i64::MAX));
+        assert!(Value::Bool(true) != Value::Uint(1));
+        assert_eq!(Value::Bool(true).partial_cmp(&Value::Uint(1)), None);
+        assert!(Value::Null < Value::Bool(false));
+        assert_eq!(Value::Null, Value::Null);
+        assert!(Value::Float(f64::NAN) != Value::Float(f64::NAN));
+        assert!(Value::Str("abc".to_string()) < Value::Str("abd".to_string()));
+        assert!(Value::Vector(vec![Value::Int(1), Value::Int(2)]) < Value::Vector(vec![Value::Int(1), Value::Int(3)]));
+        assert!(Value::Vector(vec![Value::Int(1)]) < Value::Vector(vec![Value::Int(1), Value::Int(0)]));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(format!("{}", Value::Null), "null");
+        assert_eq!(format!("{}", Value::Bool(true)), "true");
+        assert_eq!(format!("{}", Value::Int(-3)), "-3");
+        assert_eq!(format!("{}", Value::Float(2.5)), "2.5");
+        assert_eq!(format!("{}", Value::Str("a\"b".to_string())), "\"a\\\"b\"");
+        assert_eq!(format!("{}", Value::Vector(vec![Value::Uint(1), Value::Null])), "[1, null]");
     }
 }
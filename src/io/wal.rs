@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use ::io::page::{PageId, PAGE_SIZE};
+use ::{Result, Error};
+
+/// When the log is flushed to stable storage. Pages only reach the DbFile
+/// once the log entries describing them have been synced, so a policy that
+/// syncs less often keeps more committed pages in memory, and loses the most
+/// recent commits if the machine crashes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SyncPolicy {
+    /// Sync the log on every commit.
+    EveryCommit,
+    /// Sync the log once this many commits have been written to it.
+    Grouped(u32),
+    /// Never sync. Committed pages are applied as soon as they've been
+    /// logged, which only protects against the process crashing.
+    Off,
+}
+
+/// The log is emptied by a checkpoint once it grows past this size.
+pub const CHECKPOINT_SIZE: u64 = PAGE_SIZE * 0x400;
+
+const PAGE_RECORD: u8 = 1;
+const COMMIT_RECORD: u8 = 2;
+/// Every record starts with its kind, a transaction ID and a u64 argument: the
+/// page ID for page records and the number of pages for commit records.
+const RECORD_HEADER_SIZE: usize = 17;
+/// Records end with a CRC32 of the rest of the record.
+const RECORD_CHECKSUM_SIZE: usize = 4;
+const PAGE_RECORD_SIZE: usize = RECORD_HEADER_SIZE + PAGE_SIZE as usize + RECORD_CHECKSUM_SIZE;
+const COMMIT_RECORD_SIZE: usize = RECORD_HEADER_SIZE + RECORD_CHECKSUM_SIZE;
+
+/// The log belonging to the DbFile at `db_path`.
+pub fn log_path(db_path: &Path) -> PathBuf {
+    db_path.with_extension("wal")
+}
+
+/// The committed contents of a log.
+#[derive(Debug, PartialEq)]
+pub struct Recovered {
+    /// Page images in the order they were committed; later images of a page
+    /// replace earlier ones.
+    pub pages: Vec<(PageId, Vec<u8>)>,
+    /// Length of the log up to the end of the last commit.
+    pub len: u64,
+    pub last_txn: u64,
+}
+
+impl Recovered {
+    /// Parse a log, stopping at the first record that is incomplete or fails
+    /// its checksum. Pages from a transaction without a commit record are
+    /// discarded.
+    pub fn from_bytes(log: &[u8]) -> Recovered {
+        let mut recovered = Recovered {pages: vec![], len: 0, last_txn: 0};
+        let mut pending = Vec::new();
+        let mut txn = None;
+        let mut offset = 0;
+        while offset + RECORD_HEADER_SIZE <= log.len() {
+            let kind = log[offset];
+            let txn_id = LittleEndian::read_u64(&log[offset + 1 ..]);
+            let arg = LittleEndian::read_u64(&log[offset + 9 ..]);
+            let size = match kind {
+                PAGE_RECORD => PAGE_RECORD_SIZE,
+                COMMIT_RECORD => COMMIT_RECORD_SIZE,
+                _ => break,
+            };
+            if offset + size > log.len() {
+                break;
+            }
+            let body = &log[offset .. offset + size - RECORD_CHECKSUM_SIZE];
+            if crc32::checksum_ieee(body) != LittleEndian::read_u32(&log[offset + size - RECORD_CHECKSUM_SIZE ..]) {
+                break;
+            }
+            if txn.is_some() && txn != Some(txn_id) {
+                break;
+            }
+            if kind == PAGE_RECORD {
+                txn = Some(txn_id);
+                pending.push((arg, body[RECORD_HEADER_SIZE ..].to_vec()));
+            } else {
+                if pending.len() as u64 != arg {
+                    break;
+                }
+                recovered.pages.append(&mut pending);
+                recovered.len = (offset + size) as u64;
+                recovered.last_txn = txn_id;
+                txn = None;
+            }
+            offset += size;
+        }
+        recovered
+    }
+
+    /// Read the committed contents of the log at `path` without modifying
+    /// it. A missing log is empty.
+    pub fn from_file(path: &Path) -> Result<Recovered> {
+        let mut log = Vec::new();
+        match File::open(path).and_then(|mut f| f.read_to_end(&mut log)) {
+            Ok(_) => Ok(Recovered::from_bytes(&log)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Recovered::from_bytes(&[])),
+            Err(e) => Err(Error::IoError(format!("{}: failed to read log: {}", path.display(), e))),
+        }
+    }
+}
+
+/// A redo log of full page images. Each transaction is appended as one page
+/// record per modified page followed by a commit record, so on recovery a
+/// transaction is either replayed in full or not at all.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    handle: File,
+    policy: SyncPolicy,
+    len: u64,
+    next_txn: u64,
+    /// Commits written since the log was last synced.
+    unsynced: u32,
+}
+
+impl WriteAheadLog {
+    /// Open the log at `path`, creating it if necessary. Returns the log
+    /// along with its committed contents; anything after the last commit is
+    /// truncated.
+    pub fn open(path: &Path, policy: SyncPolicy) -> Result<(WriteAheadLog, Recovered)> {
+        let handle = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path) {
+            Ok(handle) => handle,
+            Err(e) => return Err(Error::IoError(format!("{}: failed to open log: {}", path.display(), e))),
+        };
+        let mut log = WriteAheadLog {
+            path: path.to_path_buf(),
+            handle,
+            policy,
+            len: 0,
+            next_txn: 1,
+            unsynced: 0,
+        };
+        let mut bytes = Vec::new();
+        if let Err(e) = log.handle.read_to_end(&mut bytes) {
+            return Err(log.io_error("read", e));
+        }
+        let recovered = Recovered::from_bytes(&bytes);
+        log.next_txn = recovered.last_txn + 1;
+        log.truncate(recovered.len)?;
+        Ok((log, recovered))
+    }
+
+    pub fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: SyncPolicy) {
+        self.policy = policy;
+    }
+
+    /// Length of the log in bytes.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Append a transaction. Returns true if the log was synced, in which
+    /// case every transaction written so far can be applied.
+    pub fn append(&mut self, pages: &BTreeMap<PageId, Vec<u8>>) -> Result<bool> {
+        let txn_id = self.next_txn;
+        let mut bytes = Vec::with_capacity(pages.len() * PAGE_RECORD_SIZE + COMMIT_RECORD_SIZE);
+        for (&page_id, page) in pages {
+            WriteAheadLog::write_record(&mut bytes, PAGE_RECORD, txn_id, page_id, page);
+        }
+        WriteAheadLog::write_record(&mut bytes, COMMIT_RECORD, txn_id, pages.len() as u64, &[]);
+
+        let written = self.handle.seek(SeekFrom::Start(self.len)).and_then(|_| self.handle.write_all(&bytes));
+        if let Err(e) = written {
+            // don't leave a partial transaction in front of later ones
+            let len = self.len;
+            let _ = self.handle.set_len(len);
+            return Err(self.io_error("write", e));
+        }
+        self.len += bytes.len() as u64;
+        self.next_txn += 1;
+        self.unsynced += 1;
+
+        match self.policy {
+            SyncPolicy::EveryCommit => self.sync()?,
+            SyncPolicy::Grouped(n) if self.unsynced >= n => self.sync()?,
+            SyncPolicy::Grouped(_) => return Ok(false),
+            SyncPolicy::Off => {}
+        }
+        Ok(true)
+    }
+
+    /// Flush any unsynced commits to stable storage, unless syncing is off.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.unsynced == 0 || self.policy == SyncPolicy::Off {
+            self.unsynced = 0;
+            return Ok(());
+        }
+        if let Err(e) = self.handle.sync_data() {
+            return Err(self.io_error("sync", e));
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Discard everything in the log after the first `len` bytes.
+    pub fn truncate(&mut self, len: u64) -> Result<()> {
+        if let Err(e) = self.handle.set_len(len) {
+            return Err(self.io_error("truncate", e));
+        }
+        self.len = len;
+        if self.policy != SyncPolicy::Off {
+            if let Err(e) = self.handle.sync_data() {
+                return Err(self.io_error("sync", e));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_record(buf: &mut Vec<u8>, kind: u8, txn_id: u64, arg: u64, data: &[u8]) {
+        let start = buf.len();
+        let mut header = [0; RECORD_HEADER_SIZE];
+        header[0] = kind;
+        LittleEndian::write_u64(&mut header[1 ..], txn_id);
+        LittleEndian::write_u64(&mut header[9 ..], arg);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(data);
+        let mut checksum = [0; RECORD_CHECKSUM_SIZE];
+        LittleEndian::write_u32(&mut checksum, crc32::checksum_ieee(&buf[start ..]));
+        buf.extend_from_slice(&checksum);
+    }
+
+    fn io_error(&self, action: &str, e: ::std::io::Error) -> Error {
+        Error::IoError(format!("{}: failed to {} log: {}", self.path.display(), action, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn page(fill: u8) -> Vec<u8> {
+        vec![fill; PAGE_SIZE as usize]
+    }
+
+    fn txn(pages: &[(PageId, u8)]) -> BTreeMap<PageId, Vec<u8>> {
+        pages.iter().map(|&(page_id, fill)| (page_id, page(fill))).collect()
+    }
+
+    #[test]
+    fn test_recover() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let path = dir.path().join("test.wal");
+        let mut ends = vec![0];
+        {
+            let (mut log, recovered) = WriteAheadLog::open(&path, SyncPolicy::EveryCommit).unwrap();
+            assert_eq!(recovered, Recovered {pages: vec![], len: 0, last_txn: 0});
+            for t in &[txn(&[(1, 1), (2, 2)]), txn(&[(2, 3)]), txn(&[(3, 4), (4, 5), (5, 6)])] {
+                assert_eq!(log.append(t), Ok(true));
+                ends.push(log.size());
+            }
+        }
+
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        let recovered = Recovered::from_bytes(&bytes);
+        assert_eq!(recovered.len, bytes.len() as u64);
+        assert_eq!(recovered.last_txn, 3);
+        let fills: Vec<(PageId, u8)> = recovered.pages.iter().map(|&(id, ref p)| (id, p[0])).collect();
+        assert_eq!(fills, vec![(1, 1), (2, 2), (2, 3), (3, 4), (4, 5), (5, 6)]);
+
+        // a log cut off anywhere keeps exactly the transactions committed
+        // before the cut
+        let record_ends = (1 .. 7).map(|i| i * PAGE_RECORD_SIZE);
+        let cuts: Vec<usize> = (0 .. bytes.len()).step_by(997).chain(record_ends).chain(ends.iter().map(|&e| e as usize)).collect();
+        for cut in cuts {
+            let recovered = Recovered::from_bytes(&bytes[.. cut]);
+            let committed = ends.iter().filter(|&&e| e <= cut as u64).count() - 1;
+            assert_eq!(recovered.last_txn, committed as u64, "cut at {}", cut);
+            assert_eq!(recovered.len, ends[committed]);
+        }
+
+        // a damaged record ends the log
+        bytes[PAGE_RECORD_SIZE * 2 + COMMIT_RECORD_SIZE + 100] ^= 1;
+        assert_eq!(Recovered::from_bytes(&bytes).last_txn, 1);
+
+        // reopening truncates the log after the last good commit, and
+        // continues numbering transactions
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        let (mut log, recovered) = WriteAheadLog::open(&path, SyncPolicy::EveryCommit).unwrap();
+        assert_eq!(recovered.len, ends[1]);
+        assert_eq!(path.metadata().unwrap().len(), ends[1]);
+        log.append(&txn(&[(9, 9)])).unwrap();
+        let recovered = Recovered::from_file(&path).unwrap();
+        assert_eq!(recovered.last_txn, 2);
+        assert_eq!(recovered.pages.len(), 3);
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let dir = TempDir::new("soupdb").unwrap();
+        let (mut log, _) = WriteAheadLog::open(&dir.path().join("test.wal"), SyncPolicy::Grouped(3)).unwrap();
+        assert_eq!(log.append(&txn(&[(1, 1)])), Ok(false));
+        assert_eq!(log.append(&txn(&[(1, 2)])), Ok(false));
+        assert_eq!(log.append(&txn(&[(1, 3)])), Ok(true));
+        assert_eq!(log.append(&txn(&[(1, 4)])), Ok(false));
+        log.sync().unwrap();
+        assert_eq!(log.unsynced, 0);
+
+        log.set_policy(SyncPolicy::Off);
+        assert_eq!(log.append(&txn(&[(1, 5)])), Ok(true));
+        log.set_policy(SyncPolicy::EveryCommit);
+        assert_eq!(log.append(&txn(&[(1, 6)])), Ok(true));
+        assert_eq!(log.unsynced, 0);
+    }
+}
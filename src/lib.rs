@@ -6,12 +6,17 @@
 
 #[macro_use] extern crate nom;
 extern crate byteorder;
+extern crate crc;
 extern crate glob;
 extern crate lru_cache;
+extern crate memmap;
+#[cfg(test)] extern crate quickcheck;
+#[cfg(test)] extern crate tempdir;
 
 pub mod ast;
 pub mod config;
 pub mod db;
+pub mod exec;
 pub mod io;
 pub mod model;
 
@@ -22,6 +27,13 @@ pub enum Error {
     NotYetImplemented,
     TypeError(String),
     IoError(String),
+    /// Data read from disk failed an integrity check.
+    Corruption(String),
+    /// A lock on a page wasn't granted before the lock timeout passed.
+    LockTimeout(String),
+    /// Waiting for a lock on a page would never end, because its holder is
+    /// waiting for a lock held by the requester.
+    Deadlock(String),
     ParseError(String),
     Custom(String),
 }
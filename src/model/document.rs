@@ -73,7 +73,7 @@ mod tests {
 
         assert_eq!(
             test_ddl,
-            (Model {name: "test_doc".to_string(), schema: Box::new(Document {})}).to_ddl()
+            (Model {name: "test_doc".to_string(), schema: Box::new(Document {}), indexes: vec![]}).to_ddl()
         );
 
         // parse the DDL into a create model command, check that the model can
@@ -35,7 +35,7 @@ mod tests {
             (Model {name: "test_geohash".to_string(), schema: Box::new(GeoHash {schema: TupleDef(vec![
                 TupleEntry {name: "col_1".to_string(), value: ValueType::Int},
                 TupleEntry {name: "col_2".to_string(), value: ValueType::Nullable(Box::new(ValueType::Vector(3, Box::new(ValueType::Float))))},
-            ])})}).to_ddl()
+            ])}), indexes: vec![]}).to_ddl()
         );
 
         // parse the DDL into a create model command, check that the model can
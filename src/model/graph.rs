@@ -39,7 +39,7 @@ mod tests {
                 TupleEntry {name: "col_2".to_string(), value: ValueType::Nullable(Box::new(ValueType::Vector(3, Box::new(ValueType::Float))))},
             ]), edge_schema: TupleDef(vec![
                 TupleEntry {name: "edge_length".to_string(), value: ValueType::Float},
-            ])})}).to_ddl()
+            ])}), indexes: vec![]}).to_ddl()
         );
 
         // parse the DDL into a create model command, check that the model can
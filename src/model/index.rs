@@ -0,0 +1,45 @@
+/// A secondary index over some of a TABLE's columns.
+///
+/// ```sql
+/// CREATE UNIQUE INDEX by_name ON inventory (name);
+/// ```
+///
+/// Each index is stored as a B+tree in its table's file, keyed by the
+/// indexed columns followed by the rowid, so that it can hold duplicate
+/// values unless it's UNIQUE. Index names only need to be unique within
+/// their table.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Index {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub unique: bool,
+}
+
+impl Index {
+    pub fn to_ddl(&self, model: &str) -> String {
+        format!(
+            "create {}index {} on {} ({});",
+            if self.unique {"unique "} else {""},
+            self.name,
+            model,
+            self.columns.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::ast::command::Command;
+    use ::ast::parse::parse_command;
+
+    #[test]
+    fn test_index_ddl() {
+        for ddl in &["create index by_a on t (a);", "create unique index by_a_b on t (a, b);"] {
+            match parse_command(ddl) {
+                Ok(Command::CreateIndex {model, index}) => assert_eq!(&index.to_ddl(&model), ddl),
+                c => panic!("unexpected {:?}", c),
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
 pub mod document;
 pub mod geohash;
 pub mod graph;
+pub mod index;
 pub mod table;
 pub mod timeseries;
 
@@ -9,12 +10,19 @@ use std::io::Write;
 use ::ast::command::Command;
 use ::ast::parse::parse_command;
 use ::ast::tuple::TupleDef;
+use ::model::index::Index;
+use ::model::table::Table;
 use ::{Error, Result};
 
 pub trait ModelType: Debug {
     fn rowid_schema(&self) -> Option<TupleDef> {
         None
     }
+    /// Returns this model as a TABLE, if it is one; only tables can currently
+    /// be queried with SQL.
+    fn as_table(&self) -> Option<&Table> {
+        None
+    }
     fn to_ddl(&self, name: &str) -> String;
 }
 
@@ -22,26 +30,53 @@ pub trait ModelType: Debug {
 pub struct Model {
     pub name: String,
     pub schema: Box<ModelType>,
+    pub indexes: Vec<Index>,
 }
 
 impl Model {
     pub fn new(name: String, schema: Box<ModelType>) -> Self {
-        Model {name: name, schema: schema}
+        Model {name: name, schema: schema, indexes: vec![]}
     }
 
+    /// Parse a model from its DDL, followed by the DDL of its indexes with
+    /// one statement per line.
     pub fn from_ddl(ddl: &str) -> Result<Model> {
-        match parse_command(ddl) {
-            Ok(Command::CreateModel {name: n, schema: s}) => Ok(Model {name: n, schema: s}),
-            Ok(c) => Err(Error::ParseError(format!("invalid DDL: {:?}", c))),
-            Err(e) => Err(e),
+        let mut lines = ddl.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        let mut model = match parse_command(lines.next().unwrap_or("")) {
+            Ok(Command::CreateModel {name: n, schema: s}) => Model::new(n, s),
+            Ok(c) => return Err(Error::ParseError(format!("invalid DDL: {:?}", c))),
+            Err(e) => return Err(e),
+        };
+        for line in lines {
+            match parse_command(line) {
+                Ok(Command::CreateIndex {model: ref m, ref index}) if *m == model.name => model.indexes.push(index.clone()),
+                Ok(c) => return Err(Error::ParseError(format!("invalid index DDL for {}: {:?}", model.name, c))),
+                Err(e) => return Err(e),
+            }
         }
+        Ok(model)
     }
 
     pub fn to_ddl(&self) -> String {
         self.schema.to_ddl(&self.name)
     }
 
-    pub fn write_schema(&self, mut to: &mut Write) {
-        to.write(self.to_ddl().as_bytes()).unwrap();
+    /// The model's DDL followed by that of the given indexes, as it's stored
+    /// in a schema file.
+    pub fn schema_ddl<'a, I: IntoIterator<Item = &'a Index>>(&self, indexes: I) -> String {
+        let mut ddl = self.to_ddl();
+        for index in indexes {
+            ddl.push('\n');
+            ddl.push_str(&index.to_ddl(&self.name));
+        }
+        ddl
+    }
+
+    pub fn write_schema(&self, mut to: &mut Write) -> Result<()> {
+        let ddl = self.schema_ddl(&self.indexes);
+        match to.write_all(ddl.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Error::IoError(format!("failed to write schema for {}: {}", self.name, e))),
+        }
     }
 }
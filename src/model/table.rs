@@ -33,10 +33,14 @@ pub struct Table {
 impl ModelType for Table {
     fn rowid_schema(&self) -> Option<TupleDef> {
         Some(TupleDef(vec![
-            TupleEntry {name: "rowid".to_string(), value: ValueType::Uint}
+            TupleEntry {name: "rowid".to_string(), value: ValueType::AutoId}
         ]))
     }
 
+    fn as_table(&self) -> Option<&Table> {
+        Some(self)
+    }
+
     fn to_ddl(&self, name: &str) -> String {
         format!("create table {} {};", name, self.schema.to_ddl())
     }
@@ -58,7 +62,7 @@ mod tests {
             (Model {name: "test_table".to_string(), schema: Box::new(Table {schema: TupleDef(vec![
                 TupleEntry {name: "col_1".to_string(), value: ValueType::Int},
                 TupleEntry {name: "col_2".to_string(), value: ValueType::Nullable(Box::new(ValueType::Vector(3, Box::new(ValueType::Float))))},
-            ])})}).to_ddl()
+            ])}), indexes: vec![]}).to_ddl()
         );
 
         // parse the DDL into a create model command, check that the model can